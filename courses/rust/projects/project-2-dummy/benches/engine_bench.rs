@@ -0,0 +1,183 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use kvs::{Config, IndexKind, IoBackend, KvStore};
+use rand::prelude::*;
+use tempfile::TempDir;
+
+const INDEX_KINDS: [(&str, IndexKind); 2] =
+    [("ordered", IndexKind::Ordered), ("hash", IndexKind::Hash)];
+
+fn index_set_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index_set_bench");
+    for &(name, kind) in &INDEX_KINDS {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let config = Config::default().with_index_kind(kind);
+                    (KvStore::open_with_config(temp_dir.path(), config).unwrap(), temp_dir)
+                },
+                |(mut store, _temp_dir)| {
+                    for i in 1..(1 << 12) {
+                        store.set(format!("key{}", i), "value".to_string()).unwrap();
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn index_get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("index_get_bench");
+    for &(name, kind) in &INDEX_KINDS {
+        group.bench_function(name, |b| {
+            let temp_dir = TempDir::new().unwrap();
+            let config = Config::default().with_index_kind(kind);
+            let mut store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+            for key_i in 1..(1 << 16) {
+                store
+                    .set(format!("key{}", key_i), "value".to_string())
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1, 1 << 16)))
+                    .unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+fn get_ref_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_ref_bench");
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    let keys: Vec<String> = (1..(1 << 12)).map(|i| format!("key{}", i)).collect();
+    for key in &keys {
+        store.set(key.clone(), "value".to_string()).unwrap();
+    }
+    group.bench_function("owned", |b| {
+        let mut rng = SmallRng::from_seed([0; 16]);
+        b.iter(|| {
+            let key = &keys[rng.gen_range(0, keys.len())];
+            store.get(key.clone()).unwrap();
+        })
+    });
+    group.bench_function("borrowed", |b| {
+        let mut rng = SmallRng::from_seed([0; 16]);
+        b.iter(|| {
+            let key = &keys[rng.gen_range(0, keys.len())];
+            store.get_ref(key).unwrap();
+        })
+    });
+    group.finish();
+}
+
+/// Drops the page cache of every log file under `dir`, so the next scan
+/// starts from a cold cache.
+#[cfg(target_os = "linux")]
+fn evict_page_cache(dir: &std::path::Path) {
+    use std::os::unix::io::AsRawFd;
+
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let f = std::fs::File::open(entry.unwrap().path()).unwrap();
+        f.sync_all().unwrap();
+        unsafe {
+            libc::posix_fadvise(f.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn evict_page_cache(_dir: &std::path::Path) {}
+
+fn cold_scan_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cold_scan_bench");
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    for key_i in 1..(1 << 14) {
+        store
+            .set(format!("key{}", key_i), "value".repeat(16))
+            .unwrap();
+    }
+    group.bench_function("scan_unordered", |b| {
+        b.iter_batched(
+            || evict_page_cache(temp_dir.path()),
+            |_| store.scan_unordered().for_each(|item| drop(item.unwrap())),
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn multi_get_bench(c: &mut Criterion) {
+    let backends = [
+        ("standard", IoBackend::Standard),
+        #[cfg(feature = "uring")]
+        ("uring", IoBackend::Uring),
+        #[cfg(feature = "mmap")]
+        ("mmap", IoBackend::Mmap),
+    ];
+    let mut group = c.benchmark_group("multi_get_bench");
+    for &(name, backend) in &backends {
+        group.bench_function(name, |b| {
+            let temp_dir = TempDir::new().unwrap();
+            let config = Config::default().with_io_backend(backend);
+            let mut store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+            for key_i in 1..(1 << 14) {
+                store
+                    .set(format!("key{}", key_i), "value".repeat(16))
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            let keys: Vec<String> = (0..4096)
+                .map(|_| format!("key{}", rng.gen_range(1, 1 << 14)))
+                .collect();
+            let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+            b.iter(|| store.multi_get(&keys).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn backend_get_bench(c: &mut Criterion) {
+    let backends = [
+        ("file", IoBackend::Standard),
+        #[cfg(feature = "mmap")]
+        ("mmap", IoBackend::Mmap),
+    ];
+    let mut group = c.benchmark_group("backend_get_bench");
+    for &(name, backend) in &backends {
+        group.bench_function(name, |b| {
+            let temp_dir = TempDir::new().unwrap();
+            let config = Config::default().with_io_backend(backend);
+            let mut store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+            for key_i in 1..(1 << 14) {
+                store
+                    .set(format!("key{}", key_i), "value".repeat(16))
+                    .unwrap();
+            }
+            let mut rng = SmallRng::from_seed([0; 16]);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1, 1 << 14)))
+                    .unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    index_set_bench,
+    index_get_bench,
+    get_ref_bench,
+    cold_scan_bench,
+    multi_get_bench,
+    backend_get_bench
+);
+criterion_main!(benches);
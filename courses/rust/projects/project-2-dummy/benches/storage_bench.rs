@@ -0,0 +1,246 @@
+use criterion::{
+    criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use kvs::{Config, KvStore, SyncPolicy};
+use rand::prelude::*;
+use std::io::Read;
+use tempfile::TempDir;
+
+/// Number of keys written or read per iteration.
+const KEYS: usize = 1 << 12;
+
+const VALUE_SIZES: [usize; 3] = [16, 1024, 16 * 1024];
+
+const SYNC_POLICIES: [(&str, SyncPolicy); 2] =
+    [("never", SyncPolicy::Never), ("every_write", SyncPolicy::OnEveryWrite)];
+
+/// Opens an empty store in a fresh temp dir. The dir must outlive the store.
+fn empty_store(config: Config) -> (KvStore, TempDir) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open_with_config(temp_dir.path(), config).unwrap();
+    (store, temp_dir)
+}
+
+/// Opens a store holding `key0..key{KEYS}` with values of `value_size` bytes.
+fn filled_store(config: Config, value_size: usize) -> (KvStore, TempDir) {
+    let (mut store, temp_dir) = empty_store(config);
+    let value = "x".repeat(value_size);
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), value.clone()).unwrap();
+    }
+    (store, temp_dir)
+}
+
+/// Samples key indexes in `0..n` where key `i` is picked with probability
+/// proportional to `1 / (i + 1)^s`.
+struct Zipf {
+    cdf: Vec<f64>,
+}
+
+impl Zipf {
+    fn new(n: usize, s: f64) -> Self {
+        let mut sum = 0.0;
+        let mut cdf: Vec<f64> = (0..n)
+            .map(|i| {
+                sum += 1.0 / ((i + 1) as f64).powf(s);
+                sum
+            })
+            .collect();
+        cdf.iter_mut().for_each(|p| *p /= sum);
+        Zipf { cdf }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> usize {
+        let p: f64 = rng.gen();
+        match self.cdf.binary_search_by(|c| c.partial_cmp(&p).unwrap()) {
+            Ok(i) | Err(i) => i.min(self.cdf.len() - 1),
+        }
+    }
+}
+
+fn set_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("set");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(KEYS as u64));
+    for &(policy_name, policy) in &SYNC_POLICIES {
+        for &value_size in &VALUE_SIZES {
+            let value = "x".repeat(value_size);
+            for &random in &[false, true] {
+                let order = if random { "random" } else { "sequential" };
+                let mut keys: Vec<usize> = (0..KEYS).collect();
+                if random {
+                    keys.shuffle(&mut SmallRng::from_seed([0; 16]));
+                }
+                let id = BenchmarkId::new(format!("{}/{}", order, policy_name), value_size);
+                group.bench_with_input(id, &keys, |b, keys| {
+                    b.iter_batched(
+                        || empty_store(Config::default().with_sync_policy(policy)),
+                        |(mut store, _temp_dir)| {
+                            for i in keys {
+                                store.set(format!("key{}", i), value.clone()).unwrap();
+                            }
+                        },
+                        BatchSize::PerIteration,
+                    )
+                });
+            }
+        }
+    }
+    group.finish();
+}
+
+fn get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    let zipf = Zipf::new(KEYS, 1.0);
+    for &value_size in &VALUE_SIZES {
+        let (mut store, _temp_dir) = filled_store(Config::default(), value_size);
+        let mut rng = SmallRng::from_seed([0; 16]);
+        let uniform: Vec<String> = (0..KEYS)
+            .map(|_| format!("key{}", rng.gen_range(0, KEYS)))
+            .collect();
+        let zipfian: Vec<String> = (0..KEYS)
+            .map(|_| format!("key{}", zipf.sample(&mut rng)))
+            .collect();
+        for (name, keys) in &[("uniform", uniform), ("zipfian", zipfian)] {
+            group.bench_with_input(BenchmarkId::new(*name, value_size), keys, |b, keys| {
+                b.iter(|| {
+                    for key in keys {
+                        store.get_ref(key).unwrap();
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// `get_ref` reads a value with a seek and a single read, since the index
+/// knows where the value starts and how long it is. `get_reader` still reads
+/// the record header first: a seek and two small reads before seeking to the
+/// value. It also opens its own file handle, so the gap between the two is an
+/// upper bound on what the header reads cost.
+fn get_index_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get_index");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    for &value_size in &VALUE_SIZES {
+        let (mut store, _temp_dir) = filled_store(Config::default(), value_size);
+        let mut rng = SmallRng::from_seed([0; 16]);
+        let keys: Vec<String> = (0..KEYS)
+            .map(|_| format!("key{}", rng.gen_range(0, KEYS)))
+            .collect();
+        group.bench_with_input(BenchmarkId::new("from_index", value_size), &keys, |b, keys| {
+            b.iter(|| {
+                for key in keys {
+                    store.get_ref(key).unwrap();
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("from_header", value_size), &keys, |b, keys| {
+            b.iter(|| {
+                let mut value = Vec::with_capacity(value_size);
+                for key in keys {
+                    value.clear();
+                    store.get_reader(key).unwrap().unwrap().read_to_end(&mut value).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn overwrite_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("overwrite");
+    group.sample_size(10);
+    // Writes a few times more than the compaction threshold, so every
+    // iteration compacts.
+    let rounds = 8;
+    group.throughput(Throughput::Elements((KEYS * rounds) as u64));
+    for &max_log_size in &[64 * 1024, 1024 * 1024] {
+        let config = Config::default().with_max_log_size(max_log_size);
+        group.bench_with_input(
+            BenchmarkId::new("max_log_size", max_log_size),
+            &config,
+            |b, config| {
+                let value = "x".repeat(256);
+                b.iter_batched(
+                    || empty_store(config.clone()),
+                    |(mut store, _temp_dir)| {
+                        for round in 0..rounds {
+                            for i in 0..KEYS {
+                                store.set(format!("key{}", i), format!("{}{}", round, value)).unwrap();
+                            }
+                        }
+                    },
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Open time of a large directory: every record is a new key, or most
+/// records overwrite one of a few keys.
+fn reopen_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reopen");
+    group.sample_size(10);
+    let records = 1 << 17;
+    group.throughput(Throughput::Elements(records as u64));
+    for &(name, keys) in &[("unique", records), ("overwrites", 1 << 10)] {
+        for &max_log_size in &[64 * 1024, 1024 * 1024] {
+            let config = Config::default()
+                .with_max_log_size(max_log_size)
+                .with_auto_compaction(false);
+            let (mut store, temp_dir) = empty_store(config.clone());
+            for i in 0..records {
+                store.set(format!("key{}", i % keys), "value".to_owned()).unwrap();
+            }
+            drop(store);
+            group.bench_with_input(BenchmarkId::new(name, max_log_size), &config, |b, config| {
+                b.iter(|| KvStore::open_with_config(temp_dir.path(), config.clone()).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Store sizes (live keys) for `compact_bench`, overridable with a
+/// comma-separated list in `KVS_BENCH_COMPACT_KEYS`.
+fn compact_sizes() -> Vec<usize> {
+    match std::env::var("KVS_BENCH_COMPACT_KEYS") {
+        Ok(sizes) => sizes.split(',').map(|v| v.trim().parse().unwrap()).collect(),
+        Err(_) => vec![1 << 12, 1 << 15],
+    }
+}
+
+/// A full `compact` of a store where every key was written twice, so half
+/// of the log is stale.
+fn compact_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compact");
+    group.sample_size(10);
+    let value = "x".repeat(256);
+    for keys in compact_sizes() {
+        group.throughput(Throughput::Elements(keys as u64));
+        group.bench_with_input(BenchmarkId::new("keys", keys), &keys, |b, &keys| {
+            b.iter_batched(
+                || {
+                    let config = Config::default().with_auto_compaction(false);
+                    let (mut store, temp_dir) = empty_store(config);
+                    for round in 0..2 {
+                        for i in 0..keys {
+                            store.set(format!("key{}", i), format!("{}{}", round, value)).unwrap();
+                        }
+                    }
+                    (store, temp_dir)
+                },
+                |(mut store, _temp_dir)| store.compact().unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set_bench, get_bench, get_index_bench, overwrite_bench, reopen_bench, compact_bench);
+criterion_main!(benches);
@@ -0,0 +1,68 @@
+use std::fs::File;
+use std::io;
+
+/// 给内核的文件访问模式提示(`posix_fadvise`)
+///
+/// 只是提示, 调用失败也不影响正确性, 所以错误直接忽略.
+/// 不支持`posix_fadvise`的平台上全部是空操作.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum FileAdvice {
+    /// 接下来会从头到尾顺序读整个文件, 加大预读并提前读进page cache
+    Sequential,
+    /// 恢复默认的预读, 顺序读完之后文件还要用来随机读时用
+    Normal,
+    /// 文件读完了(比如compact之后马上要删掉), 它的page不要再占着cache
+    DontNeed,
+}
+
+#[cfg(test)]
+thread_local! {
+    /// 测试用: 当作这个平台不支持`posix_fadvise`, 每次提示都失败
+    static UNSUPPORTED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// 测试用: 让当前线程之后的提示都失败
+#[cfg(test)]
+pub(crate) fn set_unsupported(unsupported: bool) {
+    UNSUPPORTED.with(|v| v.set(unsupported));
+}
+
+impl FileAdvice {
+    /// 给`f`提示, 失败了也不管
+    pub(crate) fn apply(self, f: &File) {
+        let _ = self.advise(f);
+    }
+
+    /// 给`f`提示, 返回第一个失败的`posix_fadvise`的错误
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    pub(crate) fn advise(self, f: &File) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        #[cfg(test)]
+        {
+            if UNSUPPORTED.with(|v| v.get()) {
+                return Err(io::ErrorKind::Unsupported.into());
+            }
+        }
+        let advice: &[libc::c_int] = match self {
+            FileAdvice::Sequential => &[libc::POSIX_FADV_SEQUENTIAL, libc::POSIX_FADV_WILLNEED],
+            FileAdvice::Normal => &[libc::POSIX_FADV_NORMAL],
+            FileAdvice::DontNeed => &[libc::POSIX_FADV_DONTNEED],
+        };
+        let fd = f.as_raw_fd();
+        let mut result = Ok(());
+        for &advice in advice {
+            // offset和len都是0表示整个文件; 出错时直接返回错误码, 不设errno
+            let ret = unsafe { libc::posix_fadvise(fd, 0, 0, advice) };
+            if ret != 0 && result.is_ok() {
+                result = Err(io::Error::from_raw_os_error(ret));
+            }
+        }
+        result
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    pub(crate) fn advise(self, _f: &File) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
@@ -0,0 +1,77 @@
+use clap::{App, Arg};
+use kvs::{KeyIndex, KvStore, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// 一个generation里各种记录的数量
+#[derive(Default)]
+struct Counts {
+    records: u64,
+    live: u64,
+    stale: u64,
+    tombstones: u64,
+    bytes: u64,
+}
+
+impl Counts {
+    fn print(&self, label: &str) {
+        println!(
+            "{} records={} live={} stale={} tombstones={} bytes={}",
+            label, self.records, self.live, self.stale, self.tombstones, self.bytes
+        );
+    }
+}
+
+fn main() -> Result<()> {
+    let matches = App::new("kvs-inspect")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Print the log layout of a kvs store without modifying it")
+        .arg(Arg::with_name("DIR").help("The store directory").required(true))
+        .get_matches();
+    let dir = Path::new(matches.value_of("DIR").unwrap());
+
+    // 只读打开, 不会创建新的generation, 也不会删掉空的log
+    let store = KvStore::open_read_only(dir)?;
+    let mut generations: BTreeMap<u64, Counts> = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            if let Some(n) = path.file_stem().and_then(|v| v.to_str()).and_then(|v| v.parse().ok()) {
+                generations.entry(n).or_default().bytes = fs::metadata(&path)?.len();
+            }
+        }
+    }
+
+    for record in store.log_cursor() {
+        let record = record?;
+        let counts = generations.entry(record.generation).or_default();
+        counts.records += 1;
+        if record.value.is_none() {
+            counts.tombstones += 1;
+            continue;
+        }
+        let live = std::str::from_utf8(&record.key)
+            .ok()
+            .and_then(|k| store.index().get(k))
+            .is_some_and(|v| v.generation() == record.generation && v.pos() == record.pos);
+        if live {
+            counts.live += 1;
+        } else {
+            counts.stale += 1;
+        }
+    }
+
+    let mut total = Counts::default();
+    for (n, counts) in &generations {
+        counts.print(&format!("generation={}", n));
+        total.records += counts.records;
+        total.live += counts.live;
+        total.stale += counts.stale;
+        total.tombstones += counts.tombstones;
+        total.bytes += counts.bytes;
+    }
+    total.print("total");
+    Ok(())
+}
@@ -31,6 +31,7 @@ fn main() -> Result<()> {
                 .about("Remove a given key")
                 .arg(Arg::with_name("KEY").help("A string key").required(true)),
         )
+        .subcommand(SubCommand::with_name("stats").about("Print statistics and metadata of the store"))
         .get_matches();
 
     match matches.subcommand() {
@@ -64,6 +65,18 @@ fn main() -> Result<()> {
                 Err(e) => return Err(e),
             }
         }
+        ("stats", Some(_)) => {
+            let store = KvStore::open(current_dir()?)?;
+            let stats = store.stats();
+            println!("keys={}", stats.keys);
+            println!("generations={}", stats.generations);
+            println!("uncompacted_bytes={}", stats.uncompacted_bytes);
+            println!("dedup_bytes_saved={}", stats.dedup_bytes_saved);
+            println!("value_log_garbage_bytes={}", stats.value_log_garbage_bytes);
+            for (key, value) in &stats.meta {
+                println!("meta.{}={}", key, value);
+            }
+        }
         _ => unreachable!(),
     }
     Ok(())
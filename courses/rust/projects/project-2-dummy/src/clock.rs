@@ -0,0 +1,64 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The source of the current time that decides when keys written with
+/// `KvStore::set_with_ttl` expire, see `Config::with_clock`.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock, used unless `Config::with_clock` says otherwise.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A `Clock` that only moves when told to, e.g. to test expiry without
+/// sleeping.
+///
+/// ```rust
+/// # use std::time::{Duration, UNIX_EPOCH};
+/// # use kvs::{Clock, ManualClock};
+/// let clock = ManualClock::new(UNIX_EPOCH);
+/// clock.advance(Duration::from_secs(60));
+/// assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(60));
+/// ```
+#[derive(Debug)]
+pub struct ManualClock {
+    /// Unix时间, 毫秒
+    millis: AtomicU64,
+}
+
+impl ManualClock {
+    /// Creates a clock standing at `now`, with millisecond precision.
+    pub fn new(now: SystemTime) -> Self {
+        ManualClock { millis: AtomicU64::new(to_millis(now)) }
+    }
+
+    /// Moves the clock to `now`, which may also be in the past.
+    pub fn set(&self, now: SystemTime) {
+        self.millis.store(to_millis(now), Ordering::SeqCst);
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        self.millis.fetch_add(by.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.millis.load(Ordering::SeqCst))
+    }
+}
+
+/// `now`的Unix时间, 毫秒; 早于1970年的算0
+pub(crate) fn to_millis(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
@@ -0,0 +1,50 @@
+use std::io;
+
+/// Compression applied to large values before they are written to the log.
+///
+/// Codecs other than `Codec::None` need the `compression` cargo feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// Values are stored as-is.
+    #[default]
+    None,
+    /// LZ4 block compression.
+    #[cfg(feature = "compression")]
+    Lz4,
+}
+
+impl Codec {
+    /// 压缩value, 压缩后没有变小的话返回None
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    pub(crate) fn compress(self, v: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Codec::None => None,
+            #[cfg(feature = "compression")]
+            Codec::Lz4 => {
+                let compressed = lz4_flex::compress_prepend_size(v);
+                if compressed.len() < v.len() {
+                    Some(compressed)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// 解压一个带压缩标记的value
+pub(crate) fn decompress(v: &[u8]) -> io::Result<Vec<u8>> {
+    #[cfg(feature = "compression")]
+    {
+        lz4_flex::decompress_size_prepended(v)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    #[cfg(not(feature = "compression"))]
+    {
+        let _ = v;
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "compressed value, but kvs was built without the `compression` feature",
+        ))
+    }
+}
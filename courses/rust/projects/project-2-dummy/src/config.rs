@@ -0,0 +1,308 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Clock, Codec, IndexKind, SystemClock};
+
+/// Controls when writes to the log are forced to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Records are handed to the OS after every write, or once the buffer of
+    /// `Config::with_write_buffer_size` fills up, but never explicitly synced.
+    #[default]
+    Never,
+    /// Every `set` and `remove` calls `sync_data` before returning. The first
+    /// write to a newly created log file also syncs the directory, so the
+    /// file itself survives a crash.
+    OnEveryWrite,
+}
+
+/// How `KvStore` issues reads of values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoBackend {
+    /// Plain positioned reads, one syscall each.
+    Standard,
+    /// Batches of reads submitted through io_uring. Needs the `uring` cargo
+    /// feature; falls back to `IoBackend::Standard` when the kernel does not
+    /// support io_uring or the platform is not Linux.
+    #[cfg(feature = "uring")]
+    Uring,
+    /// Reads copied out of memory mappings of the log files, without a
+    /// syscall per read. Needs the `mmap` cargo feature; falls back to
+    /// `IoBackend::Standard` on platforms other than Unix. A log file is
+    /// mapped again once a read goes past the end of its mapping, e.g.
+    /// after records were appended to the active log.
+    #[cfg(feature = "mmap")]
+    Mmap,
+}
+
+impl Default for IoBackend {
+    /// `IoBackend::Uring` when the `uring` feature is enabled, else
+    /// `IoBackend::Standard`.
+    fn default() -> Self {
+        #[cfg(feature = "uring")]
+        return IoBackend::Uring;
+        #[cfg(not(feature = "uring"))]
+        IoBackend::Standard
+    }
+}
+
+/// Default size at which the active log is sealed and a new generation started.
+pub const DEFAULT_MAX_LOG_SIZE: u64 = 1024 * 1024;
+
+/// Default maximum number of log files kept open for reading.
+pub const DEFAULT_MAX_OPEN_FILES: usize = 256;
+
+/// 一条记录最多放多少字节的value, 再大就分块
+const CHUNK_SIZE: u64 = 1 << 31;
+
+/// Default size above which values are compressed when a `Codec` is set.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Default size from which values go to the value log when it is enabled.
+pub const DEFAULT_VALUE_LOG_THRESHOLD: usize = 4096;
+
+/// Default number of generations `KvStore::compact_tiered` merges at once.
+pub const DEFAULT_COMPACTION_TIERS: usize = 4;
+
+/// Options for opening a `KvStore`.
+///
+/// ```rust
+/// # use kvs::{Config, SyncPolicy};
+/// let config = Config::default().with_sync_policy(SyncPolicy::OnEveryWrite);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) max_log_size: u64,
+    pub(crate) index_kind: IndexKind,
+    pub(crate) compression: Codec,
+    pub(crate) compression_threshold: usize,
+    pub(crate) direct_io: bool,
+    pub(crate) io_backend: IoBackend,
+    pub(crate) max_open_files: usize,
+    pub(crate) dedup: bool,
+    pub(crate) auto_compaction: bool,
+    /// 超过这个大小的value分块存储, 测试里会改小
+    pub(crate) chunk_size: u64,
+    pub(crate) read_only: bool,
+    pub(crate) value_log: bool,
+    pub(crate) value_log_threshold: usize,
+    pub(crate) max_value_size: Option<u64>,
+    pub(crate) lock_timeout: Duration,
+    pub(crate) compaction_tiers: usize,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) write_buffer_size: usize,
+    pub(crate) keep_history: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            sync_policy: SyncPolicy::default(),
+            max_log_size: DEFAULT_MAX_LOG_SIZE,
+            index_kind: IndexKind::default(),
+            compression: Codec::default(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            direct_io: false,
+            io_backend: IoBackend::default(),
+            max_open_files: DEFAULT_MAX_OPEN_FILES,
+            dedup: false,
+            auto_compaction: true,
+            chunk_size: CHUNK_SIZE,
+            read_only: false,
+            value_log: false,
+            value_log_threshold: DEFAULT_VALUE_LOG_THRESHOLD,
+            max_value_size: None,
+            lock_timeout: Duration::ZERO,
+            compaction_tiers: DEFAULT_COMPACTION_TIERS,
+            clock: Arc::new(SystemClock),
+            write_buffer_size: 0,
+            keep_history: false,
+        }
+    }
+}
+
+impl Config {
+    /// Sets the policy used to sync `set` and `remove` to disk.
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets the size in bytes at which the active log file is sealed.
+    ///
+    /// Writes go to a new generation once the active one reaches this size,
+    /// and `open` keeps appending to the newest generation while it is below it.
+    pub fn with_max_log_size(mut self, max_log_size: u64) -> Self {
+        self.max_log_size = max_log_size;
+        self
+    }
+
+    /// Sets the structure used for the in-memory index.
+    pub fn with_index_kind(mut self, index_kind: IndexKind) -> Self {
+        self.index_kind = index_kind;
+        self
+    }
+
+    /// Sets the codec used to compress large values.
+    ///
+    /// Existing records are readable whatever codec is configured.
+    pub fn with_compression(mut self, codec: Codec) -> Self {
+        self.compression = codec;
+        self
+    }
+
+    /// Sets the value size in bytes above which values are compressed.
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// Opens the active log with `O_DIRECT`, bypassing the page cache.
+    ///
+    /// Writes are buffered into aligned blocks and the last block is padded,
+    /// so sealed log files may end with padding. Reads stay buffered. Only
+    /// supported on Linux; elsewhere `open` returns
+    /// `KvsError::UnsupportedOperation`. Not every filesystem supports
+    /// `O_DIRECT` (e.g. tmpfs), in which case `open` fails with an I/O error.
+    pub fn with_direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// Sets the size of the buffer records are collected in before they are
+    /// written to the log, in bytes. 0 (the default) writes every part of a
+    /// record straight to the file.
+    ///
+    /// Records stay in the buffer across operations, so several `set`s and
+    /// `remove`s are handed to the OS in one write. The buffer is written out
+    /// once it is full, before the store reads its own log files (e.g. `get`,
+    /// scans and compaction), on `close` and drop, and after every write
+    /// under `SyncPolicy::OnEveryWrite`. Until then a crash of the process
+    /// loses the buffered records, and other processes don't see them.
+    /// Records larger than the buffer bypass it. Ignored with
+    /// `with_direct_io`, which has its own buffer.
+    pub fn with_write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = size;
+        self
+    }
+
+    /// Sets the maximum number of log files kept open for reading.
+    ///
+    /// Files are opened lazily on the first read of their generation, and the
+    /// least recently used one is closed once the limit is hit. The active log
+    /// has its own write handle, which isn't counted.
+    pub fn with_max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    /// Deduplicates large values written by `set`.
+    ///
+    /// When a value was already written since the store was opened, only a
+    /// small reference to the existing record is appended. Values are compared
+    /// byte for byte, so hash collisions are harmless. Compaction keeps the
+    /// referenced data alive even when its own key is gone.
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Compacts the log automatically from `set` and `transaction` once
+    /// enough stale data has piled up. Enabled by default.
+    ///
+    /// Disable it to schedule the IO burst of compaction yourself with
+    /// `KvStore::compact_if_needed`.
+    pub fn with_auto_compaction(mut self, auto_compaction: bool) -> Self {
+        self.auto_compaction = auto_compaction;
+        self
+    }
+
+    /// Opens the store for reading only.
+    ///
+    /// Log files are opened with read permission only and the directory is
+    /// never written to: no files are created, truncated or deleted. All
+    /// writes return `KvsError::ReadOnly` and `compact` does nothing.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Stores large values written by `set` in separate value log files
+    /// (`{generation}.vlog`), leaving only a small pointer in the main log.
+    ///
+    /// Compaction then only rewrites keys and pointers. Space of overwritten
+    /// values is reclaimed by `KvStore::gc_value_log`, which also runs from
+    /// `compact_if_needed`. Values written by `set_from_reader` and
+    /// `transaction` stay in the main log. Existing value logs are readable
+    /// whether this is enabled or not. The first value written to the value
+    /// log upgrades the directory to log format version 3, which older
+    /// versions of `kvs` refuse to open.
+    pub fn with_value_log(mut self, value_log: bool) -> Self {
+        self.value_log = value_log;
+        self
+    }
+
+    /// Sets the value size in bytes from which values go to the value log.
+    pub fn with_value_log_threshold(mut self, threshold: usize) -> Self {
+        self.value_log_threshold = threshold;
+        self
+    }
+
+    /// Sets the largest value in bytes that writes accept.
+    ///
+    /// `set`, `set_from_reader`, `transaction` and the other writes reject
+    /// larger values with `KvsError::ValueTooLarge` before writing anything.
+    /// Unlimited by default.
+    pub fn with_max_value_size(mut self, max_value_size: u64) -> Self {
+        self.max_value_size = Some(max_value_size);
+        self
+    }
+
+    /// Sets how long `open` waits for the directory lock.
+    ///
+    /// Only one writable store can have a directory open at a time. While
+    /// another one holds the lock, `open` retries with exponential backoff
+    /// until `timeout` has passed and then returns `KvsError::Locked`. Zero,
+    /// the default, fails right away. Read-only stores don't take the lock.
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Sets how many of the smallest generations `KvStore::compact_tiered`
+    /// merges at once. Values below 2 disable it.
+    pub fn with_compaction_tiers(mut self, tiers: usize) -> Self {
+        self.compaction_tiers = tiers;
+        self
+    }
+
+    /// Sets the clock that decides when keys written with
+    /// `KvStore::set_with_ttl` expire. `SystemClock` by default.
+    ///
+    /// Expiry times are stored as absolute times of this clock, so a clock
+    /// that is set back makes expired keys that haven't been noticed yet
+    /// live again.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets how reads of `get`, `multi_get` and compaction are issued.
+    pub fn with_io_backend(mut self, io_backend: IoBackend) -> Self {
+        self.io_backend = io_backend;
+        self
+    }
+
+    /// Keeps the index entries of overwritten and removed values in memory,
+    /// so `KvStore::get_at` can read what a key held at an earlier time.
+    /// Disabled by default.
+    ///
+    /// Every overwrite then costs memory until the next compaction, which
+    /// deletes the old records and with them the history before it. The log
+    /// is replayed in full at `open` instead of loading the hint file.
+    pub fn with_keep_history(mut self, keep_history: bool) -> Self {
+        self.keep_history = keep_history;
+        self
+    }
+}
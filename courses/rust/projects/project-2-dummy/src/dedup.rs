@@ -0,0 +1,96 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hasher;
+use std::io;
+use std::mem::size_of;
+
+use crate::index::hash_table_bytes;
+
+/// 比这个小的value不去重, 省下的空间还不够一条引用记录
+pub(crate) const DEDUP_MIN_SIZE: usize = 64;
+
+/// 引用记录的value: 被引用的记录所在的generation和位置
+pub(crate) const REF_SIZE: usize = 16;
+
+/// 去重用的旁路表: value的hash -> 存着这个value的记录的位置
+///
+/// 只记录本次open之后写入的value, 不持久化. hash相同还要逐字节比较过才算重复.
+#[derive(Default)]
+pub(crate) struct DedupTable {
+    locations: HashMap<u64, (u64, u64)>,
+    /// 本次open之后因为去重少写的字节数
+    pub(crate) bytes_saved: u64,
+}
+
+impl DedupTable {
+    pub(crate) fn hash(v: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(v);
+        hasher.finish()
+    }
+
+    pub(crate) fn get(&self, hash: u64) -> Option<(u64, u64)> {
+        self.locations.get(&hash).cloned()
+    }
+
+    pub(crate) fn insert(&mut self, hash: u64, n: u64, pos: u64) {
+        self.locations.insert(hash, (n, pos));
+    }
+
+    /// 表占用的内存, 估算的
+    pub(crate) fn approximate_memory(&self) -> usize {
+        hash_table_bytes(self.locations.capacity(), size_of::<(u64, (u64, u64))>())
+    }
+
+    /// 拿走所有位置, 省下的字节数留在原处
+    pub(crate) fn take(&mut self) -> DedupTable {
+        DedupTable { locations: std::mem::take(&mut self.locations), bytes_saved: 0 }
+    }
+
+    /// 把`older`的位置合并进来, 同一个hash以自己的为准
+    pub(crate) fn merge(&mut self, older: DedupTable) {
+        for (hash, loc) in older.locations {
+            self.locations.entry(hash).or_insert(loc);
+        }
+    }
+
+    /// compact之后记录搬到了第`n`个文件, 按`moved`更新位置, 没被搬走的就删掉
+    pub(crate) fn remap(&mut self, moved: &HashMap<(u64, u64), u64>, n: u64) {
+        self.locations = self
+            .locations
+            .drain()
+            .filter_map(|(hash, loc)| moved.get(&loc).map(|&pos| (hash, (n, pos))))
+            .collect();
+    }
+
+    /// 只有`gens`里的记录搬到了第`n`个文件: 指向它们的换成`moved`里的新位置, 没拷过去的删掉
+    pub(crate) fn remap_generations(&mut self, gens: &BTreeSet<u64>, moved: &HashMap<(u64, u64), u64>, n: u64) {
+        self.locations = self
+            .locations
+            .drain()
+            .filter_map(|(hash, loc)| match moved.get(&loc) {
+                Some(&pos) => Some((hash, (n, pos))),
+                None if gens.contains(&loc.0) => None,
+                None => Some((hash, loc)),
+            })
+            .collect();
+    }
+}
+
+pub(crate) fn encode_ref(n: u64, pos: u64) -> [u8; REF_SIZE] {
+    let mut buf = [0; REF_SIZE];
+    buf[..8].copy_from_slice(&n.to_le_bytes());
+    buf[8..].copy_from_slice(&pos.to_le_bytes());
+    buf
+}
+
+pub(crate) fn decode_ref(v: &[u8]) -> io::Result<(u64, u64)> {
+    if v.len() != REF_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed reference record"));
+    }
+    let mut n = [0; 8];
+    let mut pos = [0; 8];
+    n.copy_from_slice(&v[..8]);
+    pos.copy_from_slice(&v[8..]);
+    Ok((u64::from_le_bytes(n), u64::from_le_bytes(pos)))
+}
@@ -0,0 +1,117 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::slice;
+
+/// O_DIRECT要求的对齐大小, 文件偏移, 长度和内存地址都要按它对齐
+pub(crate) const BLOCK_SIZE: usize = 4096;
+
+/// 填在最后一个块里记录后面的字节.
+///
+/// 读出来timestamp是`u64::MAX`, 不可能是真实的时间戳, replay看到它就知道文件结束了.
+pub(crate) const PADDING: u8 = 0xFF;
+
+#[repr(C, align(4096))]
+#[derive(Clone)]
+struct Block([u8; BLOCK_SIZE]);
+
+/// 用O_DIRECT写log时的缓冲区
+///
+/// 记录先追加到按块对齐的内存里, flush时把整块(最后一块补上padding)写到对齐的位置.
+/// 最后一个没写满的块留在缓冲区里, 下次flush时连同新记录一起重写.
+pub(crate) struct DirectBuf {
+    blocks: Vec<Block>,
+    /// 缓冲区开头在文件里的位置, 总是按块对齐
+    start: u64,
+    /// 缓冲区里有效数据的长度
+    len: usize,
+}
+
+impl DirectBuf {
+    /// 接在文件的`pos`位置后面写, 先把`pos`所在块前面已有的数据读进来
+    pub(crate) fn new(file: &File, pos: u64) -> io::Result<Self> {
+        let mut buf = DirectBuf {
+            blocks: vec![Block([0; BLOCK_SIZE])],
+            start: 0,
+            len: 0,
+        };
+        buf.load_tail(file, pos)?;
+        Ok(buf)
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8]) {
+        let end = self.len + data.len();
+        let nblocks = end.div_ceil(BLOCK_SIZE);
+        if nblocks > self.blocks.len() {
+            self.blocks.resize(nblocks, Block([0; BLOCK_SIZE]));
+        }
+        let len = self.len;
+        self.bytes_mut()[len..end].copy_from_slice(data);
+        self.len = end;
+    }
+
+    /// 缓冲区占用的内存
+    pub(crate) fn capacity(&self) -> usize {
+        self.blocks.capacity() * BLOCK_SIZE
+    }
+
+    /// 把缓冲区写到文件里, 只留下最后一个没写满的块
+    pub(crate) fn flush(&mut self, file: &File) -> io::Result<()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let len = self.len;
+        let total = len.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        self.bytes_mut()[len..total].iter_mut().for_each(|b| *b = PADDING);
+        file.write_all_at(&self.bytes()[..total], self.start)?;
+
+        let full = len / BLOCK_SIZE * BLOCK_SIZE;
+        self.bytes_mut().copy_within(full..len, 0);
+        self.start += full as u64;
+        self.len -= full;
+        self.blocks.truncate(1);
+        Ok(())
+    }
+
+    /// 丢掉`pos`之后的数据(回滚用), `pos`之后文件里的内容由调用方截掉
+    pub(crate) fn truncate(&mut self, file: &File, pos: u64) -> io::Result<()> {
+        if pos >= self.start {
+            self.len = self.len.min((pos - self.start) as usize);
+            Ok(())
+        } else {
+            self.load_tail(file, pos)
+        }
+    }
+
+    fn load_tail(&mut self, file: &File, pos: u64) -> io::Result<()> {
+        self.start = pos / BLOCK_SIZE as u64 * BLOCK_SIZE as u64;
+        self.len = (pos - self.start) as usize;
+        self.blocks.truncate(1);
+        if self.len > 0 {
+            let start = self.start;
+            let block = &mut self.bytes_mut()[..BLOCK_SIZE];
+            let mut read = 0;
+            while read < BLOCK_SIZE {
+                match file.read_at(&mut block[read..], start + read as u64)? {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+            if read < self.len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn bytes(&self) -> &[u8] {
+        // Block是repr(C)的字节数组, Vec里的块是连续的
+        unsafe { slice::from_raw_parts(self.blocks.as_ptr() as *const u8, self.blocks.len() * BLOCK_SIZE) }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self.blocks.as_mut_ptr() as *mut u8, self.blocks.len() * BLOCK_SIZE)
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use crate::{KvsError, Result};
+
+/// Trait for a key value storage engine.
+pub trait KvsEngine {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Sets the value of a string key only if the key doesn't exist yet.
+    ///
+    /// Returns whether the value was written. The default checks
+    /// `contains_key` and then calls `set`.
+    fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        if self.contains_key(&key) {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Adds `delta` to the integer value of a key and returns the result.
+    ///
+    /// A missing key counts as 0. The value is stored as decimal text.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotAnInteger` if the value isn't a decimal
+    /// `i64`, and `KvsError::IntegerOverflow` if the result doesn't fit one.
+    /// Nothing is written in either case.
+    fn incr_by(&mut self, key: String, delta: i64) -> Result<i64> {
+        let current = match self.get(key.clone())? {
+            Some(v) => v.parse::<i64>().map_err(|_| KvsError::NotAnInteger(key.clone()))?,
+            None => 0,
+        };
+        let new = current.checked_add(delta).ok_or_else(|| KvsError::IntegerOverflow(key.clone()))?;
+        self.set(key, new.to_string())?;
+        Ok(new)
+    }
+
+    /// Adds 1 to the integer value of a key, see `incr_by`.
+    fn incr(&mut self, key: String) -> Result<i64> {
+        self.incr_by(key, 1)
+    }
+
+    /// Returns whether the given key exists, without reading its value.
+    fn contains_key(&self, key: &str) -> bool;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove(&mut self, key: String) -> Result<()>;
+}
@@ -1,5 +1,9 @@
+// `failure_derive` generates its impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
 use failure::Fail;
 use std::io;
+use std::path::PathBuf;
 
 /// Error type for kvs.
 #[derive(Fail, Debug)]
@@ -7,6 +11,17 @@ pub enum KvsError {
     /// IO error.
     #[fail(display = "{}", _0)]
     Io(#[cause] io::Error),
+    /// IO error while accessing the record at `pos` of generation `generation`.
+    #[fail(display = "{} (generation {}, {}.log at offset {})", source, generation, generation, pos)]
+    IoAt {
+        /// The underlying IO error.
+        #[cause]
+        source: io::Error,
+        /// Generation number of the log file.
+        generation: u64,
+        /// Offset of the record in the log file.
+        pos: u64,
+    },
     /// Serialization or deserialization error.
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
@@ -17,6 +32,92 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    /// A failed write could not be rolled back, so the active log may end
+    /// with a torn record. The store refuses further writes.
+    #[fail(display = "Store is poisoned by a failed write")]
+    Poisoned,
+    /// Another writable store has the directory open, see
+    /// `Config::with_lock_timeout`.
+    #[fail(display = "Store directory is locked by another process")]
+    Locked,
+    /// The path given to `KvStore::open` exists but isn't a directory.
+    /// Carries the path.
+    #[fail(display = "{:?} is not a directory", _0)]
+    NotADirectory(PathBuf),
+    /// The process may not create or write the store directory given to
+    /// `KvStore::open`, or may not read it when opening read-only. Carries
+    /// the path.
+    #[fail(display = "Permission denied for store directory {:?}", _0)]
+    PermissionDenied(PathBuf),
+    /// The store was opened read-only.
+    #[fail(display = "Store is opened read-only")]
+    ReadOnly,
+    /// The index points into a generation whose log file is not open or no
+    /// longer exists, e.g. because it was deleted while the store was running.
+    #[fail(display = "Log file of generation {} ({}.log) is missing", _0, _0)]
+    MissingGeneration(u64),
+    /// The key is too long to be stored; the log format holds keys up to
+    /// 16 MiB. Carries the length of the key in bytes.
+    #[fail(display = "Key of {} bytes is too large", _0)]
+    KeyTooLarge(u64),
+    /// The value is too long to be stored: larger than
+    /// `Config::with_max_value_size`, or a whole record doesn't fit in 4 GiB.
+    /// Carries the length of the value in bytes.
+    #[fail(display = "Value of {} bytes is too large", _0)]
+    ValueTooLarge(u64),
+    /// The log files were written in a newer format version than this
+    /// version of `kvs` understands. This is the version in the directory's
+    /// `VERSION` file, which tells what kinds of records the logs may hold;
+    /// it is checked once when the store is opened.
+    #[fail(display = "Unsupported log format version {}", _0)]
+    UnsupportedVersion(u32),
+    /// A log file starts with a header of a newer file format version than
+    /// this version of `kvs` understands. Carries the version of the header,
+    /// which describes the layout of that one file and is checked for each
+    /// file separately, independent of `UnsupportedVersion`.
+    #[fail(display = "Unsupported log file format version {}", _0)]
+    UnsupportedFormat(u16),
+    /// A key pattern given to `KeyPattern::new` is malformed. Carries the
+    /// pattern and what is wrong with it.
+    #[fail(display = "Invalid key pattern {}", _0)]
+    InvalidPattern(String),
+    /// A string parsed as a `ScanCursor` wasn't made from one. Carries the
+    /// string.
+    #[fail(display = "Invalid scan cursor {:?}", _0)]
+    InvalidCursor(String),
+    /// A key or value read from the log isn't valid UTF-8, e.g. because the
+    /// record is corrupted. Carries the generation and offset of the record.
+    #[fail(display = "Invalid UTF-8 in the record of generation {} ({}.log at offset {})", generation, generation, pos)]
+    InvalidUtf8 {
+        /// Generation number of the log file.
+        generation: u64,
+        /// Offset of the record in the log file.
+        pos: u64,
+    },
+    /// `incr_by` found a value that isn't a decimal `i64`. Carries the key.
+    #[fail(display = "Value of key {:?} is not an integer", _0)]
+    NotAnInteger(String),
+    /// `incr_by` would overflow an `i64`. Carries the key.
+    #[fail(display = "Increment of key {:?} overflows", _0)]
+    IntegerOverflow(String),
+    /// `KvStore::clear` was called while `SharedKvStore::compact` was
+    /// copying records.
+    #[fail(display = "A compaction is running")]
+    CompactionRunning,
+    /// `KvStore::set_meta` would make the metadata larger than 4 KiB.
+    /// Carries the size the metadata would have had, in bytes.
+    #[fail(display = "Metadata of {} bytes is too large", _0)]
+    MetaTooLarge(u64),
+    /// `KvStore::get_at` was asked about a time before the history horizon
+    /// (see `Stats::history_horizon`): compaction has discarded what the
+    /// store held then.
+    #[fail(display = "History before the horizon was discarded by compaction")]
+    HistoryTruncated,
+    /// The operation is not supported by the index or the configuration of
+    /// the store, e.g. a range scan on a store opened with `IndexKind::Hash`,
+    /// or `KvStore::get_at` without `Config::with_keep_history`.
+    #[fail(display = "Unsupported operation: {}", _0)]
+    UnsupportedOperation(&'static str),
 }
 
 impl From<io::Error> for KvsError {
@@ -0,0 +1,79 @@
+use std::fs::File;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use crate::Result;
+
+/// 一次`flush_async`: 要fsync的文件, 或者提交之前就出的错
+struct Job {
+    files: Vec<File>,
+    error: Option<io::Error>,
+    done: Sender<io::Result<()>>,
+}
+
+/// 后台fsync的线程
+///
+/// 按提交的顺序一个一个处理, 所以handle也按顺序完成. 有一次失败之后,
+/// 前面的记录是否落盘已经不知道了, 之后的handle全部返回同样的错误.
+pub(crate) struct Flusher {
+    jobs: Sender<Job>,
+}
+
+impl Flusher {
+    pub(crate) fn new() -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>();
+        thread::spawn(move || {
+            let mut failed: Option<(io::ErrorKind, String)> = None;
+            for job in rx {
+                if failed.is_none() {
+                    let result = match job.error {
+                        Some(e) => Err(e),
+                        None => job.files.iter().try_for_each(File::sync_data),
+                    };
+                    if let Err(e) = result {
+                        failed = Some((e.kind(), e.to_string()));
+                    }
+                }
+                let result = match &failed {
+                    Some((kind, msg)) => Err(io::Error::new(*kind, msg.clone())),
+                    None => Ok(()),
+                };
+                // handle已经被丢掉了也没关系
+                let _ = job.done.send(result);
+            }
+        });
+        Flusher { jobs }
+    }
+
+    /// 提交一次fsync, `error`不为None时这次直接失败
+    pub(crate) fn submit(&self, files: Vec<File>, error: Option<io::Error>) -> FlushHandle {
+        let (done, rx) = mpsc::channel();
+        // 线程不会先退出, 发送失败的话handle会在wait时报错
+        let _ = self.jobs.send(Job { files, error, done });
+        FlushHandle { done: rx }
+    }
+}
+
+/// A pending flush started by `KvStore::flush_async`.
+///
+/// Handles complete in the order they were created. Dropping a handle doesn't
+/// cancel the flush.
+pub struct FlushHandle {
+    done: Receiver<io::Result<()>>,
+}
+
+impl FlushHandle {
+    /// Blocks until every record appended before the `flush_async` call that
+    /// created this handle is flushed and synced to disk.
+    ///
+    /// Once a sync has failed, this and every later handle of the store
+    /// return the error, as the records it covered may be lost.
+    pub fn wait(self) -> Result<()> {
+        let result = self
+            .done
+            .recv()
+            .unwrap_or_else(|_| Err(io::Error::other("flusher thread exited")));
+        Ok(result?)
+    }
+}
@@ -0,0 +1,144 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::index::{IndexKind, KeyIndex};
+use crate::kv::{DataIndex, KSIZE_MASK};
+
+/// 索引的快照, compact和close时写, open时如果还有效就不用replay整个log
+pub(crate) const HINT_FILE: &str = "INDEX.hint";
+const HINT_TMP_FILE: &str = "INDEX.hint.tmp";
+
+/// hint文件的格式, 全部是little endian
+///
+/// |magic|version|uncompacted|last_end|文件数|(generation, 文件大小)...|key数|索引项...|
+/// |[u8;4]| u16  |    u64    |  u64   | u32  |        (u64, u64)       | u64 |        |
+///
+/// 每个索引项是 |ksize u32|key|n u64|pos u64|len u32|timestamp u64|vlen u32|ksize u32|vsize u32|value_len u32|expires u64|.
+/// 写hint时的每个log文件和它的大小都记下来, open时完全一样才用, 之后写过记录
+/// (文件变大), compact过(文件变了), 或者崩溃后截断过, 都会回到replay.
+const HINT_MAGIC: [u8; 4] = *b"KVSH";
+const HINT_VERSION: u16 = 5;
+
+/// 从hint文件读出来的状态, 和replay算出来的一样
+pub(crate) struct Hint<I> {
+    pub(crate) indexes: I,
+    pub(crate) uncompacted: u64,
+    /// 最后一个文件里最后一条完整记录的结尾
+    pub(crate) last_end: u64,
+}
+
+/// 把`indexes`写成hint文件, `files`是现在所有的log文件和它们的大小
+///
+/// 先写临时文件再rename, 不会留下写了一半的hint.
+pub(crate) fn write_hint<I: KeyIndex>(
+    path: &Path,
+    indexes: &I,
+    files: &[(u64, u64)],
+    uncompacted: u64,
+    last_end: u64,
+) -> io::Result<()> {
+    let tmp = path.join(HINT_TMP_FILE);
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    w.write_all(&HINT_MAGIC)?;
+    w.write_u16::<LittleEndian>(HINT_VERSION)?;
+    w.write_u64::<LittleEndian>(uncompacted)?;
+    w.write_u64::<LittleEndian>(last_end)?;
+    w.write_u32::<LittleEndian>(files.len() as u32)?;
+    for &(n, size) in files {
+        w.write_u64::<LittleEndian>(n)?;
+        w.write_u64::<LittleEndian>(size)?;
+    }
+    w.write_u64::<LittleEndian>(indexes.len() as u64)?;
+    for (key, v) in indexes.iter() {
+        w.write_u32::<LittleEndian>(key.len() as u32)?;
+        w.write_all(key.as_bytes())?;
+        w.write_u64::<LittleEndian>(v.n)?;
+        w.write_u64::<LittleEndian>(v.pos)?;
+        w.write_u32::<LittleEndian>(v.len)?;
+        w.write_u64::<LittleEndian>(v.timestamp)?;
+        w.write_u32::<LittleEndian>(v.vlen)?;
+        w.write_u32::<LittleEndian>(v.ksize)?;
+        w.write_u32::<LittleEndian>(v.vsize)?;
+        w.write_u32::<LittleEndian>(v.value_len)?;
+        w.write_u64::<LittleEndian>(v.expires)?;
+    }
+    let f = w.into_inner().map_err(|e| e.into_error())?;
+    f.sync_all()?;
+    fs::rename(tmp, path.join(HINT_FILE))
+}
+
+/// 读hint文件, 没有, 读不出来, 或者和现在的log文件`files`对不上时返回None
+pub(crate) fn read_hint<I: KeyIndex>(path: &Path, kind: IndexKind, files: &[(u64, u64)]) -> Option<Hint<I>> {
+    let f = File::open(path.join(HINT_FILE)).ok()?;
+    let hint = parse_hint(&mut BufReader::new(f), kind, files).ok().flatten()?;
+    match files.last() {
+        Some(&(n, _)) if !ends_at(path, n, hint.last_end).ok()? => None,
+        _ => Some(hint),
+    }
+}
+
+/// 第`n`个log在`end`之后没有记录了
+///
+/// 用O_DIRECT写时文件按块补齐, 在同一块里追加记录文件大小不变, 只比大小看不出来.
+fn ends_at(path: &Path, n: u64, end: u64) -> io::Result<bool> {
+    let mut f = File::open(path.join(format!("{}.log", n)))?;
+    f.seek(SeekFrom::Start(end))?;
+    match f.read_u64::<LittleEndian>() {
+        // padding的timestamp是u64::MAX
+        Ok(timestamp) => Ok(timestamp == u64::MAX),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_hint<I: KeyIndex>(r: &mut impl Read, kind: IndexKind, files: &[(u64, u64)]) -> io::Result<Option<Hint<I>>> {
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if magic != HINT_MAGIC || r.read_u16::<LittleEndian>()? != HINT_VERSION {
+        return Ok(None);
+    }
+    let uncompacted = r.read_u64::<LittleEndian>()?;
+    let last_end = r.read_u64::<LittleEndian>()?;
+    let count = r.read_u32::<LittleEndian>()? as usize;
+    if count != files.len() {
+        return Ok(None);
+    }
+    for &file in files {
+        if (r.read_u64::<LittleEndian>()?, r.read_u64::<LittleEndian>()?) != file {
+            return Ok(None);
+        }
+    }
+
+    let mut indexes = I::with_kind(kind);
+    let count = r.read_u64::<LittleEndian>()?;
+    for _ in 0..count {
+        let ksize = r.read_u32::<LittleEndian>()?;
+        if ksize > KSIZE_MASK {
+            return Ok(None);
+        }
+        let mut key = vec![0; ksize as usize];
+        r.read_exact(&mut key)?;
+        let key = String::from_utf8(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let v = DataIndex {
+            n: r.read_u64::<LittleEndian>()?,
+            pos: r.read_u64::<LittleEndian>()?,
+            len: r.read_u32::<LittleEndian>()?,
+            timestamp: r.read_u64::<LittleEndian>()?,
+            vlen: r.read_u32::<LittleEndian>()?,
+            ksize: r.read_u32::<LittleEndian>()?,
+            vsize: r.read_u32::<LittleEndian>()?,
+            value_len: r.read_u32::<LittleEndian>()?,
+            expires: r.read_u64::<LittleEndian>()?,
+        };
+        // 指向不存在的文件, 或者超出文件末尾, 说明hint和log对不上
+        let inside = files.iter().any(|&(n, size)| n == v.n && v.pos + v.len as u64 <= size);
+        if !inside {
+            return Ok(None);
+        }
+        indexes.insert(key, v);
+    }
+    Ok(Some(Hint { indexes, uncompacted, last_end }))
+}
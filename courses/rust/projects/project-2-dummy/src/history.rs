@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use crate::kv::DataIndex;
+
+/// 被覆盖和删除的value, `Config::with_keep_history`时才有, 给`KvStore::get_at`用
+///
+/// 每个key以前的版本从旧到新排好, 每个版本带着它开始的时间(Unix秒): value是记录的
+/// timestamp, 删除是删的时间. 现在的value在索引里, 不在这里. 旧版本的记录还在log里,
+/// compact删掉它们的时候整个历史清空, `horizon`往后挪.
+#[derive(Default)]
+pub(crate) struct History {
+    versions: HashMap<String, Vec<(u64, Option<DataIndex>)>>,
+    /// 从这个时间(Unix秒)起的历史是全的
+    horizon: u64,
+}
+
+impl History {
+    /// `key`原来的value`old`被覆盖了
+    pub(crate) fn push(&mut self, key: &str, old: DataIndex) {
+        self.entry(key).push((old.timestamp, Some(old)));
+    }
+
+    /// `key`原来的value`old`在`at`时被删掉了
+    pub(crate) fn push_removed(&mut self, key: &str, old: DataIndex, at: u64) {
+        let versions = self.entry(key);
+        versions.push((old.timestamp, Some(old)));
+        versions.push((at, None));
+    }
+
+    fn entry(&mut self, key: &str) -> &mut Vec<(u64, Option<DataIndex>)> {
+        if !self.versions.contains_key(key) {
+            self.versions.insert(key.to_owned(), Vec::new());
+        }
+        self.versions.get_mut(key).unwrap()
+    }
+
+    /// `at`(Unix毫秒)时`key`的value, `current`是索引里现在的
+    ///
+    /// 同一秒里的几个版本, 最后写的算数. 那时已经过期的value算不存在.
+    pub(crate) fn get_at(&self, key: &str, current: Option<&DataIndex>, at: u64) -> Option<DataIndex> {
+        let secs = at / 1000;
+        let older = self.versions.get(key).map_or(&[][..], |v| &v[..]);
+        let current = current.map(|v| (v.timestamp, Some(v.clone())));
+        let version = older.iter().cloned().chain(current).take_while(|(from, _)| *from <= secs).last()?;
+        version.1.filter(|v| !v.expired(at))
+    }
+
+    /// compact删掉了旧的log, 之前的版本都读不到了
+    pub(crate) fn truncate(&mut self, now: u64) {
+        self.versions.clear();
+        self.horizon = now;
+    }
+
+    pub(crate) fn set_horizon(&mut self, horizon: u64) {
+        self.horizon = horizon;
+    }
+
+    pub(crate) fn horizon(&self) -> u64 {
+        self.horizon
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::{BTreeMap, HashMap};
+use std::mem::{self, size_of};
+use std::ops::Bound;
+
+use crate::kv::DataIndex;
+use crate::{KvsError, Result};
+
+/// The structure used for the in-memory index of a `KvStore`.
+///
+/// The index is rebuilt from the log on every open, so the kind can be chosen
+/// per open without touching the data on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexKind {
+    /// A `BTreeMap`. Keys are kept in order, so range scans are supported.
+    #[default]
+    Ordered,
+    /// A `HashMap`. Point lookups and inserts are cheaper, but keys are
+    /// unordered and range scans return `KvsError::UnsupportedOperation`.
+    Hash,
+}
+
+/// Iterator over `(key, location)` pairs of an index.
+pub type IndexIter<'a> = Box<dyn Iterator<Item = (&'a str, &'a DataIndex)> + 'a>;
+
+/// The in-memory map from keys to record locations used by `KvStore`.
+///
+/// `KvStore` routes log replay, reads, writes and compaction through this
+/// trait, so custom structures (tries, ARTs, ...) can be plugged in with
+/// `KvStore::open_with_index`.
+pub trait KeyIndex: Default {
+    /// Creates an empty index for a store opened with `kind`.
+    ///
+    /// Implementations with a fixed structure can keep the default, which
+    /// ignores `kind`.
+    fn with_kind(kind: IndexKind) -> Self {
+        let _ = kind;
+        Self::default()
+    }
+
+    /// Returns the location of `key`.
+    fn get(&self, key: &str) -> Option<&DataIndex>;
+
+    /// Inserts the location of `key`, returning the one it replaces.
+    fn insert(&mut self, key: String, index: DataIndex) -> Option<DataIndex>;
+
+    /// Removes `key`, returning its location.
+    fn remove(&mut self, key: &str) -> Option<DataIndex>;
+
+    /// Replaces the location of `key` if it is present, returning the old
+    /// one; otherwise leaves the index unchanged and returns `None`.
+    ///
+    /// Lets `open` skip allocating the key when a record overwrites an
+    /// existing one. The default looks the key up and then calls `insert`.
+    fn replace(&mut self, key: &str, index: DataIndex) -> Option<DataIndex> {
+        if self.contains_key(key) {
+            self.insert(key.to_owned(), index)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of keys.
+    fn len(&self) -> usize;
+
+    /// Returns `true` if the index holds no keys.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if the index holds `key`.
+    fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Iterates over all entries, in no particular order.
+    fn iter(&self) -> IndexIter<'_>;
+
+    /// Iterates mutably over all locations, in no particular order.
+    fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut DataIndex> + '_>;
+
+    /// Iterates over the entries within `range` in ascending key order.
+    ///
+    /// # Errors
+    ///
+    /// Unordered indexes return `KvsError::UnsupportedOperation`.
+    fn range<'a>(&'a self, range: (Bound<&str>, Bound<&str>)) -> Result<IndexIter<'a>>;
+
+    /// Returns the smallest key.
+    ///
+    /// The default walks all entries; ordered indexes should override it.
+    fn first_key(&self) -> Option<&str> {
+        self.iter().map(|(k, _)| k).min()
+    }
+
+    /// Returns the largest key.
+    ///
+    /// The default walks all entries; ordered indexes should override it.
+    fn last_key(&self) -> Option<&str> {
+        self.iter().map(|(k, _)| k).max()
+    }
+
+    /// Returns an estimate of the heap bytes the structure needs on top of
+    /// its keys and `(String, DataIndex)` entries, e.g. node headers and
+    /// unused slots.
+    ///
+    /// Used by `KvStore::approximate_memory`. The default assumes none.
+    fn approximate_overhead(&self) -> usize {
+        0
+    }
+}
+
+/// 一条索引项(key的String和DataIndex)的大小
+const ENTRY_SIZE: usize = size_of::<String>() + size_of::<DataIndex>();
+
+/// `capacity`个`entry_size`大小的元素的HashMap占的字节数
+///
+/// hashbrown有capacity * 8 / 7个桶(2的幂), 每个桶一个元素加一个控制字节,
+/// 控制字节后面还多一组(16字节).
+pub(crate) fn hash_table_bytes(capacity: usize, entry_size: usize) -> usize {
+    if capacity == 0 {
+        return 0;
+    }
+    let buckets = (capacity * 8 / 7).next_power_of_two();
+    buckets * (entry_size + 1) + 16
+}
+
+/// 有`len`项的BTreeMap在索引项之外占的字节数
+fn btree_overhead(len: usize) -> usize {
+    // 一个节点最多11项, 加上父指针和长度; 实际平均每个叶子节点7.5项左右
+    // (顺序插入时少一些, 随机插入时多一些), 内部节点大约是叶子的1/7, 多12个子指针
+    let leaf = 11 * ENTRY_SIZE + 16;
+    let internal = leaf + 12 * size_of::<usize>();
+    let leaves = (len * 2).div_ceil(15);
+    let nodes = leaves * leaf + leaves.div_ceil(7) * internal;
+    nodes.saturating_sub(len * ENTRY_SIZE)
+}
+
+/// 对`len`个key, 用`kind`的索引要占多少字节: key, 索引项, 加上结构本身
+///
+/// HashMap按一个个插入长起来的容量估算.
+pub(crate) fn estimated_index_bytes(kind: IndexKind, len: usize, key_bytes: usize) -> usize {
+    let overhead = match kind {
+        IndexKind::Ordered => btree_overhead(len),
+        IndexKind::Hash => hash_table_bytes(len, ENTRY_SIZE).saturating_sub(len * ENTRY_SIZE),
+    };
+    key_bytes + len * ENTRY_SIZE + overhead
+}
+
+impl KeyIndex for BTreeMap<String, DataIndex> {
+    fn get(&self, key: &str) -> Option<&DataIndex> {
+        BTreeMap::get(self, key)
+    }
+
+    fn insert(&mut self, key: String, index: DataIndex) -> Option<DataIndex> {
+        BTreeMap::insert(self, key, index)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<DataIndex> {
+        BTreeMap::remove(self, key)
+    }
+
+    fn replace(&mut self, key: &str, index: DataIndex) -> Option<DataIndex> {
+        BTreeMap::get_mut(self, key).map(|v| mem::replace(v, index))
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn iter(&self) -> IndexIter<'_> {
+        Box::new(BTreeMap::iter(self).map(|(k, v)| (k.as_str(), v)))
+    }
+
+    fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut DataIndex> + '_> {
+        Box::new(BTreeMap::values_mut(self))
+    }
+
+    fn range<'a>(&'a self, range: (Bound<&str>, Bound<&str>)) -> Result<IndexIter<'a>> {
+        Ok(Box::new(
+            BTreeMap::range::<str, _>(self, range).map(|(k, v)| (k.as_str(), v)),
+        ))
+    }
+
+    fn first_key(&self) -> Option<&str> {
+        BTreeMap::keys(self).next().map(String::as_str)
+    }
+
+    fn last_key(&self) -> Option<&str> {
+        BTreeMap::keys(self).next_back().map(String::as_str)
+    }
+
+    fn approximate_overhead(&self) -> usize {
+        btree_overhead(self.len())
+    }
+}
+
+impl KeyIndex for HashMap<String, DataIndex> {
+    fn get(&self, key: &str) -> Option<&DataIndex> {
+        HashMap::get(self, key)
+    }
+
+    fn insert(&mut self, key: String, index: DataIndex) -> Option<DataIndex> {
+        HashMap::insert(self, key, index)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<DataIndex> {
+        HashMap::remove(self, key)
+    }
+
+    fn replace(&mut self, key: &str, index: DataIndex) -> Option<DataIndex> {
+        HashMap::get_mut(self, key).map(|v| mem::replace(v, index))
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn iter(&self) -> IndexIter<'_> {
+        Box::new(HashMap::iter(self).map(|(k, v)| (k.as_str(), v)))
+    }
+
+    fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut DataIndex> + '_> {
+        Box::new(HashMap::values_mut(self))
+    }
+
+    fn range<'a>(&'a self, _range: (Bound<&str>, Bound<&str>)) -> Result<IndexIter<'a>> {
+        Err(KvsError::UnsupportedOperation(
+            "range scans need an ordered index",
+        ))
+    }
+
+    fn approximate_overhead(&self) -> usize {
+        hash_table_bytes(self.capacity(), ENTRY_SIZE).saturating_sub(self.len() * ENTRY_SIZE)
+    }
+}
+
+/// The default index of `KvStore`: a `BTreeMap` or a `HashMap`, chosen at
+/// open time by `Config::with_index_kind`.
+pub enum Index {
+    /// See `IndexKind::Ordered`.
+    Ordered(BTreeMap<String, DataIndex>),
+    /// See `IndexKind::Hash`.
+    Hash(HashMap<String, DataIndex>),
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Index::Ordered(BTreeMap::new())
+    }
+}
+
+impl KeyIndex for Index {
+    fn with_kind(kind: IndexKind) -> Self {
+        match kind {
+            IndexKind::Ordered => Index::Ordered(BTreeMap::new()),
+            IndexKind::Hash => Index::Hash(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&DataIndex> {
+        match self {
+            Index::Ordered(m) => KeyIndex::get(m, key),
+            Index::Hash(m) => KeyIndex::get(m, key),
+        }
+    }
+
+    fn insert(&mut self, key: String, index: DataIndex) -> Option<DataIndex> {
+        match self {
+            Index::Ordered(m) => KeyIndex::insert(m, key, index),
+            Index::Hash(m) => KeyIndex::insert(m, key, index),
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<DataIndex> {
+        match self {
+            Index::Ordered(m) => KeyIndex::remove(m, key),
+            Index::Hash(m) => KeyIndex::remove(m, key),
+        }
+    }
+
+    fn replace(&mut self, key: &str, index: DataIndex) -> Option<DataIndex> {
+        match self {
+            Index::Ordered(m) => KeyIndex::replace(m, key, index),
+            Index::Hash(m) => KeyIndex::replace(m, key, index),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Index::Ordered(m) => KeyIndex::len(m),
+            Index::Hash(m) => KeyIndex::len(m),
+        }
+    }
+
+    fn iter(&self) -> IndexIter<'_> {
+        match self {
+            Index::Ordered(m) => KeyIndex::iter(m),
+            Index::Hash(m) => KeyIndex::iter(m),
+        }
+    }
+
+    fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut DataIndex> + '_> {
+        match self {
+            Index::Ordered(m) => KeyIndex::values_mut(m),
+            Index::Hash(m) => KeyIndex::values_mut(m),
+        }
+    }
+
+    fn range<'a>(&'a self, range: (Bound<&str>, Bound<&str>)) -> Result<IndexIter<'a>> {
+        match self {
+            Index::Ordered(m) => KeyIndex::range(m, range),
+            Index::Hash(m) => KeyIndex::range(m, range),
+        }
+    }
+
+    fn first_key(&self) -> Option<&str> {
+        match self {
+            Index::Ordered(m) => KeyIndex::first_key(m),
+            Index::Hash(m) => KeyIndex::first_key(m),
+        }
+    }
+
+    fn last_key(&self) -> Option<&str> {
+        match self {
+            Index::Ordered(m) => KeyIndex::last_key(m),
+            Index::Hash(m) => KeyIndex::last_key(m),
+        }
+    }
+
+    fn approximate_overhead(&self) -> usize {
+        match self {
+            Index::Ordered(m) => KeyIndex::approximate_overhead(m),
+            Index::Hash(m) => KeyIndex::approximate_overhead(m),
+        }
+    }
+}
@@ -1,27 +1,137 @@
-
 #![allow(missing_docs)]
-#![allow(unused_variables)]
-#![allow(dead_code)]
-#![allow(unused_imports)]
 
-use std::collections::{BTreeMap, HashMap};
-use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::cell::RefCell;
+#[cfg(test)]
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::ops::{Bound, ControlFlow, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::str::Utf8Error;
+use std::string::FromUtf8Error;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
-use serde::{Deserialize, Serialize};
 
-use crate::{KvsError, Result};
+use crate::advice::FileAdvice;
+use crate::clock::to_millis;
+use crate::codec::decompress;
+use crate::flush::{FlushHandle, Flusher};
+use crate::hint::{read_hint, write_hint};
+use crate::history::History;
+use crate::dedup::{decode_ref, encode_ref, DedupTable, DEDUP_MIN_SIZE, REF_SIZE};
+#[cfg(target_os = "linux")]
+use crate::direct::DirectBuf;
+use crate::index::{estimated_index_bytes, Index, KeyIndex};
+use crate::readers::{Readers, READ_BATCH};
+use crate::scan::{count_live_keys, Iter, LogCursor, ScanCursor, ScanPage, ScanUnordered};
+use crate::watch::{Evictions, Subscribers};
+use crate::vlog::{decode_pointer, encode_pointer, VALUE_LOG_EXT};
+use crate::{
+    ChangeEvent, Config, EvictReason, GenerationInfo, IndexKind, KeyPattern, KvsEngine, KvsError, MemKvStore, MemoryUsage, Result, RetainStats, Stats, SyncPolicy, WarmupStats,
+};
+
+pub use self::batch::Op;
+pub use self::ttl::TtlState;
+
+mod batch;
+mod compaction;
+mod ttl;
+
+/// `set_from_reader`每次从reader读多少字节
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// ksize里flags的偏移
+pub(crate) const FLAGS_SHIFT: u32 = 24;
+/// ksize里key长度的掩码
+pub(crate) const KSIZE_MASK: u32 = (1 << FLAGS_SHIFT) - 1;
+/// value是压缩过的
+pub(crate) const FLAG_COMPRESSED: u8 = 1;
+/// 去重的引用记录, value是被引用记录的generation和位置
+pub(crate) const FLAG_REF: u8 = 2;
+/// compact时为引用记录单独拷过来的value, 或者分块存储的一块, key为空, 不属于任何key
+pub(crate) const FLAG_BLOB: u8 = 4;
+/// 分块存储的value, value是每一块(blob记录)的generation和位置
+pub(crate) const FLAG_CHUNKED: u8 = 8;
+/// value存在value log里, value是value log里那条记录的位置(见`vlog::encode_pointer`)
+pub(crate) const FLAG_VLOG: u8 = 16;
+/// 会过期的value, 后面紧跟着一条它的TTL记录, `DataIndex::len`包括这两条
+pub(crate) const FLAG_EXPIRES: u8 = 32;
+/// TTL记录, value是过期的Unix时间(毫秒, 见`TTL_SIZE`), 0表示不过期
+///
+/// 跟在`FLAG_EXPIRES`的记录后面的是它的一部分; 单独的是`expire`之类改过期时间写的,
+/// 改的是这个key当时的value, compact时合进value的记录里, 所以单独的TTL记录都算进uncompacted.
+pub(crate) const FLAG_TTL: u8 = 64;
+/// TTL记录的value的长度
+const TTL_SIZE: u32 = 8;
+/// `touch`写的记录, 没有value, timestamp是这个key当时的value新的timestamp
+///
+/// 和单独的TTL记录一样, compact时合进value的记录里, 都算进uncompacted.
+pub(crate) const FLAG_TOUCH: u8 = 128;
+/// `transaction`写多条记录时, batch前后各一条的标记, 没有key和value
+///
+/// 第一条是开头, 第二条是提交: replay时没等到提交标记的batch整个不算, 和写到一半的记录一样
+/// 当作文件结束. 标记都算进uncompacted. flags的位用完了, 借用blob和touch两个位的组合,
+/// 真的blob和touch的记录不会同时有这两个位.
+pub(crate) const FLAG_BATCH: u8 = FLAG_BLOB | FLAG_TOUCH;
+
+/// 日志格式的版本, 记在目录下的`VERSION`文件里, 没有这个文件就是版本1
+///
+/// 版本2加了分块存储的value(`FLAG_CHUNKED`), 版本3加了value log(`FLAG_VLOG`),
+/// 版本4加了会过期的value(`FLAG_EXPIRES`和`FLAG_TTL`), 版本5加了`touch`的记录(`FLAG_TOUCH`),
+/// 版本6加了batch的标记(`FLAG_BATCH`).
+/// 只有真的写了这样的记录才会升级, 没用到的目录还是版本1.
+/// 这个版本只管记录里会出现哪些flags, 每个文件本身的布局见`LOG_FILE_VERSION`.
+const FORMAT_VERSION: u32 = 6;
+const CHUNKED_VERSION: u32 = 2;
+const VALUE_LOG_VERSION: u32 = 3;
+const TTL_VERSION: u32 = 4;
+const TOUCH_VERSION: u32 = 5;
+const BATCH_VERSION: u32 = 6;
+const VERSION_FILE: &str = "VERSION";
+/// 可写打开时锁住的文件, 同一个目录同时只能有一个可写的KvStore
+const LOCK_FILE: &str = "LOCK";
+/// 等锁时两次尝试之间最多等多久
+const MAX_LOCK_BACKOFF: Duration = Duration::from_millis(100);
+/// 还没写好的log文件名后面加上的扩展名, open时会被删掉
+const TMP_EXT: &str = "tmp";
+/// `clear`的标记, 里面是清空后第一个log和value log的generation, 更早的都要删掉
+const CLEAR_FILE: &str = "CLEAR";
+/// `set_meta`写的元数据, JSON对象, 整个文件原子地替换
+const META_FILE: &str = "META";
+/// 元数据所有key和value加起来最多多少字节
+const MAX_META_SIZE: usize = 4096;
+/// 上次compact或clear删掉旧log的时间(Unix秒), 更早的历史已经不全了
+const COMPACTED_FILE: &str = "COMPACTED";
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// 每个新的log文件开头都有header: |magic|format_version|, 记录从header后面开始
+/// |  [u8;4] |   u16 LE     |
+///
+/// 以前的文件没有header, 当作版本0, 记录从0开始. compact之后就都是新格式了.
+///
+/// 和目录的`FORMAT_VERSION`是两回事, 各管各的:
+/// - `LOG_FILE_VERSION`管单个文件的布局(有没有header, 记录从哪里开始), 每个文件replay前各自检查,
+///   不认识的返回`KvsError::UnsupportedFormat`. 只有记录的编码方式变了才升级.
+/// - `FORMAT_VERSION`管整个目录里会出现哪些记录(flags), open时读`VERSION`检查一次,
+///   不认识的返回`KvsError::UnsupportedVersion`. 加一种新的记录只升级它, 文件header不变.
+const LOG_MAGIC: [u8; 4] = *b"KVSL";
+pub(crate) const LOG_FILE_VERSION: u16 = 1;
+pub(crate) const FILE_HEADER_SIZE: u64 = 6;
 
 /// The `KvStore` stores string key/value pairs.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
-/// A `BTreeMap` in memory stores the keys and the value locations for fast query.
+/// The numbers are not necessarily contiguous: compaction skips some and
+/// deletes the generations it merged, so a directory may for example hold
+/// only `2.log` and `7.log`.
+/// A `BTreeMap` (or a `HashMap`, see `IndexKind`) in memory stores the keys and
+/// the value locations for fast query.
 ///
 /// ```rust
 /// # use kvs::{KvStore, Result};
@@ -35,287 +145,3307 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 /// # }
 /// ```
 ///
-/// 存储格式,此处忽略掉crc
+/// 存储格式,此处忽略掉crc(记录都不带校验和, `set_from_reader`流式写入的也一样). 每个文件开头是header(见`LOG_MAGIC`), 后面是一条条记录
 /// |timestamp|ksize|vsize|key|value|
 /// |   u64   |u32  | u32 |   |     |
 ///
-/// 删除该数据时将timestamp置为0
+/// ksize的最高8位是flags(见`FLAG_COMPRESSED`), 低24位才是key的长度.
+/// 以前的文件key都不会超过16MB, flags都是0, 可以直接读.
+///
+/// 开启`Config::with_dedup`时, 和之前写过的value一样的value只写一条引用记录(`FLAG_REF`).
+/// 超过`Config::chunk_size`的value分成多条blob记录, key对应的记录里只存每一块的位置(`FLAG_CHUNKED`).
+/// 开启`Config::with_value_log`时, 大的value写到单独的value log(`{n}.vlog`, 格式和log一样)里,
+/// log里只写一条指针记录(`FLAG_VLOG`), compact的时候不用拷贝value.
+///
+/// 删除时追加一条timestamp为0, vsize为0的记录(tombstone)
 /// 
-pub struct KvStore {
+pub struct KvStore<I: KeyIndex = Index> {
     path: PathBuf,
+    config: Config,
     /// 写到了第几个文件
     nth: u64,
-    writer: File,
-    readers: HashMap<u64, File>,
-    indexes: BTreeMap<String, DataIndex>,
+    /// 只读打开时没有writer
+    writer: Option<LogWriter>,
+    readers: Readers,
+    indexes: I,
+    /// compact能回收的字节数: 被覆盖和删除的整条记录, 加上tombstone本身
+    ///
+    /// open时replay算出来的和运行时累加的一样.
     uncompacted: u64,
+    /// 写失败后连回滚都失败了, 当前log末尾可能有残缺的记录, 拒绝继续写入
+    poisoned: bool,
+    dedup: DedupTable,
+    /// 目录的日志格式版本
+    version: u32,
+    /// value log的读句柄
+    vlogs: Readers,
+    /// 正在写的value log, 第一次往value log里写时才打开
+    vlog: Option<LogWriter>,
+    /// value log写到了第几个文件
+    vnth: u64,
+    /// value log里已经没有key指向的字节数
+    vlog_garbage: u64,
+    /// 上次`flush_async`之后写过的, 已经不是当前正在写的log和value log
+    unsynced: BTreeSet<u64>,
+    unsynced_vlogs: BTreeSet<u64>,
+    /// 第一次`flush_async`时才启动
+    flusher: Option<Flusher>,
+    /// `SharedKvStore::compact`正在不持锁地拷贝记录, 这期间不能再开始别的compact
+    compacting: bool,
+    /// `compact_budget`进行中时, 要compact的最后一个generation; 之后的是新写的
+    compact_boundary: Option<u64>,
+    subscribers: Subscribers,
+    evictions: Evictions,
+    /// `set_meta`写的元数据, open时从`META_FILE`读进来
+    meta: BTreeMap<String, String>,
+    /// `Config::with_keep_history`时被覆盖和删除的value
+    history: Option<History>,
+    /// 持有目录的锁, drop时释放; 只读打开时没有
+    _lock: Option<File>,
 }
 
-/// Represents the position and length of a json-serialized command in the log.
-struct DataIndex {
-    n: u64,
-    pos: u64,
-    len: u32,
+/// The outcome of `KvStore::compare_and_swap`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CasResult {
+    /// The current value was the expected one and has been replaced.
+    Swapped,
+    /// The current value differs from the expected one; nothing was written.
+    Mismatch {
+        /// The value found instead, `None` if the key doesn't exist.
+        current: Option<String>,
+    },
+}
+
+/// The location of the live record of a key: generation, offset and length.
+///
+/// Only `KvStore` creates these; custom `KeyIndex` implementations just store
+/// and return them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataIndex {
+    pub(crate) n: u64,
+    pub(crate) pos: u64,
+    pub(crate) len: u32,
+    pub(crate) timestamp: u64,
+    /// value在value log里时是value log里那条记录的长度, 否则是0
+    pub(crate) vlen: u32,
+    /// 记录header里的ksize, 最高8位是flags, 不包括`FLAG_EXPIRES`(看`expires`)
+    ///
+    /// 和`vsize`一起, 读value时可以直接定位, 不用先读header.
+    pub(crate) ksize: u32,
+    /// 记录里的vsize: 压缩过的, 引用和指针记录存的都是它们自己的长度
+    pub(crate) vsize: u32,
+    /// value本身的长度, 解压, 解引用之后的, 也就是`get`返回的长度
+    pub(crate) value_len: u32,
+    /// 过期的Unix时间, 毫秒; 0表示不过期
+    pub(crate) expires: u64,
+}
+
+impl DataIndex {
+    /// The generation (log file) holding the record.
+    pub fn generation(&self) -> u64 {
+        self.n
+    }
+
+    /// The offset of the record within its log file.
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// 到`now`(Unix毫秒)时已经过期了
+    pub(crate) fn expired(&self, now: u64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+
+    /// 记录的flags
+    pub(crate) fn flags(&self) -> u8 {
+        (self.ksize >> FLAGS_SHIFT) as u8
+    }
+
+    /// value在log文件里开始的位置
+    fn value_pos(&self) -> u64 {
+        self.pos + 16 + (self.ksize & KSIZE_MASK) as u64
+    }
+}
+
+/// What the index knows about an entry without reading its value, as returned
+/// by `KvStore::metadata` and passed to the callback of `KvStore::retain`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMeta {
     timestamp: u64,
+    value_len: u32,
+    generation: u64,
+}
+
+impl EntryMeta {
+    /// 从索引里的`v`得到, 不读value
+    fn new(v: &DataIndex) -> Self {
+        EntryMeta { timestamp: v.timestamp, value_len: v.value_len, generation: v.n }
+    }
+
+    /// The Unix time in seconds at which the value was written.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// The length in bytes of the value, as returned by `get`: compressed,
+    /// deduplicated, chunked and value log values report their full length,
+    /// not what their record takes on disk.
+    pub fn value_len(&self) -> u32 {
+        self.value_len
+    }
+
+    /// The generation (log file) holding the record, for debugging; it
+    /// changes when compaction moves the record.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
 }
 
 impl KvStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_config(path, Config::default())
+    }
+
+    /// Opens an empty store that lives only in memory.
+    ///
+    /// See `MemKvStore`.
+    pub fn open_in_memory() -> MemKvStore {
+        MemKvStore::new()
+    }
+
+    /// Opens the store at `path` for reading only.
+    ///
+    /// Nothing under `path` is ever created, modified or deleted, so this works
+    /// without write permission, e.g. for a dataset on a read-only mount. See
+    /// `Config::with_read_only`.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_config(path, Config::default().with_read_only(true))
+    }
+
+    /// Opens a `KvStore` at `path` with the given `Config`.
+    pub fn open_with_config(path: impl Into<PathBuf>, config: Config) -> Result<Self> {
+        Self::open_with_index(path, config)
+    }
+
+    /// Opens a `KvStore` at `path`, waiting up to `timeout` while another
+    /// store holds the directory lock.
+    ///
+    /// `open` fails right away with `KvsError::Locked` instead. See
+    /// `Config::with_lock_timeout`.
+    pub fn open_with_timeout(path: impl Into<PathBuf>, timeout: Duration) -> Result<Self> {
+        Self::open_with_config(path, Config::default().with_lock_timeout(timeout))
+    }
+
+    /// Estimates how much memory the index of the store at `path` would need
+    /// with an index of `kind`, without opening the store.
+    ///
+    /// Reads the record headers and keys of every log file, but no values,
+    /// and only keeps a hash of each key meanwhile. For the default index this
+    /// matches `estimated_index_memory` of the opened store.
+    pub fn estimate_index_memory(path: impl AsRef<Path>, kind: IndexKind) -> Result<usize> {
+        let path = path.as_ref();
+        let version = read_version(path)?;
+        if version > FORMAT_VERSION {
+            return Err(KvsError::UnsupportedVersion(version));
+        }
+        let (keys, key_bytes) = count_live_keys(path, list_generations(path)?)?;
+        Ok(estimated_index_bytes(kind, keys, key_bytes))
+    }
+}
+
+impl<I: KeyIndex> KvStore<I> {
+    /// Opens a `KvStore` at `path` that keeps its index in an `I`.
+    ///
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use kvs::{Config, DataIndex, KvStore, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let store = KvStore::<HashMap<String, DataIndex>>::open_with_index(
+    ///     std::env::current_dir()?,
+    ///     Config::default(),
+    /// )?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn open_with_index(path: impl Into<PathBuf>, config: Config) -> Result<Self> {
         let path = path.into();
-        fs::create_dir_all(&path)?;
+        let read_only = config.read_only;
+        if path.exists() && !path.is_dir() {
+            return Err(KvsError::NotADirectory(path));
+        }
+        let lock = if read_only {
+            None
+        } else {
+            fs::create_dir_all(&path).map_err(|e| permission_denied(e.into(), &path))?;
+            Some(lock_dir(&path, config.lock_timeout).map_err(|e| permission_denied(e, &path))?)
+        };
+        let version = read_version(&path).map_err(|e| permission_denied(e, &path))?;
+        if version > FORMAT_VERSION {
+            return Err(KvsError::UnsupportedVersion(version));
+        }
+        let meta = read_meta(&path)?;
 
         // read all log files under path, then init them
 
-        let mut readers = HashMap::new();
-        let mut indexes = BTreeMap::new();
-        let mut vec: Vec<u64> = Vec::new();
+        let mut readers = Readers::new(path.clone(), &config);
+        let mut indexes = I::with_kind(config.index_kind);
         let mut uncompacted: u64 = 0;
-        let mut entries: Vec<_> = fs::read_dir(&path)?
-            .flat_map(|v| v.map(|e| e.path()))
-            .flat_map(|v| v.file_name().unwrap()
-                .to_str().unwrap()
-                .trim_end_matches(".log")
-                .parse::<u64>()
-            )
-            .collect();
-        entries.sort_unstable();
+        if !read_only {
+            remove_tmp_logs(&path)?;
+        }
+        // 上次clear到一半崩溃了, 标记之前的generation都不算
+        let (floor, vfloor) = read_clear_marker(&path)?;
+        if !read_only && floor > 0 {
+            finish_clear(&path, floor, vfloor)?;
+        }
+        let entries: Vec<u64> = list_generations(&path)?.into_iter().filter(|&n| n >= floor).collect();
+
+        // 最后一个generation如果还没写满, 接着往里面写
+        let last = entries.last().cloned();
+        let mut last_end = 0;
+        // 所有记录共用一个读key的缓冲区
+        let mut key = Vec::new();
+        let mut history = if config.keep_history { Some(History::default()) } else { None };
+        // replay到的最早和最晚的记录时间, tombstone没有自己的时间, 按它前面最晚的记录算
+        let mut oldest = None;
+        let mut latest = 0;
+
+        // hint文件和现在的log文件对得上, 索引直接从hint读, 不用replay
+        let mut files = Vec::with_capacity(entries.len());
+        for &num in &entries {
+            files.push((num, fs::metadata(path.join(format!("{}.log", num)))?.len()));
+        }
+        let mut hinted_end = None;
+        // 要保留历史时hint不够用, 被覆盖的记录只有replay才看得到
+        let hint = if config.keep_history { None } else { read_hint::<I>(&path, config.index_kind, &files) };
+        if let Some(hint) = hint {
+            indexes = hint.indexes;
+            uncompacted = hint.uncompacted;
+            hinted_end = Some(hint.last_end);
+        }
+
+        // 提交了的batch的标记的长度, 最后算进uncompacted
+        let mut markers = 0;
+        // 一条记录对索引的改动; batch里的记录等到提交标记才交给它
+        let mut apply = |key: &str, data: DataIndex, flags: u8| {
+            if data.timestamp > 0 {
+                oldest = Some(oldest.map_or(data.timestamp, |t: u64| t.min(data.timestamp)));
+                latest = latest.max(data.timestamp);
+            }
+            // 单独的TTL记录只改现在那个value的过期时间
+            if flags & FLAG_TTL != 0 {
+                if let Some(v) = indexes.get(key).cloned() {
+                    indexes.replace(key, DataIndex { expires: data.expires, ..v });
+                }
+                uncompacted += data.len as u64;
+                return;
+            }
+            if flags & FLAG_TOUCH != 0 {
+                if let Some(v) = indexes.get(key).cloned() {
+                    indexes.replace(key, DataIndex { timestamp: data.timestamp, ..v.clone() });
+                    if let Some(history) = &mut history {
+                        history.push(key, v);
+                    }
+                }
+                uncompacted += data.len as u64;
+                return;
+            }
+            // timestamp == 0的代表被删除, 等待compact程序运行
+            // 旧版本是原地把timestamp置0, 新版本是追加tombstone, 两种都按删除key处理
+            if data.timestamp == 0 {
+                if let Some(v) = indexes.remove(key) {
+                    uncompacted += v.len as u64;
+                    if let Some(history) = &mut history {
+                        history.push_removed(key, v, latest);
+                    }
+                }
+                uncompacted += data.len as u64;
+                return;
+            }
 
+            // 后写入的记录覆盖先写入的, 新的key才需要分配String
+            if !indexes.contains_key(key) {
+                indexes.insert(key.to_owned(), data);
+            } else if let Some(v) = indexes.replace(key, data) {
+                uncompacted += v.len as u64;
+                if let Some(history) = &mut history {
+                    history.push(key, v);
+                }
+            }
+        };
         for num in entries {
-            let (mut f, cpath) = open_file(&path, num);
-            if f.metadata().unwrap().len() == 0 {
-                fs::remove_file(cpath).unwrap();
+            let (mut f, cpath) = if read_only {
+                let cpath = path.join(format!("{}.log", num));
+                (File::open(&cpath)?, cpath)
+            } else {
+                open_file(&path, num)
+            };
+            let flen = f.metadata()?.len();
+            if flen == 0 && Some(num) != last {
+                if !read_only {
+                    fs::remove_file(cpath)?;
+                }
+                continue;
+            }
+            if let Some(end) = hinted_end {
+                readers.put(num, f);
+                last_end = end;
                 continue;
             }
+            FileAdvice::Sequential.apply(&f);
+            let format = read_file_header(&mut f)?;
+            if format > LOG_FILE_VERSION {
+                return Err(KvsError::UnsupportedFormat(format));
+            }
+            let mut end = fpos(&mut f)?;
+            // 下一条记录开始的位置, 读不出来时报告
+            let mut next = end;
+            // 还没提交的batch里的记录, 和开头标记的长度
+            let mut batch: Option<Vec<(String, DataIndex, u8)>> = None;
+            let mut begin = 0;
             loop {
-                let item = read_item(num, &mut f);
-                if item.is_err() {
-                    // let err = item.err().unwrap();
-                    // println!("{:#?}", err);
+                let (data, flags) = match read_item(num, &mut f, &mut key) {
+                    Ok(item) => item,
+                    // 文件末尾残缺的记录(写到一半崩溃了), 之后没有别的记录
+                    Err(KvsError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    // 其余的错误不能当作文件结束, 不然后面完好的记录会被截掉
+                    Err(KvsError::Io(e)) => return Err(io_at(num, next)(e)),
+                    Err(e) => return Err(e),
+                };
+                // 记录不完整(写到一半崩溃了)
+                if data.pos + data.len as u64 > flen {
                     break;
                 }
-
-                let (key, data) = item.unwrap();
-
-                // timestamp == 0的代表被删除, 等待compact程序运行
-                if data.timestamp == 0 {
-                    uncompacted += data.len as u64;
+                next = data.pos + data.len as u64;
+                // 没提交的batch不算, 文件从batch开头算结束
+                if flags & FLAG_BATCH == FLAG_BATCH {
+                    match batch.take() {
+                        None => {
+                            batch = Some(Vec::new());
+                            begin = data.len;
+                        }
+                        Some(records) => {
+                            for (key, data, flags) in records {
+                                apply(&key, data, flags);
+                            }
+                            markers += (begin + data.len) as u64;
+                            end = data.pos + data.len as u64;
+                        }
+                    }
                     continue;
                 }
+                if batch.is_none() {
+                    end = data.pos + data.len as u64;
+                }
 
-                if let Some(v) = indexes.insert(key, data) {
-                    remove_item(readers.get_mut(&v.n).unwrap(), v.pos);
-                    uncompacted += v.len as u64;
+                // 只有引用记录会用到
+                if flags & FLAG_BLOB != 0 {
+                    continue;
+                }
+                let key = std::str::from_utf8(&key)
+                    .map_err(|_| KvsError::InvalidUtf8 { generation: num, pos: data.pos })?;
+                match &mut batch {
+                    Some(records) => records.push((key.to_owned(), data, flags)),
+                    None => apply(key, data, flags),
                 }
             }
 
-            readers.insert(num, f);
-            vec.push(num);
+            // replay完了, 之后这个文件是给get随机读的
+            FileAdvice::Normal.apply(&f);
+            readers.put(num, f);
+            last_end = end;
+        }
+
+        uncompacted += markers;
+
+        // 关着的时候过期的key不再加载, 记录留给compact清理
+        let now = to_millis(config.clock.now());
+        let expired: Vec<String> =
+            indexes.iter().filter(|(_, v)| v.expired(now)).map(|(k, _)| k.to_owned()).collect();
+        for key in expired {
+            if let Some(v) = indexes.remove(&key) {
+                uncompacted += v.len as u64;
+                // 过期前的value还算历史, get_at按过期时间判断
+                if let Some(history) = &mut history {
+                    history.push(&key, v);
+                }
+            }
+        }
+        // 上次compact之前的历史都没了; 没compact过时历史从最早的记录开始
+        if let Some(history) = &mut history {
+            history.set_horizon(read_compacted(&path)?.or(oldest).unwrap_or_else(unix_time));
         }
 
-        vec.sort_unstable();
-        let mut maxn: u64 = 0;
-        if let Some(n) = vec.last() {
-            maxn = *n;
+        // 编号不一定连续, 接着写的是编号最大的那个; 空文件只有是最后一个时才会留下
+        let mut maxn = last.unwrap_or(0);
+
+        // value log可能是以前开着`Config::with_value_log`写的, 不管现在开没开都要能读
+        let mut vlogs = Readers::with_extension(path.clone(), VALUE_LOG_EXT, &config);
+        let mut vlog_size = 0;
+        for entry in fs::read_dir(&path)? {
+            let vpath = entry?.path();
+            if vpath.extension().is_some_and(|ext| ext == VALUE_LOG_EXT) {
+                let n = vpath.file_stem().and_then(|v| v.to_str()).and_then(|v| v.parse().ok());
+                if let Some(n) = n.filter(|&n| n >= vfloor) {
+                    vlogs.add(n);
+                    vlog_size += fs::metadata(&vpath)?.len();
+                }
+            }
+        }
+        let vnth = vlogs.generations().last().cloned().unwrap_or(0);
+        // replay只知道记录里的vsize, 不是原样存的value要读出它们有多长; hint里本来就有
+        if hinted_end.is_none() {
+            let encoded = FLAG_COMPRESSED | FLAG_REF | FLAG_CHUNKED | FLAG_VLOG;
+            for v in indexes.values_mut().filter(|v| v.flags() & encoded != 0) {
+                v.value_len = resolve_len(&mut readers, &mut vlogs, v.n, v.pos)?;
+            }
         }
-        maxn += 1;
+        let vlog_live: u64 = indexes.iter().map(|(_, v)| v.vlen as u64).sum();
 
-        readers.insert(maxn, open_file(&path, maxn).0);
-        let writer = open_file(&path, maxn).0;
-        return Ok(KvStore {
+        let writer = if read_only {
+            None
+        } else if maxn > 0 && last_end < config.max_log_size {
+            // 末尾残缺的记录截掉, 新记录接在最后一条完整的记录后面
+            open_file(&path, maxn).0.set_len(last_end)?;
+            Some(LogWriter::open(&path, maxn, config.direct_io, config.write_buffer_size)?)
+        } else {
+            maxn += 1;
+            readers.add(maxn);
+            Some(LogWriter::create(&path, maxn, config.direct_io, config.write_buffer_size)?)
+        };
+        Ok(KvStore {
             path,
+            config,
             nth: maxn,
             writer,
             readers,
             indexes,
-            uncompacted
+            uncompacted,
+            poisoned: false,
+            dedup: DedupTable::default(),
+            version,
+            vlogs,
+            vlog: None,
+            vnth,
+            vlog_garbage: vlog_size.saturating_sub(vlog_live),
+            unsynced: BTreeSet::new(),
+            unsynced_vlogs: BTreeSet::new(),
+            flusher: None,
+            compacting: false,
+            compact_boundary: None,
+            subscribers: Subscribers::default(),
+            evictions: Evictions::default(),
+            meta,
+            history,
+            _lock: lock,
         })
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let mut file = &self.writer;
-        let curpos = file.seek(SeekFrom::Current(0)).unwrap();
-        let k = key.as_bytes();
-        let v = value.as_bytes();
+        self.set_value(key, &value)
+    }
+
+    /// `set`的实现, value只需要借用
+    fn set_value(&mut self, key: String, value: &str) -> Result<()> {
+        self.set_expiring(key, value, 0)
+    }
+
+    /// Sets the timestamp of `key` to now, without rewriting its value, and
+    /// returns whether the key exists.
+    ///
+    /// The new timestamp is what `metadata` and `retain` report from then
+    /// on, e.g. for an LRU policy outside the store. Only a small record
+    /// without a value is appended; compaction folds the timestamp into the
+    /// value's record. Touching the first key upgrades the directory to log
+    /// format version 5, which older versions of `kvs` refuse to open.
+    pub fn touch(&mut self, key: &str) -> Result<bool> {
+        self.check_writable()?;
+        let v = match self.lookup(key) {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        self.upgrade_version(TOUCH_VERSION)?;
+        let timestamp = unix_time();
+        let (_, len) = self.append_record(timestamp, FLAG_TOUCH, key.as_bytes(), &[], 0)?;
+        self.indexes.replace(key, DataIndex { timestamp, ..v.clone() });
+        if let Some(history) = &mut self.history {
+            history.push(key, v);
+        }
+        self.uncompacted += len as u64;
+        Ok(true)
+    }
+
+    /// 现在的时间, 按`Config::with_clock`的时钟, 毫秒
+    fn now_millis(&self) -> u64 {
+        to_millis(self.config.clock.now())
+    }
+
+    /// 写`key`的value, `expires`不为0时到那个时间过期
+    fn set_expiring(&mut self, key: String, value: &str, expires: u64) -> Result<()> {
+        self.check_value_size(value.len() as u64)?;
+        if expires != 0 {
+            self.check_writable()?;
+            self.upgrade_version(TTL_VERSION)?;
+        }
+        if value.len() as u64 > self.config.chunk_size {
+            self.set_from_reader(key.clone(), value.len() as u64, value.as_bytes())?;
+            // 分块的value和TTL记录分开写, 中间崩溃的话value不会过期
+            return match expires {
+                0 => Ok(()),
+                _ => self.write_ttl(&key, expires),
+            };
+        }
         let unixtime = unix_time();
+        let (curpos, len, vlen, flags) = self.append_value(unixtime, key.as_bytes(), value.as_bytes(), expires)?;
+        let ksize = index_ksize(flags, key.len());
+        let vsize = record_vsize(key.len(), len, expires);
+        let value_len = value.len() as u32;
+        self.commit_set(key, DataIndex { n: self.nth, pos: curpos, len, timestamp: unixtime, vlen, ksize, vsize, value_len, expires })
+    }
 
-        file.write_all(&unixtime.to_le_bytes()[..])?;
-        file.write_all(&(k.len() as u32).to_le_bytes()[..])?;
-        file.write_all(&(v.len() as u32).to_le_bytes()[..])?;
-        file.write_all(k)?;
-        file.write_all(v)?;
-        file.flush().unwrap();
+    /// Sets `key` to `value` and returns the value it replaces, like
+    /// `HashMap::insert`.
+    ///
+    /// The old value is read through the index entry the new record
+    /// displaces. Without an old value this is the same as `set` and returns
+    /// `None`.
+    ///
+    /// # Errors
+    ///
+    /// If the old value can't be read, nothing is written.
+    pub fn set_get(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let old = match self.lookup(&key) {
+            Some(v) => Some(self.read_string(&v)?),
+            None => None,
+        };
+        self.set(key, value)?;
+        Ok(old)
+    }
+
+    /// Sets `key` to a value of `len` bytes read from `reader`.
+    ///
+    /// The value is streamed into the log in chunks instead of being held in
+    /// memory, so it is neither compressed nor deduplicated. If `reader` ends
+    /// before `len` bytes or fails, nothing is written. Read it back with
+    /// `get_reader`; `get` only works if the value is valid UTF-8.
+    ///
+    /// No checksum is computed while streaming: the log format has no CRC
+    /// field (see the format notes on `KvStore`), so a streamed value that is
+    /// later damaged on disk is returned as is by `get_reader` and not noticed
+    /// when the log is replayed, same as any other record.
+    ///
+    /// Values too large for a single record (2 GiB) are split into several
+    /// records transparently. This upgrades the directory to log format
+    /// version 2, which older versions of `kvs` refuse to open.
+    pub fn set_from_reader(&mut self, key: String, len: u64, reader: impl Read) -> Result<()> {
+        self.check_value_size(len)?;
+        let chunked = len > self.config.chunk_size;
+        let vsize = if chunked {
+            len.div_ceil(self.config.chunk_size) * REF_SIZE as u64
+        } else {
+            len
+        };
+        check_sizes(key.as_bytes(), vsize)?;
+        self.check_writable()?;
+        if chunked {
+            self.upgrade_version(CHUNKED_VERSION)?;
+        }
 
-        // file.write_u64::<LittleEndian>(unixtime).unwrap();
-        // file.write_u32::<LittleEndian>(k.len() as _).unwrap();
-        // file.write_u32::<LittleEndian>(v.len() as _).unwrap();
-        // file.write_all(k).unwrap();
-        // file.write_all(v).unwrap();
+        let unixtime = unix_time();
+        let curpos = self.writer().pos;
+        let written = if chunked {
+            self.stream_chunks(unixtime, key.as_bytes(), len, reader)
+        } else {
+            self.stream_item(unixtime, 0, key.as_bytes(), len as u32, reader).map(|_| curpos)
+        };
+        let pos = match written.and_then(|pos| self.sync_writer().map(|_| pos)) {
+            Ok(pos) => pos,
+            Err(e) => {
+                self.rollback(curpos);
+                return Err(io_at(self.nth, curpos)(e));
+            }
+        };
+        let value_len = len as u32;
+        let len = (self.writer().pos - pos) as u32;
+        let ksize = index_ksize(if chunked { FLAG_CHUNKED } else { 0 }, key.len());
+        let vsize = vsize as u32;
+        self.commit_set(key, DataIndex { n: self.nth, pos, len, timestamp: unixtime, vlen: 0, ksize, vsize, value_len, expires: 0 })
+    }
 
-        let len = 16 + (k.len() + v.len()) as u32;
-        if let Some(v) = self.indexes.insert(key, DataIndex {
-            n: self.nth,
-            pos: curpos,
-            len,
-            timestamp: unixtime,
-        })
-        {
+    /// 把`reader`里的`len`个字节分成多条blob记录写进当前log, 最后写一条记下所有块的记录
+    ///
+    /// 所有块都在同一个log里, 失败时截断一次就能回滚. 返回最后那条记录的位置.
+    fn stream_chunks(&mut self, timestamp: u64, k: &[u8], len: u64, mut reader: impl Read) -> io::Result<u64> {
+        let mut chunks = Vec::new();
+        let mut left = len;
+        while left > 0 {
+            let size = left.min(self.config.chunk_size);
+            chunks.extend_from_slice(&encode_ref(self.nth, self.writer().pos));
+            self.stream_item(timestamp, FLAG_BLOB, &[], size as u32, &mut reader)?;
+            left -= size;
+        }
+        let pos = self.writer().pos;
+        encode_item(self.writer(), timestamp, FLAG_CHUNKED, k, &chunks)?;
+        Ok(pos)
+    }
+
+    /// 把刚写好的记录放进索引
+    fn commit_set(&mut self, key: String, index: DataIndex) -> Result<()> {
+        self.subscribers.notify(|| ChangeEvent::Set { key: key.clone() });
+        // 只有保留历史时才要多一份key
+        let history_key = self.history.as_ref().map(|_| key.clone());
+        if let Some(v) = self.indexes.insert(key, index) {
             self.uncompacted += v.len as u64;
-            if let Some(file) = self.readers.get_mut(&v.n) {
-                remove_item(file, v.pos);
+            self.vlog_garbage += v.vlen as u64;
+            if let (Some(history), Some(key)) = (&mut self.history, history_key) {
+                history.push(&key, v);
             }
         }
 
-        if self.uncompacted >= COMPACTION_THRESHOLD {
-            self.compact();
+        self.roll_if_full()?;
+        if self.config.auto_compaction {
+            self.compact_if_needed()?;
         }
         Ok(())
     }
 
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(vv) = self.indexes.get(&key) {
-            let s = self.readers.get_mut(&vv.n).map(|f| {
-                // to vsize start postion
-                f.seek(SeekFrom::Start(vv.pos + 8 + 4)).unwrap();
-                let vsize = f.read_u32::<LittleEndian>().unwrap();
-
-                // to vdata start position
-                let ksize = vv.len - 16 - vsize;
-                f.seek(SeekFrom::Current((ksize) as _)).unwrap();
-
-                let mut s = String::with_capacity(vsize as _);
-                let mut take_reader = f.take(vsize as _);
-                take_reader.read_to_string(&mut s).unwrap();
-                s
-            });
-            return Ok(s);
+    /// 把`reader`里的`len`个字节当作value写成一条记录
+    ///
+    /// 每写一块就flush一次, 用O_DIRECT时缓冲区也不会攒下整个value.
+    fn stream_item(&mut self, timestamp: u64, flags: u8, k: &[u8], len: u32, reader: impl Read) -> io::Result<()> {
+        encode_header(self.writer(), timestamp, flags, k.len() as u32, len)?;
+        self.writer().write_all(k)?;
+
+        let mut reader = reader.take(len as u64);
+        let mut buf = vec![0; STREAM_CHUNK_SIZE];
+        let mut left = len as usize;
+        while left > 0 {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            self.writer().write_all(&buf[..n])?;
+            self.writer().flush()?;
+            left -= n;
         }
-        Ok(None)
+        Ok(())
     }
 
-    pub fn remove(&mut self, key:String) -> Result<()> {
-        if let Some(v) = self.indexes.remove(&key) {
-            self.uncompacted += v.len as u64;
-            remove_item(self.readers.get_mut(&v.n).unwrap(), v.pos);
-            return Ok(());
+    /// Sets `key` to `value` only if the key doesn't exist yet.
+    ///
+    /// Returns whether the value was written. An existing key is left
+    /// untouched and nothing is appended to the log.
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        if self.lookup(&key).is_none() {
+            self.set(key, value)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Replaces the value of `key` with `new` only if it is currently
+    /// `expected`.
+    ///
+    /// `None` stands for a missing key on both sides, so `expected: None`
+    /// creates the key only if it is absent and `new: None` removes it. On a
+    /// mismatch nothing is written and the value found is returned, ready
+    /// for a retry. Swapping a missing key for `None` succeeds without
+    /// writing anything.
+    pub fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> Result<CasResult> {
+        let current = self.get_ref(&key)?;
+        if current != expected {
+            return Ok(CasResult::Mismatch { current });
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None if current.is_some() => self.remove_ref(&key)?,
+            None => {}
+        }
+        Ok(CasResult::Swapped)
+    }
+
+    /// Replaces the value of `key` with what `f` makes of it.
+    ///
+    /// `f` is called exactly once, with the current value or `None` if the key
+    /// doesn't exist, and returns the new value, or `None` to remove the key.
+    /// Returns what was written, i.e. the result of `f`. If the current value
+    /// can't be read or `f` panics, nothing is written.
+    pub fn update(&mut self, key: String, f: impl FnOnce(Option<&str>) -> Option<String>) -> Result<Option<String>> {
+        let current = self.get_ref(&key)?;
+        let new = f(current.as_deref());
+        match &new {
+            Some(value) => self.set_value(key, value)?,
+            None if current.is_some() => self.remove_ref(&key)?,
+            None => {}
         }
-        Err(KvsError::KeyNotFound)
+        Ok(new)
+    }
+
+    /// Adds `delta` to the integer value of `key` and returns the result,
+    /// counting a missing key as 0.
+    ///
+    /// The value is stored as decimal text, so `get` returns e.g. `"-3"`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::NotAnInteger` if the current value isn't a decimal
+    /// `i64`, and `KvsError::IntegerOverflow` if the result doesn't fit one.
+    /// Nothing is written in either case.
+    pub fn incr_by(&mut self, key: String, delta: i64) -> Result<i64> {
+        let current = match self.get_ref(&key)? {
+            Some(v) => v.parse::<i64>().map_err(|_| KvsError::NotAnInteger(key.clone()))?,
+            None => 0,
+        };
+        let new = current.checked_add(delta).ok_or_else(|| KvsError::IntegerOverflow(key.clone()))?;
+        self.set_value(key, &new.to_string())?;
+        Ok(new)
+    }
+
+    /// Appends `suffix` to the value of `key` and returns the new length of
+    /// the value in bytes. A missing key is created with `suffix` as value.
+    ///
+    /// The whole new value is written as one record, so `get` still reads a
+    /// single record; the space of the old one is reclaimed by compaction.
+    pub fn append(&mut self, key: String, suffix: &str) -> Result<u64> {
+        let mut value = self.get_ref(&key)?.unwrap_or_default();
+        value.push_str(suffix);
+        self.set_value(key, &value)?;
+        Ok(value.len() as u64)
+    }
+
+    /// Same as `incr_by` with a `delta` of 1.
+    pub fn incr(&mut self, key: String) -> Result<i64> {
+        self.incr_by(key, 1)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.get_ref(&key)
+    }
+
+    /// Returns whether `key` exists.
+    ///
+    /// Only the in-memory index is consulted; unlike `get`, no value is read
+    /// from disk. Expired keys don't exist, but stay in the index until a
+    /// `get` drops them.
+    pub fn contains_key(&self, key: &str) -> bool {
+        let now = self.now_millis();
+        self.indexes.get(key).is_some_and(|v| !v.expired(now))
+    }
+
+    /// Returns where the live record of `key` is: its generation, its offset
+    /// within that log file and its length in bytes, or `None` if the key
+    /// doesn't exist.
+    ///
+    /// Only the in-memory index is consulted, like `contains_key`. The length
+    /// covers the whole record, including the expiry stored with it; a value
+    /// in the value log (see `Config::with_value_log`) or a deduplicated one
+    /// is located by its pointer or reference record. Meant for debugging
+    /// locality and compaction; the numbers change whenever the key is
+    /// written or moved.
+    pub fn locate(&self, key: &str) -> Option<(u64, u64, u32)> {
+        let now = self.now_millis();
+        self.indexes.get(key).filter(|v| !v.expired(now)).map(|v| (v.n, v.pos, v.len))
+    }
+
+    /// Returns the `EntryMeta` of `key`: when it was last written, how long
+    /// its value is and which generation holds it, or `None` if the key
+    /// doesn't exist.
+    ///
+    /// Only the in-memory index is consulted, like `contains_key`. The
+    /// timestamp is the one stored in the record, so it survives reopening
+    /// and compaction; every write of the key refreshes it.
+    pub fn metadata(&self, key: &str) -> Option<EntryMeta> {
+        let now = self.now_millis();
+        self.indexes.get(key).filter(|v| !v.expired(now)).map(EntryMeta::new)
     }
 
-    pub fn compact(&mut self) {
-        let oldfile_num = self.nth + 1;
-        self.nth += 2;
+    /// Returns the value `key` had at `as_of`, or `None` if the key didn't
+    /// exist then.
+    ///
+    /// Needs `Config::with_keep_history`, otherwise this fails with
+    /// `KvsError::UnsupportedOperation`. Versions are told apart by their
+    /// record timestamps, which have second precision: of several writes in
+    /// the same second, the last one wins, and a value expired by `as_of`
+    /// counts as absent. A removal is dated by the clock when it happens;
+    /// removals replayed at `open` carry no timestamp of their own and are
+    /// dated by the newest record before them.
+    ///
+    /// Every compaction, including the one `gc_value_log` runs, and `clear`
+    /// delete the old records and with them the history before that moment.
+    /// Asking about a time before the horizon reported by `stats` fails with
+    /// `KvsError::HistoryTruncated`.
+    pub fn get_at(&mut self, key: &str, as_of: SystemTime) -> Result<Option<String>> {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return Err(KvsError::UnsupportedOperation("get_at needs Config::with_keep_history")),
+        };
+        let at = to_millis(as_of);
+        if at / 1000 < history.horizon() {
+            return Err(KvsError::HistoryTruncated);
+        }
+        // 现在的value过期了但还在索引里, 也交给history按时间判断
+        match history.get_at(key, self.indexes.get(key), at) {
+            Some(v) => self.read_string(&v).map(Some),
+            None => Ok(None),
+        }
+    }
 
-        let ( mut oldfile, oldfilepath ) = open_file(&self.path, oldfile_num);
-        let mut pos = 0;
-        for v in self.indexes.values_mut() {
-            if let Some(f) = self.readers.get_mut(&v.n) {
-                f.seek(SeekFrom::Start(v.pos)).unwrap();
-                let nwrite = io::copy(&mut f.take(v.len as _), &mut oldfile).unwrap();
+    /// Returns the length in bytes of the value of `key`, or `None` if the key
+    /// doesn't exist.
+    ///
+    /// Only the in-memory index is consulted, like `contains_key`. This is the
+    /// length `get` returns, as in `EntryMeta::value_len`, also for
+    /// compressed, deduplicated, chunked and value log values.
+    pub fn value_len(&self, key: &str) -> Option<u32> {
+        self.metadata(key).map(|meta| meta.value_len())
+    }
 
-                v.n = oldfile_num;
-                v.pos = pos;
+    /// 查`key`的索引项, 已经过期的从索引里删掉, 当作没有
+    fn lookup(&mut self, key: &str) -> Option<DataIndex> {
+        let now = self.now_millis();
+        self.lookup_at(key, now)
+    }
 
-                pos += nwrite;
+    /// `lookup`, 按给定的时间`now`(Unix毫秒)判断过期
+    ///
+    /// 删的时候不写tombstone: 记录本身带着过期时间, replay时一样会被去掉.
+    fn lookup_at(&mut self, key: &str, now: u64) -> Option<DataIndex> {
+        let v = self.indexes.get(key)?;
+        if !v.expired(now) {
+            return Some(v.clone());
+        }
+        if let Some(v) = self.indexes.remove(key) {
+            self.uncompacted += v.len as u64;
+            self.vlog_garbage += v.vlen as u64;
+            self.evictions.notify(|| key.to_owned(), EvictReason::Expired);
+            // 过期的value照样算历史, get_at按过期时间判断
+            if let Some(history) = &mut self.history {
+                history.push(key, v);
             }
         }
+        None
+    }
 
-        let (mut writer, writerpath) = open_file(&self.path, self.nth);
+    /// Returns the number of live keys, from the in-memory index.
+    pub fn len(&self) -> usize {
+        self.indexes.len()
+    }
+
+    /// Iterates over the live keys in ascending order, from the in-memory
+    /// index; no value is read from disk.
+    ///
+    /// With `IndexKind::Hash` the keys are collected and sorted first. The
+    /// iterator borrows the store, so the store can't be written while
+    /// iterating:
+    ///
+    /// ```rust,compile_fail,E0502
+    /// # use kvs::{KvStore, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut store = KvStore::open(std::env::current_dir()?)?;
+    /// for key in store.keys() {
+    ///     store.remove(key.to_owned())?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn keys(&self) -> impl Iterator<Item = &str> + '_ {
+        let keys: Box<dyn Iterator<Item = &str>> = match self.indexes.range((Bound::Unbounded, Bound::Unbounded)) {
+            Ok(entries) => Box::new(entries.map(|(k, _)| k)),
+            // 无序的索引不支持range, 只能排序一遍
+            Err(_) => {
+                let mut keys: Vec<&str> = self.indexes.iter().map(|(k, _)| k).collect();
+                keys.sort_unstable();
+                Box::new(keys.into_iter())
+            }
+        };
+        keys
+    }
+
+    /// Returns the live keys last written after `since`, a Unix time in
+    /// seconds, in ascending order.
+    ///
+    /// Only the timestamps in the index are compared; no value is read.
+    /// Compaction keeps the timestamps of the records it moves, so keys only
+    /// show up here when they were actually set. Removed keys are not
+    /// reported.
+    pub fn modified_since(&self, since: u64) -> Vec<String> {
+        let now = self.now_millis();
+        let mut keys: Vec<String> = self
+            .indexes
+            .iter()
+            .filter(|(_, v)| v.timestamp > since && !v.expired(now))
+            .map(|(k, _)| k.to_owned())
+            .collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Returns the smallest key, reading nothing from disk.
+    ///
+    /// With `IndexKind::Hash` all keys are compared to find it.
+    pub fn first_key(&self) -> Option<String> {
+        self.indexes.first_key().map(str::to_owned)
+    }
+
+    /// Returns the largest key, reading nothing from disk.
+    ///
+    /// With `IndexKind::Hash` all keys are compared to find it.
+    pub fn last_key(&self) -> Option<String> {
+        self.indexes.last_key().map(str::to_owned)
+    }
+
+    /// Removes the smallest key and returns it with its value, or `None` if
+    /// the store is empty.
+    ///
+    /// The removal is logged like `remove`. Useful for treating the store as a
+    /// queue ordered by key; see `SharedKvStore::pop_first` to share one.
+    pub fn pop_first(&mut self) -> Result<Option<(String, String)>> {
+        self.pop(Self::first_key)
+    }
 
-        let mut readers = HashMap::new();
-        readers.insert(oldfile_num, oldfile);
-        readers.insert(self.nth, writer);
+    /// Removes the largest key and returns it with its value, or `None` if
+    /// the store is empty. See `pop_first`.
+    pub fn pop_last(&mut self) -> Result<Option<(String, String)>> {
+        self.pop(Self::last_key)
+    }
 
-        for (n, _) in &self.readers {
-            fs::remove_file(self.path.join(format!("{}.log", n))).unwrap();
+    /// 读出`next`找到的key的value再删掉, 读失败时不删
+    ///
+    /// 已经过期的key被`get_ref`从索引里删掉, 接着找下一个.
+    fn pop(&mut self, next: impl Fn(&Self) -> Option<String>) -> Result<Option<(String, String)>> {
+        while let Some(key) = next(self) {
+            if let Some(value) = self.get_ref(&key)? {
+                self.remove_ref(&key)?;
+                return Ok(Some((key, value)));
+            }
         }
+        Ok(None)
+    }
 
-        self.readers = readers;
-        self.writer = open_file(&self.path, self.nth).0;
+    /// Returns whether the store holds no live keys.
+    pub fn is_empty(&self) -> bool {
+        self.indexes.is_empty()
     }
-}
 
-fn fpos(f: &mut File) -> io::Result<u64> {
-    f.seek(SeekFrom::Current(0))
-}
+    /// Gets the value of a borrowed key.
+    ///
+    /// Same as `get`, but the caller doesn't need to allocate a `String` for
+    /// the key; only the returned value is allocated.
+    pub fn get_ref(&mut self, key: &str) -> Result<Option<String>> {
+        match self.lookup(key) {
+            Some(v) => self.read_string(&v).map(Some),
+            None => Ok(None),
+        }
+    }
 
-// fn read_data(f: &mut File, pos: u64) -> Result<Option<>>
+    /// 读出`v`指向的value, 再转成String
+    fn read_string(&mut self, v: &DataIndex) -> Result<String> {
+        let value = self.read_live(v)?;
+        String::from_utf8(value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            .map_err(io_at(v.n, v.pos))
+    }
 
-fn read_item(n: u64, f: &mut File) -> Result<(String, DataIndex)> {
-    let pos = fpos(f)?;
+    /// Gets the value of a key as `Bytes`.
+    ///
+    /// The value is read into a single buffer which is handed out without
+    /// copying, so it can go straight into a response frame. Unlike `get`, the
+    /// value is not checked to be valid UTF-8.
+    #[cfg(feature = "bytes")]
+    pub fn get_bytes(&mut self, key: &str) -> Result<Option<bytes::Bytes>> {
+        match self.lookup(key) {
+            Some(v) => Ok(Some(self.read_live(&v)?.into())),
+            None => Ok(None),
+        }
+    }
 
-    let timestamp: u64 = f.read_u64::<LittleEndian>()?;
-    let ksize = f.read_u32::<LittleEndian>()?;
-    let vsize = f.read_u32::<LittleEndian>()?;
+    /// Returns a reader over the value of a key, without loading the value
+    /// into memory.
+    ///
+    /// The reader has its own handle to the log file, so it stays valid across
+    /// later writes and compactions and doesn't count against
+    /// `Config::with_max_open_files`. Compressed values are decompressed into
+    /// memory first.
+    pub fn get_reader(&mut self, key: &str) -> Result<Option<impl Read>> {
+        let (mut n, mut pos) = match self.lookup(key) {
+            Some(vv) => (vv.n, vv.pos),
+            None => return Ok(None),
+        };
+        self.flush_buffers()?;
+        let mut f = self.readers.open_new(n)?;
+        let (mut flags, mut vsize) = seek_value(&mut f, pos).map_err(io_at(n, pos))?;
+        if flags & FLAG_CHUNKED != 0 {
+            let mut raw = vec![0; vsize as usize];
+            f.read_exact(&mut raw).map_err(io_at(n, pos))?;
+            let mut chunks = Vec::new();
+            for target in raw.chunks(REF_SIZE) {
+                let (tn, tpos) = decode_ref(target).map_err(io_at(n, pos))?;
+                let mut f = self.readers.open_new(tn)?;
+                let (_, tvsize) = seek_value(&mut f, tpos).map_err(io_at(tn, tpos))?;
+                chunks.push(f.take(tvsize as u64));
+            }
+            chunks.reverse();
+            return Ok(Some(ValueReader::Chunks(chunks)));
+        }
+        if flags & (FLAG_REF | FLAG_VLOG) != 0 {
+            let mut raw = vec![0; vsize as usize];
+            f.read_exact(&mut raw).map_err(io_at(n, pos))?;
+            if flags & FLAG_VLOG != 0 {
+                let (vn, vpos, _) = decode_pointer(&raw).map_err(io_at(n, pos))?;
+                n = vn;
+                pos = vpos;
+                f = self.vlogs.open_new(n)?;
+            } else {
+                let target = decode_ref(&raw).map_err(io_at(n, pos))?;
+                n = target.0;
+                pos = target.1;
+                f = self.readers.open_new(n)?;
+            }
+            let (tflags, tvsize) = seek_value(&mut f, pos).map_err(io_at(n, pos))?;
+            flags = tflags;
+            vsize = tvsize;
+        }
 
-    // let mut key: Vec<u8> = Vec::with_capacity(ksize as _);
-    // f.take(ksize as _).read_to_end(&mut key)?;
+        if flags & FLAG_COMPRESSED != 0 {
+            let mut raw = vec![0; vsize as usize];
+            f.read_exact(&mut raw).map_err(io_at(n, pos))?;
+            let value = decode_bytes(flags, raw).map_err(io_at(n, pos))?;
+            return Ok(Some(ValueReader::Memory(io::Cursor::new(value))));
+        }
+        Ok(Some(ValueReader::File(f.take(vsize as u64))))
+    }
 
-    let mut key: Vec<u8> = vec![0; ksize as _];
-    f.read_exact(&mut key)?;
+    /// Copies the value of a key into `out` without loading the value into
+    /// memory; see `get_reader`.
+    ///
+    /// The value is copied in chunks of 64 KiB. Returns whether the key
+    /// exists; nothing is written for a missing key.
+    pub fn get_into(&mut self, key: &str, out: &mut impl Write) -> Result<bool> {
+        let mut reader = match self.get_reader(key)? {
+            Some(reader) => reader,
+            None => return Ok(false),
+        };
+        let mut buf = vec![0; STREAM_CHUNK_SIZE];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            out.write_all(&buf[..n])?;
+        }
+        Ok(true)
+    }
 
-    f.seek(SeekFrom::Current(vsize as _)).unwrap();
+    /// 读出`v`指向的记录的value, 解压和解引用过的
+    fn read_live(&mut self, v: &DataIndex) -> Result<Vec<u8>> {
+        self.flush_buffers()?;
+        let (n, pos) = (v.n, v.pos);
+        let (flags, raw) = if self.readers.batched() {
+            let buf = self.readers.read_records(&[(n, pos, v.len)])?.pop().unwrap();
+            let (flags, raw) = value_from_record(&buf).map_err(io_at(n, pos))?;
+            (flags, raw.to_vec())
+        } else {
+            // 索引里有, 文件却没了, 不能当作key不存在
+            let f = self.readers.get(n)?;
+            // 索引里有flags, key和value的长度, 一次读出value, 不用先读header
+            let mut raw = vec![0; v.vsize as usize];
+            f.seek(SeekFrom::Start(v.value_pos()))
+                .and_then(|_| f.read_exact(&mut raw))
+                .map_err(io_at(n, pos))?;
+            (v.flags(), raw)
+        };
+        resolve_bytes(&mut self.readers, &mut self.vlogs, n, pos, flags, raw)
+    }
 
-    Ok((
-        String::from_utf8(key).unwrap(),
-        DataIndex{
-            n,
-            pos,
-            len: 16 + ksize + vsize,
-            timestamp
+    /// Gets the values of several keys at once.
+    ///
+    /// The result has one entry per key, in the same order. With
+    /// `IoBackend::Uring` all reads are submitted as one batch; otherwise this
+    /// is the same as calling `get_ref` for each key.
+    pub fn multi_get(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        if self.readers.batched() {
+            self.flush_buffers()?;
+            let locs: Vec<_> = keys
+                .iter()
+                .map(|k| self.lookup(k).map(|v| (v.n, v.pos, v.len)))
+                .collect();
+            let reqs: Vec<_> = locs.iter().flatten().cloned().collect();
+            let mut bufs = self.readers.read_records(&reqs)?.into_iter();
+            let mut values = Vec::with_capacity(keys.len());
+            for loc in locs {
+                let (n, pos, _) = match loc {
+                    Some(loc) => loc,
+                    None => {
+                        values.push(None);
+                        continue;
+                    }
+                };
+                let buf = bufs.next().unwrap();
+                let (flags, raw) = value_from_record(&buf).map_err(io_at(n, pos))?;
+                values.push(Some(resolve_value(&mut self.readers, &mut self.vlogs, n, pos, flags, raw.to_vec())?));
+            }
+            return Ok(values);
         }
-    ))
-}
 
-fn remove_item(f: &mut File, pos: u64) {
-    f.seek(SeekFrom::Start(pos)).unwrap();
-    f.write_u64::<LittleEndian>(0).unwrap();
-}
+        keys.iter().map(|k| self.get_ref(k)).collect()
+    }
 
+    pub fn remove(&mut self, key:String) -> Result<()> {
+        self.remove_ref(&key)
+    }
 
-fn open_file(path: &PathBuf, n: u64) -> (File, PathBuf) {
-    let fpath = path.join(format!("{}.log", n));
-    (OpenOptions::new().read(true).write(true).create(true).open(&fpath).unwrap(), fpath)
-}
+    /// Removes `key` and returns its value, or `None` if it didn't exist.
+    ///
+    /// The value is read through the index entry that the tombstone then
+    /// replaces, so this is one operation instead of a `get` followed by a
+    /// `remove`.
+    ///
+    /// # Errors
+    ///
+    /// If the value can't be read, e.g. because the record is corrupted, the
+    /// key is still removed and the read error is returned.
+    pub fn take(&mut self, key: String) -> Result<Option<String>> {
+        let value = match self.lookup(&key) {
+            Some(v) => self.read_string(&v),
+            None => return Ok(None),
+        };
+        self.remove_ref(&key)?;
+        value.map(Some)
+    }
 
-fn unix_time() -> u64 {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
-}
+    /// Removes a borrowed key.
+    ///
+    /// Same as `remove`, but the caller doesn't need to allocate a `String`
+    /// for the key.
+    pub fn remove_ref(&mut self, key: &str) -> Result<()> {
+        if self.lookup(key).is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
 
+        // tombstone先落盘(按SyncPolicy), 再修改索引
+        let (_, len, _) = self.append_item(0, key.as_bytes(), &[], 0)?;
+        if let Some(v) = self.indexes.remove(key) {
+            self.uncompacted += (v.len + len) as u64;
+            self.vlog_garbage += v.vlen as u64;
+            if let Some(history) = &mut self.history {
+                history.push_removed(key, v, unix_time());
+            }
+        }
+        self.subscribers.notify(|| ChangeEvent::Remove { key: key.to_owned() });
+        self.roll_if_full()
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::fs::OpenOptions;
-    use std::io::{Seek, SeekFrom, Write};
-    use std::path::PathBuf;
-    use byteorder::{LittleEndian, WriteBytesExt};
+    /// Removes every entry for which `f` returns `false`, logging a
+    /// tombstone for each like `remove`.
+    ///
+    /// `f` sees each key with its `EntryMeta`, so no value is read. The keys
+    /// are visited in no particular order; all are visited before the first
+    /// one is removed. The store is borrowed for the whole call, so `f` can't
+    /// use it:
+    ///
+    /// ```rust,compile_fail,E0502
+    /// # use kvs::{KvStore, Result};
+    /// # fn try_main() -> Result<()> {
+    /// let mut store = KvStore::open(std::env::current_dir()?)?;
+    /// store.retain(|key, _| store.contains_key(key))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Stops at the first failed removal; the entries removed before it stay
+    /// removed.
+    pub fn retain(&mut self, mut f: impl FnMut(&str, &EntryMeta) -> bool) -> Result<RetainStats> {
+        self.check_writable()?;
+        let mut stats = RetainStats::default();
+        let mut removed = Vec::new();
+        let now = self.now_millis();
+        for (key, v) in self.indexes.iter().filter(|(_, v)| !v.expired(now)) {
+            if f(key, &EntryMeta::new(v)) {
+                stats.kept += 1;
+            } else {
+                removed.push(key.to_owned());
+            }
+        }
+        for key in removed {
+            self.remove_ref(&key)?;
+            stats.removed += 1;
+        }
+        Ok(stats)
+    }
 
-    use crate::{KvsError, KvStore};
+    /// Removes every key, leaving an empty store that is ready for writes.
+    ///
+    /// Instead of writing a tombstone per key, a new empty generation is
+    /// started and all older log and value log files are deleted, so this
+    /// takes time proportional to the number of files, not keys. A crash in
+    /// the middle leaves either all of the old data or none of it visible:
+    /// a marker naming the new generation is written before anything is
+    /// deleted, and `open` finishes the deletion while the marker exists.
+    /// Subscribers get a `ChangeEvent::Remove` for every key.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::CompactionRunning` while `SharedKvStore::compact`
+    /// is copying records.
+    pub fn clear(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if self.compacting {
+            return Err(KvsError::CompactionRunning);
+        }
+        // 先换到新的空generation, 写标记失败的话数据还都在
+        self.writer().flush()?;
+        let n = self.nth + 1;
+        let writer = LogWriter::create(&self.path, n, self.config.direct_io, self.config.write_buffer_size)?;
+        self.unsynced.insert(self.nth);
+        self.nth = n;
+        self.readers.add(n);
+        self.writer = Some(writer);
+        let vn = self.vnth + 1;
+        write_clear_marker(&self.path, n, vn)?;
 
-    #[test]
-    pub fn test_init() {
-        let path= PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        let logpath = path.join("target/owntest");
-        let mut kvs = KvStore::open(logpath).unwrap();
-        // let result = kvs.get("hello".to_owned());
-        // assert!(result.is_err());
+        // 标记写下去就算清空了, 之后删文件失败了open也会接着删
+        for (key, _) in self.indexes.iter() {
+            self.subscribers.notify(|| ChangeEvent::Remove { key: key.to_owned() });
+        }
+        self.indexes = I::with_kind(self.config.index_kind);
+        self.uncompacted = 0;
+        self.dedup.take();
+        for g in self.readers.generations() {
+            if g < n {
+                self.readers.remove(g);
+            }
+        }
+        for g in self.vlogs.generations() {
+            self.vlogs.remove(g);
+        }
+        self.vlog = None;
+        self.vnth = vn;
+        self.vlog_garbage = 0;
+        self.unsynced.clear();
+        self.unsynced_vlogs.clear();
+        finish_clear(&self.path, n, vn)?;
+        self.truncate_history()?;
+        let _ = self.write_hint();
+        Ok(())
+    }
 
-        kvs.set("yongwei".to_string(), "iewgnoy555678".to_string()).unwrap();
-        let val = kvs.get("yongwei".to_string());
-        let s = val.unwrap().unwrap();
-        println!("{}", s);
-        assert_eq!(s, "iewgnoy555678".to_string());
+    /// 只读打开的, 或者被poisoned的store不能写
+    fn check_writable(&self) -> Result<()> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        if self.poisoned {
+            return Err(KvsError::Poisoned);
+        }
+        Ok(())
     }
 
-    #[test]
-    pub fn test_seek_write() {
-        let path= PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        let logpath = path.join("target/owntest");
+    /// 要写的记录需要格式版本`version`, 目录的版本比它低就先升级
+    fn upgrade_version(&mut self, version: u32) -> Result<()> {
+        if self.version < version {
+            write_version(&self.path, version)?;
+            self.version = version;
+        }
+        Ok(())
+    }
 
-        let p = logpath.join("tt.log");
-        let mut file = OpenOptions::new().read(true).write(true).create(true).open(&p).unwrap();
-        file.seek(SeekFrom::Start(0));
-        file.write_u64::<LittleEndian>(1);
+    /// 当前的writer, 只能在`check_writable`之后调用
+    fn writer(&mut self) -> &mut LogWriter {
+        self.writer.as_mut().expect("write to a read-only store")
+    }
 
-        file.flush().unwrap();
+    /// value超过了`Config::with_max_value_size`就报错, 在写任何东西之前检查
+    fn check_value_size(&self, len: u64) -> Result<()> {
+        match self.config.max_value_size {
+            Some(max) if len > max => Err(KvsError::ValueTooLarge(len)),
+            _ => Ok(()),
+        }
+    }
+
+    /// 当前log写满了就换一个新的generation
+    fn roll_if_full(&mut self) -> Result<()> {
+        if self.writer().pos >= self.config.max_log_size {
+            self.writer().flush()?;
+            self.unsynced.insert(self.nth);
+            self.nth += 1;
+            self.readers.add(self.nth);
+            self.writer = Some(LogWriter::create(&self.path, self.nth, self.config.direct_io, self.config.write_buffer_size)?);
+        }
+        Ok(())
+    }
+
+    /// 在当前writer末尾追加一条记录, 并按照SyncPolicy刷盘
+    ///
+    /// 返回记录的起始位置, 长度和flags. 写入失败时把文件截断回记录开始的位置,
+    /// 这样log末尾不会留下残缺的记录(比如磁盘满了).
+    fn append_item(&mut self, timestamp: u64, k: &[u8], v: &[u8], expires: u64) -> Result<(u64, u32, u8)> {
+        self.check_writable()?;
+
+        let curpos = self.writer().pos;
+        let flags = match self.write_item(timestamp, k, v, expires).and_then(|flags| self.sync_writer().map(|_| flags)) {
+            Ok(flags) => flags,
+            Err(e) => {
+                self.rollback(curpos);
+                return Err(io_at(self.nth, curpos)(e));
+            }
+        };
+
+        Ok((curpos, (self.writer().pos - curpos) as u32, flags))
+    }
+
+    /// 追加一条set记录, 返回记录的位置, 长度, value log里记录的长度和记录的flags
+    ///
+    /// 开了value log并且value够大时, value写到value log里, log里只写一条指针记录.
+    /// 否则开了去重, 而且之前写过一样的value时, 只写一条指向它的引用记录.
+    /// `expires`不为0时后面再跟一条TTL记录.
+    fn append_value(&mut self, timestamp: u64, k: &[u8], v: &[u8], expires: u64) -> Result<(u64, u32, u32, u8)> {
+        check_sizes(k, v.len() as u64)?;
+        if self.config.value_log && v.len() >= self.config.value_log_threshold {
+            self.check_writable()?;
+            self.upgrade_version(VALUE_LOG_VERSION)?;
+            let compressed = self.compress(v);
+            let (flags, v) = match &compressed {
+                Some(compressed) => (FLAG_COMPRESSED, &compressed[..]),
+                None => (0, v),
+            };
+            let (vn, vpos, vlen) = self.append_value_log(|w| encode_item(w, timestamp, flags, k, v))?;
+            let (curpos, len) = self.append_record(timestamp, FLAG_VLOG, k, &encode_pointer(vn, vpos, vlen), expires)?;
+            return Ok((curpos, len, vlen, FLAG_VLOG));
+        }
+        if !self.config.dedup || v.len() < DEDUP_MIN_SIZE {
+            let (curpos, len, flags) = self.append_item(timestamp, k, v, expires)?;
+            return Ok((curpos, len, 0, flags));
+        }
+
+        let hash = DedupTable::hash(v);
+        if let Some((n, pos)) = self.dedup.get(hash) {
+            // hash一样还要比较内容, 被引用的记录读不出来就当作没有重复
+            if self.value_at(n, pos).ok().as_deref() == Some(v) {
+                let (curpos, len) = self.append_record(timestamp, FLAG_REF, k, &encode_ref(n, pos), expires)?;
+                self.dedup.bytes_saved += (v.len() - REF_SIZE) as u64;
+                return Ok((curpos, len, 0, FLAG_REF));
+            }
+        }
+        let (curpos, len, flags) = self.append_item(timestamp, k, v, expires)?;
+        self.dedup.insert(hash, self.nth, curpos);
+        Ok((curpos, len, 0, flags))
+    }
+
+    /// 在value log末尾用`write`写一条记录, 并按照SyncPolicy刷盘
+    ///
+    /// 返回记录所在的generation, 位置和长度. 写入失败时截断回去, 截断失败也没关系:
+    /// value log里的记录只会通过指针访问, 残缺的记录不会被读到.
+    fn append_value_log(
+        &mut self,
+        write: impl FnOnce(&mut LogWriter) -> io::Result<()>,
+    ) -> Result<(u64, u64, u32)> {
+        if self.vlog.is_none() {
+            // `clear`之后要写的value log还没登记
+            self.vnth = self.vnth.max(1);
+            self.vlogs.add(self.vnth);
+            let vpath = self.path.join(format!("{}.{}", self.vnth, VALUE_LOG_EXT));
+            self.vlog = Some(LogWriter::open_at(&vpath, self.config.direct_io, self.config.write_buffer_size)?);
+        }
+
+        let sync = self.config.sync_policy == SyncPolicy::OnEveryWrite;
+        let path = &self.path;
+        let vlog = self.vlog.as_mut().unwrap();
+        let curpos = vlog.pos;
+        let written = write(vlog)
+            .and_then(|_| vlog.end_write())
+            .and_then(|_| if sync { vlog.flush().and_then(|_| vlog.sync(path)) } else { Ok(()) });
+        if let Err(e) = written {
+            let _ = vlog.truncate(curpos);
+            return Err(io_at(self.vnth, curpos)(e));
+        }
+        let r = (self.vnth, curpos, (vlog.pos - curpos) as u32);
+
+        // value log写满了就换一个新的generation
+        if vlog.pos >= self.config.max_log_size {
+            vlog.flush()?;
+            self.unsynced_vlogs.insert(self.vnth);
+            self.vnth += 1;
+            self.vlogs.add(self.vnth);
+            let vpath = self.path.join(format!("{}.{}", self.vnth, VALUE_LOG_EXT));
+            self.vlog = Some(LogWriter::open_at(&vpath, self.config.direct_io, self.config.write_buffer_size)?);
+        }
+        Ok(r)
+    }
+
+    /// 追加一条原样写入(不压缩)的记录, 和`append_item`一样失败时回滚
+    fn append_record(&mut self, timestamp: u64, flags: u8, k: &[u8], v: &[u8], expires: u64) -> Result<(u64, u32)> {
+        self.check_writable()?;
+
+        let curpos = self.writer().pos;
+        let written = encode_expiring(self.writer(), timestamp, flags, k, v, expires);
+        if let Err(e) = written.and_then(|_| self.sync_writer()) {
+            self.rollback(curpos);
+            return Err(io_at(self.nth, curpos)(e));
+        }
+
+        Ok((curpos, (self.writer().pos - curpos) as u32))
+    }
+
+    /// 读出第`n`个文件`pos`处记录的value(解压过的)
+    fn value_at(&mut self, n: u64, pos: u64) -> Result<Vec<u8>> {
+        self.flush_buffers()?;
+        let f = self.readers.get(n)?;
+        let (flags, raw) = read_raw_value(f, pos).map_err(io_at(n, pos))?;
+        decode_bytes(flags, raw).map_err(io_at(n, pos))
+    }
+
+    /// 写一条set记录, 超过阈值的value压缩, 返回记录的flags
+    fn write_item(&mut self, timestamp: u64, k: &[u8], v: &[u8], expires: u64) -> io::Result<u8> {
+        match self.compress(v) {
+            Some(compressed) => {
+                encode_expiring(self.writer(), timestamp, FLAG_COMPRESSED, k, &compressed, expires)?;
+                Ok(FLAG_COMPRESSED)
+            }
+            None => encode_expiring(self.writer(), timestamp, 0, k, v, expires).map(|_| 0),
+        }
+    }
+
+    /// 超过阈值的value按配置的Codec压缩
+    fn compress(&self, v: &[u8]) -> Option<Vec<u8>> {
+        if v.len() > self.config.compression_threshold {
+            self.config.compression.compress(v)
+        } else {
+            None
+        }
+    }
+
+    /// 写入失败, 把当前log截断回`curpos`, 截断也失败的话标记为poisoned
+    fn rollback(&mut self, curpos: u64) {
+        if self.writer().truncate(curpos).is_err() {
+            self.poisoned = true;
+        }
+    }
+
+    /// 一次写操作结束, 按`SyncPolicy`落盘
+    ///
+    /// 只有`SyncPolicy::OnEveryWrite`马上把写缓冲区写到文件里; 其余的等缓冲区满了,
+    /// 或者读文件之前(`flush_buffers`)再写, 几条记录合成一次write.
+    fn sync_writer(&mut self) -> io::Result<()> {
+        let path = &self.path;
+        let writer = self.writer.as_mut().expect("write to a read-only store");
+        writer.end_write()?;
+        if self.config.sync_policy == SyncPolicy::OnEveryWrite {
+            writer.flush()?;
+            writer.sync(path)?;
+        }
+        Ok(())
+    }
+
+    /// 读文件之前把写缓冲区里攒着的记录交给OS, 不然读不到最近写的记录
+    fn flush_buffers(&self) -> io::Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.write_buf()?;
+        }
+        if let Some(vlog) = &self.vlog {
+            vlog.write_buf()?;
+        }
+        Ok(())
+    }
+
+    /// Returns a channel that receives a `ChangeEvent` for every key set or
+    /// removed from now on, in the order the changes are applied.
+    ///
+    /// A `transaction` sends one event per key it changes, for the final state
+    /// of the key, once the whole batch is applied. Compaction and value log
+    /// garbage collection move records without changing values, so they send
+    /// nothing. Dropping the receiver unsubscribes it.
+    pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+        self.subscribers.subscribe()
+    }
+
+    /// Registers `f` to be called with every live entry the store drops on
+    /// its own, i.e. not through `remove` or another write, and the reason.
+    ///
+    /// For now that's keys found expired by a read or by compaction. Keys
+    /// that expired while the store was closed are dropped by `open`, before
+    /// a callback can be registered, and aren't reported.
+    ///
+    /// `f` runs on a thread of its own, never while the store (or the lock of
+    /// a `SharedKvStore`) is held, so it may call back into a shared store.
+    /// Events are queued for it in order; the store never waits for it.
+    /// Once 1024 events are queued because `f` is slower than the store
+    /// evicts, further events for it are dropped and counted by
+    /// `dropped_evictions`. The thread ends when the store is dropped, after
+    /// the queued events are handled, or when `f` panics.
+    pub fn on_evict(&mut self, f: impl FnMut(&str, EvictReason) + Send + 'static) {
+        self.evictions.register(f);
+    }
+
+    /// Returns how many eviction events were dropped because an `on_evict`
+    /// callback had too many queued.
+    pub fn dropped_evictions(&self) -> u64 {
+        self.evictions.dropped()
+    }
+
+    /// Iterates over all live key/value pairs in ascending key order.
+    ///
+    /// Values are read one at a time as the iterator advances, and a failed
+    /// read only fails its own pair. The iterator borrows the store mutably,
+    /// so it sees exactly the keys live when it was created. Use `rev` for
+    /// descending order. See `Iter`; `scan_unordered` is cheaper when the
+    /// order doesn't matter.
+    pub fn iter(&mut self) -> Iter<'_, I> {
+        let keys = self.keys().map(str::to_owned).collect();
+        Iter::new(self, keys)
+    }
+
+    /// Iterates over the live key/value pairs with keys within `range`, in
+    /// ascending key order.
+    ///
+    /// Bounds behave exactly like those of `BTreeMap::range`, which also
+    /// means this panics if the start of `range` is after its end, or if both
+    /// are the same excluded key. Values are read lazily as with `iter`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::UnsupportedOperation` with `IndexKind::Hash`.
+    pub fn range<R: RangeBounds<String>>(&mut self, range: R) -> Result<Iter<'_, I>> {
+        let bounds = (
+            range.start_bound().map(String::as_str),
+            range.end_bound().map(String::as_str),
+        );
+        self.iter_range(bounds)
+    }
+
+    /// Iterates over the live key/value pairs whose keys start with `prefix`,
+    /// in ascending key order.
+    ///
+    /// Only the matching part of the index is walked. An empty prefix
+    /// matches every key. Values are read lazily as with `iter`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::UnsupportedOperation` with `IndexKind::Hash`.
+    pub fn scan_prefix(&mut self, prefix: &str) -> Result<Iter<'_, I>> {
+        let end = prefix_end(prefix);
+        self.iter_range((Bound::Included(prefix), end.as_ref().map(String::as_str)))
+    }
+
+    /// Iterates over the live key/value pairs whose keys match the glob
+    /// `pattern`, in ascending key order.
+    ///
+    /// See `KeyPattern` for the syntax. Only the part of the index starting
+    /// with the literal prefix of the pattern is walked, and only the values
+    /// of matching keys are read.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::InvalidPattern` for a malformed pattern and
+    /// `KvsError::UnsupportedOperation` with `IndexKind::Hash`.
+    pub fn scan_match(&mut self, pattern: &str) -> Result<Iter<'_, I>> {
+        let pattern = KeyPattern::new(pattern)?;
+        let prefix = pattern.literal_prefix();
+        let end = prefix_end(prefix);
+        let keys = self
+            .indexes
+            .range((Bound::Included(prefix), end.as_ref().map(String::as_str)))?
+            .filter(|(k, _)| pattern.matches(k.as_bytes()))
+            .map(|(k, _)| k.to_owned())
+            .collect();
+        Ok(Iter::new(self, keys))
+    }
+
+    /// Returns the next page of up to `limit` live key/value pairs in
+    /// ascending key order, starting after `cursor` (from the first key with
+    /// `None`), and the cursor of the following page.
+    ///
+    /// The returned cursor is `None` once the last key was returned. No state
+    /// is kept between calls: each page reflects the store at the time of the
+    /// call, so keys set or removed between calls show up or disappear, and
+    /// a cursor stays valid when its key was removed. With a `limit` of 0 the
+    /// page is empty and `cursor` is returned as is.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::UnsupportedOperation` with `IndexKind::Hash`.
+    pub fn scan_page(&mut self, cursor: Option<ScanCursor>, limit: usize) -> Result<ScanPage> {
+        if limit == 0 {
+            return Ok((Vec::new(), cursor));
+        }
+        let start = match &cursor {
+            Some(cursor) => Bound::Excluded(cursor.last_key.as_str()),
+            None => Bound::Unbounded,
+        };
+        // 多取一个, 看后面还有没有
+        let mut keys: Vec<String> = self
+            .indexes
+            .range((start, Bound::Unbounded))?
+            .take(limit + 1)
+            .map(|(k, _)| k.to_owned())
+            .collect();
+        let more = keys.len() > limit;
+        keys.truncate(limit);
+        let next = match keys.last() {
+            Some(last) if more => Some(ScanCursor { last_key: last.clone() }),
+            _ => None,
+        };
+        let page = Iter::new(self, keys).collect::<Result<_>>()?;
+        Ok((page, next))
+    }
+
+    /// 索引里`bounds`范围内的key, 从小到大
+    fn iter_range(&mut self, bounds: (Bound<&str>, Bound<&str>)) -> Result<Iter<'_, I>> {
+        let keys = self.indexes.range(bounds)?.map(|(k, _)| k.to_owned()).collect();
+        Ok(Iter::new(self, keys))
+    }
+
+    /// Iterates over all live key/value pairs in file order.
+    ///
+    /// Each generation is read sequentially from front to back exactly once,
+    /// and only the records the index still points at are yielded. This makes
+    /// full exports much cheaper than a `get` per key, but the order of the
+    /// results is unspecified.
+    pub fn scan_unordered(&self) -> ScanUnordered<'_, I> {
+        let flushed = self.flush_buffers();
+        ScanUnordered::new(&self.path, &self.indexes, self.readers.generations(), self.now_millis(), flushed)
+    }
+
+    /// Calls `f` with every live key and its value, in ascending key order,
+    /// until it returns `ControlFlow::Break`.
+    ///
+    /// Unlike `iter`, nothing is allocated per entry: each record is read
+    /// into a buffer that is reused for the next one, and `f` gets borrowed
+    /// slices. Values are handed out as bytes without checking that they are
+    /// UTF-8. Compressed, deduplicated, chunked and value log values still
+    /// need a buffer of their own to be decoded into. With `IndexKind::Hash`
+    /// the keys are sorted first.
+    ///
+    /// # Errors
+    ///
+    /// Stops at the first value that can't be read.
+    pub fn for_each(&mut self, mut f: impl FnMut(&str, &[u8]) -> ControlFlow<()>) -> Result<()> {
+        self.flush_buffers()?;
+        let now = self.now_millis();
+        let keys: Box<dyn Iterator<Item = (&str, &DataIndex)>> =
+            match self.indexes.range((Bound::Unbounded, Bound::Unbounded)) {
+                Ok(entries) => entries,
+                Err(_) => {
+                    let mut entries: Vec<_> = self.indexes.iter().collect();
+                    entries.sort_unstable_by_key(|&(k, _)| k);
+                    Box::new(entries.into_iter())
+                }
+            };
+        let mut buf = Vec::new();
+        for (key, v) in keys.filter(|(_, d)| !d.expired(now)) {
+            let (n, pos) = (v.n, v.pos);
+            let file = self.readers.get(n)?;
+            buf.resize(v.len as usize, 0);
+            file.seek(SeekFrom::Start(pos))
+                .and_then(|_| file.read_exact(&mut buf))
+                .map_err(io_at(n, pos))?;
+            let (flags, raw) = value_from_record(&buf).map_err(io_at(n, pos))?;
+            let flow = if flags == 0 {
+                f(key, raw)
+            } else {
+                let value = resolve_bytes(&mut self.readers, &mut self.vlogs, n, pos, flags, raw.to_vec())?;
+                f(key, &value)
+            };
+            if flow.is_break() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with every live key and its value in the order the records
+    /// are stored, until it returns `ControlFlow::Break`.
+    ///
+    /// Reads the log files front to back like `scan_unordered`, and reuses
+    /// its buffers like `for_each`, so this is the fastest way to visit all
+    /// entries when their order doesn't matter.
+    pub fn for_each_unordered(&self, f: impl FnMut(&str, &[u8]) -> ControlFlow<()>) -> Result<()> {
+        self.scan_unordered().visit(f)
+    }
+
+    /// Returns a cursor over every record of the log in write order.
+    ///
+    /// This includes tombstones and overwritten records, so it can be used to
+    /// follow all changes of the store. See `LogCursor`.
+    pub fn log_cursor(&self) -> LogCursor<'_> {
+        LogCursor::new(&self.path, self.readers.generations(), self.flush_buffers())
+    }
+
+    /// Returns statistics about the store.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            keys: self.indexes.len(),
+            generations: self.readers.generations().len(),
+            uncompacted_bytes: self.uncompacted,
+            dedup_bytes_saved: self.dedup.bytes_saved,
+            value_log_garbage_bytes: self.vlog_garbage,
+            meta: self.meta.clone(),
+            history_horizon: self.history.as_ref().map(|h| UNIX_EPOCH + Duration::from_secs(h.horizon())),
+        }
+    }
+
+    /// Returns the metadata value stored under `key` by `set_meta`.
+    ///
+    /// The metadata is read when the store is opened, so this never touches
+    /// the disk.
+    pub fn get_meta(&self, key: &str) -> Option<&str> {
+        self.meta.get(key).map(String::as_str)
+    }
+
+    /// Stores `value` under `key` in the metadata of the store, e.g. a schema
+    /// version or the id of the application that owns the directory.
+    ///
+    /// Metadata lives in a `META` file next to the logs, apart from the
+    /// regular keys: it isn't returned by `get` or scans, and compaction and
+    /// `clear` leave it alone; `compact_to` copies it. The file is replaced
+    /// atomically, so a crash leaves either the old or the new metadata.
+    /// All keys and values together may take up to 4 KiB; a write beyond that
+    /// fails with `KvsError::MetaTooLarge` and changes nothing.
+    pub fn set_meta(&mut self, key: &str, value: &str) -> Result<()> {
+        self.check_writable()?;
+        let mut meta = self.meta.clone();
+        meta.insert(key.to_owned(), value.to_owned());
+        let size = meta_size(&meta);
+        if size > MAX_META_SIZE {
+            return Err(KvsError::MetaTooLarge(size as u64));
+        }
+        write_meta(&self.path, &meta)?;
+        self.meta = meta;
+        Ok(())
+    }
+
+    /// Returns the space usage of every log file, in ascending generation
+    /// order.
+    ///
+    /// Live bytes are summed from the index, so this walks all keys. Blobs
+    /// kept alive only by deduplicated references (see `Config::with_dedup`)
+    /// count as dead. The generation with the most dead bytes gains the most
+    /// from compaction.
+    pub fn generations(&self) -> Result<Vec<GenerationInfo>> {
+        self.flush_buffers()?;
+        let mut live: HashMap<u64, u64> = HashMap::new();
+        for (_, v) in self.indexes.iter() {
+            *live.entry(v.n).or_default() += v.len as u64;
+        }
+        let mut infos = Vec::new();
+        for n in self.readers.generations() {
+            let file_size = fs::metadata(self.path.join(format!("{}.log", n)))?.len();
+            let live_bytes = live.get(&n).cloned().unwrap_or(0);
+            infos.push(GenerationInfo {
+                generation: n,
+                file_size,
+                live_bytes,
+                dead_bytes: file_size.saturating_sub(live_bytes),
+            });
+        }
+        Ok(infos)
+    }
+
+    /// Estimates the memory used by the store, mostly by its index.
+    ///
+    /// Walks all keys, so it takes time linear in the number of keys. The
+    /// estimate of the index structure follows `KeyIndex::approximate_overhead`.
+    pub fn approximate_memory(&self) -> MemoryUsage {
+        let mut buffer_bytes = 0;
+        #[cfg(target_os = "linux")]
+        for w in self.writer.iter().chain(self.vlog.iter()) {
+            buffer_bytes += w.direct.as_ref().map_or(0, DirectBuf::capacity);
+        }
+        MemoryUsage {
+            key_bytes: self.indexes.iter().map(|(k, _)| k.len()).sum(),
+            entry_bytes: self.indexes.len() * (size_of::<String>() + size_of::<DataIndex>()),
+            index_overhead_bytes: self.indexes.approximate_overhead(),
+            dedup_bytes: self.dedup.approximate_memory(),
+            buffer_bytes,
+        }
+    }
+
+    /// Estimates the memory used by the index alone: the keys, their entries
+    /// and the index structure, as in `approximate_memory`.
+    pub fn estimated_index_memory(&self) -> usize {
+        let usage = self.approximate_memory();
+        usage.key_bytes + usage.entry_bytes + usage.index_overhead_bytes
+    }
+
+    /// Starts syncing everything written so far to disk in the background,
+    /// without blocking the caller.
+    ///
+    /// The returned handle completes once every record appended before this
+    /// call is flushed and synced, including records of log files that were
+    /// sealed since the last call. Syncs run one after another on a
+    /// background thread started by the first call, so handles complete in
+    /// order. On a read-only store the handle completes right away.
+    pub fn flush_async(&mut self) -> FlushHandle {
+        let mut files = Vec::new();
+        let error = self.sealed_files(&mut files).err();
+        self.flusher.get_or_insert_with(Flusher::new).submit(files, error)
+    }
+
+    /// Flushes and syncs everything written to disk, then closes the store.
+    ///
+    /// Unlike dropping the store, which flushes on a best-effort basis and
+    /// ignores errors, this returns the first error hit while flushing, so
+    /// the caller knows whether the data made it to disk. All file handles
+    /// are closed when this returns. On a read-only store this only closes
+    /// the files.
+    pub fn close(mut self) -> Result<()> {
+        let mut files = Vec::new();
+        self.sealed_files(&mut files)?;
+        for f in files {
+            f.sync_data()?;
+        }
+        self.write_hint()?;
+        Ok(())
+    }
+
+    /// 把索引写成hint文件(见`hint::HINT_FILE`), 下次open时不用replay; 只读时什么都不做
+    fn write_hint(&mut self) -> io::Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        self.writer().flush()?;
+        let mut files = Vec::new();
+        for n in self.readers.generations() {
+            files.push((n, fs::metadata(self.path.join(format!("{}.log", n)))?.len()));
+        }
+        let last_end = self.writer().pos;
+        write_hint(&self.path, &self.indexes, &files, self.uncompacted, last_end)
+    }
+
+    /// 删掉旧log之后调用: 之前的历史读不到了, 时间记下来给下次open用
+    fn truncate_history(&mut self) -> io::Result<()> {
+        let now = unix_time();
+        if let Some(history) = &mut self.history {
+            history.truncate(now);
+        }
+        write_compacted(&self.path, now)
+    }
+
+    /// 把还没fsync过的log和value log都打开放进`files`里, 写缓冲区先交给OS
+    fn sealed_files(&mut self, files: &mut Vec<File>) -> io::Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        self.writer().flush()?;
+        self.unsynced.insert(self.nth);
+        if let Some(vlog) = self.vlog.as_mut() {
+            vlog.flush()?;
+            self.unsynced_vlogs.insert(self.vnth);
+        }
+
+        // compact或者回收之后被删掉的文件不用管了, 里面的记录已经拷到别的文件里
+        let (readers, vlogs) = (&self.readers, &self.vlogs);
+        let logs = std::mem::take(&mut self.unsynced).into_iter().map(|n| readers.open_new(n));
+        let vlogs = std::mem::take(&mut self.unsynced_vlogs).into_iter().map(|n| vlogs.open_new(n));
+        for f in logs.chain(vlogs) {
+            match f {
+                Ok(f) => files.push(f),
+                Err(KvsError::MissingGeneration(_)) => {}
+                Err(KvsError::Io(e)) => return Err(e),
+                Err(e) => return Err(io::Error::other(e.to_string())),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads live records ahead of time to pull them into the OS page cache,
+    /// e.g. right after a restart.
+    ///
+    /// With `None` every live record is read, generation by generation in
+    /// file order; with a list of keys only their records are read and missing
+    /// keys are skipped. Values in the value log are read along with their
+    /// pointers. Reading stops before more than `max_bytes` would be read, so
+    /// the cost can be bounded. `kvs` has no value cache of its own; only the
+    /// page cache is filled.
+    pub fn warm_up(&mut self, keys: Option<&[String]>, max_bytes: u64) -> Result<WarmupStats> {
+        self.flush_buffers()?;
+        let mut locs: Vec<_> = match keys {
+            None => self.indexes.iter().map(|(_, v)| (v.n, v.pos, v.len, v.vlen)).collect(),
+            Some(keys) => keys
+                .iter()
+                .filter_map(|k| self.indexes.get(k))
+                .map(|v| (v.n, v.pos, v.len, v.vlen))
+                .collect(),
+        };
+        // 按文件里的顺序读
+        locs.sort_unstable();
+
+        let mut stats = WarmupStats::default();
+        for chunk in locs.chunks(READ_BATCH) {
+            let mut batch = Vec::with_capacity(chunk.len());
+            for &(n, pos, len, vlen) in chunk {
+                let size = len as u64 + vlen as u64;
+                if stats.bytes_read + size > max_bytes {
+                    break;
+                }
+                stats.bytes_read += size;
+                stats.records += 1;
+                batch.push((n, pos, len));
+            }
+
+            // value log里的value也一起读进来
+            let bufs = self.readers.read_records(&batch)?;
+            let mut pointers = Vec::new();
+            for (&(n, pos, _, vlen), buf) in chunk.iter().zip(bufs) {
+                if vlen > 0 {
+                    let (_, raw) = value_from_record(&buf).map_err(io_at(n, pos))?;
+                    pointers.push(decode_pointer(raw).map_err(io_at(n, pos))?);
+                }
+            }
+            pointers.sort_unstable();
+            self.vlogs.read_records(&pointers)?;
+
+            if batch.len() < chunk.len() {
+                break;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Returns the in-memory index of the store.
+    pub fn index(&self) -> &I {
+        &self.indexes
+    }
+}
+
+impl<I: KeyIndex> KvsEngine for KvStore<I> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        KvStore::set_if_absent(self, key, value)
+    }
+
+    fn incr_by(&mut self, key: String, delta: i64) -> Result<i64> {
+        KvStore::incr_by(self, key, delta)
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        KvStore::contains_key(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+}
+
+/// 只打印概况, 不打印文件句柄和整个索引
+impl<I: KeyIndex> fmt::Debug for KvStore<I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvStore")
+            .field("path", &self.path)
+            .field("nth", &self.nth)
+            .field("keys", &self.indexes.len())
+            .field("generations", &self.readers.generations().len())
+            .field("uncompacted", &self.uncompacted)
+            .finish_non_exhaustive()
+    }
+}
+
+/// 没有调用`close`就drop时尽量把写缓冲区交给OS, 出错也没法报告
+impl<I: KeyIndex> Drop for KvStore<I> {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.as_mut() {
+            let _ = writer.flush();
+        }
+        if let Some(vlog) = self.vlog.as_mut() {
+            let _ = vlog.flush();
+        }
+    }
+}
+
+/// 当前正在写入的log文件, 记录下一条记录的写入位置
+struct LogWriter {
+    file: File,
+    /// 逻辑上的写位置, 包括还在`buf`里的
+    pos: u64,
+    /// 攒着还没写到文件里的记录, 满了或者读文件之前写出去; `Config::with_write_buffer_size`
+    ///
+    /// 读的时候只拿到`&self`, 所以放在RefCell里.
+    buf: RefCell<Vec<u8>>,
+    /// `buf`的大小, 0是不缓冲
+    buf_size: usize,
+    /// 用O_DIRECT打开时, 记录先攒在对齐的缓冲区里, flush时才写到文件
+    #[cfg(target_os = "linux")]
+    direct: Option<DirectBuf>,
+    /// 文件是新建的, 目录项还没有fsync过
+    new_file: bool,
+    #[cfg(test)]
+    faults: Faults,
+    /// 测试用: 调用了多少次文件的write
+    #[cfg(test)]
+    file_writes: Cell<usize>,
+}
+
+impl LogWriter {
+    fn new(mut file: File, buf_size: usize) -> io::Result<Self> {
+        let pos = file.seek(SeekFrom::End(0))?;
+        Ok(LogWriter {
+            file,
+            pos,
+            buf: RefCell::new(Vec::with_capacity(buf_size)),
+            buf_size,
+            new_file: pos == 0,
+            #[cfg(target_os = "linux")]
+            direct: None,
+            #[cfg(test)]
+            faults: Faults::default(),
+            #[cfg(test)]
+            file_writes: Cell::new(0),
+        })
+    }
+
+    /// 打开第`n`个log, 接在文件末尾写, 新的文件先写上header
+    ///
+    /// `buf_size`是写缓冲区的大小, 用O_DIRECT时不用它.
+    fn open(path: &Path, n: u64, direct_io: bool, buf_size: usize) -> Result<Self> {
+        let mut writer = if !direct_io {
+            LogWriter::new(open_file(path, n).0, buf_size)?
+        } else {
+            LogWriter::open_at(&path.join(format!("{}.log", n)), direct_io, buf_size)?
+        };
+        if writer.pos == 0 {
+            write_file_header(&mut writer)?;
+            writer.flush()?;
+        }
+        Ok(writer)
+    }
+
+    /// 新建第`n`个log: header先写到临时文件里, 再rename成`n.log`
+    ///
+    /// 目录里不会出现没有header的log. 目录项等到第一次`sync`时才落盘.
+    fn create(path: &Path, n: u64, direct_io: bool, buf_size: usize) -> Result<Self> {
+        let tmp = tmp_log_path(path, n);
+        let _ = fs::remove_file(&tmp);
+        let mut writer = LogWriter::open_at(&tmp, direct_io, buf_size)?;
+        write_file_header(&mut writer)?;
+        writer.flush()?;
+        fs::rename(&tmp, path.join(format!("{}.log", n)))?;
+        Ok(writer)
+    }
+
+    /// 打开`fpath`, 接在文件末尾写
+    fn open_at(fpath: &Path, direct_io: bool, buf_size: usize) -> Result<Self> {
+        if !direct_io {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(fpath)?;
+            return Ok(LogWriter::new(file, buf_size)?);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .custom_flags(libc::O_DIRECT)
+                .open(fpath)?;
+            // DirectBuf自己就是缓冲区
+            let mut writer = LogWriter::new(file, 0)?;
+            writer.direct = Some(DirectBuf::new(&writer.file, writer.pos)?);
+            Ok(writer)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Err(KvsError::UnsupportedOperation("direct I/O is only supported on Linux"))
+    }
+
+    /// fsync写过的数据; 新建的文件第一次fsync时还要fsync所在的目录`dir`,
+    /// 不然崩溃之后目录里可能没有这个文件
+    fn sync(&mut self, dir: &Path) -> io::Result<()> {
+        self.write_buf()?;
+        self.file.sync_data()?;
+        if self.new_file {
+            #[cfg(test)]
+            {
+                if self.faults.fail_dir_sync {
+                    return Err(io::ErrorKind::StorageFull.into());
+                }
+            }
+            sync_dir(dir)?;
+            self.new_file = false;
+        }
+        Ok(())
+    }
+
+    /// 丢弃`pos`之后的内容, 下一次从`pos`开始写
+    ///
+    /// `pos`之后的都还在`buf`里时只截短`buf`, 前面攒着的记录留着.
+    fn truncate(&mut self, pos: u64) -> io::Result<()> {
+        #[cfg(test)]
+        {
+            if self.faults.fail_truncate {
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(direct) = self.direct.as_mut() {
+                direct.truncate(&self.file, pos)?;
+            }
+        }
+        let buf = self.buf.get_mut();
+        let written = self.pos - buf.len() as u64;
+        if pos >= written {
+            buf.truncate((pos - written) as usize);
+            self.pos = pos;
+            return Ok(());
+        }
+        buf.clear();
+        self.file.set_len(pos)?;
+        self.pos = self.file.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+
+    /// 把`buf`里攒着的写到文件里
+    ///
+    /// 写失败时没写出去的留在`buf`里, 下次再写; 调用的人会用`truncate`丢掉写了一半的记录.
+    fn write_buf(&self) -> io::Result<()> {
+        let mut buf = self.buf.borrow_mut();
+        while !buf.is_empty() {
+            #[cfg(test)]
+            self.file_writes.set(self.file_writes.get() + 1);
+            match (&self.file).write(&buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// 一次写操作写完了: O_DIRECT的缓冲区交给文件, 写缓冲区留着攒后面的记录
+    fn end_write(&mut self) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(direct) = self.direct.as_mut() {
+                return direct.flush(&self.file);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(test)]
+        {
+            if let Some(left) = self.faults.writes_left.as_mut() {
+                if *left == 0 {
+                    return Err(io::ErrorKind::StorageFull.into());
+                }
+                *left -= 1;
+            }
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(direct) = self.direct.as_mut() {
+                direct.write(buf);
+                self.pos += buf.len() as u64;
+                return Ok(buf.len());
+            }
+        }
+        if self.buf.get_mut().len() + buf.len() > self.buf_size {
+            self.write_buf()?;
+        }
+        // 放不进缓冲区的直接写
+        if buf.len() >= self.buf_size {
+            #[cfg(test)]
+            self.file_writes.set(self.file_writes.get() + 1);
+            let len = self.file.write(buf)?;
+            self.pos += len as u64;
+            return Ok(len);
+        }
+        self.buf.get_mut().extend_from_slice(buf);
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        #[cfg(test)]
+        {
+            if self.faults.fail_flush {
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(direct) = self.direct.as_mut() {
+                return direct.flush(&self.file);
+            }
+        }
+        self.write_buf()?;
+        self.file.flush()
+    }
+}
+
+/// 测试用的故障注入
+#[cfg(test)]
+#[derive(Default)]
+struct Faults {
+    /// 还剩多少次write调用会成功, 之后的write都返回ENOSPC
+    writes_left: Option<usize>,
+    /// 回滚时set_len也失败
+    fail_truncate: bool,
+    /// flush失败
+    fail_flush: bool,
+    /// fsync目录失败
+    fail_dir_sync: bool,
+}
+
+/// 按存储格式写一条记录
+pub(crate) fn encode_item<W: Write>(w: &mut W, timestamp: u64, flags: u8, k: &[u8], v: &[u8]) -> io::Result<()> {
+    encode_header(w, timestamp, flags, k.len() as u32, v.len() as u32)?;
+    w.write_all(k)?;
+    w.write_all(v)
+}
+
+/// 写一条记录, `expires`不为0时带上`FLAG_EXPIRES`, 后面紧跟着它的TTL记录
+fn encode_expiring<W: Write>(w: &mut W, timestamp: u64, flags: u8, k: &[u8], v: &[u8], expires: u64) -> io::Result<()> {
+    if expires == 0 {
+        return encode_item(w, timestamp, flags, k, v);
+    }
+    encode_item(w, timestamp, flags | FLAG_EXPIRES, k, v)?;
+    encode_item(w, timestamp, FLAG_TTL, k, &expires.to_le_bytes())
+}
+
+/// key的长度要放得进ksize的低24位, 整条记录的长度要放得进`DataIndex::len`
+fn check_sizes(k: &[u8], vsize: u64) -> Result<()> {
+    if k.len() > KSIZE_MASK as usize {
+        return Err(KvsError::KeyTooLarge(k.len() as u64));
+    }
+    if 16 + k.len() as u64 + vsize > u32::MAX as u64 {
+        return Err(KvsError::ValueTooLarge(vsize));
+    }
+    Ok(())
+}
+
+/// 写一条记录的header, 后面要紧接着写`ksize`字节的key和`vsize`字节的value
+fn encode_header<W: Write>(w: &mut W, timestamp: u64, flags: u8, ksize: u32, vsize: u32) -> io::Result<()> {
+    let ksize = (flags as u32) << FLAGS_SHIFT | ksize;
+    w.write_all(&timestamp.to_le_bytes()[..])?;
+    w.write_all(&ksize.to_le_bytes()[..])?;
+    w.write_all(&vsize.to_le_bytes()[..])
+}
+
+/// `DataIndex::ksize`: 带着`flags`的key的长度
+fn index_ksize(flags: u8, ksize: usize) -> u32 {
+    ((flags & !FLAG_EXPIRES) as u32) << FLAGS_SHIFT | ksize as u32
+}
+
+/// 刚写的一条`len`字节的记录里value的长度, 过期时间是`expires`, key有`ksize`字节
+fn record_vsize(ksize: usize, len: u32, expires: u64) -> u32 {
+    len - 16 - ksize as u32 - expiry_len(ksize as u32, expires)
+}
+
+/// 过期时间是`expires`时, 跟在value后面的TTL记录有多长
+fn expiry_len(ksize: u32, expires: u64) -> u32 {
+    match expires {
+        0 => 0,
+        _ => 16 + ksize + TTL_SIZE,
+    }
+}
+
+/// `get_reader`返回的reader
+enum ValueReader {
+    /// 没压缩的value直接从文件里读
+    File(io::Take<File>),
+    /// 压缩过的value先解压到内存里
+    Memory(io::Cursor<Vec<u8>>),
+    /// 分块存储的value, 倒着放, 最后一个是正在读的块
+    Chunks(Vec<io::Take<File>>),
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ValueReader::File(r) => r.read(buf),
+            ValueReader::Memory(r) => r.read(buf),
+            ValueReader::Chunks(chunks) => {
+                while let Some(r) = chunks.last_mut() {
+                    match r.read(buf)? {
+                        0 if !buf.is_empty() => {
+                            chunks.pop();
+                        }
+                        n => return Ok(n),
+                    }
+                }
+                Ok(0)
+            }
+        }
+    }
+}
+
+fn fpos<R: Seek>(f: &mut R) -> io::Result<u64> {
+    f.stream_position()
+}
+
+/// 读出`index`指向的记录的value
+///
+/// 不处理引用记录, 只给不去重的`MemKvStore`用.
+pub(crate) fn read_value<R: Read + Seek>(f: &mut R, index: &DataIndex) -> io::Result<String> {
+    let (flags, raw) = read_raw_value(f, index.pos)?;
+    decode_value(flags, raw)
+}
+
+/// 读出`pos`处记录的flags和原始的value(可能是压缩过的, 或者是引用)
+fn read_raw_value<R: Read + Seek>(f: &mut R, pos: u64) -> io::Result<(u8, Vec<u8>)> {
+    let (flags, vsize) = seek_value(f, pos)?;
+    let mut raw = Vec::with_capacity(vsize as _);
+    f.take(vsize as _).read_to_end(&mut raw)?;
+    if raw.len() < vsize as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok((flags, raw))
+}
+
+/// 读出`pos`处记录的flags和value的长度, 文件停在value开始的位置
+fn seek_value<R: Read + Seek>(f: &mut R, pos: u64) -> io::Result<(u8, u32)> {
+    // to ksize start postion
+    f.seek(SeekFrom::Start(pos + 8))?;
+    let ksize = f.read_u32::<LittleEndian>()?;
+    let vsize = f.read_u32::<LittleEndian>()?;
+    let flags = (ksize >> FLAGS_SHIFT) as u8;
+
+    // to vdata start position
+    f.seek(SeekFrom::Current((ksize & KSIZE_MASK) as _))?;
+    Ok((flags, vsize))
+}
+
+/// 从一整条记录里取出flags和原始的value
+fn value_from_record(buf: &[u8]) -> io::Result<(u8, &[u8])> {
+    let mut header = &buf[8..16];
+    let ksize = header.read_u32::<LittleEndian>()?;
+    let vsize = header.read_u32::<LittleEndian>()?;
+    let flags = (ksize >> FLAGS_SHIFT) as u8;
+    let start = 16 + (ksize & KSIZE_MASK) as usize;
+    let end = start + vsize as usize;
+    if end > buf.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok((flags, &buf[start..end]))
+}
+
+/// 解出第`n`个文件`pos`处记录的value, 再转成String
+fn resolve_value(
+    readers: &mut Readers,
+    vlogs: &mut Readers,
+    n: u64,
+    pos: u64,
+    flags: u8,
+    raw: Vec<u8>,
+) -> Result<String> {
+    let value = resolve_bytes(readers, vlogs, n, pos, flags, raw)?;
+    String::from_utf8(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        .map_err(io_at(n, pos))
+}
+
+/// 解出第`n`个文件`pos`处记录的value
+///
+/// 引用记录还要再去读被引用的记录, 分块的value要把每一块读出来拼在一起,
+/// 指针记录要去value log里读.
+fn resolve_bytes(
+    readers: &mut Readers,
+    vlogs: &mut Readers,
+    n: u64,
+    pos: u64,
+    flags: u8,
+    raw: Vec<u8>,
+) -> Result<Vec<u8>> {
+    if flags & FLAG_VLOG != 0 {
+        let (vn, vpos, vlen) = decode_pointer(&raw).map_err(io_at(n, pos))?;
+        let buf = vlogs.read_records(&[(vn, vpos, vlen)])?.pop().unwrap();
+        let (vflags, vraw) = value_from_record(&buf).map_err(io_at(vn, vpos))?;
+        return decode_bytes(vflags, vraw.to_vec()).map_err(io_at(vn, vpos));
+    }
+    if flags & (FLAG_REF | FLAG_CHUNKED) == 0 {
+        return decode_bytes(flags, raw).map_err(io_at(n, pos));
+    }
+    let mut value = Vec::new();
+    for target in raw.chunks(REF_SIZE) {
+        let (tn, tpos) = decode_ref(target).map_err(io_at(n, pos))?;
+        let f = readers.get(tn)?;
+        let (tflags, traw) = read_raw_value(f, tpos).map_err(io_at(tn, tpos))?;
+        let chunk = decode_bytes(tflags, traw).map_err(io_at(tn, tpos))?;
+        if value.is_empty() {
+            value = chunk;
+        } else {
+            value.extend_from_slice(&chunk);
+        }
+    }
+    Ok(value)
+}
+
+/// 第`n`个文件`pos`处记录的value解出来有多长, 只读header, 引用和指针
+///
+/// 压缩过的value开头存着解压后的长度, 不用解压.
+fn resolve_len(readers: &mut Readers, vlogs: &mut Readers, n: u64, pos: u64) -> Result<u32> {
+    let f = readers.get(n)?;
+    let (flags, vsize) = seek_value(f, pos).map_err(io_at(n, pos))?;
+    if flags & (FLAG_REF | FLAG_CHUNKED | FLAG_VLOG) == 0 {
+        return decoded_len(f, flags, vsize).map_err(io_at(n, pos));
+    }
+    let mut raw = vec![0; vsize as usize];
+    f.read_exact(&mut raw).map_err(io_at(n, pos))?;
+    if flags & FLAG_VLOG != 0 {
+        let (vn, vpos, _) = decode_pointer(&raw).map_err(io_at(n, pos))?;
+        let f = vlogs.get(vn)?;
+        let (vflags, vsize) = seek_value(f, vpos).map_err(io_at(vn, vpos))?;
+        return decoded_len(f, vflags, vsize).map_err(io_at(vn, vpos));
+    }
+    let mut len = 0;
+    for target in raw.chunks(REF_SIZE) {
+        let (tn, tpos) = decode_ref(target).map_err(io_at(n, pos))?;
+        let f = readers.get(tn)?;
+        let (tflags, tvsize) = seek_value(f, tpos).map_err(io_at(tn, tpos))?;
+        len += decoded_len(f, tflags, tvsize).map_err(io_at(tn, tpos))?;
+    }
+    Ok(len)
+}
+
+/// `f`停在value开头时, value按flags解出来的长度
+fn decoded_len<R: Read>(f: &mut R, flags: u8, vsize: u32) -> io::Result<u32> {
+    if flags & FLAG_COMPRESSED != 0 {
+        // 和`codec::decompress`一样, 解压后的长度放在最前面
+        f.read_u32::<LittleEndian>()
+    } else {
+        Ok(vsize)
+    }
+}
+
+/// 把读出来的value按flags解压, 再转成String
+pub(crate) fn decode_value(flags: u8, raw: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(decode_bytes(flags, raw)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// 把读出来的value按flags解压
+pub(crate) fn decode_bytes(flags: u8, raw: Vec<u8>) -> io::Result<Vec<u8>> {
+    if flags & FLAG_COMPRESSED != 0 {
+        decompress(&raw)
+    } else {
+        Ok(raw)
+    }
+}
+
+/// `BTreeMap::range`会panic的范围, 以及明显为空的范围
+fn range_is_empty(start: &Bound<String>, end: &Bound<String>) -> bool {
+    match (start, end) {
+        (Bound::Included(s), Bound::Included(e)) => s > e,
+        (Bound::Included(s), Bound::Excluded(e))
+        | (Bound::Excluded(s), Bound::Included(e))
+        | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+        _ => false,
+    }
+}
+
+/// 以`prefix`开头的key的上界(不包含): 比它们都大的最小的字符串.
+/// `prefix`为空或者全是`char::MAX`时没有上界.
+///
+/// String按字节比较, 和按char比较顺序一样, 所以把最后一个能加一的char加一, 后面的去掉.
+fn prefix_end(prefix: &str) -> Bound<String> {
+    let mut end = prefix.to_owned();
+    while let Some(c) = end.pop() {
+        // 跳过surrogate的那一段
+        let next = (c as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+/// 给IO错误加上出错的generation和位置
+///
+/// 读出来的key或value不是合法的utf8时(包着`FromUtf8Error`或者`Utf8Error`)报`InvalidUtf8`.
+pub(crate) fn io_at(generation: u64, pos: u64) -> impl FnOnce(io::Error) -> KvsError {
+    move |source| {
+        let utf8 = source.get_ref().is_some_and(|e| e.is::<FromUtf8Error>() || e.is::<Utf8Error>());
+        if utf8 {
+            return KvsError::InvalidUtf8 { generation, pos };
+        }
+        KvsError::IoAt { source, generation, pos }
+    }
+}
+
+/// 读一条记录的位置和flags, key读到`key`里
+fn read_item<R: Read + Seek>(n: u64, f: &mut R, key: &mut Vec<u8>) -> Result<(DataIndex, u8)> {
+    let pos = fpos(f)?;
+
+    let timestamp: u64 = f.read_u64::<LittleEndian>()?;
+    // 用O_DIRECT写的log最后一个块里填的padding, 后面没有记录了
+    if timestamp == u64::MAX {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let ksize = f.read_u32::<LittleEndian>()?;
+    let flags = (ksize >> FLAGS_SHIFT) as u8;
+    let ksize = ksize & KSIZE_MASK;
+    let vsize = f.read_u32::<LittleEndian>()?;
+
+    key.resize(ksize as usize, 0);
+    f.read_exact(key)?;
+
+    // 指向value log的记录要读出指针, 才知道value log里那条记录有多长
+    let mut expires = 0;
+    let vlen = if flags & FLAG_VLOG != 0 {
+        let mut pointer = vec![0; vsize as usize];
+        f.read_exact(&mut pointer)?;
+        decode_pointer(&pointer)?.2
+    } else if flags & FLAG_TTL != 0 {
+        expires = read_ttl_value(vsize, f)?;
+        0
+    } else {
+        f.seek(SeekFrom::Current(vsize as _))?;
+        0
+    };
+
+    let mut len = 16 + ksize + vsize;
+    // 带过期时间的value后面紧跟着它的TTL记录, 两条一起算一个索引项; TTL记录没写完时整对都算残缺
+    if flags & FLAG_EXPIRES != 0 {
+        f.seek(SeekFrom::Current(8))?;
+        let ttl_ksize = f.read_u32::<LittleEndian>()? & KSIZE_MASK;
+        let ttl_vsize = f.read_u32::<LittleEndian>()?;
+        f.seek(SeekFrom::Current(ttl_ksize as _))?;
+        expires = read_ttl_value(ttl_vsize, f)?;
+        len += 16 + ttl_ksize + ttl_vsize;
+    }
+
+    Ok((
+        DataIndex{
+            n,
+            pos,
+            len,
+            timestamp,
+            vlen,
+            ksize: index_ksize(flags, ksize as usize),
+            vsize,
+            // 压缩, 引用和指针记录的等open最后读出来(`resolve_len`)
+            value_len: vsize,
+            expires,
+        },
+        flags,
+    ))
+}
+
+/// 读TTL记录的value: 过期的时间, Unix毫秒
+fn read_ttl_value<R: Read>(vsize: u32, f: &mut R) -> Result<u64> {
+    if vsize != TTL_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed ttl record").into());
+    }
+    Ok(f.read_u64::<LittleEndian>()?)
+}
+
+/// 读出目录的日志格式版本
+fn read_version(path: &Path) -> Result<u32> {
+    match fs::read_to_string(path.join(VERSION_FILE)) {
+        Ok(s) => s.trim().parse().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("malformed {} file", VERSION_FILE)).into()
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(1),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 写新log文件开头的header
+fn write_file_header<W: Write>(w: &mut W) -> io::Result<()> {
+    w.write_all(&LOG_MAGIC)?;
+    w.write_u16::<LittleEndian>(LOG_FILE_VERSION)
+}
+
+/// 读log文件开头的header, 返回文件格式版本, 之后`f`停在第一条记录的开头
+///
+/// 开头不是magic的是没有header的旧文件, 返回版本0, `f`回到0. 版本是否支持由调用方检查.
+pub(crate) fn read_file_header<R: Read + Seek>(f: &mut R) -> io::Result<u16> {
+    let mut header = [0; FILE_HEADER_SIZE as usize];
+    f.seek(SeekFrom::Start(0))?;
+    let complete = match f.read_exact(&mut header) {
+        Ok(()) => true,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+    if !complete || header[..4] != LOG_MAGIC {
+        f.seek(SeekFrom::Start(0))?;
+        return Ok(0);
+    }
+    (&header[4..]).read_u16::<LittleEndian>()
+}
+
+/// fsync目录本身, 让里面新建的文件的目录项落盘; 只有unix上能打开目录
+fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// 锁住`path`的LOCK文件, 返回的文件关闭时释放
+///
+/// 被别的KvStore锁着时重试, 每次多等一倍的时间, 过了`timeout`就返回`KvsError::Locked`.
+fn lock_dir(path: &Path, timeout: Duration) -> Result<File> {
+    let file = OpenOptions::new().write(true).create(true).truncate(false).open(path.join(LOCK_FILE))?;
+    let deadline = Instant::now() + timeout;
+    let mut backoff = Duration::from_millis(1);
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(file),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+            Err(TryLockError::WouldBlock) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(KvsError::Locked);
+                }
+                thread::sleep(backoff.min(deadline - now));
+                backoff = (backoff * 2).min(MAX_LOCK_BACKOFF);
+            }
+        }
+    }
+}
+
+/// 打开目录时没有权限的IO错误换成`KvsError::PermissionDenied`, 带上目录
+fn permission_denied(e: KvsError, path: &Path) -> KvsError {
+    match e {
+        KvsError::Io(ref source) if source.kind() == io::ErrorKind::PermissionDenied => {
+            KvsError::PermissionDenied(path.to_owned())
+        }
+        e => e,
+    }
+}
+
+/// 第`n`个log写好之前用的临时文件
+fn tmp_log_path(path: &Path, n: u64) -> PathBuf {
+    path.join(format!("{}.log.{}", n, TMP_EXT))
+}
+
+/// 新建(或清空)临时文件`tmp`
+fn create_tmp(tmp: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).create(true).truncate(true).open(tmp)
+}
+
+/// 把fsync过的临时文件`tmp`换成`dest`, 再fsync目录让rename先于之后的删除落盘
+fn publish_log(tmp: &Path, dest: &Path) -> io::Result<()> {
+    fs::rename(tmp, dest)?;
+    sync_dir(dest.parent().unwrap())
+}
+
+/// 删掉上次没rename就崩溃留下的临时log
+fn remove_tmp_logs(path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let p = entry?.path();
+        let tmp = p.file_name().and_then(|v| v.to_str()).is_some_and(|v| v.ends_with(&format!(".log.{}", TMP_EXT)));
+        if tmp {
+            fs::remove_file(p)?;
+        }
+    }
+    Ok(())
+}
+
+/// `path`下所有log文件的generation, 从小到大
+fn list_generations(path: &Path) -> io::Result<Vec<u64>> {
+    let mut entries: Vec<_> = fs::read_dir(path)?
+        .flat_map(|v| v.map(|e| e.path()))
+        .filter_map(|v| {
+            let name = v.file_name().and_then(|v| v.to_str())?;
+            name.strip_suffix(".log")?.parse::<u64>().ok()
+        })
+        .collect();
+    entries.sort_unstable();
+    Ok(entries)
+}
+
+/// 写`clear`的标记: 先写临时文件fsync, 再rename, 不会留下写了一半的标记
+fn write_clear_marker(path: &Path, n: u64, vn: u64) -> io::Result<()> {
+    let tmp = path.join(format!("{}.{}", CLEAR_FILE, TMP_EXT));
+    let mut f = File::create(&tmp)?;
+    write!(f, "{} {}", n, vn)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path.join(CLEAR_FILE))?;
+    sync_dir(path)
+}
+
+/// 读`clear`的标记, 没有时返回(0, 0)
+fn read_clear_marker(path: &Path) -> io::Result<(u64, u64)> {
+    let s = match fs::read_to_string(path.join(CLEAR_FILE)) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, 0)),
+        Err(e) => return Err(e),
+    };
+    let mut nums = s.split_whitespace().map(str::parse::<u64>);
+    match (nums.next(), nums.next()) {
+        (Some(Ok(n)), Some(Ok(vn))) => Ok((n, vn)),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid CLEAR file")),
+    }
+}
+
+/// 删掉第`n`个之前的log和第`vn`个之前的value log, 都删完了再删标记
+fn finish_clear(path: &Path, n: u64, vn: u64) -> io::Result<()> {
+    for g in list_generations(path)? {
+        if g < n {
+            fs::remove_file(path.join(format!("{}.log", g)))?;
+        }
+    }
+    for entry in fs::read_dir(path)? {
+        let vpath = entry?.path();
+        if vpath.extension().is_some_and(|ext| ext == VALUE_LOG_EXT) {
+            let g: Option<u64> = vpath.file_stem().and_then(|v| v.to_str()).and_then(|v| v.parse().ok());
+            if g.is_some_and(|g| g < vn) {
+                fs::remove_file(vpath)?;
+            }
+        }
+    }
+    // 删除都落盘了才能删标记, 否则崩溃后旧文件可能又出现
+    sync_dir(path)?;
+    fs::remove_file(path.join(CLEAR_FILE))?;
+    sync_dir(path)
+}
+
+/// 读元数据, 没有`META_FILE`时是空的
+fn read_meta(path: &Path) -> Result<BTreeMap<String, String>> {
+    match fs::read(path.join(META_FILE)) {
+        Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// 写元数据: 和`write_clear_marker`一样先写临时文件再rename
+fn write_meta(path: &Path, meta: &BTreeMap<String, String>) -> Result<()> {
+    let tmp = path.join(format!("{}.{}", META_FILE, TMP_EXT));
+    let f = File::create(&tmp)?;
+    serde_json::to_writer(&f, meta)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path.join(META_FILE))?;
+    Ok(sync_dir(path)?)
+}
+
+/// 读上次删掉旧log的时间, 从来没删过时是None
+fn read_compacted(path: &Path) -> io::Result<Option<u64>> {
+    match fs::read_to_string(path.join(COMPACTED_FILE)) {
+        Ok(s) => s.trim().parse().map(Some).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid COMPACTED file")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// 记下删掉旧log的时间`now`, 和`write_meta`一样先写临时文件再rename
+fn write_compacted(path: &Path, now: u64) -> io::Result<()> {
+    let tmp = path.join(format!("{}.{}", COMPACTED_FILE, TMP_EXT));
+    let mut f = File::create(&tmp)?;
+    write!(f, "{}", now)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path.join(COMPACTED_FILE))?;
+    sync_dir(path)
+}
+
+/// 元数据所有key和value的字节数
+fn meta_size(meta: &BTreeMap<String, String>) -> usize {
+    meta.iter().map(|(k, v)| k.len() + v.len()).sum()
+}
+
+/// 记下目录的格式版本, 和log一样先写临时文件再rename, 崩溃时不会留下空的VERSION
+fn write_version(path: &Path, version: u32) -> io::Result<()> {
+    let tmp = path.join(format!("{}.{}", VERSION_FILE, TMP_EXT));
+    let mut f = File::create(&tmp)?;
+    writeln!(f, "{}", version)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path.join(VERSION_FILE))?;
+    sync_dir(path)
+}
+
+fn open_file(path: &Path, n: u64) -> (File, PathBuf) {
+    let fpath = path.join(format!("{}.log", n));
+    (OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&fpath).unwrap(), fpath)
+}
+
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::ops::Bound;
+    use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+    use tempfile::TempDir;
+
+    use crate::{Config, KeyIndex, KvsError, KvStore, Op, SyncPolicy};
+
+    use super::{prefix_end, FileAdvice, write_clear_marker, LogWriter, CLEAR_FILE, FILE_HEADER_SIZE};
+
+    #[test]
+    pub fn test_init() {
+        let dir = TempDir::new().unwrap();
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        // let result = kvs.get("hello".to_owned());
+        // assert!(result.is_err());
+
+        kvs.set("yongwei".to_string(), "iewgnoy555678".to_string()).unwrap();
+        let val = kvs.get("yongwei".to_string());
+        let s = val.unwrap().unwrap();
+        println!("{}", s);
+        assert_eq!(s, "iewgnoy555678".to_string());
+    }
+
+    #[test]
+    pub fn test_seek_write() {
+        let dir = TempDir::new().unwrap();
+
+        let p = dir.path().join("tt.log");
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&p).unwrap();
+        file.rewind().unwrap();
+        file.write_u64::<LittleEndian>(1).unwrap();
+
+        file.flush().unwrap();
+    }
+
+    #[test]
+    pub fn test_set_rollback_on_enospc() {
+        let dir = TempDir::new().unwrap();
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        kvs.set("k1".to_owned(), "v1".to_owned()).unwrap();
+        let size = kvs.writer().pos;
+
+        // 每条记录5次write, 第3次write失败
+        kvs.writer().faults.writes_left = Some(2);
+        let err = kvs.set("k2".to_owned(), "v2".to_owned()).unwrap_err();
+        match err {
+            KvsError::IoAt { source, generation, pos } => {
+                assert_eq!(source.kind(), std::io::ErrorKind::StorageFull);
+                assert_eq!(generation, kvs.nth);
+                assert_eq!(pos, size);
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        assert_eq!(kvs.writer().pos, size);
+        assert_eq!(kvs.writer().file.metadata().unwrap().len(), size);
+        assert!(!kvs.indexes.contains_key("k2"));
+        // tombstone同样写失败, k1仍然存在
+        assert!(kvs.remove("k1".to_owned()).is_err());
+        assert_eq!(kvs.get("k1".to_owned()).unwrap(), Some("v1".to_owned()));
+
+        // 空间释放后继续正常写入
+        kvs.writer().faults.writes_left = None;
+        kvs.set("k2".to_owned(), "v2".to_owned()).unwrap();
+        drop(kvs);
+
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        assert_eq!(kvs.get("k1".to_owned()).unwrap(), Some("v1".to_owned()));
+        assert_eq!(kvs.get("k2".to_owned()).unwrap(), Some("v2".to_owned()));
+    }
+
+    #[test]
+    pub fn test_poisoned_when_rollback_fails() {
+        let dir = TempDir::new().unwrap();
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        kvs.writer().faults.writes_left = Some(4);
+        kvs.writer().faults.fail_truncate = true;
+        assert!(kvs.set("k1".to_owned(), "v1".to_owned()).is_err());
+
+        kvs.writer().faults = Default::default();
+        assert!(matches!(kvs.set("k1".to_owned(), "v1".to_owned()), Err(KvsError::Poisoned)));
+        assert_eq!(kvs.get("k1".to_owned()).unwrap(), None);
+    }
+
+    #[test]
+    pub fn test_flush_async_failure_is_sticky() {
+        let dir = TempDir::new().unwrap();
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        kvs.set("k1".to_owned(), "v1".to_owned()).unwrap();
+        let first = kvs.flush_async();
+
+        kvs.writer().faults.fail_flush = true;
+        let failed = kvs.flush_async();
+        kvs.writer().faults = Default::default();
+        kvs.set("k2".to_owned(), "v2".to_owned()).unwrap();
+        let later = kvs.flush_async();
+
+        // Handles before the failure succeed, every later one reports it.
+        assert!(first.wait().is_ok());
+        assert!(matches!(failed.wait(), Err(KvsError::Io(e)) if e.kind() == std::io::ErrorKind::StorageFull));
+        assert!(matches!(later.wait(), Err(KvsError::Io(e)) if e.kind() == std::io::ErrorKind::StorageFull));
+    }
+
+    #[test]
+    pub fn test_close_reports_flush_error() {
+        let dir = TempDir::new().unwrap();
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        kvs.set("k1".to_owned(), "v1".to_owned()).unwrap();
+        kvs.writer().faults.fail_flush = true;
+        assert!(matches!(kvs.close(), Err(KvsError::Io(e)) if e.kind() == std::io::ErrorKind::StorageFull));
+
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        assert_eq!(kvs.get("k1".to_owned()).unwrap(), Some("v1".to_owned()));
+        assert!(kvs.close().is_ok());
+    }
+
+    #[test]
+    pub fn test_write_buffer_coalesces_records() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::default().with_write_buffer_size(4096);
+        let mut kvs = KvStore::open_with_config(dir.path(), config.clone()).unwrap();
+        let writes = kvs.writer().file_writes.get();
+        for i in 0..10 {
+            kvs.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        kvs.remove("key0".to_owned()).unwrap();
+        assert_eq!(kvs.writer().file_writes.get(), writes);
+        assert_eq!(kvs.writer().file.metadata().unwrap().len(), FILE_HEADER_SIZE);
+
+        // 读之前攒着的11条记录一次写出去
+        assert_eq!(kvs.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+        assert_eq!(kvs.writer().file_writes.get(), writes + 1);
+        assert_eq!(kvs.writer().file.metadata().unwrap().len(), kvs.writer().pos);
+
+        // 写失败只丢掉写了一半的记录, 前面攒着的还在
+        kvs.set("key10".to_owned(), "value10".to_owned()).unwrap();
+        kvs.writer().faults.writes_left = Some(2);
+        assert!(kvs.set("key11".to_owned(), "value11".to_owned()).is_err());
+        kvs.writer().faults.writes_left = None;
+        assert_eq!(kvs.get("key10".to_owned()).unwrap(), Some("value10".to_owned()));
+        drop(kvs);
+
+        let mut kvs = KvStore::open_with_config(dir.path(), config.with_sync_policy(SyncPolicy::OnEveryWrite)).unwrap();
+        assert_eq!(kvs.len(), 10);
+        assert_eq!(kvs.get("key11".to_owned()).unwrap(), None);
+        // OnEveryWrite每次写都交出去
+        let writes = kvs.writer().file_writes.get();
+        kvs.set("key11".to_owned(), "value11".to_owned()).unwrap();
+        kvs.set("key12".to_owned(), "value12".to_owned()).unwrap();
+        assert_eq!(kvs.writer().file_writes.get(), writes + 2);
+    }
+
+    #[test]
+    pub fn test_interrupted_clear_drops_old_generations() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::default().with_max_log_size(256);
+        let mut kvs = KvStore::open_with_config(dir.path(), config.clone()).unwrap();
+        for i in 0..100 {
+            kvs.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        let old = kvs.readers.generations();
+        assert!(old.len() > 1);
+
+        // 标记写下去了, 旧文件还没删就崩溃
+        let n = kvs.nth + 1;
+        drop(LogWriter::create(&kvs.path, n, false, 0).unwrap());
+        write_clear_marker(&kvs.path, n, kvs.vnth + 1).unwrap();
+        drop(kvs);
+
+        // 只读打开不删文件, 但也看不到旧数据
+        let mut kvs = KvStore::open_with_config(dir.path(), config.clone().with_read_only(true)).unwrap();
+        assert_eq!(kvs.get("key1".to_owned()).unwrap(), None);
+        assert!(dir.path().join(CLEAR_FILE).exists());
+        drop(kvs);
+
+        let mut kvs = KvStore::open_with_config(dir.path(), config).unwrap();
+        assert!(!dir.path().join(CLEAR_FILE).exists());
+        assert!(old.iter().all(|g| !dir.path().join(format!("{}.log", g)).exists()));
+        assert_eq!(kvs.readers.generations(), vec![n]);
+        assert_eq!(kvs.stats().keys, 0);
+        assert_eq!(kvs.get("key1".to_owned()).unwrap(), None);
+        kvs.set("key1".to_owned(), "new".to_owned()).unwrap();
+        assert_eq!(kvs.get("key1".to_owned()).unwrap(), Some("new".to_owned()));
+
+        // 在线compact拷贝的旧数据会在clear之后又被装回来, 所以拒绝
+        let c = kvs.begin_compaction().unwrap().unwrap();
+        assert!(matches!(kvs.clear(), Err(KvsError::CompactionRunning)));
+        kvs.abort_compaction(c);
+        kvs.clear().unwrap();
+    }
+
+    #[test]
+    pub fn test_durable_write_syncs_new_directory_entry() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::default().with_sync_policy(SyncPolicy::OnEveryWrite).with_max_log_size(64);
+        let mut kvs = KvStore::open_with_config(dir.path(), config.clone()).unwrap();
+        assert!(kvs.writer().new_file);
+        kvs.set("k1".to_owned(), "v1".to_owned()).unwrap();
+        assert!(!kvs.writer().new_file);
+
+        // 写满换了新的generation, 第一次写入时fsync目录失败要报错并回滚
+        let nth = kvs.nth;
+        while kvs.nth == nth {
+            kvs.set("k2".to_owned(), "v2".repeat(8)).unwrap();
+        }
+        assert!(kvs.writer().new_file);
+        kvs.writer().faults.fail_dir_sync = true;
+        assert!(kvs.set("k3".to_owned(), "v3".to_owned()).is_err());
+        assert_eq!(kvs.get("k3".to_owned()).unwrap(), None);
+        kvs.writer().faults.fail_dir_sync = false;
+        kvs.set("k3".to_owned(), "v3".to_owned()).unwrap();
+        assert!(!kvs.writer().new_file);
+
+        // transaction和set一样
+        while kvs.nth == nth + 1 {
+            kvs.set("k2".to_owned(), "v2".repeat(8)).unwrap();
+        }
+        assert!(kvs.writer().new_file);
+        kvs.transaction(vec![Op::Set { key: "k4".to_owned(), value: "v4".to_owned() }]).unwrap();
+        assert!(!kvs.writer().new_file);
+
+        // 已有的文件不用fsync目录, 不开启durable模式也不fsync
+        drop(kvs);
+        let kvs = KvStore::open_with_config(dir.path(), config).unwrap();
+        assert!(!kvs.writer.as_ref().unwrap().new_file);
+        let dir = TempDir::new().unwrap();
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        kvs.set("k1".to_owned(), "v1".to_owned()).unwrap();
+        kvs.transaction(vec![Op::Remove { key: "k1".to_owned() }]).unwrap();
+        assert!(kvs.writer().new_file);
+    }
+
+    #[test]
+    pub fn test_prefix_end() {
+        assert_eq!(prefix_end("tenant/1/"), Bound::Excluded("tenant/10".to_owned()));
+        assert_eq!(prefix_end("a\u{10FFFF}"), Bound::Excluded("b".to_owned()));
+        assert_eq!(prefix_end("\u{D7FF}"), Bound::Excluded("\u{E000}".to_owned()));
+        assert_eq!(prefix_end("\u{10FFFF}\u{10FFFF}"), Bound::Unbounded);
+        assert_eq!(prefix_end(""), Bound::Unbounded);
+    }
+
+    #[test]
+    pub fn test_transaction_write_failure() {
+        let dir = TempDir::new().unwrap();
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        kvs.set("a".to_owned(), "1".to_owned()).unwrap();
+        let size = kvs.writer().pos;
+        let uncompacted = kvs.uncompacted;
+
+        // 第一条记录写完, 第二条记录写到一半失败
+        kvs.writer().faults.writes_left = Some(7);
+        let ops = vec![
+            Op::Set { key: "a".to_owned(), value: "2".to_owned() },
+            Op::Set { key: "b".to_owned(), value: "2".to_owned() },
+            Op::Remove { key: "a".to_owned() },
+        ];
+        assert!(kvs.transaction(ops).is_err());
+        assert_eq!(kvs.writer().pos, size);
+        assert_eq!(kvs.uncompacted, uncompacted);
+        assert!(!kvs.indexes.contains_key("b"));
+        assert_eq!(kvs.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(kvs.get("b".to_owned()).unwrap(), None);
+
+        kvs.writer().faults.writes_left = None;
+        drop(kvs);
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        assert_eq!(kvs.get("a".to_owned()).unwrap(), Some("1".to_owned()));
+        assert_eq!(kvs.get("b".to_owned()).unwrap(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    pub fn test_direct_io_rollback() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::default().with_direct_io(true);
+        let mut kvs = KvStore::open_with_config(dir.path(), config.clone()).unwrap();
+        kvs.set("k1".to_owned(), "v1".to_owned()).unwrap();
+        let size = kvs.writer().pos;
+
+        // 写到对齐缓冲区里一半失败, 回滚之后缓冲区也不能留下残缺的记录
+        kvs.writer().faults.writes_left = Some(2);
+        assert!(kvs.set("k2".to_owned(), "v2".to_owned()).is_err());
+        assert_eq!(kvs.writer().pos, size);
+
+        kvs.writer().faults.writes_left = None;
+        kvs.set("k3".to_owned(), "v3".to_owned()).unwrap();
+        drop(kvs);
+
+        let mut kvs = KvStore::open_with_config(dir.path(), config).unwrap();
+        assert_eq!(kvs.get("k1".to_owned()).unwrap(), Some("v1".to_owned()));
+        assert_eq!(kvs.get("k2".to_owned()).unwrap(), None);
+        assert_eq!(kvs.get("k3".to_owned()).unwrap(), Some("v3".to_owned()));
+    }
+
+    #[test]
+    pub fn test_get_missing_generation() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::default().with_max_log_size(64);
+        let mut kvs = KvStore::open_with_config(dir.path(), config).unwrap();
+        kvs.set("k1".to_owned(), "x".repeat(64)).unwrap();
+        kvs.set("k2".to_owned(), "v2".to_owned()).unwrap();
+        let n = kvs.indexes.get("k1").unwrap().n;
+        assert_ne!(n, kvs.nth);
+
+        // 文件在运行中被删掉了, 句柄也不在了
+        std::fs::remove_file(dir.path().join(format!("{}.log", n))).unwrap();
+        kvs.readers.remove(n);
+
+        match kvs.get("k1".to_owned()) {
+            Err(KvsError::MissingGeneration(m)) => assert_eq!(m, n),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(kvs.get("k1".to_owned()).unwrap_err().to_string().contains(&format!("{}.log", n)));
+        assert_eq!(kvs.get("k2".to_owned()).unwrap(), Some("v2".to_owned()));
+    }
+
+    #[test]
+    pub fn test_chunked_values() {
+        let dir = TempDir::new().unwrap();
+        let config = Config { chunk_size: 1000, ..Config::default() };
+        let mut kvs = KvStore::open_with_config(dir.path(), config.clone()).unwrap();
+        kvs.set("small".to_owned(), "v".repeat(1000)).unwrap();
+        assert!(!dir.path().join("VERSION").exists());
+
+        let value: String = (0..10_500).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        kvs.set("big".to_owned(), value.clone()).unwrap();
+        kvs.set_from_reader("streamed".to_owned(), value.len() as u64, value.as_bytes()).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.path().join("VERSION")).unwrap(), "2\n");
+        // VERSION是rename过去的, 不留临时文件
+        assert!(!dir.path().join("VERSION.tmp").exists());
+        assert_eq!(kvs.get("big".to_owned()).unwrap(), Some(value.clone()));
+        let mut read = String::new();
+        kvs.get_reader("streamed").unwrap().unwrap().read_to_string(&mut read).unwrap();
+        assert_eq!(read, value);
+        assert_eq!(kvs.value_len("big"), Some(10_500));
+        assert_eq!(kvs.value_len("streamed"), Some(10_500));
+
+        // 块不属于任何key, 但compact之后还在
+        kvs.compact().unwrap();
+        assert_eq!(kvs.indexes.len(), 3);
+        assert_eq!(kvs.get("streamed".to_owned()).unwrap(), Some(value.clone()));
+        let mut pairs: Vec<_> = kvs.scan_unordered().map(|r| r.unwrap()).collect();
+        pairs.sort();
+        assert_eq!(pairs[0], ("big".to_owned(), value.clone()));
+
+        // 删掉之后compact把所有块一起清掉
+        kvs.remove("big".to_owned()).unwrap();
+        kvs.remove("streamed".to_owned()).unwrap();
+        kvs.compact().unwrap();
+        let size: u64 = kvs.readers.generations().iter()
+            .map(|n| std::fs::metadata(dir.path().join(format!("{}.log", n))).unwrap().len())
+            .sum();
+        // compact出来的文件和新的log各有一个header
+        assert_eq!(size, 2 * FILE_HEADER_SIZE + 16 + 5 + 1000);
+
+        kvs.set("big".to_owned(), value.clone()).unwrap();
+        drop(kvs);
+        let mut kvs = KvStore::open_with_config(dir.path(), config).unwrap();
+        // replay时从块的header算出整个value的长度
+        assert_eq!(kvs.value_len("big"), Some(10_500));
+        assert_eq!(kvs.get("big".to_owned()).unwrap(), Some(value));
+        assert_eq!(kvs.get("small".to_owned()).unwrap(), Some("v".repeat(1000)));
+
+        // 更新的格式版本打不开
+        drop(kvs);
+        std::fs::write(dir.path().join("VERSION"), "7\n").unwrap();
+        assert!(matches!(KvStore::open(dir.path()), Err(KvsError::UnsupportedVersion(7))));
+    }
+
+    #[test]
+    pub fn test_lazy_readers_lru() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::default().with_max_log_size(64).with_max_open_files(2);
+        let mut kvs = KvStore::open_with_config(dir.path(), config.clone()).unwrap();
+        for i in 0..5 {
+            kvs.set(format!("k{}", i), "x".repeat(64)).unwrap();
+        }
+        assert!(kvs.readers.generations().len() >= 5);
+        assert!(kvs.readers.open_count() <= 2);
+        drop(kvs);
+
+        let mut kvs = KvStore::open_with_config(dir.path(), config).unwrap();
+        assert!(kvs.readers.open_count() <= 2);
+        for _ in 0..2 {
+            for i in 0..5 {
+                assert_eq!(kvs.get(format!("k{}", i)).unwrap(), Some("x".repeat(64)));
+                assert!(kvs.readers.open_count() <= 2);
+            }
+        }
+
+        // 句柄被LRU关掉之后文件被删了, 下次打开时发现
+        let n = kvs.indexes.get("k0").unwrap().n;
+        kvs.get("k4".to_owned()).unwrap();
+        kvs.get("k3".to_owned()).unwrap();
+        std::fs::remove_file(dir.path().join(format!("{}.log", n))).unwrap();
+        match kvs.get("k0".to_owned()) {
+            Err(KvsError::MissingGeneration(m)) => assert_eq!(m, n),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    pub fn test_index_record_sizes() {
+        // 索引里的ksize和vsize要和记录header里的一样, 不管value是怎么存的
+        let chunked = Config { chunk_size: 16, ..Config::default() };
+        let configs = vec![
+            Config::default(),
+            Config::default().with_dedup(true),
+            Config::default().with_value_log(true).with_value_log_threshold(0),
+            chunked,
+        ];
+        for config in configs {
+            let dir = TempDir::new().unwrap();
+            let mut kvs = KvStore::open_with_config(dir.path(), config.clone()).unwrap();
+            kvs.set("a".to_owned(), "x".repeat(100)).unwrap();
+            kvs.set("b".to_owned(), "x".repeat(100)).unwrap();
+            kvs.set_with_ttl("c".to_owned(), "y".repeat(40), std::time::Duration::from_secs(3600)).unwrap();
+            kvs.expire("a", std::time::Duration::from_secs(3600)).unwrap();
+            for round in 0..3 {
+                for key in &["a", "b", "c"] {
+                    let v = kvs.indexes.get(key).unwrap().clone();
+                    let f = kvs.readers.get(v.n).unwrap();
+                    f.seek(SeekFrom::Start(v.pos + 8)).unwrap();
+                    let ksize = f.read_u32::<LittleEndian>().unwrap();
+                    let vsize = f.read_u32::<LittleEndian>().unwrap();
+                    assert_eq!(v.ksize, ksize & !((super::FLAG_EXPIRES as u32) << super::FLAGS_SHIFT));
+                    assert_eq!(v.vsize, vsize);
+                }
+                assert_eq!(kvs.get("a".to_owned()).unwrap(), Some("x".repeat(100)));
+                assert_eq!(kvs.get("c".to_owned()).unwrap(), Some("y".repeat(40)));
+                // 重新打开(有hint的话从hint读, 再是replay), compact之后都一样
+                drop(kvs);
+                if round == 1 {
+                    let _ = std::fs::remove_file(dir.path().join(crate::hint::HINT_FILE));
+                }
+                kvs = KvStore::open_with_config(dir.path(), config.clone()).unwrap();
+                if round == 1 {
+                    kvs.compact().unwrap();
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_replay_without_advice() {
+        // 管道不能用posix_fadvise, 真的调用也会失败
+        #[cfg(target_os = "linux")]
+        {
+            let (reader, _writer) = std::io::pipe().unwrap();
+            let f = std::fs::File::from(std::os::fd::OwnedFd::from(reader));
+            assert!(FileAdvice::Sequential.advise(&f).is_err());
+            FileAdvice::Sequential.apply(&f);
+        }
+
+        // 提示全都失败时replay, compact和读照样正常
+        crate::advice::set_unsupported(true);
+        let dir = TempDir::new().unwrap();
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        for i in 0..100 {
+            kvs.set(format!("k{}", i % 10), format!("v{}", i)).unwrap();
+        }
+        drop(kvs);
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        assert_eq!(kvs.get("k3".to_owned()).unwrap(), Some("v93".to_owned()));
+        kvs.compact().unwrap();
+        drop(kvs);
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        assert_eq!(kvs.indexes.len(), 10);
+        assert_eq!(kvs.get("k9".to_owned()).unwrap(), Some("v99".to_owned()));
+        crate::advice::set_unsupported(false);
     }
 }
@@ -0,0 +1,283 @@
+use std::collections::HashSet;
+use std::ops::{Bound, RangeBounds};
+
+use crate::index::KeyIndex;
+use crate::{ChangeEvent, KvsError, RemoveReport, Result};
+
+use super::{
+    check_sizes, encode_item, index_ksize, io_at, prefix_end, range_is_empty, record_vsize, unix_time, DataIndex, KvStore, BATCH_VERSION,
+    FLAG_BATCH,
+};
+
+/// `remove_range`每个transaction最多删多少个key
+const REMOVE_BATCH: usize = 1024;
+
+/// One operation of a `KvStore::transaction`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Sets `key` to `value`.
+    Set {
+        /// The key to set.
+        key: String,
+        /// The new value.
+        value: String,
+    },
+    /// Removes `key`, which must exist at this point of the batch.
+    Remove {
+        /// The key to remove.
+        key: String,
+    },
+}
+
+impl<I: KeyIndex> KvStore<I> {
+    /// Moves the value of `from` to the key `to`.
+    ///
+    /// The value is read once and written under `to` together with a
+    /// tombstone for `from` as one `transaction`, so either both happen or
+    /// neither does, and after a crash at any point exactly one of the two
+    /// keys holds the value. An existing `to` is overwritten. Renaming a key
+    /// to itself does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if `from` does not exist.
+    pub fn rename(&mut self, from: String, to: String) -> Result<()> {
+        let value = self.get_ref(&from)?.ok_or(KvsError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        self.transaction(vec![Op::Set { key: to, value }, Op::Remove { key: from }])
+    }
+
+    /// Exchanges the values of `a` and `b`.
+    ///
+    /// Both values are read first and written back crosswise as one
+    /// `transaction`, so if a write fails neither key changes. Like with
+    /// `rename`, a crash at any point keeps either both writes or neither,
+    /// so the two keys never end up holding the same value. Swapping a key
+    /// with itself does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::KeyNotFound` if either key doesn't exist; a missing
+    /// key isn't treated as empty.
+    pub fn swap(&mut self, a: String, b: String) -> Result<()> {
+        let va = self.get_ref(&a)?.ok_or(KvsError::KeyNotFound)?;
+        let vb = self.get_ref(&b)?.ok_or(KvsError::KeyNotFound)?;
+        if a == b {
+            return Ok(());
+        }
+        self.transaction(vec![Op::Set { key: a, value: vb }, Op::Set { key: b, value: va }])
+    }
+
+    /// Removes every existing key of `keys` and reports how many existed.
+    ///
+    /// The tombstones are written back to back as one `transaction`, so the
+    /// log is synced once under `SyncPolicy::OnEveryWrite`, and either all of
+    /// them are removed or none is. Missing keys are skipped
+    /// instead of failing the batch.
+    pub fn remove_all(&mut self, keys: impl IntoIterator<Item = String>) -> Result<RemoveReport> {
+        let mut report = RemoveReport::default();
+        let mut seen = HashSet::new();
+        let mut ops = Vec::new();
+        for key in keys {
+            if self.lookup(&key).is_some() && seen.insert(key.clone()) {
+                ops.push(Op::Remove { key });
+            } else {
+                report.missing += 1;
+            }
+        }
+        report.removed = ops.len();
+        if !ops.is_empty() {
+            self.transaction(ops)?;
+        }
+        Ok(report)
+    }
+
+    /// Removes all keys within `range`, returning how many were removed.
+    ///
+    /// Bounds behave like those of `range`, except that an empty or inverted
+    /// range removes nothing instead of panicking. The keys are removed in
+    /// batches of up to 1024 keys, each written as one
+    /// `transaction`, so a large range never has all its keys in memory at
+    /// once. Every batch is removed entirely or not at all, but a failure or
+    /// crash in the middle of a range leaves the earlier batches removed.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::UnsupportedOperation` with `IndexKind::Hash`.
+    pub fn remove_range<R: RangeBounds<String>>(&mut self, range: R) -> Result<u64> {
+        let mut start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let mut count = 0;
+        while !range_is_empty(&start, &end) {
+            // 先把这一批key收集起来, 再去改索引
+            let keys: Vec<String> = self
+                .indexes
+                .range((start.as_ref().map(String::as_str), end.as_ref().map(String::as_str)))?
+                .take(REMOVE_BATCH)
+                .map(|(k, _)| k.to_owned())
+                .collect();
+            let last = match keys.last() {
+                Some(last) => last.clone(),
+                None => break,
+            };
+            let done = keys.len() < REMOVE_BATCH;
+            count += keys.len() as u64;
+            self.transaction(keys.into_iter().map(|key| Op::Remove { key }).collect())?;
+            if done {
+                break;
+            }
+            start = Bound::Excluded(last);
+        }
+        Ok(count)
+    }
+
+    /// Removes all keys starting with `prefix`, returning how many were
+    /// removed. An empty prefix removes every key.
+    ///
+    /// Batched like `remove_range`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::UnsupportedOperation` with `IndexKind::Hash`.
+    pub fn remove_prefix(&mut self, prefix: &str) -> Result<u64> {
+        self.remove_range((Bound::Included(prefix.to_owned()), prefix_end(prefix)))
+    }
+
+    /// Applies a batch of operations as one unit.
+    ///
+    /// All records are appended to the log first, and synced once under
+    /// `SyncPolicy::OnEveryWrite`; then the index changes are swapped in
+    /// together. If any write fails, or a `Op::Remove` targets a key that
+    /// does not exist (taking the earlier ops of the batch into account), the
+    /// log is rolled back and the index is left unchanged.
+    ///
+    /// A batch of several ops is framed by markers in the log, and `open`
+    /// ignores a batch whose closing marker never made it to disk, so a
+    /// crash keeps all of the batch or none of it. Such a batch upgrades the
+    /// directory to log format version 6, which older versions of `kvs`
+    /// refuse to open.
+    pub fn transaction(&mut self, ops: Vec<Op>) -> Result<()> {
+        self.check_writable()?;
+        for op in &ops {
+            if let Op::Set { value, .. } = op {
+                self.check_value_size(value.len() as u64)?;
+            }
+        }
+
+        if ops.len() > 1 {
+            self.upgrade_version(BATCH_VERSION)?;
+        }
+
+        // 先按op的顺序把索引的变化暂存起来, None代表删除
+        let mut staged: Vec<(String, Option<DataIndex>)> = Vec::new();
+        let mut uncompacted = 0;
+        let curpos = self.writer().pos;
+        if let Err(e) = self.stage_ops(ops, &mut staged, &mut uncompacted) {
+            self.rollback(curpos);
+            return Err(match e {
+                KvsError::Io(e) => io_at(self.nth, curpos)(e),
+                e => e,
+            });
+        }
+
+        let now = unix_time();
+        for (key, v) in staged {
+            let removed = v.is_none();
+            let history_key = self.history.as_ref().map(|_| key.clone());
+            let old = match v {
+                Some(v) => {
+                    self.subscribers.notify(|| ChangeEvent::Set { key: key.clone() });
+                    self.indexes.insert(key, v)
+                }
+                None => {
+                    self.subscribers.notify(|| ChangeEvent::Remove { key: key.clone() });
+                    self.indexes.remove(&key)
+                }
+            };
+            if let Some(old) = old {
+                self.vlog_garbage += old.vlen as u64;
+                match (&mut self.history, history_key) {
+                    (Some(history), Some(key)) if removed => history.push_removed(&key, old, now),
+                    (Some(history), Some(key)) => history.push(&key, old),
+                    _ => {}
+                }
+            }
+        }
+        self.uncompacted += uncompacted;
+
+        self.roll_if_full()?;
+        if self.config.auto_compaction {
+            self.compact_if_needed()?;
+        }
+        Ok(())
+    }
+
+    /// 把`ops`全部写入log并同步一次, 索引的变化记到`staged`里
+    fn stage_ops(
+        &mut self,
+        ops: Vec<Op>,
+        staged: &mut Vec<(String, Option<DataIndex>)>,
+        uncompacted: &mut u64,
+    ) -> Result<()> {
+        // 只有一条记录时本来就是原子的, 不用标记
+        let batch = ops.len() > 1;
+        if batch {
+            *uncompacted += self.write_batch_marker()?;
+        }
+        for op in ops {
+            let key = match &op {
+                Op::Set { key, .. } | Op::Remove { key } => key,
+            };
+            // 同一个key在这批里最后一次的变化才是它现在的样子
+            let prev_len = match staged.iter().rev().find(|(k, _)| k == key) {
+                Some((_, v)) => v.as_ref().map(|v| v.len),
+                None => self.lookup(key).map(|v| v.len),
+            };
+
+            let pos = self.writer().pos;
+            match op {
+                Op::Set { key, value } => {
+                    check_sizes(key.as_bytes(), value.len() as u64)?;
+                    let unixtime = unix_time();
+                    let flags = self.write_item(unixtime, key.as_bytes(), value.as_bytes(), 0)?;
+                    *uncompacted += prev_len.unwrap_or(0) as u64;
+                    let len = (self.writer().pos - pos) as u32;
+                    let ksize = index_ksize(flags, key.len());
+                    let vsize = record_vsize(key.len(), len, 0);
+                    staged.push((key, Some(DataIndex {
+                        n: self.nth,
+                        pos,
+                        len,
+                        timestamp: unixtime,
+                        vlen: 0,
+                        ksize,
+                        vsize,
+                        value_len: value.len() as u32,
+                        expires: 0,
+                    })));
+                }
+                Op::Remove { key } => {
+                    let prev_len = prev_len.ok_or(KvsError::KeyNotFound)?;
+                    self.write_item(0, key.as_bytes(), &[], 0)?;
+                    *uncompacted += prev_len as u64 + self.writer().pos - pos;
+                    staged.push((key, None));
+                }
+            }
+        }
+        if batch {
+            *uncompacted += self.write_batch_marker()?;
+        }
+
+        self.sync_writer()?;
+        Ok(())
+    }
+
+    /// 写一条`FLAG_BATCH`的标记, 返回它的长度
+    fn write_batch_marker(&mut self) -> Result<u64> {
+        let pos = self.writer().pos;
+        encode_item(self.writer(), unix_time(), FLAG_BATCH, &[], &[])?;
+        Ok(self.writer().pos - pos)
+    }
+}
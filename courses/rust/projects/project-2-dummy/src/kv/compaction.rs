@@ -0,0 +1,969 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::advice::FileAdvice;
+use crate::dedup::{decode_ref, encode_ref, DedupTable, REF_SIZE};
+use crate::index::KeyIndex;
+use crate::readers::{Readers, READ_BATCH};
+use crate::vlog::{decode_pointer, encode_pointer, VALUE_LOG_EXT};
+use crate::{CompactStatus, CompactionProgress, KvsError, Result};
+
+use super::{
+    create_tmp, encode_expiring, encode_header, encode_item, expiry_len, io_at, publish_log, read_file_header, read_item, seek_value,
+    tmp_log_path, unix_time, value_from_record, write_compacted, write_file_header, write_meta, write_version, DataIndex, KvStore, LogWriter,
+    FILE_HEADER_SIZE, FLAGS_SHIFT, FLAG_BATCH, FLAG_BLOB, FLAG_CHUNKED, FLAG_EXPIRES, FLAG_REF, FLAG_TOUCH, FLAG_TTL, FLAG_VLOG, KSIZE_MASK,
+    LOG_FILE_VERSION, TTL_SIZE,
+};
+
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// value log里的垃圾超过这么多才回收, 比compact的阈值大, 回收得没那么频繁
+const VALUE_LOG_GC_THRESHOLD: u64 = 4 * COMPACTION_THRESHOLD;
+/// compact时比这个大的记录直接在文件之间流式拷贝, 不整条读进内存
+const STREAM_COPY_SIZE: u32 = 1024 * 1024;
+
+/// `SharedKvStore::compact`进行中的状态, 不持锁时只用自己的句柄读旧文件
+pub(crate) struct OnlineCompaction {
+    /// 拷到第几个文件
+    n: u64,
+    /// 拷贝时写的临时文件, 结束时才rename成`n.log`
+    path: PathBuf,
+    /// 开始时的活记录, 拷贝之后改成新位置
+    entries: Vec<(String, DataIndex)>,
+    /// 每条活记录开始时的(generation, 位置), 用来判断拷贝期间有没有被改过
+    origins: Vec<(u64, u64)>,
+    /// 要删的generation
+    old: Vec<u64>,
+    readers: Readers,
+    dedup: DedupTable,
+    /// 开始时的`KvStore::uncompacted`, 都能回收
+    uncompacted: u64,
+    moved: HashMap<(u64, u64), u64>,
+}
+
+impl OnlineCompaction {
+    /// 不持锁调用, 把开始时的活记录拷到新文件里, 返回写好的文件
+    pub(crate) fn run(&mut self, progress: &mut dyn FnMut(CompactionProgress)) -> Result<File> {
+        let mut dest = BufWriter::new(create_tmp(&self.path)?);
+        for f in self.readers.open_files() {
+            FileAdvice::Sequential.apply(f);
+        }
+        let mut values: Vec<&mut DataIndex> = self.entries.iter_mut().map(|(_, v)| v).collect();
+        self.moved = copy_records(&mut self.readers, &mut values, &mut dest, self.n, true, progress)?;
+        let file = dest.into_inner().map_err(|e| e.into_error())?;
+        file.sync_data()?;
+        Ok(file)
+    }
+}
+
+impl<I: KeyIndex> KvStore<I> {
+    /// Rewrites all live records into a new generation and deletes the old ones.
+    ///
+    /// The index is the source of truth: it already holds the winning record
+    /// of every key, so exactly one record per key survives. When a key has
+    /// several records, the one in the later generation wins, and within a
+    /// generation the one at the later offset wins; that is the same rule
+    /// `open` applies when replaying the logs.
+    ///
+    /// Does nothing on a read-only store. If copying or publishing the new
+    /// generation fails, the partial file is deleted and the store is
+    /// unchanged; a failure while deleting the old generations leaves the
+    /// rest of them for the next compaction.
+    pub fn compact(&mut self) -> Result<()> {
+        self.compact_with_progress(&mut |_| {})
+    }
+
+    /// Same as `compact`, but calls `progress` while copying records.
+    ///
+    /// `progress` is called after every batch of records copied and once
+    /// more at the end, when `copied` equals `total`, the number of live
+    /// keys. Nothing is called on a read-only store.
+    pub fn compact_with_progress(&mut self, progress: &mut dyn FnMut(CompactionProgress)) -> Result<()> {
+        if self.writer.is_none() || self.compacting {
+            return Ok(());
+        }
+        self.writer().flush()?;
+        self.drop_expired();
+        let oldfile_num = self.nth + 1;
+        let nth = self.nth + 2;
+
+        // 先写到临时文件里, 写完fsync了再rename, 崩溃时目录里不会有写了一半的generation
+        let tmp = tmp_log_path(&self.path, oldfile_num);
+        for f in self.readers.open_files() {
+            FileAdvice::Sequential.apply(f);
+        }
+        let path = &self.path;
+        let config = &self.config;
+        let readers = &mut self.readers;
+        let values: Vec<&mut DataIndex> = self.indexes.values_mut().filter(|v| readers.contains(v.n)).collect();
+        // 在副本上改, 发布了新文件才换进索引, 失败了索引还是原样
+        let mut copies: Vec<DataIndex> = values.iter().map(|v| (**v).clone()).collect();
+        let copied = create_tmp(&tmp).map_err(KvsError::from).and_then(|file| {
+            let mut targets: Vec<&mut DataIndex> = copies.iter_mut().collect();
+            let mut dest = BufWriter::new(file);
+            let moved = copy_records(readers, &mut targets, &mut dest, oldfile_num, true, progress)?;
+            let file = dest.into_inner().map_err(|e| e.into_error())?;
+            file.sync_data()?;
+            // 新的writer在发布之前建好, 发布之后就不会再失败到一半
+            let writer = LogWriter::create(path, nth, config.direct_io, config.write_buffer_size)?;
+            publish_log(&tmp, &path.join(format!("{}.log", oldfile_num)))?;
+            Ok((file, moved, writer))
+        });
+        let (oldfile, moved, writer) = match copied {
+            Ok(v) => v,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+        for (v, copy) in values.into_iter().zip(copies) {
+            *v = copy;
+        }
+        self.dedup.remap(&moved, oldfile_num);
+
+        let old = self.readers.generations();
+        self.readers.put(oldfile_num, oldfile);
+        self.nth = nth;
+        self.readers.add(self.nth);
+        self.writer = Some(writer);
+        self.uncompacted = 0;
+        for n in old {
+            if let Some(f) = self.readers.file(n) {
+                FileAdvice::DontNeed.apply(f);
+            }
+            self.readers.remove(n);
+            self.unsynced.remove(&n);
+            fs::remove_file(self.path.join(format!("{}.log", n)))?;
+        }
+        self.truncate_history()?;
+        // hint只是为了open得快, 写不了就下次replay
+        let _ = self.write_hint();
+        Ok(())
+    }
+
+    /// 在线compact的第一步, 持锁调用: 记下现在的活记录和generation, 换一个新的log接着写
+    ///
+    /// 之后的写入都进新的log, 要删的文件不会再变. 去重表先拿走, 免得新写的引用记录
+    /// 指向要删的文件. 只读或者已经在compact时返回None.
+    pub(crate) fn begin_compaction(&mut self) -> Result<Option<OnlineCompaction>> {
+        if self.writer.is_none() || self.compacting {
+            return Ok(None);
+        }
+        self.drop_expired();
+        self.writer().flush()?;
+        let n = self.nth + 1;
+        let old = self.readers.generations();
+        let mut readers = Readers::new(self.path.clone(), &self.config);
+        for &g in &old {
+            readers.add(g);
+        }
+        let entries: Vec<(String, DataIndex)> = self
+            .indexes
+            .iter()
+            .filter(|(_, v)| self.readers.contains(v.n))
+            .map(|(k, v)| (k.to_owned(), v.clone()))
+            .collect();
+
+        self.unsynced.insert(self.nth);
+        self.nth += 2;
+        self.readers.add(self.nth);
+        self.writer = Some(LogWriter::create(&self.path, self.nth, self.config.direct_io, self.config.write_buffer_size)?);
+        self.compacting = true;
+        Ok(Some(OnlineCompaction {
+            n,
+            path: tmp_log_path(&self.path, n),
+            origins: entries.iter().map(|(_, v)| (v.n, v.pos)).collect(),
+            entries,
+            old,
+            readers,
+            dedup: self.dedup.take(),
+            uncompacted: self.uncompacted,
+            moved: HashMap::new(),
+        }))
+    }
+
+    /// 在线compact的最后一步, 持锁调用: 拷贝期间没被改过的key指向新位置, 再删掉旧文件
+    ///
+    /// 先把临时文件rename过去, 再换索引, 最后删文件, 持锁读的时候不会看到不存在的generation.
+    pub(crate) fn finish_compaction(&mut self, c: OnlineCompaction, file: File) -> Result<()> {
+        let out = c.n;
+        let dest = self.path.join(format!("{}.log", out));
+        if let Err(e) = publish_log(&c.path, &dest) {
+            let _ = fs::remove_file(&dest);
+            self.abort_compaction(c);
+            return Err(e.into());
+        }
+        for ((key, v), origin) in c.entries.into_iter().zip(c.origins) {
+            // 拷贝期间单独改过的过期时间和`touch`过的timestamp在新log里, 索引里留着现在的
+            let (expires, timestamp) = match self.indexes.get(&key) {
+                Some(cur) if (cur.n, cur.pos) == origin => (cur.expires, cur.timestamp),
+                _ => continue,
+            };
+            self.indexes.replace(&key, DataIndex { expires, timestamp, ..v });
+        }
+        let mut dedup = c.dedup;
+        dedup.remap(&c.moved, out);
+        self.dedup.merge(dedup);
+
+        self.readers.put(out, file);
+        for n in c.old {
+            if let Some(f) = self.readers.file(n) {
+                FileAdvice::DontNeed.apply(f);
+            }
+            self.readers.remove(n);
+            self.unsynced.remove(&n);
+            fs::remove_file(self.path.join(format!("{}.log", n)))?;
+        }
+        self.truncate_history()?;
+        // 拷贝期间被覆盖的旧记录, 在新文件里的那份一样是垃圾
+        self.uncompacted = self.uncompacted.saturating_sub(c.uncompacted);
+        self.compacting = false;
+        let _ = self.write_hint();
+        Ok(())
+    }
+
+    /// 拷贝失败时持锁调用, 删掉写了一半的文件, 旧文件和索引都没动过
+    pub(crate) fn abort_compaction(&mut self, c: OnlineCompaction) {
+        let _ = fs::remove_file(&c.path);
+        self.dedup.merge(c.dedup);
+        self.compacting = false;
+    }
+
+    /// Merges the smallest sealed generations into one new generation,
+    /// leaving the larger ones untouched.
+    ///
+    /// Up to `Config::with_compaction_tiers` generations are picked by file
+    /// size; the active log is never merged. Only their live records are
+    /// copied, so this rewrites much less than `compact` when most data sits
+    /// in a few large, mostly live generations. Tombstones of removed keys
+    /// are copied as well, since the untouched generations may still hold
+    /// older records of those keys; `compact` drops them for good. Records
+    /// elsewhere that refer to a merged generation (see `Config::with_dedup`)
+    /// are copied along, which needs a small read per live record of the
+    /// newer generations.
+    ///
+    /// Does nothing with fewer than two sealed generations. If a write
+    /// fails, the partial file is deleted and the store is unchanged.
+    pub fn compact_tiered(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if self.compacting || self.config.compaction_tiers < 2 {
+            return Ok(());
+        }
+        self.drop_expired();
+        self.writer().flush()?;
+        let mut sealed = Vec::new();
+        for n in self.readers.generations() {
+            if n != self.nth {
+                sealed.push((fs::metadata(self.path.join(format!("{}.log", n)))?.len(), n));
+            }
+        }
+        if sealed.len() < 2 {
+            return Ok(());
+        }
+        sealed.sort_unstable();
+        let merged: BTreeSet<u64> = sealed.iter().take(self.config.compaction_tiers).map(|&(_, n)| n).collect();
+        let oldest = *merged.iter().next().unwrap();
+
+        // 被删掉的key的tombstone留着, 回收的是其它不在索引里的记录
+        let mut tombstones = Vec::new();
+        let mut seen = HashSet::new();
+        let mut reclaimed = 0;
+        // 新写的tombstone和TTL记录, 都算进uncompacted
+        let mut added = 0;
+        // 单独的TTL记录改过过期时间的key, 它的value不一定跟着拷贝; `touch`过的key也一样
+        let mut ttl_keys = HashSet::new();
+        let mut touch_keys = HashSet::new();
+        let mut key = Vec::new();
+        for &n in &merged {
+            let mut f = BufReader::new(self.readers.open_new(n)?);
+            if read_file_header(&mut f)? > LOG_FILE_VERSION {
+                continue;
+            }
+            while let Ok((data, flags)) = read_item(n, &mut f, &mut key) {
+                if flags & FLAG_BATCH == FLAG_BATCH {
+                    reclaimed += data.len as u64;
+                    continue;
+                }
+                // blob只被引用, 从来不算进uncompacted
+                if flags & FLAG_BLOB != 0 {
+                    continue;
+                }
+                let live = std::str::from_utf8(&key)
+                    .ok()
+                    .and_then(|k| self.indexes.get(k))
+                    .is_some_and(|v| (v.n, v.pos) == (n, data.pos));
+                if live {
+                    continue;
+                }
+                let indexed = std::str::from_utf8(&key).is_ok_and(|k| self.indexes.contains_key(k));
+                if flags & FLAG_TTL != 0 && indexed {
+                    ttl_keys.insert(key.clone());
+                    reclaimed += data.len as u64;
+                    continue;
+                }
+                if flags & FLAG_TOUCH != 0 {
+                    if indexed {
+                        touch_keys.insert(key.clone());
+                    }
+                    reclaimed += data.len as u64;
+                    continue;
+                }
+                // 过期时没写tombstone, 去掉过期的记录后更早的generation里的value不能复活
+                let expiring = flags & (FLAG_EXPIRES | FLAG_TTL) != 0;
+                if (data.timestamp == 0 || expiring) && !indexed && seen.insert(key.clone()) {
+                    tombstones.push(key.clone());
+                    if expiring {
+                        reclaimed += data.len as u64;
+                        added += 16 + key.len() as u64;
+                    }
+                } else {
+                    reclaimed += data.len as u64;
+                }
+            }
+        }
+
+        // 引用只会指向同一个或者更早的generation, 只用看比最早合并的generation新的记录
+        let mut candidates: Vec<(u64, u64, u32)> = self
+            .indexes
+            .iter()
+            .filter(|(_, v)| v.n > oldest && !merged.contains(&v.n))
+            .map(|(_, v)| (v.n, v.pos, v.len))
+            .collect();
+        candidates.sort_unstable();
+        let mut referencing = HashSet::new();
+        let mut displaced = 0;
+        for (n, pos, len) in candidates {
+            let f = self.readers.get(n)?;
+            let (flags, vsize) = seek_value(f, pos).map_err(io_at(n, pos))?;
+            if flags & (FLAG_REF | FLAG_CHUNKED) == 0 {
+                continue;
+            }
+            let mut raw = vec![0; vsize as usize];
+            f.read_exact(&mut raw).map_err(io_at(n, pos))?;
+            for target in raw.chunks(REF_SIZE) {
+                if merged.contains(&decode_ref(target).map_err(io_at(n, pos))?.0) {
+                    referencing.insert((n, pos));
+                    displaced += len as u64;
+                    break;
+                }
+            }
+        }
+
+        // 拷贝的value带着现在的过期时间和timestamp, 其余的key要重写一条TTL记录或者touch的记录
+        let left_behind = |key: &[u8]| {
+            let v = std::str::from_utf8(key).ok().and_then(|k| self.indexes.get(k));
+            v.filter(|v| !merged.contains(&v.n) && !referencing.contains(&(v.n, v.pos))).cloned()
+        };
+        let mut ttls = Vec::new();
+        for key in ttl_keys {
+            if let Some(v) = left_behind(&key) {
+                added += 16 + key.len() as u64 + TTL_SIZE as u64;
+                ttls.push((key, v.expires));
+            }
+        }
+        let mut touches = Vec::new();
+        for key in touch_keys {
+            if let Some(v) = left_behind(&key) {
+                added += 16 + key.len() as u64;
+                touches.push((key, v.timestamp));
+            }
+        }
+
+        let out = self.nth + 1;
+        let tmp = tmp_log_path(&self.path, out);
+        let path = &self.path;
+        let readers = &mut self.readers;
+        let values: Vec<&mut DataIndex> = self
+            .indexes
+            .values_mut()
+            .filter(|v| merged.contains(&v.n) || referencing.contains(&(v.n, v.pos)))
+            .collect();
+        // 拷贝可能改变记录的长度, 在副本上改, 全部写完了才换进索引
+        let mut copies: Vec<DataIndex> = values.iter().map(|v| (**v).clone()).collect();
+        let copied = create_tmp(&tmp).map_err(KvsError::from).and_then(|file| {
+            let mut dest = BufWriter::new(file);
+            let mut targets: Vec<&mut DataIndex> = copies.iter_mut().collect();
+            let moved = copy_records(readers, &mut targets, &mut dest, out, true, &mut |_| {})?;
+            for key in &tombstones {
+                encode_item(&mut dest, 0, 0, key, &[])?;
+            }
+            for (key, expires) in &ttls {
+                encode_item(&mut dest, unix_time(), FLAG_TTL, key, &expires.to_le_bytes())?;
+            }
+            for (key, timestamp) in &touches {
+                encode_item(&mut dest, *timestamp, FLAG_TOUCH, key, &[])?;
+            }
+            let file = dest.into_inner().map_err(|e| e.into_error())?;
+            file.sync_data()?;
+            publish_log(&tmp, &path.join(format!("{}.log", out)))?;
+            Ok((file, moved))
+        });
+        let (file, moved) = match copied {
+            Ok(copied) => copied,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+        for (v, copy) in values.into_iter().zip(copies) {
+            *v = copy;
+        }
+        self.dedup.remap_generations(&merged, &moved, out);
+
+        for &n in &merged {
+            if let Some(f) = self.readers.file(n) {
+                FileAdvice::DontNeed.apply(f);
+            }
+            self.readers.remove(n);
+            self.unsynced.remove(&n);
+            fs::remove_file(self.path.join(format!("{}.log", n)))?;
+        }
+        self.truncate_history()?;
+        self.readers.put(out, file);
+        self.unsynced.insert(self.nth);
+        self.nth += 2;
+        self.readers.add(self.nth);
+        self.writer = Some(LogWriter::create(&self.path, self.nth, self.config.direct_io, self.config.write_buffer_size)?);
+        self.uncompacted = (self.uncompacted + displaced + added).saturating_sub(reclaimed);
+        let _ = self.write_hint();
+        Ok(())
+    }
+
+    /// Compacts the store a bit at a time: copies at most `max_bytes` of live
+    /// records per call and returns whether more work remains.
+    ///
+    /// The first call picks the generations to compact: all of them, after
+    /// sealing the active log. Each call then moves the next live records
+    /// of the oldest of these generations to the end of the active log,
+    /// synced like any write, and deletes the generation once nothing live
+    /// is left in it. Between calls the store is consistent, and reads and
+    /// writes go on as usual; records written meanwhile aren't compacted
+    /// again by this run. Keep calling until it returns
+    /// `CompactStatus::Done`; the result then holds the same data as after
+    /// `compact`. The call after that starts a new run.
+    ///
+    /// The budget is overshot by a single record larger than `max_bytes`,
+    /// which is still moved on its own, and by the records of newer
+    /// generations that refer to the generation being deleted (see
+    /// `Config::with_dedup`), which are moved along with the data they
+    /// refer to in the last step of that generation.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `KvsError::CompactionRunning` while `SharedKvStore::compact`
+    /// is copying records. If a write fails, the records of this step are
+    /// rolled back and the store is unchanged.
+    pub fn compact_budget(&mut self, max_bytes: u64) -> Result<CompactStatus> {
+        self.check_writable()?;
+        if self.compacting {
+            return Err(KvsError::CompactionRunning);
+        }
+        let boundary = match self.compact_boundary {
+            Some(boundary) => boundary,
+            None => {
+                // 现在的log也要compact, 拷贝的记录和新的写入都接到新的log里
+                if self.writer().pos > FILE_HEADER_SIZE {
+                    self.writer().flush()?;
+                    self.unsynced.insert(self.nth);
+                    self.nth += 1;
+                    self.readers.add(self.nth);
+                    self.writer = Some(LogWriter::create(&self.path, self.nth, self.config.direct_io, self.config.write_buffer_size)?);
+                }
+                self.nth - 1
+            }
+        };
+        self.compact_boundary = Some(boundary);
+        self.drop_expired();
+        self.flush_buffers()?;
+        let g = match self.readers.generations().into_iter().next() {
+            Some(g) if g <= boundary => g,
+            _ => {
+                self.compact_boundary = None;
+                return Ok(CompactStatus::Done);
+            }
+        };
+
+        // 按文件里的顺序拷, 一次不超过max_bytes, 但至少拷一条
+        let mut live: Vec<(u64, u32)> =
+            self.indexes.iter().filter(|(_, v)| v.n == g).map(|(_, v)| (v.pos, v.len)).collect();
+        live.sort_unstable();
+        let mut budget = 0;
+        let mut selected = HashSet::new();
+        for &(pos, len) in &live {
+            if !selected.is_empty() && budget + len as u64 > max_bytes {
+                break;
+            }
+            budget += len as u64;
+            selected.insert((g, pos));
+        }
+        // 这是这个generation的最后一步: 引用它的记录也要搬走, 之后才能删
+        let last = selected.len() == live.len();
+        if last {
+            let mut candidates: Vec<(u64, u64)> =
+                self.indexes.iter().filter(|(_, v)| v.n > g).map(|(_, v)| (v.n, v.pos)).collect();
+            candidates.sort_unstable();
+            for (n, pos) in candidates {
+                let f = self.readers.get(n)?;
+                let (flags, vsize) = seek_value(f, pos).map_err(io_at(n, pos))?;
+                if flags & (FLAG_REF | FLAG_CHUNKED) == 0 {
+                    continue;
+                }
+                let mut raw = vec![0; vsize as usize];
+                f.read_exact(&mut raw).map_err(io_at(n, pos))?;
+                for target in raw.chunks(REF_SIZE) {
+                    if decode_ref(target).map_err(io_at(n, pos))?.0 == g {
+                        selected.insert((n, pos));
+                        break;
+                    }
+                }
+            }
+        }
+
+        self.writer().flush()?;
+        let n = self.nth;
+        let start = self.writer().pos;
+        let path = &self.path;
+        let readers = &mut self.readers;
+        let writer = self.writer.as_mut().expect("write to a read-only store");
+        let values: Vec<&mut DataIndex> =
+            self.indexes.values_mut().filter(|v| selected.contains(&(v.n, v.pos))).collect();
+        let displaced: u64 = values.iter().map(|v| v.len as u64).sum();
+        // 和compact_tiered一样在副本上改, 写完并且落盘了才换进索引
+        let mut copies: Vec<DataIndex> = values.iter().map(|v| (**v).clone()).collect();
+        let mut targets: Vec<&mut DataIndex> = copies.iter_mut().collect();
+        let copied = copy_records_at(readers, &mut targets, writer, n, start, true, &mut |_| {})
+            .and_then(|moved| {
+                writer.flush()?;
+                writer.sync(path)?;
+                Ok(moved)
+            });
+        let moved = match copied {
+            Ok(moved) => moved,
+            Err(e) => {
+                self.rollback(start);
+                return Err(e);
+            }
+        };
+        for (v, copy) in values.into_iter().zip(copies) {
+            *v = copy;
+        }
+        self.uncompacted += displaced;
+
+        if last {
+            self.dedup.remap_generations(&BTreeSet::from([g]), &moved, n);
+            let file = self.path.join(format!("{}.log", g));
+            let garbage = fs::metadata(&file)?.len().saturating_sub(FILE_HEADER_SIZE);
+            if let Some(f) = self.readers.file(g) {
+                FileAdvice::DontNeed.apply(f);
+            }
+            self.readers.remove(g);
+            self.unsynced.remove(&g);
+            fs::remove_file(file)?;
+            self.truncate_history()?;
+            self.uncompacted = self.uncompacted.saturating_sub(garbage);
+            let _ = self.write_hint();
+        } else {
+            self.dedup.remap(&moved, n);
+        }
+        self.roll_if_full()?;
+
+        let more = self.readers.generations().into_iter().next().is_some_and(|g| g <= boundary);
+        if !more {
+            self.compact_boundary = None;
+            return Ok(CompactStatus::Done);
+        }
+        Ok(CompactStatus::MoreWork)
+    }
+
+    /// Compacts the log if enough stale data has piled up.
+    ///
+    /// Also garbage collects the value log (see `gc_value_log`) once enough
+    /// overwritten values have piled up there. Returns whether compaction ran.
+    /// With `Config::with_auto_compaction` disabled, this is the only way
+    /// compaction is triggered besides calling `compact` directly.
+    pub fn compact_if_needed(&mut self) -> Result<bool> {
+        self.check_writable()?;
+        if self.compacting {
+            return Ok(false);
+        }
+        if self.vlog_garbage >= VALUE_LOG_GC_THRESHOLD {
+            self.gc_value_log()?;
+            return Ok(true);
+        }
+        if self.uncompacted < COMPACTION_THRESHOLD {
+            return Ok(false);
+        }
+        self.compact()?;
+        Ok(true)
+    }
+
+    /// Rewrites the live values of the value log (see
+    /// `Config::with_value_log`) into a new value log file and deletes the old
+    /// ones, reclaiming the space of overwritten and removed values.
+    ///
+    /// Live values get new pointer records, and the main log is compacted
+    /// afterwards so that no record points into a deleted file.
+    pub fn gc_value_log(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let old = self.vlogs.generations();
+        // 在线compact结束前不能compact, 也就不能删旧的value log
+        if old.is_empty() || self.compacting {
+            return Ok(());
+        }
+
+        // 从一个新的value log开始写, 旧的全部回收
+        self.flush_buffers()?;
+        self.vnth += 1;
+        self.vlogs.add(self.vnth);
+        let vpath = self.path.join(format!("{}.{}", self.vnth, VALUE_LOG_EXT));
+        self.vlog = Some(LogWriter::open_at(&vpath, self.config.direct_io, self.config.write_buffer_size)?);
+
+        let live: Vec<(String, DataIndex)> = self
+            .indexes
+            .iter()
+            .filter(|(_, v)| v.vlen > 0)
+            .map(|(k, v)| (k.to_owned(), v.clone()))
+            .collect();
+        for chunk in live.chunks(READ_BATCH) {
+            let locs: Vec<_> = chunk.iter().map(|(_, v)| (v.n, v.pos, v.len)).collect();
+            let mut pointers = Vec::with_capacity(chunk.len());
+            for ((_, v), buf) in chunk.iter().zip(self.readers.read_records(&locs)?) {
+                let (_, raw) = value_from_record(&buf).map_err(io_at(v.n, v.pos))?;
+                pointers.push(decode_pointer(raw).map_err(io_at(v.n, v.pos))?);
+            }
+            let records = self.vlogs.read_records(&pointers)?;
+
+            for ((key, v), record) in chunk.iter().zip(records) {
+                // value log里的记录原样拷过去, 再写一条指向新位置的指针记录
+                let (vn, vpos, vlen) = self.append_value_log(|w| w.write_all(&record))?;
+                let pointer = encode_pointer(vn, vpos, vlen);
+                let (pos, len) = self.append_record(v.timestamp, FLAG_VLOG, key.as_bytes(), &pointer, v.expires)?;
+                self.indexes.insert(key.clone(), DataIndex { n: self.nth, pos, len, vlen, ..v.clone() });
+                self.uncompacted += v.len as u64;
+                self.roll_if_full()?;
+            }
+        }
+        // 旧的value log删掉之前拷过去的value必须落盘, 不管SyncPolicy; 中途写满换掉的value log也一样
+        for &n in &self.unsynced_vlogs {
+            self.vlogs.open_new(n)?.sync_data()?;
+        }
+        let path = &self.path;
+        if let Some(vlog) = self.vlog.as_mut() {
+            vlog.sync(path)?;
+        }
+        self.writer.as_mut().expect("write to a read-only store").sync(path)?;
+
+        // compact之后log里就没有指向旧value log的记录了, 可以删掉
+        self.compact()?;
+        for n in old {
+            self.vlogs.remove(n);
+            fs::remove_file(self.path.join(format!("{}.{}", n, VALUE_LOG_EXT)))?;
+        }
+        self.vlog_garbage = 0;
+        Ok(())
+    }
+
+    /// 把索引指向的记录拷贝到第`n`个文件`dest`里(先写header), `update`为true时顺便更新索引
+    ///
+    /// 每拷完一批记录调用一次`progress`, 引用记录要等到最后才处理, 先不算进去.
+    ///
+    /// 引用记录最后处理: 被引用的记录也拷过去了就指向它的新位置, 否则把被引用的value
+    /// 单独拷成一条blob记录. 返回拷过去的记录从(generation, 旧位置)到新位置的映射.
+    fn copy_live_records<W: Write>(
+        &mut self,
+        dest: &mut W,
+        n: u64,
+        update: bool,
+        progress: &mut dyn FnMut(CompactionProgress),
+    ) -> Result<HashMap<(u64, u64), u64>> {
+        self.flush_buffers()?;
+        let readers = &mut self.readers;
+        let mut values: Vec<&mut DataIndex> = self
+            .indexes
+            .values_mut()
+            .filter(|v| readers.contains(v.n))
+            .collect();
+        copy_records(readers, &mut values, dest, n, update, progress)
+    }
+
+    /// Writes a fully compacted copy of the live data into `dest`.
+    ///
+    /// The source files and index are left untouched. `dest` is created if it
+    /// does not exist and must not already contain log files.
+    pub fn compact_to(&mut self, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest)?;
+        let exists = fs::read_dir(dest)?
+            .flat_map(|v| v.map(|e| e.path()))
+            .any(|v| v.extension().is_some_and(|ext| ext == "log"));
+        if exists {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already contains log files", dest.display()),
+            ).into());
+        }
+
+        // 指针记录原样拷过去, value log也要原样拷过去, 而且要先于log落盘
+        for n in self.vlogs.generations() {
+            let name = format!("{}.{}", n, VALUE_LOG_EXT);
+            fs::copy(self.path.join(&name), dest.join(&name))?;
+            File::open(dest.join(&name))?.sync_all()?;
+        }
+        if self.version > 1 {
+            write_version(dest, self.version)?;
+        }
+        if !self.meta.is_empty() {
+            write_meta(dest, &self.meta)?;
+        }
+
+        // 只拷贝索引指向的记录, 写到generation 1; 和compact一样先写临时文件, fsync了再rename
+        let tmp = tmp_log_path(dest, 1);
+        let copied = create_tmp(&tmp).map_err(KvsError::from).and_then(|file| {
+            let mut destfile = BufWriter::new(file);
+            self.copy_live_records(&mut destfile, 1, false, &mut |_| {})?;
+            let file = destfile.into_inner().map_err(|e| e.into_error())?;
+            file.sync_all()?;
+            Ok(publish_log(&tmp, &dest.join("1.log"))?)
+        });
+        if let Err(e) = copied {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        write_compacted(dest, unix_time())?;
+        Ok(())
+    }
+}
+
+/// `KvStore::copy_live_records`的实现, 拷贝`values`指向的记录, 从`readers`读
+fn copy_records<W: Write>(
+    readers: &mut Readers,
+    values: &mut [&mut DataIndex],
+    dest: &mut W,
+    n: u64,
+    update: bool,
+    progress: &mut dyn FnMut(CompactionProgress),
+) -> Result<HashMap<(u64, u64), u64>> {
+    write_file_header(dest)?;
+    copy_records_at(readers, values, dest, n, FILE_HEADER_SIZE, update, progress)
+}
+
+/// `copy_records`的实现, 记录接着写在`dest`的`offset`处, 不写文件header
+fn copy_records_at<W: Write>(
+    readers: &mut Readers,
+    values: &mut [&mut DataIndex],
+    dest: &mut W,
+    n: u64,
+    offset: u64,
+    update: bool,
+    progress: &mut dyn FnMut(CompactionProgress),
+) -> Result<HashMap<(u64, u64), u64>> {
+    let mut pos = offset;
+    let mut moved = HashMap::new();
+    let mut refs = Vec::new();
+
+    for start in (0..values.len()).step_by(READ_BATCH) {
+        let end = (start + READ_BATCH).min(values.len());
+        let chunk = &mut values[start..end];
+        let locs: Vec<_> = chunk
+            .iter()
+            .filter(|v| v.len <= STREAM_COPY_SIZE)
+            .map(|v| (v.n, v.pos, v.len))
+            .collect();
+        let mut bufs = readers.read_records(&locs)?.into_iter();
+        for (i, v) in chunk.iter_mut().enumerate() {
+            let len = if v.len > STREAM_COPY_SIZE {
+                let f = readers.get(v.n)?;
+                copy_expiring(f, v.pos, v.timestamp, v.expires, dest).map_err(io_at(v.n, v.pos))?
+            } else {
+                let buf = bufs.next().unwrap();
+                let (flags, _) = value_from_record(&buf).map_err(io_at(v.n, v.pos))?;
+                if flags & (FLAG_REF | FLAG_CHUNKED) != 0 {
+                    refs.push((start + i, buf));
+                    continue;
+                }
+                write_expiring(&buf, v.timestamp, v.expires, dest)?
+            };
+            moved.insert((v.n, v.pos), pos);
+            if update {
+                v.n = n;
+                v.pos = pos;
+                v.len = len;
+            }
+            pos += len as u64;
+        }
+        progress(CompactionProgress { copied: end - refs.len(), total: values.len() });
+    }
+
+    for (i, buf) in refs {
+        let v = &mut values[i];
+        let ksize = (&buf[8..12]).read_u32::<LittleEndian>()? & KSIZE_MASK;
+        let (flags, raw) = value_from_record(&buf).map_err(io_at(v.n, v.pos))?;
+        // 引用记录只有一个目标, 分块的value每一块是一个目标
+        let mut targets = Vec::with_capacity(raw.len());
+        for target in raw.chunks(REF_SIZE) {
+            let target = decode_ref(target).map_err(io_at(v.n, v.pos))?;
+            let tpos = match moved.get(&target) {
+                Some(&tpos) => tpos,
+                None => {
+                    let f = readers.get(target.0)?;
+                    let len = copy_as_blob(f, target.1, v.timestamp, dest)
+                        .map_err(io_at(target.0, target.1))?;
+                    moved.insert(target, pos);
+                    let tpos = pos;
+                    pos += len;
+                    tpos
+                }
+            };
+            targets.extend_from_slice(&encode_ref(n, tpos));
+        }
+        let key = &buf[16..16 + ksize as usize];
+        encode_expiring(dest, v.timestamp, flags & !FLAG_EXPIRES, key, &targets, v.expires)?;
+        let len = 16 + ksize + targets.len() as u32 + expiry_len(ksize, v.expires);
+        moved.insert((v.n, v.pos), pos);
+        if update {
+            v.n = n;
+            v.pos = pos;
+            v.len = len;
+        }
+        pos += len as u64;
+    }
+    progress(CompactionProgress { copied: values.len(), total: values.len() });
+    Ok(moved)
+}
+
+/// 把`pos`处记录的value拷成一条blob记录, 不整条读进内存, 返回写了多少字节
+fn copy_as_blob<R: Read + Seek, W: Write>(f: &mut R, pos: u64, timestamp: u64, dest: &mut W) -> io::Result<u64> {
+    let (flags, vsize) = seek_value(f, pos)?;
+    // blob后面没有TTL记录
+    encode_header(dest, timestamp, flags & !FLAG_EXPIRES | FLAG_BLOB, 0, vsize)?;
+    if io::copy(&mut f.take(vsize as u64), dest)? < vsize as u64 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(16 + vsize as u64)
+}
+
+/// `write_expiring`的流式版本, 给太大, 不整条读进内存的记录
+fn copy_expiring<R: Read + Seek, W: Write>(
+    f: &mut R,
+    pos: u64,
+    timestamp: u64,
+    expires: u64,
+    dest: &mut W,
+) -> io::Result<u32> {
+    f.seek(SeekFrom::Start(pos + 8))?;
+    let ksize = f.read_u32::<LittleEndian>()?;
+    let vsize = f.read_u32::<LittleEndian>()?;
+    let flags = (ksize >> FLAGS_SHIFT) as u8 & !FLAG_EXPIRES;
+    let ksize = ksize & KSIZE_MASK;
+    let mut key = vec![0; ksize as usize];
+    f.read_exact(&mut key)?;
+    encode_header(dest, timestamp, if expires == 0 { flags } else { flags | FLAG_EXPIRES }, ksize, vsize)?;
+    dest.write_all(&key)?;
+    if io::copy(&mut f.take(vsize as u64), dest)? < vsize as u64 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if expires != 0 {
+        encode_item(dest, timestamp, FLAG_TTL, &key, &expires.to_le_bytes())?;
+    }
+    Ok(16 + ksize + vsize + expiry_len(ksize, expires))
+}
+
+/// 把`buf`里的一条记录(带着它的TTL记录的话也在里面)拷到`dest`, timestamp换成`timestamp`,
+/// 过期时间换成`expires`
+///
+/// 单独的TTL记录和`touch`的记录这样合进value的记录里. 返回写了多少字节.
+fn write_expiring<W: Write>(buf: &[u8], timestamp: u64, expires: u64, dest: &mut W) -> io::Result<u32> {
+    let mut header = &buf[8..16];
+    let ksize = header.read_u32::<LittleEndian>()?;
+    let vsize = header.read_u32::<LittleEndian>()?;
+    let flags = (ksize >> FLAGS_SHIFT) as u8 & !FLAG_EXPIRES;
+    let ksize = ksize & KSIZE_MASK;
+    let end = 16 + (ksize + vsize) as usize;
+    if end > buf.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let key = &buf[16..16 + ksize as usize];
+    encode_expiring(dest, timestamp, flags, key, &buf[16 + ksize as usize..end], expires)?;
+    Ok(end as u32 + expiry_len(ksize, expires))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use crate::{Config, KeyIndex, KvStore};
+
+    use super::super::{open_file, read_file_header, read_item};
+
+    #[test]
+    pub fn test_interrupted_compaction_keeps_old_generations() {
+        let dir = TempDir::new().unwrap();
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        for i in 0..100 {
+            kvs.set(format!("key{}", i % 10), format!("value{}", i)).unwrap();
+        }
+        let old = kvs.readers.generations();
+
+        // 拷完了, 还没rename就崩溃: 目录里只有临时文件
+        let mut c = kvs.begin_compaction().unwrap().unwrap();
+        c.run(&mut |_| {}).unwrap();
+        let tmp = c.path.clone();
+        assert!(tmp.exists());
+        assert!(!dir.path().join(format!("{}.log", c.n)).exists());
+        drop(c);
+        drop(kvs);
+
+        let mut kvs = KvStore::open(dir.path()).unwrap();
+        assert!(!tmp.exists());
+        assert!(old.iter().all(|n| kvs.readers.contains(*n)));
+        for i in 90..100 {
+            assert_eq!(kvs.get(format!("key{}", i % 10)).unwrap(), Some(format!("value{}", i)));
+        }
+        assert!(kvs.stats().uncompacted_bytes > 0);
+
+        // 正常的compact和换generation也不会留下临时文件
+        kvs.compact().unwrap();
+        let names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert!(names.iter().all(|v| !v.ends_with(".tmp")), "{:?}", names);
+        assert_eq!(kvs.get("key7".to_owned()).unwrap(), Some("value97".to_owned()));
+    }
+
+    #[test]
+    pub fn test_compact_keeps_newest() {
+        let dir = TempDir::new().unwrap();
+        let config = Config::default().with_max_log_size(64);
+        let mut kvs = KvStore::open_with_config(dir.path(), config.clone()).unwrap();
+
+        // 同一个key写进两个generation
+        kvs.set("k".to_owned(), "v1".to_owned()).unwrap();
+        let first = kvs.nth;
+        kvs.set("filler".to_owned(), "x".repeat(64)).unwrap();
+        kvs.set("k".to_owned(), "v2".to_owned()).unwrap();
+        assert!(kvs.indexes.get("k").unwrap().n > first);
+
+        kvs.compact().unwrap();
+
+        let mut records = 0;
+        for n in kvs.readers.generations() {
+            let mut f = open_file(dir.path(), n).0;
+            read_file_header(&mut f).unwrap();
+            let mut key = Vec::new();
+            while read_item(n, &mut f, &mut key).is_ok() {
+                if key == b"k" {
+                    records += 1;
+                }
+            }
+        }
+        assert_eq!(records, 1);
+        assert_eq!(kvs.get("k".to_owned()).unwrap(), Some("v2".to_owned()));
+
+        drop(kvs);
+        let mut kvs = KvStore::open_with_config(dir.path(), config).unwrap();
+        assert_eq!(kvs.get("k".to_owned()).unwrap(), Some("v2".to_owned()));
+    }
+}
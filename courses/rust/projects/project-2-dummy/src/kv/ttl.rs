@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use crate::index::KeyIndex;
+use crate::Result;
+
+use super::{unix_time, DataIndex, KvStore, FLAG_TTL, TTL_VERSION};
+
+/// The expiry of a key, as returned by `KvStore::ttl`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtlState {
+    /// The key doesn't exist, or has expired.
+    Missing,
+    /// The key exists and never expires.
+    NoExpiry,
+    /// The key expires after this much time, which is never zero.
+    ExpiresIn(Duration),
+}
+
+impl<I: KeyIndex> KvStore<I> {
+    /// Sets `key` to `value`, expiring it after `ttl`.
+    ///
+    /// The expiry is an absolute time of the configured `Clock` (see
+    /// `Config::with_clock`), stored in a small record written right after
+    /// the value and synced with it. From then on `get`, `contains_key` and
+    /// the other reads treat the key as absent; `get` also drops it from the
+    /// index. `open` drops keys that expired while the store was closed.
+    /// Writing the key again with `set` or any other write clears the
+    /// expiry; `expire` and `persist` change it in place. Expired keys stay
+    /// listed by `keys`, `len` and `stats` until a read or `open` notices
+    /// them. Writing the first expiring key upgrades the directory to log
+    /// format version 4, which older versions of `kvs` refuse to open.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires = self.now_millis().saturating_add(ttl.as_millis() as u64).max(1);
+        self.set_expiring(key, &value, expires)
+    }
+
+    /// Makes `key` expire after `ttl`, replacing any expiry it had, and
+    /// returns whether the key exists.
+    ///
+    /// Only a small record with the new expiry is appended; the value isn't
+    /// rewritten, and compaction folds the expiry into the value's record.
+    /// Like a TTL given to `set_with_ttl`, it lasts until the key is written
+    /// again: a later `set` makes the key permanent.
+    pub fn expire(&mut self, key: &str, ttl: Duration) -> Result<bool> {
+        self.check_writable()?;
+        if self.lookup(key).is_none() {
+            return Ok(false);
+        }
+        let expires = self.now_millis().saturating_add(ttl.as_millis() as u64).max(1);
+        self.write_ttl(key, expires)?;
+        Ok(true)
+    }
+
+    /// Removes the expiry of `key`, so that it stays until it is removed, and
+    /// returns whether the key exists. See `expire`.
+    ///
+    /// Nothing is written if the key has no expiry.
+    pub fn persist(&mut self, key: &str) -> Result<bool> {
+        self.check_writable()?;
+        match self.lookup(key) {
+            None => Ok(false),
+            Some(v) if v.expires == 0 => Ok(true),
+            Some(_) => self.write_ttl(key, 0).map(|_| true),
+        }
+    }
+
+    /// Returns how long `key` lives on, by the configured `Clock` (see
+    /// `Config::with_clock`).
+    ///
+    /// Expiry is decided the same way as by `get`, from a single reading of
+    /// the clock: at that reading, a key reported as `ExpiresIn` is live and
+    /// an expired key is `Missing` (and dropped from the index like `get`
+    /// does). With a clock that moves, a later `get` finds the key gone once
+    /// the reported time has passed.
+    pub fn ttl(&mut self, key: &str) -> Result<TtlState> {
+        let now = self.now_millis();
+        Ok(match self.lookup_at(key, now) {
+            None => TtlState::Missing,
+            Some(v) if v.expires == 0 => TtlState::NoExpiry,
+            Some(v) => TtlState::ExpiresIn(Duration::from_millis(v.expires - now)),
+        })
+    }
+
+    /// 追加一条单独的TTL记录, 把`key`现在的value改成在`expires`过期, 0是不过期
+    ///
+    /// compact时会合进value的记录里, 所以这条记录整条算进uncompacted.
+    pub(super) fn write_ttl(&mut self, key: &str, expires: u64) -> Result<()> {
+        self.check_writable()?;
+        self.upgrade_version(TTL_VERSION)?;
+        let (_, len) = self.append_record(unix_time(), FLAG_TTL, key.as_bytes(), &expires.to_le_bytes(), 0)?;
+        if let Some(v) = self.indexes.get(key).cloned() {
+            self.indexes.replace(key, DataIndex { expires, ..v });
+        }
+        self.uncompacted += len as u64;
+        Ok(())
+    }
+
+    /// 把已经过期的key都从索引里删掉, compact之前调用, 过期的记录就不用拷贝了
+    pub(super) fn drop_expired(&mut self) {
+        let now = self.now_millis();
+        let expired: Vec<String> =
+            self.indexes.iter().filter(|(_, v)| v.expired(now)).map(|(k, _)| k.to_owned()).collect();
+        for key in expired {
+            self.lookup_at(&key, now);
+        }
+    }
+}
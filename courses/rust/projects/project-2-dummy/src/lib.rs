@@ -1,8 +1,42 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use codec::Codec;
+pub use config::{Config, IoBackend, SyncPolicy};
+pub use engine::KvsEngine;
 pub use error::{KvsError, Result};
-pub use kv::KvStore;
+pub use flush::FlushHandle;
+pub use index::{Index, IndexIter, IndexKind, KeyIndex};
+pub use kv::{CasResult, DataIndex, EntryMeta, KvStore, Op, TtlState};
+pub use memory::MemKvStore;
+pub use pattern::KeyPattern;
+pub use scan::{Iter, LogCursor, LogRecord, ScanCursor, ScanPage, ScanUnordered};
+pub use shared::SharedKvStore;
+pub use stats::{CompactStatus, CompactionProgress, GenerationInfo, MemoryUsage, RemoveReport, RetainStats, Stats, WarmupStats};
+pub use watch::{ChangeEvent, EvictReason};
 
+mod advice;
+mod clock;
+mod codec;
+mod config;
+mod dedup;
+#[cfg(target_os = "linux")]
+mod direct;
+mod engine;
 mod error;
+mod flush;
+mod hint;
+mod history;
+mod index;
 mod kv;
+mod memory;
+mod pattern;
+mod readers;
+mod scan;
+mod shared;
+mod stats;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+mod vlog;
+mod watch;
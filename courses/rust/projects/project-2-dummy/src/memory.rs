@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use crate::kv::{encode_item, read_value, DataIndex};
+use crate::{KvsEngine, KvsError, Result};
+
+/// An in-memory key/value store for tests and ephemeral use.
+///
+/// Records are appended to a `Vec<u8>` in the same format as the on-disk log,
+/// so overwrites and tombstones go through the same encoding as `KvStore`.
+/// Nothing is persisted; the data is gone once the store is dropped.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut store = KvStore::open_in_memory();
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct MemKvStore {
+    log: Cursor<Vec<u8>>,
+    indexes: BTreeMap<String, DataIndex>,
+    uncompacted: u64,
+}
+
+impl MemKvStore {
+    /// Creates an empty in-memory store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 追加一条记录, 返回记录的起始位置和长度
+    fn append_item(&mut self, timestamp: u64, k: &[u8], v: &[u8]) -> Result<(u64, u32)> {
+        let pos = self.log.get_ref().len() as u64;
+        self.log.set_position(pos);
+        encode_item(&mut self.log, timestamp, 0, k, v)?;
+        Ok((pos, (self.log.position() - pos) as u32))
+    }
+}
+
+impl KvsEngine for MemKvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let timestamp = crate::kv::unix_time();
+        let (pos, len) = self.append_item(timestamp, key.as_bytes(), value.as_bytes())?;
+        let index = DataIndex { n: 0, pos, len, timestamp, vlen: 0, ksize: key.len() as u32, vsize: value.len() as u32, value_len: value.len() as u32, expires: 0 };
+        if let Some(v) = self.indexes.insert(key, index) {
+            self.uncompacted += v.len as u64;
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.indexes.get(&key) {
+            Some(index) => Ok(Some(read_value(&mut self.log, index)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.indexes.contains_key(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if !self.indexes.contains_key(&key) {
+            return Err(KvsError::KeyNotFound);
+        }
+        let (_, len) = self.append_item(0, key.as_bytes(), &[])?;
+        if let Some(v) = self.indexes.remove(&key) {
+            self.uncompacted += (v.len + len) as u64;
+        }
+        Ok(())
+    }
+}
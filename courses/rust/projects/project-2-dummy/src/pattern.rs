@@ -0,0 +1,174 @@
+use crate::{KvsError, Result};
+
+/// 编译过的pattern里的一项, 每项匹配key的一个字节(`Star`匹配任意多个)
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Byte(u8),
+    Any,
+    Star,
+    /// 字节闭区间的集合, `negated`时匹配不在集合里的字节
+    Class { negated: bool, ranges: Vec<(u8, u8)> },
+}
+
+impl Token {
+    fn matches(&self, b: u8) -> bool {
+        match self {
+            Token::Byte(c) => *c == b,
+            Token::Any => true,
+            Token::Star => false,
+            Token::Class { negated, ranges } => {
+                ranges.iter().any(|&(lo, hi)| lo <= b && b <= hi) != *negated
+            }
+        }
+    }
+}
+
+/// A glob pattern matched against whole keys, as used by
+/// `KvStore::scan_match`.
+///
+/// Matching works on the bytes of the key and is anchored at both ends:
+///
+/// - `*` matches any sequence of bytes, including none.
+/// - `?` matches exactly one byte.
+/// - `[abc]` matches one of the listed bytes, `[a-z]` one byte in the range
+///   (inclusive), and `[!...]` or `[^...]` one byte not in the class. A `]`
+///   right after the opening `[` (or `[!`) is a member of the class.
+/// - `\` makes the next character literal, e.g. `\*` matches a `*`.
+/// - Every other character matches itself.
+///
+/// Since matching is on bytes, `?` matches a single byte of a multi-byte
+/// UTF-8 character, and non-ASCII characters in a class add each of their
+/// bytes.
+///
+/// ```rust
+/// # use kvs::{KeyPattern, Result};
+/// # fn try_main() -> Result<()> {
+/// let pattern = KeyPattern::new("user:*:settings")?;
+/// assert!(pattern.matches(b"user:42:settings"));
+/// assert!(!pattern.matches(b"user:42:settings:old"));
+/// assert_eq!(pattern.literal_prefix(), "user:");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPattern {
+    tokens: Vec<Token>,
+    /// 第一个通配符之前的字面量
+    prefix: String,
+}
+
+impl KeyPattern {
+    /// Compiles `pattern`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `KvsError::InvalidPattern` for a `[` without a closing `]`, a
+    /// trailing `\`, or a range whose end is smaller than its start.
+    pub fn new(pattern: &str) -> Result<Self> {
+        let invalid = |reason: &str| KvsError::InvalidPattern(format!("{:?}: {}", pattern, reason));
+        let mut tokens = Vec::new();
+        let mut prefix = String::new();
+        let mut literal = true;
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            let c = match c {
+                '*' => {
+                    // 连续的*等价于一个
+                    if tokens.last() != Some(&Token::Star) {
+                        tokens.push(Token::Star);
+                    }
+                    literal = false;
+                    continue;
+                }
+                '?' => {
+                    tokens.push(Token::Any);
+                    literal = false;
+                    continue;
+                }
+                '[' => {
+                    let negated = chars.next_if(|&c| c == '!' || c == '^').is_some();
+                    let mut members: Vec<u8> = Vec::new();
+                    let mut ranges = Vec::new();
+                    let mut closed = false;
+                    let mut first = true;
+                    while let Some(c) = chars.next() {
+                        if c == ']' && !first {
+                            closed = true;
+                            break;
+                        }
+                        first = false;
+                        let c = if c == '\\' { chars.next().ok_or_else(|| invalid("trailing `\\`"))? } else { c };
+                        // 区间两端都是单字节时才是区间, 否则`-`是普通成员
+                        if c.is_ascii() && chars.peek() == Some(&'-') {
+                            let mut ahead = chars.clone();
+                            ahead.next();
+                            if let Some(hi) = ahead.next().filter(|&hi| hi != ']' && hi.is_ascii()) {
+                                if hi < c {
+                                    return Err(invalid("range end before start"));
+                                }
+                                ranges.push((c as u8, hi as u8));
+                                chars = ahead;
+                                continue;
+                            }
+                        }
+                        let mut buf = [0; 4];
+                        members.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    }
+                    if !closed {
+                        return Err(invalid("unclosed `[`"));
+                    }
+                    ranges.extend(members.into_iter().map(|b| (b, b)));
+                    tokens.push(Token::Class { negated, ranges });
+                    literal = false;
+                    continue;
+                }
+                '\\' => chars.next().ok_or_else(|| invalid("trailing `\\`"))?,
+                c => c,
+            };
+            if literal {
+                prefix.push(c);
+            }
+            let mut buf = [0; 4];
+            tokens.extend(c.encode_utf8(&mut buf).bytes().map(Token::Byte));
+        }
+        Ok(KeyPattern { tokens, prefix })
+    }
+
+    /// Returns whether the whole `key` matches the pattern.
+    pub fn matches(&self, key: &[u8]) -> bool {
+        let tokens = &self.tokens;
+        let (mut t, mut k) = (0, 0);
+        // 上一个*之后的位置, 和*当前吃到的key位置, 匹配失败时让*多吃一个字节
+        let mut star: Option<(usize, usize)> = None;
+        while k < key.len() {
+            match tokens.get(t) {
+                Some(Token::Star) => {
+                    star = Some((t + 1, k));
+                    t += 1;
+                    continue;
+                }
+                Some(token) if token.matches(key[k]) => {
+                    t += 1;
+                    k += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            match star {
+                Some((st, sk)) => {
+                    t = st;
+                    k = sk + 1;
+                    star = Some((st, sk + 1));
+                }
+                None => return false,
+            }
+        }
+        tokens[t..].iter().all(|token| *token == Token::Star)
+    }
+
+    /// The literal part of the pattern before its first wildcard; every
+    /// matching key starts with it.
+    pub fn literal_prefix(&self) -> &str {
+        &self.prefix
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use crate::kv::io_at;
+#[cfg(all(feature = "mmap", unix))]
+use memmap2::{Mmap, MmapOptions};
+#[cfg(all(feature = "uring", target_os = "linux"))]
+use crate::uring::{ReadAt, Ring};
+#[cfg(any(all(feature = "uring", target_os = "linux"), all(feature = "mmap", unix)))]
+use crate::IoBackend;
+use crate::{Config, KvsError, Result};
+
+/// `read_records`一批最多读多少条
+pub(crate) const READ_BATCH: usize = 256;
+
+/// 一个打开的文件句柄和它最后一次被用到的时间
+struct OpenFile {
+    file: File,
+    used: u64,
+    /// `IoBackend::Mmap`时第一次读才映射, 文件变大后读到映射外面时重新映射
+    ///
+    /// 映射之后文件变大了, 多出来的部分看不到, 要重新映射. 截短文件之后再访问
+    /// 被截掉的整页会SIGBUS, 所以只读索引指向的记录: 它们都在截断的位置之前.
+    /// 文件被删除不影响已经映射的部分.
+    #[cfg(all(feature = "mmap", unix))]
+    map: Option<Mmap>,
+}
+
+impl OpenFile {
+    fn new(file: File, used: u64) -> Self {
+        OpenFile {
+            file,
+            used,
+            #[cfg(all(feature = "mmap", unix))]
+            map: None,
+        }
+    }
+}
+
+/// 所有generation的读句柄
+///
+/// 句柄在第一次读某个generation时才打开, 最多同时打开`max_open`个,
+/// 超过时关掉最久没用过的那个(LRU).
+pub(crate) struct Readers {
+    path: PathBuf,
+    /// 文件的扩展名, 文件名是`{generation}.{ext}`
+    ext: &'static str,
+    max_open: usize,
+    /// 所有存在的generation, 不管句柄是否打开
+    generations: BTreeSet<u64>,
+    open: HashMap<u64, OpenFile>,
+    /// 每次访问加一, 用来比较新旧
+    tick: u64,
+    /// `IoBackend::Uring`并且内核支持时才有
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    ring: Option<Ring>,
+    /// `IoBackend::Mmap`时从映射里读
+    #[cfg(all(feature = "mmap", unix))]
+    mmap: bool,
+}
+
+impl Readers {
+    pub(crate) fn new(path: PathBuf, config: &Config) -> Self {
+        Self::with_extension(path, "log", config)
+    }
+
+    pub(crate) fn with_extension(path: PathBuf, ext: &'static str, config: &Config) -> Self {
+        Readers {
+            path,
+            ext,
+            max_open: config.max_open_files.max(1),
+            generations: BTreeSet::new(),
+            open: HashMap::new(),
+            tick: 0,
+            // 内核不支持io_uring就退回普通的读
+            #[cfg(all(feature = "uring", target_os = "linux"))]
+            ring: match config.io_backend {
+                IoBackend::Uring => Ring::new().ok(),
+                _ => None,
+            },
+            #[cfg(all(feature = "mmap", unix))]
+            mmap: config.io_backend == IoBackend::Mmap,
+        }
+    }
+
+    /// 是否整条记录一起读: 用io_uring批量读, 或者从映射里读
+    pub(crate) fn batched(&self) -> bool {
+        #[cfg(all(feature = "mmap", unix))]
+        {
+            if self.mmap {
+                return true;
+            }
+        }
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        return self.ring.is_some();
+        #[cfg(not(all(feature = "uring", target_os = "linux")))]
+        false
+    }
+
+    /// 读出一批完整的记录, `locs`是(generation, 位置, 长度)
+    ///
+    /// 有io_uring的话整批一起提交, 否则一条一条读.
+    pub(crate) fn read_records(&mut self, locs: &[(u64, u64, u32)]) -> Result<Vec<Vec<u8>>> {
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        {
+            if self.ring.is_some() {
+                let mut bufs = Vec::with_capacity(locs.len());
+                for chunk in locs.chunks(READ_BATCH) {
+                    // 这一批用到的句柄先全部打开, 读完再按上限关掉
+                    for &(n, _, _) in chunk {
+                        self.ensure_open(n)?;
+                    }
+                    let open = &self.open;
+                    let reqs: Vec<_> = chunk
+                        .iter()
+                        .map(|&(n, pos, len)| ReadAt { file: &open[&n].file, pos, len })
+                        .collect();
+                    let results = self.ring.as_mut().unwrap().read_batch(&reqs);
+                    drop(reqs);
+                    self.trim();
+                    for (&(n, pos, _), buf) in chunk.iter().zip(results?) {
+                        bufs.push(buf.map_err(io_at(n, pos))?);
+                    }
+                }
+                return Ok(bufs);
+            }
+        }
+
+        let mut bufs = Vec::with_capacity(locs.len());
+        for &(n, pos, len) in locs {
+            #[cfg(all(feature = "mmap", unix))]
+            {
+                if self.mmap {
+                    let mapped = self.read_mapped(n, pos, len);
+                    self.trim();
+                    if let Some(buf) = mapped? {
+                        bufs.push(buf);
+                        continue;
+                    }
+                }
+            }
+            let f = self.get(n)?;
+            let mut buf = vec![0; len as usize];
+            f.seek(SeekFrom::Start(pos))
+                .and_then(|_| f.read_exact(&mut buf))
+                .map_err(io_at(n, pos))?;
+            bufs.push(buf);
+        }
+        Ok(bufs)
+    }
+
+    /// 从第`n`个generation的映射里拷出一条记录, 记录超出文件末尾时返回None, 交给普通的读报错
+    #[cfg(all(feature = "mmap", unix))]
+    fn read_mapped(&mut self, n: u64, pos: u64, len: u32) -> Result<Option<Vec<u8>>> {
+        self.ensure_open(n)?;
+        let f = self.open.get_mut(&n).unwrap();
+        let end = pos + len as u64;
+        // 正在写的log会变大, 读到映射外面时按现在的大小重新映射
+        if f.map.as_ref().is_none_or(|m| (m.len() as u64) < end) {
+            let size = f.file.metadata().map_err(io_at(n, pos))?.len();
+            if size < end {
+                return Ok(None);
+            }
+            // SAFETY: 映射是只读的, 文件只会被我们自己追加或者截短. 追加的部分在映射外面,
+            // 截短只截掉失败的写入留下的尾巴, 索引不会指向那里, 所以读到的字节一直有效
+            let map = unsafe { MmapOptions::new().len(size as usize).map(&f.file) };
+            f.map = Some(map.map_err(io_at(n, pos))?);
+        }
+        let map = f.map.as_ref().unwrap();
+        Ok(Some(map[pos as usize..end as usize].to_vec()))
+    }
+
+    /// 登记一个generation, 先不打开
+    pub(crate) fn add(&mut self, n: u64) {
+        self.generations.insert(n);
+    }
+
+    /// 登记一个generation, 顺便把已经打开的句柄放进来
+    pub(crate) fn put(&mut self, n: u64, file: File) {
+        self.generations.insert(n);
+        self.tick += 1;
+        self.open.insert(n, OpenFile::new(file, self.tick));
+        self.trim();
+    }
+
+    /// 注销一个generation并关掉它的句柄
+    pub(crate) fn remove(&mut self, n: u64) -> bool {
+        self.open.remove(&n);
+        self.generations.remove(&n)
+    }
+
+    pub(crate) fn contains(&self, n: u64) -> bool {
+        self.generations.contains(&n)
+    }
+
+    /// 所有generation, 从小到大
+    pub(crate) fn generations(&self) -> Vec<u64> {
+        self.generations.iter().cloned().collect()
+    }
+
+    /// 当前打开着的句柄
+    pub(crate) fn open_files(&self) -> impl Iterator<Item = &File> {
+        self.open.values().map(|f| &f.file)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn open_count(&self) -> usize {
+        self.open.len()
+    }
+
+    /// 取第`n`个generation的句柄, 没打开就打开它
+    ///
+    /// generation没登记过, 或者文件已经不在了, 返回`KvsError::MissingGeneration`.
+    pub(crate) fn get(&mut self, n: u64) -> Result<&mut File> {
+        self.ensure_open(n)?;
+        self.trim();
+        Ok(&mut self.open.get_mut(&n).unwrap().file)
+    }
+
+    /// 保证第`n`个generation的句柄是打开的, 这里不做LRU淘汰.
+    ///
+    /// 一批读要同时用到多个句柄时先对每个调用它, 用`file`取句柄, 最后再`trim`.
+    pub(crate) fn ensure_open(&mut self, n: u64) -> Result<()> {
+        if !self.generations.contains(&n) {
+            return Err(KvsError::MissingGeneration(n));
+        }
+        self.tick += 1;
+        if let Some(f) = self.open.get_mut(&n) {
+            f.used = self.tick;
+            return Ok(());
+        }
+
+        let file = self.open_new(n)?;
+        self.open.insert(n, OpenFile::new(file, self.tick));
+        Ok(())
+    }
+
+    /// 给第`n`个generation另外打开一个句柄, 不放进LRU里
+    pub(crate) fn open_new(&self, n: u64) -> Result<File> {
+        if !self.generations.contains(&n) {
+            return Err(KvsError::MissingGeneration(n));
+        }
+        File::open(self.path.join(format!("{}.{}", n, self.ext))).map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                KvsError::MissingGeneration(n)
+            } else {
+                e.into()
+            }
+        })
+    }
+
+    /// 已经打开的句柄, 不会去打开文件
+    pub(crate) fn file(&self, n: u64) -> Option<&File> {
+        self.open.get(&n).map(|f| &f.file)
+    }
+
+    /// 句柄超过上限时关掉最久没用过的
+    pub(crate) fn trim(&mut self) {
+        while self.open.len() > self.max_open {
+            let oldest = *self.open.iter().min_by_key(|(_, f)| f.used).unwrap().0;
+            self.open.remove(&oldest);
+        }
+    }
+}
@@ -0,0 +1,491 @@
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::fmt;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::str::FromStr;
+use std::vec;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use serde::{Deserialize, Serialize};
+
+use crate::advice::FileAdvice;
+use crate::index::KeyIndex;
+use crate::dedup::{decode_ref, DedupTable, REF_SIZE};
+use crate::kv::{decode_bytes, io_at, read_file_header, FLAGS_SHIFT, FLAG_BLOB, FLAG_CHUNKED, FLAG_REF, FLAG_TOUCH, FLAG_TTL, FLAG_VLOG, KSIZE_MASK};
+use crate::vlog::{decode_pointer, VALUE_LOG_EXT};
+use crate::{KvStore, KvsError, Result};
+
+/// 一条记录的header和它的位置
+struct Header {
+    generation: u64,
+    pos: u64,
+    timestamp: u64,
+    flags: u8,
+    ksize: u32,
+    vsize: u32,
+}
+
+/// 按generation从小到大, 每个文件从头到尾顺序读记录
+///
+/// 用自己打开的文件句柄, 不影响store的readers.
+struct LogReader<'a> {
+    path: &'a Path,
+    generations: vec::IntoIter<u64>,
+    /// 正在读的文件和它的长度
+    current: Option<(BufReader<File>, u64)>,
+    /// 正在读的generation
+    n: u64,
+    /// 正在读的记录的起始位置(出错时用)
+    start: u64,
+    /// 下一条记录在当前文件里的位置
+    pos: u64,
+    /// 解引用记录时打开的文件, 按(generation, 扩展名)
+    targets: HashMap<(u64, &'static str), File>,
+    /// 开始读之前store交出写缓冲区时的错误, 第一次读时报告
+    pending: Option<io::Error>,
+}
+
+impl<'a> LogReader<'a> {
+    fn new(path: &'a Path, generations: Vec<u64>) -> Self {
+        LogReader {
+            path,
+            generations: generations.into_iter(),
+            current: None,
+            n: 0,
+            start: 0,
+            pos: 0,
+            targets: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    /// 读下一条完整记录的header, 读完所有文件返回None.
+    ///
+    /// 之后必须用`read_bytes`或者`skip`把key和value读掉.
+    fn next_header(&mut self) -> io::Result<Option<Header>> {
+        if let Some(e) = self.pending.take() {
+            return Err(e);
+        }
+        loop {
+            if self.current.is_none() {
+                self.n = match self.generations.next() {
+                    Some(n) => n,
+                    None => return Ok(None),
+                };
+                self.start = 0;
+                let f = File::open(self.path.join(format!("{}.log", self.n)))?;
+                let flen = f.metadata()?.len();
+                FileAdvice::Sequential.apply(&f);
+                let mut r = BufReader::new(f);
+                // open时已经检查过header的版本
+                read_file_header(&mut r)?;
+                self.pos = r.stream_position()?;
+                self.current = Some((r, flen));
+            }
+
+            let (r, flen) = self.current.as_mut().unwrap();
+            let pos = self.pos;
+            self.start = pos;
+            if pos + 16 > *flen {
+                self.current = None;
+                continue;
+            }
+
+            let timestamp = r.read_u64::<LittleEndian>()?;
+            let ksize = r.read_u32::<LittleEndian>()?;
+            let vsize = r.read_u32::<LittleEndian>()?;
+            let flags = (ksize >> FLAGS_SHIFT) as u8;
+            let ksize = ksize & KSIZE_MASK;
+            let len = 16 + ksize as u64 + vsize as u64;
+            // O_DIRECT的padding, 或者末尾残缺的记录, 和open时一样当作文件结束
+            if timestamp == u64::MAX || pos + len > *flen {
+                self.current = None;
+                continue;
+            }
+            self.pos += len;
+            // 只有引用记录会用到; TTL记录和touch的记录只改过期时间和timestamp, 不是一个value
+            if flags & (FLAG_BLOB | FLAG_TTL | FLAG_TOUCH) != 0 {
+                r.seek_relative(len as i64 - 16)?;
+                continue;
+            }
+
+            return Ok(Some(Header {
+                generation: self.n,
+                pos,
+                timestamp,
+                flags,
+                ksize,
+                vsize,
+            }));
+        }
+    }
+
+    fn read_bytes(&mut self, len: u32) -> io::Result<Vec<u8>> {
+        let r = &mut self.current.as_mut().unwrap().0;
+        let mut buf = Vec::with_capacity(len as usize);
+        r.take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() < len as usize {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buf)
+    }
+
+    /// 和`read_bytes`一样, 读到`buf`里, 不用每次分配
+    fn read_into(&mut self, len: u32, buf: &mut Vec<u8>) -> io::Result<()> {
+        let r = &mut self.current.as_mut().unwrap().0;
+        buf.clear();
+        r.take(len as u64).read_to_end(buf)?;
+        if buf.len() < len as usize {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, len: u32) -> io::Result<()> {
+        self.current.as_mut().unwrap().0.seek_relative(len as i64)
+    }
+
+    /// 把读出来的value解压, 引用记录和分块的value要去读被引用的记录, 指针记录要去读value log
+    fn resolve(&mut self, flags: u8, raw: Vec<u8>) -> io::Result<Vec<u8>> {
+        if flags & FLAG_VLOG != 0 {
+            let (n, pos, _) = decode_pointer(&raw)?;
+            return self.read_target(n, VALUE_LOG_EXT, pos);
+        }
+        if flags & (FLAG_REF | FLAG_CHUNKED) == 0 {
+            return decode_bytes(flags, raw);
+        }
+        let mut value = Vec::new();
+        for target in raw.chunks(REF_SIZE) {
+            let (n, pos) = decode_ref(target)?;
+            value.extend_from_slice(&self.read_target(n, "log", pos)?);
+        }
+        Ok(value)
+    }
+
+    /// 读出`{n}.{ext}`文件`pos`处的value, 解压过的
+    fn read_target(&mut self, n: u64, ext: &'static str, pos: u64) -> io::Result<Vec<u8>> {
+        let f = match self.targets.entry((n, ext)) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                e.insert(File::open(self.path.join(format!("{}.{}", n, ext)))?)
+            }
+        };
+        f.seek(SeekFrom::Start(pos + 8))?;
+        let ksize = f.read_u32::<LittleEndian>()?;
+        let vsize = f.read_u32::<LittleEndian>()?;
+        f.seek(SeekFrom::Current((ksize & KSIZE_MASK) as i64))?;
+        let mut raw = vec![0; vsize as usize];
+        f.read_exact(&mut raw)?;
+        decode_bytes((ksize >> FLAGS_SHIFT) as u8, raw)
+    }
+
+    /// 出错之后不再继续读, 返回带上位置的错误
+    fn fail(&mut self, e: io::Error) -> KvsError {
+        self.current = None;
+        self.generations = Vec::new().into_iter();
+        io_at(self.n, self.start)(e)
+    }
+}
+
+/// 不建索引, 只读header和key, 数出最后还活着的key的个数和总长度
+///
+/// key只记hash, 占的内存比索引小得多; hash冲突会少算一个key, 对估算没有影响.
+pub(crate) fn count_live_keys(path: &Path, generations: Vec<u64>) -> Result<(usize, usize)> {
+    let mut log = LogReader::new(path, generations);
+    let mut live: HashMap<u64, usize> = HashMap::new();
+    let mut key = Vec::new();
+    loop {
+        let h = match log.next_header() {
+            Ok(Some(h)) => h,
+            Ok(None) => break,
+            Err(e) => return Err(log.fail(e)),
+        };
+        if let Err(e) = log.read_into(h.ksize, &mut key).and_then(|_| log.skip(h.vsize)) {
+            return Err(log.fail(e));
+        }
+        let hash = DedupTable::hash(&key);
+        if h.timestamp == 0 {
+            live.remove(&hash);
+        } else {
+            live.insert(hash, key.len());
+        }
+    }
+    Ok((live.len(), live.values().sum()))
+}
+
+/// Iterator over the live key/value pairs of a `KvStore`, in file order.
+///
+/// Created by `KvStore::scan_unordered`.
+pub struct ScanUnordered<'a, I> {
+    log: LogReader<'a>,
+    indexes: &'a I,
+    /// 开始扫描的时间, Unix毫秒; 到这时已经过期的key跳过
+    now: u64,
+}
+
+impl<'a, I: KeyIndex> ScanUnordered<'a, I> {
+    pub(crate) fn new(path: &'a Path, indexes: &'a I, generations: Vec<u64>, now: u64, flushed: io::Result<()>) -> Self {
+        let mut log = LogReader::new(path, generations);
+        log.pending = flushed.err();
+        ScanUnordered {
+            log,
+            indexes,
+            now,
+        }
+    }
+
+    /// 读下一条记录, 索引还指向它的才返回
+    fn next_live(&mut self) -> io::Result<Option<(String, String)>> {
+        while let Some(h) = self.log.next_header()? {
+            let key = self.log.read_bytes(h.ksize)?;
+            let live = std::str::from_utf8(&key)
+                .ok()
+                .and_then(|k| self.indexes.get(k))
+                .is_some_and(|d| d.n == h.generation && d.pos == h.pos && !d.expired(self.now));
+            if !live {
+                self.log.skip(h.vsize)?;
+                continue;
+            }
+
+            let raw = self.log.read_bytes(h.vsize)?;
+            let value = String::from_utf8(self.log.resolve(h.flags, raw)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            // 索引里查到了, key一定是合法的utf8
+            let key = String::from_utf8(key).unwrap();
+            return Ok(Some((key, value)));
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, I: KeyIndex> ScanUnordered<'a, I> {
+    /// `KvStore::for_each_unordered`的实现, key和value都读进重复使用的缓冲区
+    pub(crate) fn visit(mut self, mut f: impl FnMut(&str, &[u8]) -> ControlFlow<()>) -> Result<()> {
+        let mut key = Vec::new();
+        let mut raw = Vec::new();
+        loop {
+            match self.visit_next(&mut key, &mut raw, &mut f) {
+                Ok(Some(ControlFlow::Continue(()))) => {}
+                Ok(_) => return Ok(()),
+                Err(e) => return Err(self.log.fail(e)),
+            }
+        }
+    }
+
+    /// 找到下一条活记录交给`f`, 读完所有文件返回None
+    fn visit_next(
+        &mut self,
+        key: &mut Vec<u8>,
+        raw: &mut Vec<u8>,
+        f: &mut impl FnMut(&str, &[u8]) -> ControlFlow<()>,
+    ) -> io::Result<Option<ControlFlow<()>>> {
+        while let Some(h) = self.log.next_header()? {
+            self.log.read_into(h.ksize, key)?;
+            let k = match std::str::from_utf8(key) {
+                Ok(k) if self.indexes.get(k).is_some_and(|d| d.n == h.generation && d.pos == h.pos && !d.expired(self.now)) => k,
+                _ => {
+                    self.log.skip(h.vsize)?;
+                    continue;
+                }
+            };
+            self.log.read_into(h.vsize, raw)?;
+            // 普通记录直接用缓冲区, 其余的要解出来
+            if h.flags == 0 {
+                return Ok(Some(f(k, raw)));
+            }
+            let value = self.log.resolve(h.flags, raw.clone())?;
+            return Ok(Some(f(k, &value)));
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, I: KeyIndex> Iterator for ScanUnordered<'a, I> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_live() {
+            Ok(item) => item.map(Ok),
+            Err(e) => Some(Err(self.log.fail(e))),
+        }
+    }
+}
+
+/// Iterator over the live key/value pairs of a `KvStore`, in ascending key
+/// order.
+///
+/// The keys are taken from the index when the iterator is created; each value
+/// is read from disk when its pair is reached. The iterator borrows the store
+/// mutably, so the store can't change while iterating and every key it yields
+/// is still live. A failed read yields an `Err` for that pair and iteration
+/// goes on with the next key.
+///
+/// Created by `KvStore::iter` and `KvStore::range`.
+pub struct Iter<'a, I: KeyIndex> {
+    store: &'a mut KvStore<I>,
+    keys: vec::IntoIter<String>,
+}
+
+impl<'a, I: KeyIndex> Iter<'a, I> {
+    pub(crate) fn new(store: &'a mut KvStore<I>, keys: Vec<String>) -> Self {
+        Iter { store, keys: keys.into_iter() }
+    }
+}
+
+impl<'a, I: KeyIndex> Iter<'a, I> {
+    /// 迭代期间store不会变, 但key可能在迭代时过期了, 这时返回None
+    fn read(&mut self, key: String) -> Option<Result<(String, String)>> {
+        match self.store.get_ref(&key) {
+            Ok(value) => value.map(|value| Ok((key, value))),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl<'a, I: KeyIndex> Iterator for Iter<'a, I> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.keys.next()?;
+            if let Some(item) = self.read(key) {
+                return Some(item);
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // 过期的key会被跳过, 下限只能是0
+        (0, self.keys.size_hint().1)
+    }
+}
+
+/// Walks the keys in descending order, e.g. with `rev`. Stepping from both
+/// ends of one iterator is fine; every pair is yielded once.
+impl<'a, I: KeyIndex> DoubleEndedIterator for Iter<'a, I> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.keys.next_back()?;
+            if let Some(item) = self.read(key) {
+                return Some(item);
+            }
+        }
+    }
+}
+
+/// Where a paginated scan stopped, as returned by `KvStore::scan_page`.
+///
+/// A cursor holds the last key of a page; the next page starts after it,
+/// whether or not that key still exists. It survives reopening the store and
+/// converts to and from a printable string (the key in lowercase hex) with
+/// `to_string` and `parse`, e.g. to put it in a URL.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanCursor {
+    pub(crate) last_key: String,
+}
+
+/// A page of `KvStore::scan_page`: the pairs and the cursor of the next page.
+pub type ScanPage = (Vec<(String, String)>, Option<ScanCursor>);
+
+impl fmt::Display for ScanCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.last_key.as_bytes() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ScanCursor {
+    type Err = KvsError;
+
+    /// # Errors
+    ///
+    /// Returns `KvsError::InvalidCursor` if `s` wasn't made by `to_string`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || KvsError::InvalidCursor(s.to_owned());
+        if !s.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(invalid)?;
+        let last_key = String::from_utf8(bytes).map_err(|_| invalid())?;
+        Ok(ScanCursor { last_key })
+    }
+}
+
+/// A raw record of the log, as returned by `LogCursor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// The generation (log file) holding the record.
+    pub generation: u64,
+    /// The offset of the record within its log file.
+    pub pos: u64,
+    /// The key of the record.
+    pub key: Vec<u8>,
+    /// The value of the record, decompressed and dereferenced (see
+    /// `Config::with_dedup` and `Config::with_value_log`) if needed; `None`
+    /// for a tombstone.
+    pub value: Option<Vec<u8>>,
+    /// The time the record was written, in seconds since the Unix epoch.
+    /// Tombstones have a timestamp of 0.
+    pub timestamp: u64,
+}
+
+/// Iterator over every record of the log in on-disk order, tombstones and
+/// overwritten records included.
+///
+/// Generations are read in ascending order and each file from front to back,
+/// i.e. in write order. Unlike `scan_unordered`, records are not checked
+/// against the index. The cursor uses its own file handles and never changes
+/// the store.
+///
+/// Created by `KvStore::log_cursor`.
+pub struct LogCursor<'a> {
+    log: LogReader<'a>,
+}
+
+impl<'a> LogCursor<'a> {
+    pub(crate) fn new(path: &'a Path, generations: Vec<u64>, flushed: io::Result<()>) -> Self {
+        let mut log = LogReader::new(path, generations);
+        log.pending = flushed.err();
+        LogCursor { log }
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<LogRecord>> {
+        let h = match self.log.next_header()? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        let key = self.log.read_bytes(h.ksize)?;
+        let raw = self.log.read_bytes(h.vsize)?;
+        let value = if h.timestamp == 0 {
+            None
+        } else {
+            Some(self.log.resolve(h.flags, raw)?)
+        };
+        Ok(Some(LogRecord {
+            generation: h.generation,
+            pos: h.pos,
+            key,
+            value,
+            timestamp: h.timestamp,
+        }))
+    }
+}
+
+impl<'a> Iterator for LogCursor<'a> {
+    type Item = Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_record() {
+            Ok(record) => record.map(Ok),
+            Err(e) => Some(Err(self.log.fail(e))),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use crate::index::{Index, KeyIndex};
+use crate::{CasResult, ChangeEvent, CompactStatus, CompactionProgress, EvictReason, KvStore, RemoveReport, Result, TtlState};
+
+/// A `KvStore` that can be shared between threads.
+///
+/// Clones are handles to the same store, and every operation locks it for
+/// its duration. The exception is `compact`: it copies the live records to a
+/// new generation without holding the lock, so reads and writes keep being
+/// served from the old generations meanwhile. The index is only switched to
+/// the new generation at the end, and the old files are deleted after that.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, SharedKvStore};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use std::thread;
+/// let store = SharedKvStore::new(KvStore::open(current_dir()?)?);
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let reader = store.clone();
+/// let handle = thread::spawn(move || reader.get("key".to_owned()));
+/// store.compact()?;
+/// assert_eq!(handle.join().unwrap()?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct SharedKvStore<I: KeyIndex = Index> {
+    inner: Arc<Mutex<KvStore<I>>>,
+}
+
+impl<I: KeyIndex> SharedKvStore<I> {
+    /// Wraps `store` for sharing.
+    pub fn new(store: KvStore<I>) -> Self {
+        SharedKvStore { inner: Arc::new(Mutex::new(store)) }
+    }
+
+    /// Locks the store, e.g. for operations without a shortcut here.
+    ///
+    /// A panic while the lock was held doesn't make the store unusable;
+    /// failed writes are handled by the store itself (see `KvsError::Poisoned`).
+    pub fn lock(&self) -> MutexGuard<'_, KvStore<I>> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// See `KvStore::get`.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.lock().get(key)
+    }
+
+    /// See `KvStore::contains_key`.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.lock().contains_key(key)
+    }
+
+    /// See `KvStore::set`.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.lock().set(key, value)
+    }
+
+    /// See `KvStore::set_with_ttl`.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.lock().set_with_ttl(key, value, ttl)
+    }
+
+    /// See `KvStore::expire`.
+    pub fn expire(&self, key: &str, ttl: Duration) -> Result<bool> {
+        self.lock().expire(key, ttl)
+    }
+
+    /// See `KvStore::persist`.
+    pub fn persist(&self, key: &str) -> Result<bool> {
+        self.lock().persist(key)
+    }
+
+    /// See `KvStore::ttl`.
+    pub fn ttl(&self, key: &str) -> Result<TtlState> {
+        self.lock().ttl(key)
+    }
+
+    /// See `KvStore::get_at`.
+    pub fn get_at(&self, key: &str, as_of: SystemTime) -> Result<Option<String>> {
+        self.lock().get_at(key, as_of)
+    }
+
+    /// See `KvStore::get_meta`.
+    pub fn get_meta(&self, key: &str) -> Option<String> {
+        self.lock().get_meta(key).map(str::to_owned)
+    }
+
+    /// See `KvStore::set_meta`.
+    pub fn set_meta(&self, key: &str, value: &str) -> Result<()> {
+        self.lock().set_meta(key, value)
+    }
+
+    /// See `KvStore::touch`.
+    pub fn touch(&self, key: &str) -> Result<bool> {
+        self.lock().touch(key)
+    }
+
+    /// See `KvStore::set_if_absent`. Of several threads setting the same
+    /// absent key, exactly one wins.
+    pub fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.lock().set_if_absent(key, value)
+    }
+
+    /// See `KvStore::compare_and_swap`. The compare and the write happen
+    /// under one lock, so a concurrent write either comes before the
+    /// compare or fails its own.
+    pub fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<CasResult> {
+        self.lock().compare_and_swap(key, expected, new)
+    }
+
+    /// See `KvStore::update`. The lock is held while `f` runs, so no other
+    /// write comes between reading the value and writing the result.
+    pub fn update(&self, key: String, f: impl FnOnce(Option<&str>) -> Option<String>) -> Result<Option<String>> {
+        self.lock().update(key, f)
+    }
+
+    /// See `KvStore::incr_by`. Concurrent increments are never lost.
+    pub fn incr_by(&self, key: String, delta: i64) -> Result<i64> {
+        self.lock().incr_by(key, delta)
+    }
+
+    /// See `KvStore::incr`.
+    pub fn incr(&self, key: String) -> Result<i64> {
+        self.incr_by(key, 1)
+    }
+
+    /// See `KvStore::append`. Appends from several threads all land.
+    pub fn append(&self, key: String, suffix: &str) -> Result<u64> {
+        self.lock().append(key, suffix)
+    }
+
+    /// See `KvStore::rename`.
+    pub fn rename(&self, from: String, to: String) -> Result<()> {
+        self.lock().rename(from, to)
+    }
+
+    /// See `KvStore::swap`.
+    pub fn swap(&self, a: String, b: String) -> Result<()> {
+        self.lock().swap(a, b)
+    }
+
+    /// See `KvStore::set_get`. No other write comes between reading the old
+    /// value and writing the new one.
+    pub fn set_get(&self, key: String, value: String) -> Result<Option<String>> {
+        self.lock().set_get(key, value)
+    }
+
+    /// See `KvStore::remove`.
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.lock().remove(key)
+    }
+
+    /// See `KvStore::remove_all`. The lock is taken once for all keys.
+    pub fn remove_all(&self, keys: impl IntoIterator<Item = String>) -> Result<RemoveReport> {
+        self.lock().remove_all(keys)
+    }
+
+    /// See `KvStore::remove_prefix`. The lock is held until every batch is
+    /// written.
+    pub fn remove_prefix(&self, prefix: &str) -> Result<u64> {
+        self.lock().remove_prefix(prefix)
+    }
+
+    /// See `KvStore::clear`. Fails with `KvsError::CompactionRunning` while
+    /// `compact` is copying records.
+    pub fn clear(&self) -> Result<()> {
+        self.lock().clear()
+    }
+
+    /// See `KvStore::take`. The value is read and removed under one lock.
+    pub fn take(&self, key: String) -> Result<Option<String>> {
+        self.lock().take(key)
+    }
+
+    /// See `KvStore::pop_first`. No other operation runs between reading
+    /// the entry and removing it, so each entry is popped only once.
+    pub fn pop_first(&self) -> Result<Option<(String, String)>> {
+        self.lock().pop_first()
+    }
+
+    /// See `KvStore::pop_last`; atomic like `pop_first`.
+    pub fn pop_last(&self) -> Result<Option<(String, String)>> {
+        self.lock().pop_last()
+    }
+
+    /// See `KvStore::on_evict`. `f` is never called while the store is
+    /// locked.
+    pub fn on_evict(&self, f: impl FnMut(&str, EvictReason) + Send + 'static) {
+        self.lock().on_evict(f)
+    }
+
+    /// See `KvStore::subscribe`. The receiver can be moved to another thread.
+    pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+        self.lock().subscribe()
+    }
+
+    /// Compacts the store without blocking other threads while records are
+    /// copied.
+    ///
+    /// Keys written while the copy is running keep their new values, and
+    /// writes meanwhile don't start another compaction. Does nothing on a
+    /// read-only store or when another compaction is still running. If the
+    /// copy fails, the partial file is deleted and the store is unchanged.
+    pub fn compact(&self) -> Result<()> {
+        self.compact_with_progress(&mut |_| {})
+    }
+
+    /// See `KvStore::compact_budget`. The lock is held for one step, so
+    /// other threads get to run between the calls.
+    pub fn compact_budget(&self, max_bytes: u64) -> Result<CompactStatus> {
+        self.lock().compact_budget(max_bytes)
+    }
+
+    /// Like `compact`, but reports progress to `progress` as in
+    /// `KvStore::compact_with_progress`.
+    pub fn compact_with_progress(&self, progress: &mut dyn FnMut(CompactionProgress)) -> Result<()> {
+        let mut compaction = match self.lock().begin_compaction()? {
+            Some(compaction) => compaction,
+            None => return Ok(()),
+        };
+        // 拷贝的时候不持锁
+        match compaction.run(progress) {
+            Ok(file) => self.lock().finish_compaction(compaction, file),
+            Err(e) => {
+                self.lock().abort_compaction(compaction);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<I: KeyIndex> Clone for SharedKvStore<I> {
+    fn clone(&self) -> Self {
+        SharedKvStore { inner: Arc::clone(&self.inner) }
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// Statistics about a `KvStore`, as returned by `KvStore::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of live keys.
+    pub keys: usize,
+    /// The number of log files.
+    pub generations: usize,
+    /// Bytes of stale records that the next compaction would reclaim.
+    pub uncompacted_bytes: u64,
+    /// Bytes not written thanks to value deduplication since the store was
+    /// opened.
+    pub dedup_bytes_saved: u64,
+    /// Bytes of overwritten or removed values in the value log that
+    /// `KvStore::gc_value_log` would reclaim.
+    pub value_log_garbage_bytes: u64,
+    /// The metadata written by `KvStore::set_meta`.
+    pub meta: BTreeMap<String, String>,
+    /// The oldest time `KvStore::get_at` can answer for, or `None` without
+    /// `Config::with_keep_history`. Every compaction moves it to the time the
+    /// old log files were deleted. Second precision, like record timestamps.
+    pub history_horizon: Option<SystemTime>,
+}
+
+/// Space usage of one log file, as returned by `KvStore::generations`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerationInfo {
+    /// The generation number of the log file.
+    pub generation: u64,
+    /// The size of the log file in bytes.
+    pub file_size: u64,
+    /// Bytes of the records the index points at.
+    pub live_bytes: u64,
+    /// The rest of the file: overwritten records, tombstones, the file
+    /// header and padding. Compacting reclaims most of it.
+    pub dead_bytes: u64,
+}
+
+/// Whether `KvStore::compact_budget` has finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactStatus {
+    /// Every generation of the run has been compacted.
+    Done,
+    /// Some generations are still to be compacted by further calls.
+    MoreWork,
+}
+
+/// How far a compaction got, as passed to the callback of
+/// `KvStore::compact_with_progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionProgress {
+    /// The number of live records copied so far.
+    pub copied: usize,
+    /// The number of live records to copy.
+    pub total: usize,
+}
+
+/// What `KvStore::warm_up` read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WarmupStats {
+    /// The number of live records read.
+    pub records: usize,
+    /// Bytes read from the log and value log files.
+    pub bytes_read: u64,
+}
+
+/// Estimated memory used by a `KvStore`, as returned by
+/// `KvStore::approximate_memory`.
+///
+/// The numbers are estimates of heap bytes; allocator overhead is not counted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Bytes of the keys held by the index.
+    pub key_bytes: usize,
+    /// Bytes of the index entries: the key `String`s plus their `DataIndex`.
+    pub entry_bytes: usize,
+    /// Bytes the index structure needs on top of its entries, depending on
+    /// the `IndexKind` (see `KeyIndex::approximate_overhead`).
+    pub index_overhead_bytes: usize,
+    /// Bytes of the deduplication table (see `Config::with_dedup`).
+    pub dedup_bytes: usize,
+    /// Bytes of write buffers, e.g. the aligned buffers of direct I/O.
+    pub buffer_bytes: usize,
+}
+
+impl MemoryUsage {
+    /// The sum of all parts.
+    pub fn total(&self) -> usize {
+        self.key_bytes + self.entry_bytes + self.index_overhead_bytes + self.dedup_bytes + self.buffer_bytes
+    }
+}
+
+/// What `KvStore::retain` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetainStats {
+    /// The number of entries kept.
+    pub kept: usize,
+    /// The number of entries removed.
+    pub removed: usize,
+}
+
+/// What `KvStore::remove_all` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RemoveReport {
+    /// The number of keys that existed and were removed.
+    pub removed: usize,
+    /// The number of keys that didn't exist, counting repeated keys after
+    /// their first removal.
+    pub missing: usize,
+}
@@ -0,0 +1,95 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use io_uring::{opcode, types, IoUring};
+
+/// 一次最多提交多少个读请求
+const RING_ENTRIES: u32 = 256;
+
+/// 一个要读的位置: 文件, 偏移, 长度
+pub(crate) struct ReadAt<'a> {
+    pub(crate) file: &'a File,
+    pub(crate) pos: u64,
+    pub(crate) len: u32,
+}
+
+/// 用io_uring批量提交定位读
+///
+/// 对外还是同步接口, ring只是传输方式: 提交一批请求, 等它们全部完成再返回.
+pub(crate) struct Ring {
+    ring: IoUring,
+}
+
+impl Ring {
+    /// 内核不支持io_uring(或者被seccomp禁掉了)时返回错误, 调用方退回普通的读
+    pub(crate) fn new() -> io::Result<Self> {
+        Ok(Ring {
+            ring: IoUring::new(RING_ENTRIES)?,
+        })
+    }
+
+    /// 读出所有请求的数据, 结果和请求的顺序一致
+    ///
+    /// 外层的错误是ring本身出错, 单个请求读失败放在对应的结果里.
+    pub(crate) fn read_batch(&mut self, reqs: &[ReadAt<'_>]) -> io::Result<Vec<io::Result<Vec<u8>>>> {
+        let mut bufs: Vec<Vec<u8>> = reqs.iter().map(|r| vec![0; r.len as usize]).collect();
+        // 每个请求已经读到的字节数
+        let mut done = vec![0usize; reqs.len()];
+        let mut errors: Vec<Option<io::Error>> = reqs.iter().map(|_| None).collect();
+
+        let mut pending: Vec<usize> = (0..reqs.len()).collect();
+        while !pending.is_empty() {
+            let batch: Vec<usize> = pending
+                .drain(..pending.len().min(RING_ENTRIES as usize))
+                .collect();
+            for &i in &batch {
+                let buf = &mut bufs[i][done[i]..];
+                let entry = opcode::Read::new(
+                    types::Fd(reqs[i].file.as_raw_fd()),
+                    buf.as_mut_ptr(),
+                    buf.len() as _,
+                )
+                .offset(reqs[i].pos + done[i] as u64)
+                .build()
+                .user_data(i as u64);
+                // batch不超过ring的大小, 一定放得下
+                unsafe { self.ring.submission().push(&entry) }
+                    .map_err(io::Error::other)?;
+            }
+            self.ring.submit_and_wait(batch.len())?;
+
+            let mut completed = 0;
+            while completed < batch.len() {
+                let cqes: Vec<_> = self.ring.completion().collect();
+                if cqes.is_empty() {
+                    self.ring.submit_and_wait(batch.len() - completed)?;
+                    continue;
+                }
+                for cqe in cqes {
+                    completed += 1;
+                    let i = cqe.user_data() as usize;
+                    match cqe.result() {
+                        n if n < 0 => errors[i] = Some(io::Error::from_raw_os_error(-n)),
+                        0 => errors[i] = Some(io::ErrorKind::UnexpectedEof.into()),
+                        n => {
+                            done[i] += n as usize;
+                            // 读短了, 剩下的下一轮再读
+                            if done[i] < bufs[i].len() {
+                                pending.push(i);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(bufs
+            .into_iter()
+            .zip(errors)
+            .map(|(buf, e)| match e {
+                Some(e) => Err(e),
+                None => Ok(buf),
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,28 @@
+use std::io;
+
+/// value log文件的扩展名
+pub(crate) const VALUE_LOG_EXT: &str = "vlog";
+
+/// 指针记录的value: value log的generation, 位置和整条记录的长度
+pub(crate) const POINTER_SIZE: usize = 20;
+
+pub(crate) fn encode_pointer(n: u64, pos: u64, len: u32) -> [u8; POINTER_SIZE] {
+    let mut buf = [0; POINTER_SIZE];
+    buf[..8].copy_from_slice(&n.to_le_bytes());
+    buf[8..16].copy_from_slice(&pos.to_le_bytes());
+    buf[16..].copy_from_slice(&len.to_le_bytes());
+    buf
+}
+
+pub(crate) fn decode_pointer(v: &[u8]) -> io::Result<(u64, u64, u32)> {
+    if v.len() != POINTER_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed value log pointer"));
+    }
+    let mut n = [0; 8];
+    let mut pos = [0; 8];
+    let mut len = [0; 4];
+    n.copy_from_slice(&v[..8]);
+    pos.copy_from_slice(&v[8..16]);
+    len.copy_from_slice(&v[16..]);
+    Ok((u64::from_le_bytes(n), u64::from_le_bytes(pos), u32::from_le_bytes(len)))
+}
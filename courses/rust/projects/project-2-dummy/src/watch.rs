@@ -0,0 +1,106 @@
+use std::cell::RefCell;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::thread;
+
+/// 每个`on_evict`回调最多攒着这么多没处理的事件, 再多的丢掉
+pub(crate) const EVICT_QUEUE_LEN: usize = 1024;
+
+/// A change to a key of a `KvStore`, as received from `KvStore::subscribe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    /// The key was set to a new value.
+    Set {
+        /// The key that was set.
+        key: String,
+    },
+    /// The key was removed.
+    Remove {
+        /// The key that was removed.
+        key: String,
+    },
+}
+
+/// Why a `KvStore` dropped a live entry on its own, as passed to the
+/// callbacks of `KvStore::on_evict`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictReason {
+    /// The entry's time to live (see `KvStore::set_with_ttl`) ran out.
+    Expired,
+    /// A compaction filter decided not to keep the entry. The store has no
+    /// compaction filters yet, so this isn't reported for now.
+    FilteredByCompaction,
+    /// A retention policy purged the entry. The store has no retention
+    /// policies yet (`KvStore::retain` removes entries explicitly), so this
+    /// isn't reported for now.
+    RetentionPolicy,
+}
+
+/// 注册了的`on_evict`回调, 每个在自己的线程里跑
+///
+/// 发送不等回调: channel满了就丢掉事件, 记在`dropped`里, 所以慢的回调不会拖住store.
+/// 线程退出了(回调panic)的channel顺便删掉.
+#[derive(Default)]
+pub(crate) struct Evictions {
+    senders: Vec<SyncSender<(String, EvictReason)>>,
+    dropped: u64,
+}
+
+impl Evictions {
+    pub(crate) fn register(&mut self, mut f: impl FnMut(&str, EvictReason) + Send + 'static) {
+        let (tx, rx) = mpsc::sync_channel::<(String, EvictReason)>(EVICT_QUEUE_LEN);
+        // store drop了, channel关掉, 处理完剩下的事件线程就退出
+        thread::spawn(move || {
+            for (key, reason) in rx {
+                f(&key, reason);
+            }
+        });
+        self.senders.push(tx);
+    }
+
+    /// 没有回调的时候不调用`key`, 不用为事件分配key
+    pub(crate) fn notify(&mut self, key: impl FnOnce() -> String, reason: EvictReason) {
+        if self.senders.is_empty() {
+            return;
+        }
+        let key = key();
+        let dropped = &mut self.dropped;
+        self.senders.retain(|tx| match tx.try_send((key.clone(), reason)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                *dropped += 1;
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// 订阅了变化的channel
+///
+/// `subscribe`只拿到`&self`, 所以放在RefCell里. 发送失败说明receiver已经drop了, 顺便删掉.
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    senders: RefCell<Vec<Sender<ChangeEvent>>>,
+}
+
+impl Subscribers {
+    pub(crate) fn subscribe(&self) -> Receiver<ChangeEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.senders.borrow_mut().push(tx);
+        rx
+    }
+
+    /// 没有订阅的时候不调用`event`, 不用为事件分配key
+    pub(crate) fn notify(&self, event: impl FnOnce() -> ChangeEvent) {
+        let mut senders = self.senders.borrow_mut();
+        if senders.is_empty() {
+            return;
+        }
+        let event = event();
+        senders.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,109 @@
+// Counts heap allocations and live heap bytes, so it lives in its own test binary: a global
+// allocator would otherwise apply to every test in `tests.rs`.
+// The io_uring backend allocates its read buffers differently, so it is skipped there.
+#![cfg(not(feature = "uring"))]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use kvs::{Config, IndexKind, KvStore, Result};
+use tempfile::TempDir;
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    static LIVE_BYTES: Cell<isize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        LIVE_BYTES.with(|n| n.set(n.get() + layout.size() as isize));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE_BYTES.with(|n| n.set(n.get() - layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (result, ALLOCATIONS.with(Cell::get) - before)
+}
+
+// `get_ref` should not allocate for the key; only the returned value is allocated.
+#[test]
+fn get_ref_does_not_allocate_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    // The first read of a generation opens its file.
+    store.get_ref("key1")?;
+
+    let (value, n) = allocations(|| store.get_ref("key2"));
+    assert_eq!(value?, None);
+    assert_eq!(n, 0);
+
+    let (value, n) = allocations(|| store.get_ref("key1"));
+    assert_eq!(value?, Some("value1".to_owned()));
+    assert_eq!(n, 1);
+
+    store.remove_ref("key1")?;
+    assert_eq!(store.get_ref("key1")?, None);
+    Ok(())
+}
+
+// Replaying records of keys that are already in the index should not allocate
+// per record.
+#[test]
+fn open_does_not_allocate_per_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10_000 {
+        store.set(format!("key{}", i % 10), "value".to_owned())?;
+    }
+    drop(store);
+
+    let (store, n) = allocations(|| KvStore::open(temp_dir.path()));
+    assert_eq!(store?.stats().keys, 10);
+    assert!(n < 1000, "{} allocations", n);
+    Ok(())
+}
+
+// `approximate_memory` should be within 20% of the heap actually held by a
+// freshly opened store, for both index kinds.
+#[test]
+fn approximate_memory_tracks_heap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100_000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    drop(store);
+
+    for &kind in &[IndexKind::Ordered, IndexKind::Hash] {
+        let config = Config::default().with_index_kind(kind);
+        let before = LIVE_BYTES.with(Cell::get);
+        let store = KvStore::open_with_config(temp_dir.path(), config)?;
+        let held = (LIVE_BYTES.with(Cell::get) - before) as f64;
+
+        let usage = store.approximate_memory();
+        assert_eq!(usage.key_bytes, (0..100_000).map(|i| format!("key{}", i).len()).sum::<usize>());
+        let estimate = usage.total() as f64;
+        assert!(
+            (estimate - held).abs() < held * 0.2,
+            "{:?}: estimated {} bytes, held {}",
+            kind,
+            estimate,
+            held
+        );
+    }
+    Ok(())
+}
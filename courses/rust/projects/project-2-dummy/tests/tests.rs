@@ -1,8 +1,17 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    CasResult, ChangeEvent, CompactStatus, Config, DataIndex, EvictReason, Index, IndexKind, IoBackend, KeyIndex, KeyPattern, KvStore, KvsEngine, KvsError,
+    ManualClock, Op, RemoveReport, Result, RetainStats, ScanCursor, SharedKvStore, Stats, SyncPolicy, TtlState,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{Bound, ControlFlow};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +26,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +37,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +50,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +63,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -72,7 +81,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +89,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -100,7 +109,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +117,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +130,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +145,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +166,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,130 +181,3814 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
 
-// Should get previously stored value.
+// `kvs-inspect <DIR>` should print per-generation record counts without
+// touching the store.
 #[test]
-fn get_stored_value() -> Result<()> {
+fn cli_inspect() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
-
+    let config = Config::default().with_max_log_size(90);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let files = |path: &std::path::Path| -> Vec<_> {
+        let mut files: Vec<_> = std::fs::read_dir(path)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files(temp_dir.path());
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    // The tombstone fills up the first generation.
+    Command::cargo_bin("kvs-inspect")
+        .unwrap()
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(eq(concat!(
+            "generation=1 records=4 live=1 stale=2 tombstones=1 bytes=104\n",
+            "generation=2 records=1 live=1 stale=0 tombstones=0 bytes=32\n",
+            "total records=5 live=2 stale=2 tombstones=1 bytes=136\n",
+        )));
+    assert_eq!(files(temp_dir.path()), before);
+    Ok(())
+}
 
-    // Open from disk again and check persistent data.
-    drop(store);
+// `kvs stats` should print the statistics and the metadata of the store.
+#[test]
+fn cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_meta("owner", "tests")?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys=1"))
+        .stdout(contains("meta.owner=tests"));
+
+    Ok(())
+}
+
+// The standard tests run against both layouts: values inline in the log, and
+// every value in the value log.
+fn layouts() -> Vec<Config> {
+    vec![
+        Config::default(),
+        Config::default().with_value_log(true).with_value_log_threshold(0),
+    ]
+}
 
+// Should get previously stored value.
+#[test]
+fn get_stored_value() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        // Open from disk again and check persistent data.
+        drop(store);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    }
     Ok(())
 }
 
 // Should overwrite existent value.
 #[test]
 fn overwrite_value() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+        // Open from disk again and check persistent data.
+        drop(store);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    }
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key.
+#[test]
+fn get_non_existent_value() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        // Open from disk again and check persistent data.
+        drop(store);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+    }
+    Ok(())
+}
+
+#[test]
+fn remove_non_existent_key() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert!(store.remove("key1".to_owned()).is_err());
+    }
+    Ok(())
+}
+
+#[test]
+fn remove_key() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert!(store.remove("key1".to_owned()).is_ok());
+        assert_eq!(store.get("key1".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    'layouts: for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+
+        let dir_size = || {
+            let entries = WalkDir::new(temp_dir.path()).into_iter();
+            let len: walkdir::Result<u64> = entries
+                .map(|res| {
+                    res.and_then(|entry| entry.metadata())
+                        .map(|metadata| metadata.len())
+                })
+                .sum();
+            len.expect("fail to get directory size")
+        };
+
+        let mut current_size = dir_size();
+        for iter in 0..1000 {
+            for key_id in 0..1000 {
+                let key = format!("key{}", key_id);
+                let value = format!("{}", iter);
+                store.set(key, value)?;
+            }
+
+            let new_size = dir_size();
+            if new_size > current_size {
+                current_size = new_size;
+                continue;
+            }
+            // Compaction triggered.
+
+            drop(store);
+            // reopen and check content.
+            let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+            for key_id in 0..1000 {
+                let key = format!("key{}", key_id);
+                assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            }
+            continue 'layouts;
+        }
+
+        panic!("No compaction detected");
+    }
+    Ok(())
+}
+
+// `compact_to` should write a smaller copy with the same keys and leave the source untouched.
+#[test]
+fn compact_to_another_dir() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let src = temp_dir.path().join("src");
+    let dest = temp_dir.path().join("dest");
+    let mut store = KvStore::open(&src)?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let dir_size = |path: &std::path::Path| -> u64 {
+        WalkDir::new(path)
+            .into_iter()
+            .map(|res| res.and_then(|entry| entry.metadata()).unwrap().len())
+            .sum()
+    };
+    let src_size = dir_size(&src);
+    store.compact_to(&dest)?;
+    assert_eq!(dir_size(&src), src_size);
+    assert!(dir_size(&dest) < src_size);
+    // The new log is renamed into place, so no temporary file is left behind.
+    assert!(WalkDir::new(&dest).into_iter().all(|entry| entry.unwrap().path().extension() != Some("tmp".as_ref())));
+
+    let mut copy = KvStore::open(&dest)?;
+    for key_id in 0..10 {
+        assert_eq!(copy.get(format!("key{}", key_id))?, None);
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    for key_id in 10..100 {
+        assert_eq!(copy.get(format!("key{}", key_id))?, Some("value9".to_owned()));
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value9".to_owned()));
+    }
+
+    // Compacting into a directory with logs would mix two stores.
+    assert!(store.compact_to(&dest).is_err());
+
+    Ok(())
+}
 
-    // Open from disk again and check persistent data.
+// Removed keys should stay removed after the process is killed without a clean shutdown.
+#[test]
+fn remove_survives_crash() -> Result<()> {
+    const DIR_VAR: &str = "KVS_CRASH_TEST_DIR";
+
+    // Child process: remove the keys, then die without running destructors.
+    if let Ok(dir) = std::env::var(DIR_VAR) {
+        let config = Config::default().with_sync_policy(SyncPolicy::OnEveryWrite);
+        let mut store = KvStore::open_with_config(dir, config)?;
+        for key_id in 0..5 {
+            store.remove(format!("key{}", key_id))?;
+        }
+        std::process::abort();
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
     drop(store);
+
+    let status = Command::new(std::env::current_exe()?)
+        .args(["remove_survives_crash", "--exact", "--test-threads=1"])
+        .env(DIR_VAR, temp_dir.path())
+        .status()?;
+    assert!(!status.success());
+
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    for key_id in 0..5 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    for key_id in 5..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value".to_owned()));
+    }
+    Ok(())
+}
+
+// Under `SyncPolicy::OnEveryWrite` every acknowledged `set` should survive the
+// process being killed, even with a write buffer and across roll-overs to new
+// log files.
+#[test]
+fn durable_set_survives_crash() -> Result<()> {
+    const DIR_VAR: &str = "KVS_DURABLE_TEST_DIR";
+
+    let config = Config::default()
+        .with_sync_policy(SyncPolicy::OnEveryWrite)
+        .with_write_buffer_size(64 * 1024)
+        .with_max_log_size(256);
+    // Child process: write the keys, then die without running destructors.
+    if let Ok(dir) = std::env::var(DIR_VAR) {
+        let mut store = KvStore::open_with_config(dir, config)?;
+        for key_id in 0..50 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        std::process::abort();
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let status = Command::new(std::env::current_exe()?)
+        .args(["durable_set_survives_crash", "--exact", "--test-threads=1"])
+        .env(DIR_VAR, temp_dir.path())
+        .status()?;
+    assert!(!status.success());
 
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert!(store.stats().generations > 1);
+    for key_id in 0..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
     Ok(())
 }
 
-// Should get `None` when getting a non-existent key.
+// A compaction killed before its new log is renamed into place should leave
+// only a temporary file, which `open` deletes, and the store as it was before.
 #[test]
-fn get_non_existent_value() -> Result<()> {
+fn interrupted_compaction() -> Result<()> {
+    const DIR_VAR: &str = "KVS_COMPACTION_TEST_DIR";
+
+    // Child process: die in the middle of copying the live records.
+    if let Ok(dir) = std::env::var(DIR_VAR) {
+        let mut store = KvStore::open(dir)?;
+        store.compact_with_progress(&mut |progress| {
+            if progress.copied < progress.total {
+                std::process::abort();
+            }
+        })?;
+        return Ok(());
+    }
+
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let generations = store.stats().generations;
+    drop(store);
+
+    let status = Command::new(std::env::current_exe()?)
+        .args(["interrupted_compaction", "--exact", "--test-threads=1"])
+        .env(DIR_VAR, temp_dir.path())
+        .status()?;
+    assert!(!status.success());
+    let files = || -> Vec<_> {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect()
+    };
+    assert!(files().iter().any(|name| name.ends_with(".log.tmp")));
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!files().iter().any(|name| name.ends_with(".tmp")));
+    assert_eq!(store.stats().generations, generations);
+    assert_eq!(store.len(), 1000);
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    Ok(())
+}
+
+// A `get` right after a `remove` must not see the old value.
+#[test]
+fn get_after_remove_with_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_sync_policy(SyncPolicy::OnEveryWrite);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
 
-    // Open from disk again and check persistent data.
     drop(store);
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// `open` should drop a partial record at the end of the log, left by a `set`
+// interrupted after its header, keep every record before it, and append new
+// records after the last complete one.
+#[test]
+fn partial_record_recovery() -> Result<()> {
+    use std::io::Write;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&1u64.to_le_bytes());
+    header.extend_from_slice(&4u32.to_le_bytes());
+    header.extend_from_slice(&1000u32.to_le_bytes());
+    let mut expiring = header.clone();
+    expiring[11] = 32;
+    let partials = [
+        header[..10].to_vec(),
+        [&header[..], b"key9", &[b'v'; 500][..]].concat(),
+        // a value that should be followed by its expiry
+        [&expiring[..12], &1u32.to_le_bytes(), b"key9", b"v"].concat(),
+    ];
+    for partial in &partials {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+        let log = temp_dir.path().join("1.log");
+        let len = std::fs::metadata(&log)?.len();
+        std::fs::OpenOptions::new().append(true).open(&log)?.write_all(partial)?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(std::fs::metadata(&log)?.len(), len);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key9".to_owned())?, None);
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
 
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.len(), 3);
+    }
     Ok(())
 }
 
+// A `set` that fails halfway through its record should cut the log back to
+// where the record started, so later writes and the next open work normally.
+// The file size limit of the shell makes writes past 2 KiB fail.
+#[cfg(unix)]
 #[test]
-fn remove_non_existent_key() -> Result<()> {
+fn cli_set_rolls_back_failed_write() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&log)?.len();
+
+    Command::new("sh")
+        .args(["-c", "trap '' XFSZ; ulimit -f 4; exec \"$0\" set key2 \"$1\""])
+        .arg(env!("CARGO_BIN_EXE_kvs"))
+        .arg("v".repeat(8000))
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert_eq!(std::fs::metadata(&log)?.len(), len);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
     Ok(())
 }
 
+// `close` should return the error of flushing the buffered records, which
+// dropping the store would swallow. The test runs itself again under a file
+// size limit, so the flush fails when it goes past 2 KiB.
+#[cfg(unix)]
 #[test]
-fn remove_key() -> Result<()> {
+fn close_reports_flush_error() -> Result<()> {
+    const DIR_VAR: &str = "KVS_CLOSE_TEST_DIR";
+
+    // Child process: buffer a record too large for the limit, then close.
+    if let Ok(dir) = std::env::var(DIR_VAR) {
+        let config = Config::default().with_write_buffer_size(64 * 1024);
+        let mut store = KvStore::open_with_config(dir, config)?;
+        store.set("key2".to_owned(), "v".repeat(8000))?;
+        match store.close() {
+            Err(KvsError::Io(source)) | Err(KvsError::IoAt { source, .. }) => {
+                assert_eq!(source.kind(), std::io::ErrorKind::FileTooLarge)
+            }
+            other => panic!("expected a flush error, got {:?}", other),
+        }
+        return Ok(());
+    }
+
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+    Command::new("sh")
+        .args(["-c", "trap '' XFSZ; ulimit -f 4; exec \"$0\" close_reports_flush_error --exact --test-threads=1"])
+        .arg(std::env::current_exe()?)
+        .env(DIR_VAR, temp_dir.path())
+        .assert()
+        .success();
+
+    // The part of the record that made it to disk is dropped on open.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.close()?;
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// `open` should fail on a corrupt record in the middle of the log instead of
+// treating it as a torn tail and cutting off the records after it.
 #[test]
-fn compaction() -> Result<()> {
+fn corrupt_record_fails_open() -> Result<()> {
+    use std::io::Write;
+
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&log)?.len();
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
-    };
+    // A TTL record whose value is too short to hold an expiry time, followed
+    // by a complete record.
+    let mut corrupt = Vec::new();
+    corrupt.extend_from_slice(&1u64.to_le_bytes());
+    corrupt.extend_from_slice(&(4u32 | 64 << 24).to_le_bytes());
+    corrupt.extend_from_slice(&4u32.to_le_bytes());
+    corrupt.extend_from_slice(b"key1abcd");
+    corrupt.extend_from_slice(&1u64.to_le_bytes());
+    corrupt.extend_from_slice(&4u32.to_le_bytes());
+    corrupt.extend_from_slice(&6u32.to_le_bytes());
+    corrupt.extend_from_slice(b"key2value2");
+    std::fs::OpenOptions::new().append(true).open(&log)?.write_all(&corrupt)?;
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::IoAt { generation, pos, .. }) => {
+            assert_eq!(generation, 1);
+            assert_eq!(pos, len);
         }
+        other => panic!("expected IoAt error, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(std::fs::metadata(&log)?.len(), len + corrupt.len() as u64);
+    Ok(())
+}
+
+// IO errors while reading a record should name the generation they happened in.
+#[test]
+fn read_error_names_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
 
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
+    // Cut the active log in the middle of the record header, which follows
+    // the 6 byte file header.
+    let log = std::fs::OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("1.log"))?;
+    log.set_len(10)?;
+
+    match store.get("key1".to_owned()) {
+        Err(err @ KvsError::IoAt { .. }) => {
+            assert!(err.to_string().contains("1.log"));
+            if let KvsError::IoAt { generation, pos, .. } = err {
+                assert_eq!(generation, 1);
+                assert_eq!(pos, 6);
+            }
         }
-        // Compaction triggered.
+        other => panic!("expected IoAt error, got {:?}", other),
+    }
+    Ok(())
+}
 
-        drop(store);
-        // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+// `get` should report a log file deleted while the store is running as a
+// missing generation, instead of panicking or claiming the key is absent.
+#[test]
+fn get_from_deleted_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_max_log_size(64);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "v".repeat(64))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // `key1` filled the first log, so `key2` went to the next one. With a
+    // single open file the first log is closed again once `key2` is read.
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.with_max_open_files(1))?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    std::fs::remove_file(temp_dir.path().join("1.log"))?;
+
+    match store.get("key1".to_owned()) {
+        Err(err @ KvsError::MissingGeneration(1)) => {
+            assert!(err.to_string().contains("1.log"));
         }
-        return Ok(());
+        other => panic!("expected MissingGeneration error, got {:?}", other),
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A transaction applies all of its operations, or none of them.
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    store.transaction(vec![
+        Op::Set { key: "key2".to_owned(), value: "value2".to_owned() },
+        Op::Remove { key: "key1".to_owned() },
+        Op::Set { key: "key3".to_owned(), value: "value3".to_owned() },
+    ])?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // Removing a missing key aborts the whole batch.
+    let result = store.transaction(vec![
+        Op::Set { key: "key4".to_owned(), value: "value4".to_owned() },
+        Op::Remove { key: "key1".to_owned() },
+    ]);
+    assert!(matches!(result, Err(KvsError::KeyNotFound)));
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    Ok(())
+}
+
+// Reopening the store should keep appending to the newest generation instead
+// of creating one file per open.
+#[test]
+fn reopen_reuses_last_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for i in 0..100 {
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let log_files = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter(|entry| {
+            entry.as_ref().unwrap().path().extension() == Some("log".as_ref())
+        })
+        .count();
+    assert!(log_files <= 2, "found {} log files", log_files);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
+    // Keys written by different opens share the generation file.
+    let generation = store.index().get("key0").unwrap().generation();
+    assert_eq!(store.index().get("key99").unwrap().generation(), generation);
+    Ok(())
+}
 
-    panic!("No compaction detected");
+// Once the active log reaches `max_log_size` new writes go to a new generation.
+#[test]
+fn roll_over_to_new_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_max_log_size(256);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+    assert!(temp_dir.path().join("5.log").exists());
+
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// A store opened with a hash index behaves the same for point operations.
+#[test]
+fn hash_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_index_kind(IndexKind::Hash);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    drop(store);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Benchmark-style comparison of point lookups on both index kinds. Timings
+// vary too much between machines to assert on, so they are only printed
+// (run with `--nocapture`); the lookups themselves must all hit.
+#[test]
+fn index_lookup_throughput() -> Result<()> {
+    // `DataIndex` can't be built outside `kvs`, so borrow one from a real store.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let location = store.index().get("key").unwrap().clone();
+
+    let keys: Vec<String> = (0..100_000).map(|i| format!("key{}", i)).collect();
+    for &kind in &[IndexKind::Ordered, IndexKind::Hash] {
+        let mut index = Index::with_kind(kind);
+        for key in &keys {
+            index.insert(key.clone(), location.clone());
+        }
+
+        let start = std::time::Instant::now();
+        let mut hits = 0;
+        for _ in 0..5 {
+            for key in &keys {
+                hits += index.get(key).is_some() as usize;
+            }
+        }
+        let elapsed = start.elapsed();
+        assert_eq!(hits, 5 * keys.len());
+        println!(
+            "{:?}: {:.0} lookups/s",
+            kind,
+            hits as f64 / elapsed.as_secs_f64().max(1e-9)
+        );
+    }
+    Ok(())
+}
+
+// The basic semantics shared by every `KvsEngine`.
+fn engine_semantics<E: KvsEngine>(store: &mut E) -> Result<()> {
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(store.contains_key("key1"));
+    assert!(!store.contains_key("key3"));
+
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!store.contains_key("key1"));
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::KeyNotFound)));
+
+    // Setting a removed key brings it back.
+    store.set("key1".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+
+    assert!(!store.set_if_absent("key1".to_owned(), "value5".to_owned())?);
+    assert!(store.set_if_absent("key4".to_owned(), "value5".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value5".to_owned()));
+
+    assert_eq!(store.incr("key5".to_owned())?, 1);
+    assert_eq!(store.incr_by("key5".to_owned(), -3)?, -2);
+    assert_eq!(store.get("key5".to_owned())?, Some("-2".to_owned()));
+    assert!(matches!(store.incr("key4".to_owned()), Err(KvsError::NotAnInteger(key)) if key == "key4"));
+    store.set("key5".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(store.incr("key5".to_owned()), Err(KvsError::IntegerOverflow(_))));
+    assert_eq!(store.get("key5".to_owned())?, Some(i64::MAX.to_string()));
+    store.remove("key5".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn in_memory_store() -> Result<()> {
+    let mut store = KvStore::open_in_memory();
+    engine_semantics(&mut store)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    engine_semantics(&mut store)
+}
+
+// The same store semantics hold for every `KeyIndex`, across reopen and compaction.
+fn index_semantics<I: KeyIndex>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::<I>::open_with_index(temp_dir.path(), Config::default())?;
+    engine_semantics(&mut store)?;
+    store.set("key2".to_owned(), "value5".to_owned())?;
+    store.compact()?;
+
+    drop(store);
+    let mut store = KvStore::<I>::open_with_index(temp_dir.path(), Config::default())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value5".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert!(store.contains_key("key2"));
+    assert!(!store.contains_key("key3"));
+    Ok(())
+}
+
+#[test]
+fn pluggable_index() -> Result<()> {
+    index_semantics::<BTreeMap<String, DataIndex>>()?;
+    index_semantics::<HashMap<String, DataIndex>>()?;
+    index_semantics::<Index>()
+}
+
+// Only ordered indexes support range iteration.
+#[test]
+fn index_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &["a", "b", "c", "d"] {
+        store.set((*key).to_owned(), "value".to_owned())?;
+    }
+    drop(store);
+
+    let store = KvStore::<BTreeMap<String, DataIndex>>::open_with_index(
+        temp_dir.path(),
+        Config::default(),
+    )?;
+    // The inherent `BTreeMap::range` shadows the trait method.
+    let range = (Bound::Included("b"), Bound::Excluded("d"));
+    let keys: Vec<_> = KeyIndex::range(store.index(), range)?
+        .map(|(k, _)| k.to_owned())
+        .collect();
+    assert_eq!(keys, vec!["b", "c"]);
+    assert_eq!(KeyIndex::len(store.index()), 4);
+    drop(store);
+
+    let store =
+        KvStore::<HashMap<String, DataIndex>>::open_with_index(temp_dir.path(), Config::default())?;
+    assert!(matches!(
+        store.index().range((Bound::Unbounded, Bound::Unbounded)),
+        Err(KvsError::UnsupportedOperation(_))
+    ));
+    Ok(())
+}
+
+// `scan_unordered` should yield every live pair exactly once, skipping overwritten and removed keys.
+#[test]
+fn scan_unordered() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_max_log_size(256);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    for key_id in 90..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(temp_dir.path().join("5.log").exists());
+
+    let mut pairs = store.scan_unordered().collect::<Result<Vec<_>>>()?;
+    pairs.sort();
+    let mut expected: Vec<_> = (0..90)
+        .map(|key_id| {
+            let value = if key_id < 50 { "new" } else { "value" };
+            (format!("key{}", key_id), format!("{}{}", value, key_id))
+        })
+        .collect();
+    expected.sort();
+    assert_eq!(pairs, expected);
+    Ok(())
+}
+
+// The standard store semantics for a given `Config`: point operations, reopen,
+// rollover and compaction.
+fn store_semantics(config: Config) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = config.with_max_log_size(8 * 1024);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    engine_semantics(&mut store)?;
+
+    for iter in 0..3 {
+        for key_id in 0..500 {
+            store.set(format!("key{}", key_id), format!("value{}-{}", key_id, iter))?;
+        }
+        drop(store);
+        store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    }
+    for key_id in 0..250 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "again".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("again".to_owned()));
+    for key_id in 1..250 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    for key_id in 250..500 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}-2", key_id)));
+    }
+    Ok(())
+}
+
+#[test]
+fn buffered_io() -> Result<()> {
+    store_semantics(Config::default())
+}
+
+// With `O_DIRECT` the store behaves the same, and the files it writes are block aligned.
+#[cfg(target_os = "linux")]
+#[test]
+fn direct_io() -> Result<()> {
+    store_semantics(Config::default().with_direct_io(true))?;
+    store_semantics(
+        Config::default()
+            .with_direct_io(true)
+            .with_sync_policy(SyncPolicy::OnEveryWrite),
+    )?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_direct_io(true);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let len = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert_eq!(len % 4096, 0);
+
+    drop(store);
+    // Reopening with buffered I/O skips the padding as well.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `log_cursor` should yield every record in write order, tombstones included.
+#[test]
+fn log_cursor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+
+    let records = store.log_cursor().collect::<Result<Vec<_>>>()?;
+    let records: Vec<_> = records.into_iter().map(|r| (r.key, r.value)).collect();
+    assert_eq!(
+        records,
+        vec![
+            (b"key1".to_vec(), Some(b"value1".to_vec())),
+            (b"key1".to_vec(), None),
+            (b"key1".to_vec(), Some(b"value2".to_vec())),
+        ]
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// `multi_get` should return one result per key, in order, with every I/O backend.
+#[test]
+fn multi_get() -> Result<()> {
+    let backends = [
+        IoBackend::Standard,
+        #[cfg(feature = "uring")]
+        IoBackend::Uring,
+        #[cfg(feature = "mmap")]
+        IoBackend::Mmap,
+    ];
+
+    for backend in backends {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = Config::default()
+            .with_io_backend(backend)
+            .with_max_log_size(1024);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        for key_id in 0..300 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key7".to_owned())?;
+
+        let keys: Vec<String> = (0..310).map(|key_id| format!("key{}", key_id)).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let values = store.multi_get(&keys)?;
+        assert_eq!(values.len(), keys.len());
+        for (key_id, value) in values.into_iter().enumerate() {
+            let expected = if key_id < 300 && key_id != 7 {
+                Some(format!("value{}", key_id))
+            } else {
+                None
+            };
+            assert_eq!(value, expected);
+        }
+
+        store.compact()?;
+        assert_eq!(store.get("key299".to_owned())?, Some("value299".to_owned()));
+    }
+    Ok(())
+}
+
+// `set_if_absent` should only write keys that don't exist.
+#[test]
+fn set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let log_size = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(std::fs::metadata(temp_dir.path().join("1.log"))?.len(), log_size);
+
+    // A removed key can be set again.
+    store.remove("key1".to_owned())?;
+    assert!(store.set_if_absent("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// `get_bytes` should return the same data as `get`.
+#[cfg(feature = "bytes")]
+#[test]
+fn get_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "x".repeat(4096))?;
+
+    assert_eq!(store.get_bytes("key1")?.as_deref(), Some(&b"value1"[..]));
+    assert_eq!(store.get_bytes("key2")?.as_deref(), Some("x".repeat(4096).as_bytes()));
+    assert_eq!(store.get_bytes("key3")?, None);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get_bytes("key1")?, None);
+    Ok(())
+}
+
+/// Produces `left` bytes of a repeating pattern without holding them in memory.
+struct Pattern {
+    offset: u64,
+    left: u64,
+}
+
+impl std::io::Read for Pattern {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = buf.len().min(self.left as usize);
+        for b in &mut buf[..n] {
+            *b = (self.offset % 251) as u8;
+            self.offset += 1;
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Checks that `r` yields exactly `len` bytes of `Pattern`, 64 KB at a time.
+fn check_pattern(mut r: impl std::io::Read, len: u64) {
+    let mut expected = Pattern { offset: 0, left: len };
+    let mut buf = vec![0; 64 * 1024];
+    let mut want = vec![0; 64 * 1024];
+    let mut total = 0;
+    loop {
+        let n = r.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        std::io::Read::read_exact(&mut expected, &mut want[..n]).unwrap();
+        assert_eq!(buf[..n], want[..n]);
+        total += n as u64;
+    }
+    assert_eq!(total, len);
+}
+
+// `keys` should list the live keys in ascending order with every index kind.
+#[test]
+fn keys() -> Result<()> {
+    for kind in [IndexKind::Ordered, IndexKind::Hash] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = Config::default().with_index_kind(kind);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.keys().count(), 0);
+        for key in ["key3", "key1", "key2", "key4"] {
+            store.set(key.to_owned(), "value".to_owned())?;
+        }
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        store.remove("key4".to_owned())?;
+
+        let keys: Vec<&str> = store.keys().collect();
+        assert_eq!(keys, ["key1", "key2", "key3"]);
+        // The keys can be collected first and then used to write.
+        let keys: Vec<String> = store.keys().map(str::to_owned).collect();
+        for key in keys {
+            store.remove(key)?;
+        }
+        assert!(store.is_empty());
+    }
+    Ok(())
+}
+
+// `iter` should yield the live pairs in key order and report a failed read
+// as an error for that pair only.
+#[test]
+fn iter() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        // Every record fills up its own generation.
+        let config = config.with_max_log_size(30);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        store.set("key4".to_owned(), "value4".to_owned())?;
+        store.set("key3".to_owned(), "value5".to_owned())?;
+        store.remove("key4".to_owned())?;
+
+        let pairs: Vec<_> = store.iter().collect::<Result<_>>()?;
+        assert_eq!(
+            pairs,
+            [
+                ("key1".to_owned(), "value1".to_owned()),
+                ("key2".to_owned(), "value2".to_owned()),
+                ("key3".to_owned(), "value5".to_owned()),
+            ]
+        );
+
+        // Cut the record of `key2` in the first generation.
+        let log = std::fs::OpenOptions::new()
+            .write(true)
+            .open(temp_dir.path().join("1.log"))?;
+        log.set_len(10)?;
+        let results: Vec<_> = store.iter().collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().0, "key1");
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap().0, "key3");
+    }
+    Ok(())
+}
+
+// `generations` should count the records the index points at as live bytes
+// of their generation, and the rest of each file as dead.
+#[test]
+fn generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_max_log_size(100).with_auto_compaction(false);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    for round in 0..3 {
+        for key_id in 0..5 {
+            store.set(format!("key{}", key_id), format!("value{}", round).repeat(key_id + 1))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let mut expected = BTreeMap::new();
+    for key_id in 1..5 {
+        let key = format!("key{}", key_id);
+        let generation = store.index().get(&key).unwrap().generation();
+        // Header, key and value of the record.
+        *expected.entry(generation).or_insert(0) += 16 + key.len() as u64 + 6 * (key_id as u64 + 1);
+    }
+
+    let infos = store.generations()?;
+    assert!(infos.len() > 3);
+    for info in &infos {
+        let path = temp_dir.path().join(format!("{}.log", info.generation));
+        assert_eq!(info.file_size, std::fs::metadata(path)?.len());
+        assert_eq!(info.live_bytes, expected.get(&info.generation).cloned().unwrap_or(0));
+        assert_eq!(info.live_bytes + info.dead_bytes, info.file_size);
+    }
+
+    // After compaction only the file headers are dead.
+    store.compact()?;
+    let infos = store.generations()?;
+    let live: u64 = infos.iter().map(|info| info.live_bytes).sum();
+    assert_eq!(live, expected.values().sum::<u64>());
+    assert!(infos.iter().all(|info| info.dead_bytes == 6));
+    Ok(())
+}
+
+// `range` should yield the same pairs as `BTreeMap::range` for every
+// combination of bounds, and panic where it panics.
+#[test]
+fn range() -> Result<()> {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut model = BTreeMap::new();
+    for key_id in (0..10).step_by(2) {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        model.insert(format!("key{}", key_id), format!("value{}", key_id));
+    }
+
+    // Stored keys, keys between them, and keys before and after all of them.
+    let points = ["", "key0", "key1", "key4", "key45", "key8", "zzz"];
+    let mut bounds = vec![Bound::Unbounded];
+    for point in &points {
+        bounds.push(Bound::Included(point.to_string()));
+        bounds.push(Bound::Excluded(point.to_string()));
+    }
+    for start in &bounds {
+        for end in &bounds {
+            let range = (start.clone(), end.clone());
+            let expected = catch_unwind(|| {
+                model.range(range.clone()).map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>()
+            });
+            let actual = catch_unwind(AssertUnwindSafe(|| {
+                store.range(range.clone()).unwrap().collect::<Result<Vec<_>>>().unwrap()
+            }));
+            match (expected, actual) {
+                (Ok(expected), Ok(actual)) => assert_eq!(actual, expected, "{:?}", range),
+                (Err(_), Err(_)) => {}
+                _ => panic!("range {:?} behaves differently", range),
+            }
+        }
+    }
+
+    // Unordered indexes can't scan ranges.
+    drop(store);
+    let config = Config::default().with_index_kind(IndexKind::Hash);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert!(matches!(store.range(..), Err(KvsError::UnsupportedOperation(_))));
+    Ok(())
+}
+
+// `iter` and `range` should also walk backwards, and from both ends at once.
+#[test]
+fn reverse_iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let key = |r: Option<Result<(String, String)>>| r.unwrap().unwrap().0;
+
+    let keys: Vec<_> = store.iter().rev().map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, ["key4", "key3", "key2", "key1", "key0"]);
+    let mut range = store.range("key1".to_owned()..="key3".to_owned())?.rev();
+    assert_eq!(range.next().unwrap()?, ("key3".to_owned(), "value3".to_owned()));
+    assert_eq!(key(range.next()), "key2");
+    assert_eq!(key(range.next()), "key1");
+    assert!(range.next().is_none());
+
+    let mut iter = store.iter();
+    assert_eq!(key(iter.next()), "key0");
+    assert_eq!(key(iter.next_back()), "key4");
+    assert_eq!(key(iter.next_back()), "key3");
+    assert_eq!(key(iter.next()), "key1");
+    assert_eq!(key(iter.next()), "key2");
+    assert!(iter.next().is_none());
+    assert!(iter.next_back().is_none());
+    Ok(())
+}
+
+// `{:?}` should print a summary of the store.
+#[test]
+fn debug_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let debug = format!("{:?}", store);
+    assert!(debug.starts_with("KvStore {"), "{}", debug);
+    assert!(debug.contains("keys: 2"), "{}", debug);
+    assert!(debug.contains("generations: 1"), "{}", debug);
+    assert!(!debug.contains("value1"), "{}", debug);
+    Ok(())
+}
+
+// `scan_prefix` should yield exactly the keys starting with the prefix.
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let keys = [
+        "tenant/1",
+        "tenant/1/object/1",
+        "tenant/1/object/2",
+        "tenant/10/object/1",
+        "tenant/2/object/1",
+        "\u{10FFFF}",
+        "\u{10FFFF}\u{10FFFF}a",
+    ];
+    for key in &keys {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    let mut scan = |prefix: &str| -> Result<Vec<String>> {
+        store.scan_prefix(prefix)?.map(|r| r.map(|(k, _)| k)).collect()
+    };
+
+    assert_eq!(scan("tenant/1/")?, ["tenant/1/object/1", "tenant/1/object/2"]);
+    // A prefix that is itself a key includes that key.
+    assert_eq!(scan("tenant/1/object/1")?, ["tenant/1/object/1"]);
+    assert_eq!(scan("tenant/1")?.len(), 4);
+    assert!(scan("tenant/3")?.is_empty());
+    // The empty prefix matches everything, a prefix without successor
+    // everything after it.
+    assert_eq!(scan("")?, keys);
+    assert_eq!(scan("\u{10FFFF}")?, ["\u{10FFFF}", "\u{10FFFF}\u{10FFFF}a"]);
+    assert_eq!(scan("\u{10FFFF}\u{10FFFF}")?, ["\u{10FFFF}\u{10FFFF}a"]);
+    Ok(())
+}
+
+// `rename` should move a value to a new key, overwriting an existing one.
+#[test]
+fn rename() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        store.rename("key1".to_owned(), "key3".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+
+        // An existing target is overwritten.
+        store.rename("key3".to_owned(), "key2".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.len(), 1);
+
+        store.rename("key2".to_owned(), "key2".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+        assert!(matches!(
+            store.rename("key1".to_owned(), "key4".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+        assert_eq!(store.get("key4".to_owned())?, None);
+        let stats = store.stats();
+
+        // Replaying the log should account for both displaced records the same way.
+        drop(store);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.stats(), stats);
+    }
+    Ok(())
+}
+
+// Cuts the log at every byte of the batch `write` appends, as a crash while
+// writing it would, and runs `check` on the reopened store.
+fn check_torn_batch(
+    prepare: impl Fn(&mut KvStore) -> Result<()>,
+    write: impl Fn(&mut KvStore) -> Result<()>,
+    check: impl Fn(&mut KvStore) -> Result<()>,
+) -> Result<()> {
+    for config in layouts() {
+        for cut in 1.. {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+            prepare(&mut store)?;
+            let log = temp_dir.path().join("1.log");
+            let before = std::fs::metadata(&log)?.len();
+            write(&mut store)?;
+            let after = std::fs::metadata(&log)?.len();
+            drop(store);
+            if before + cut > after {
+                break;
+            }
+            std::fs::OpenOptions::new().write(true).open(&log)?.set_len(after - cut)?;
+            let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+            check(&mut store)?;
+        }
+    }
+    Ok(())
+}
+
+// A crash in the middle of `rename` should leave the value under exactly one
+// of the keys.
+#[test]
+fn rename_torn_batch() -> Result<()> {
+    check_torn_batch(
+        |store| store.set("from".to_owned(), "value".to_owned()),
+        |store| store.rename("from".to_owned(), "to".to_owned()),
+        |store| {
+            assert_eq!(store.get("from".to_owned())?, Some("value".to_owned()));
+            assert_eq!(store.get("to".to_owned())?, None);
+            Ok(())
+        },
+    )
+}
+
+// `KeyPattern` should match whole keys byte by byte.
+#[test]
+fn key_pattern() -> Result<()> {
+    let cases: &[(&str, &[&str], &[&str])] = &[
+        ("user:*:settings", &["user:1:settings", "user::settings", "user:a:b:settings"], &["user:1:settings2", "xuser:1:settings"]),
+        ("a?c", &["abc", "a-c"], &["ac", "abbc"]),
+        ("[abc]x", &["ax", "cx"], &["dx", "x"]),
+        ("k[0-9][!0-9]", &["k1a", "k9-"], &["k11", "ka1"]),
+        ("[^a-c]*", &["d", "zzz"], &["abc", ""]),
+        ("[]]", &["]"], &["["]),
+        ("a[-x]", &["a-", "ax"], &["ab"]),
+        (r"\*\?", &["*?"], &["ab"]),
+        ("*a*b*", &["ab", "xaybz", "aab"], &["ba", "b"]),
+        ("**", &["", "anything"], &[]),
+        ("", &[""], &["a"]),
+        ("é?", &["éa"], &["éé"]),
+    ];
+    for (pattern, matching, other) in cases {
+        let compiled = KeyPattern::new(pattern)?;
+        for key in *matching {
+            assert!(compiled.matches(key.as_bytes()), "{} should match {}", pattern, key);
+        }
+        for key in *other {
+            assert!(!compiled.matches(key.as_bytes()), "{} should not match {}", pattern, key);
+        }
+    }
+    assert_eq!(KeyPattern::new(r"user\*:*")?.literal_prefix(), "user*:");
+    assert_eq!(KeyPattern::new("?x")?.literal_prefix(), "");
+
+    for pattern in &["[abc", r"abc\", "[z-a]"] {
+        assert!(matches!(KeyPattern::new(pattern), Err(KvsError::InvalidPattern(_))), "{}", pattern);
+    }
+    Ok(())
+}
+
+// `scan_match` should yield the pairs with keys matching the pattern.
+#[test]
+fn scan_match() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &["user:1:settings", "user:1:name", "user:22:settings", "users:settings", "other"] {
+        store.set(key.to_string(), format!("{}-value", key))?;
+    }
+    let pairs: Vec<_> = store.scan_match("user:*:settings")?.collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        [
+            ("user:1:settings".to_owned(), "user:1:settings-value".to_owned()),
+            ("user:22:settings".to_owned(), "user:22:settings-value".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan_match("*")?.count(), 5);
+    assert_eq!(store.scan_match("user?:*")?.count(), 1);
+    assert_eq!(store.scan_match("missing*")?.count(), 0);
+    assert!(matches!(store.scan_match("user:[1"), Err(KvsError::InvalidPattern(_))));
+    Ok(())
+}
+
+// `compact_with_progress` should report copied records until every live key
+// is copied.
+#[test]
+fn compaction_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 1000), format!("value{}", iter))?;
+    }
+
+    let mut calls = Vec::new();
+    store.compact_with_progress(&mut |progress| calls.push(progress))?;
+    assert!(calls.len() > 1);
+    assert!(calls.windows(2).all(|w| w[0].copied <= w[1].copied));
+    assert!(calls.iter().all(|p| p.total == 1000));
+    assert_eq!(calls.last().unwrap().copied, store.len());
+    assert_eq!(store.get("key999".to_owned())?, Some("value1999".to_owned()));
+    Ok(())
+}
+
+// `scan_page` should page through the keys, resuming after the cursor even
+// across reopen and changes between pages.
+#[test]
+fn scan_page() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..25 {
+        store.set(format!("key{:02}", key_id), format!("value{}", key_id))?;
+    }
+
+    let mut keys = Vec::new();
+    let mut cursor = None;
+    loop {
+        let (page, next) = store.scan_page(cursor, 10)?;
+        assert!(page.len() <= 10);
+        keys.extend(page.into_iter().map(|(k, _)| k));
+        cursor = next;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(keys, (0..25).map(|i| format!("key{:02}", i)).collect::<Vec<_>>());
+
+    // The cursor survives as a string, reopen, and the removal of its key.
+    let (page, cursor) = store.scan_page(None, 3)?;
+    assert_eq!(page[2], ("key02".to_owned(), "value2".to_owned()));
+    let token = cursor.unwrap().to_string();
+    store.remove("key02".to_owned())?;
+    store.remove("key03".to_owned())?;
+    store.set("key01a".to_owned(), "before".to_owned())?;
+    store.set("key02a".to_owned(), "after".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    let (page, _) = store.scan_page(Some(token.parse()?), 2)?;
+    assert_eq!(page, [("key02a".to_owned(), "after".to_owned()), ("key04".to_owned(), "value4".to_owned())]);
+
+    let (page, cursor) = store.scan_page(Some(token.parse()?), 0)?;
+    assert!(page.is_empty());
+    assert_eq!(cursor.unwrap().to_string(), token);
+    for bad in &["abc", "zz", "ff"] {
+        assert!(matches!(bad.parse::<ScanCursor>(), Err(KvsError::InvalidCursor(_))), "{}", bad);
+    }
+    Ok(())
+}
+
+// Reads should never fail while another thread compacts a `SharedKvStore`.
+#[test]
+fn shared_compact_under_reads() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = SharedKvStore::new(KvStore::open_with_config(temp_dir.path(), config.clone())?);
+        for round in 0..3 {
+            for i in 0..500 {
+                store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+            }
+        }
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4)
+            .map(|t| {
+                let store = store.clone();
+                let done = Arc::clone(&done);
+                thread::spawn(move || -> Result<usize> {
+                    let mut reads = 0;
+                    while !done.load(Ordering::SeqCst) || reads == 0 {
+                        let i = (reads * 7 + t) % 500;
+                        let value = store.get(format!("key{}", i))?;
+                        assert_eq!(value, Some(format!("value{}-2", i)));
+                        reads += 1;
+                    }
+                    Ok(reads)
+                })
+            })
+            .collect();
+        for _ in 0..20 {
+            store.compact()?;
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            assert!(reader.join().unwrap()? > 0);
+        }
+        assert_eq!(store.lock().stats().uncompacted_bytes, 0);
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.len(), 500);
+        assert_eq!(store.get("key499".to_owned())?, Some("value499-2".to_owned()));
+    }
+    Ok(())
+}
+
+// Writes made while a `SharedKvStore` compacts should win over the copied records.
+#[test]
+fn shared_compact_keeps_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(KvStore::open(temp_dir.path())?);
+    for i in 0..200 {
+        store.set(format!("key{}", i), "old".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..100 {
+                store.set(format!("key{}", i), "new".to_owned())?;
+                store.remove(format!("key{}", 100 + i))?;
+                store.set(format!("extra{}", i), "new".to_owned())?;
+            }
+            Ok(())
+        })
+    };
+    for _ in 0..5 {
+        store.compact()?;
+    }
+    writer.join().unwrap()?;
+    store.compact()?;
+    assert!(store.contains_key("key0"));
+    assert!(!store.contains_key("key100"));
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.len(), 200);
+        for i in 0..100 {
+            assert_eq!(store.get(format!("key{}", i))?.as_deref(), Some("new"));
+            assert_eq!(store.get(format!("key{}", 100 + i))?, None);
+            assert_eq!(store.get(format!("extra{}", i))?.as_deref(), Some("new"));
+        }
+        Ok(())
+    };
+    check(&mut store.lock())?;
+    drop(store);
+    check(&mut KvStore::open(temp_dir.path())?)
+}
+
+// `first_key`, `last_key` and the pops should follow key order and handle an empty store.
+#[test]
+fn pop_first_last() -> Result<()> {
+    for kind in &[IndexKind::Ordered, IndexKind::Hash] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = Config::default().with_index_kind(*kind);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.first_key(), None);
+        assert_eq!(store.last_key(), None);
+        assert_eq!(store.pop_first()?, None);
+        assert_eq!(store.pop_last()?, None);
+
+        for key in &["job3", "job1", "job4", "job2"] {
+            store.set(key.to_string(), format!("{}-value", key))?;
+        }
+        assert_eq!(store.first_key().as_deref(), Some("job1"));
+        assert_eq!(store.last_key().as_deref(), Some("job4"));
+        assert_eq!(store.pop_first()?, Some(("job1".to_owned(), "job1-value".to_owned())));
+        assert_eq!(store.pop_last()?, Some(("job4".to_owned(), "job4-value".to_owned())));
+        assert_eq!(store.first_key().as_deref(), Some("job2"));
+        assert_eq!(store.len(), 2);
+
+        // The pops are logged.
+        drop(store);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.keys().collect::<Vec<_>>(), ["job2", "job3"]);
+        assert_eq!(store.pop_last()?, Some(("job3".to_owned(), "job3-value".to_owned())));
+        assert_eq!(store.pop_last()?, Some(("job2".to_owned(), "job2-value".to_owned())));
+        assert_eq!(store.pop_first()?, None);
+    }
+    Ok(())
+}
+
+// Expired keys should count as absent for the pops, `set_if_absent`,
+// `remove_all`, `for_each`, `iter`, `retain` and `transaction`, and shouldn't
+// get tombstones.
+#[test]
+fn expired_keys_are_absent() -> Result<()> {
+    for kind in &[IndexKind::Ordered, IndexKind::Hash] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let start = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let clock = Arc::new(ManualClock::new(start));
+        let config = Config::default().with_index_kind(*kind).with_clock(clock.clone());
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        for key in &["a", "b", "d", "f", "g"] {
+            store.set_with_ttl(key.to_string(), format!("{}-old", key), Duration::from_secs(1))?;
+        }
+        for key in &["c", "e"] {
+            store.set(key.to_string(), format!("{}-value", key))?;
+        }
+        clock.advance(Duration::from_secs(1));
+
+        assert_eq!(store.iter().size_hint().0, 0);
+        let mut seen = Vec::new();
+        store.for_each(|k, _| {
+            seen.push(k.to_owned());
+            ControlFlow::Continue(())
+        })?;
+        assert_eq!(seen, ["c", "e"]);
+        let mut seen = Vec::new();
+        let stats = store.retain(|k, _| {
+            seen.push(k.to_owned());
+            true
+        })?;
+        assert_eq!(stats, RetainStats { kept: 2, removed: 0 });
+        seen.sort();
+        assert_eq!(seen, ["c", "e"]);
+        let result = store.transaction(vec![Op::Remove { key: "a".to_owned() }]);
+        assert!(matches!(result, Err(KvsError::KeyNotFound)));
+
+        assert_eq!(store.pop_first()?, Some(("c".to_owned(), "c-value".to_owned())));
+        assert_eq!(store.pop_last()?, Some(("e".to_owned(), "e-value".to_owned())));
+        assert!(store.set_if_absent("d".to_owned(), "d-new".to_owned())?);
+        assert_eq!(store.get("d".to_owned())?, Some("d-new".to_owned()));
+        let keys = vec!["d".to_owned(), "f".to_owned(), "g".to_owned()];
+        assert_eq!(store.remove_all(keys)?, RemoveReport { removed: 1, missing: 2 });
+        assert_eq!(store.pop_first()?, None);
+        assert_eq!(store.pop_last()?, None);
+        drop(store);
+
+        // Back at the start the expired keys are live again: nothing removed them.
+        let config = Config::default().with_index_kind(*kind).with_clock(Arc::new(ManualClock::new(start)));
+        let store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.keys().collect::<Vec<_>>(), ["a", "b", "f", "g"]);
+    }
+    Ok(())
+}
+
+// `SharedKvStore` pops should hand every entry to exactly one thread.
+#[test]
+fn shared_pop_first() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(KvStore::open(temp_dir.path())?);
+    for i in 0..400 {
+        store.set(format!("job{:03}", i), i.to_string())?;
+    }
+    let workers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<Vec<String>> {
+                let mut popped = Vec::new();
+                while let Some((key, _)) = store.pop_first()? {
+                    popped.push(key);
+                }
+                Ok(popped)
+            })
+        })
+        .collect();
+    let mut popped = Vec::new();
+    for worker in workers {
+        let keys = worker.join().unwrap()?;
+        // Every thread sees the keys in ascending order.
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        popped.extend(keys);
+    }
+    popped.sort();
+    assert_eq!(popped, (0..400).map(|i| format!("job{:03}", i)).collect::<Vec<_>>());
+    assert!(store.lock().is_empty());
+    Ok(())
+}
+
+// Subscribers should receive the changes in order, and dropped ones should be pruned.
+#[test]
+fn subscribe() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let events = store.subscribe();
+    let dropped = store.subscribe();
+    drop(dropped);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(
+        events.try_iter().collect::<Vec<_>>(),
+        [
+            ChangeEvent::Set { key: "key1".to_owned() },
+            ChangeEvent::Set { key: "key2".to_owned() },
+            ChangeEvent::Remove { key: "key1".to_owned() },
+        ]
+    );
+
+    // Failed writes and compaction send nothing.
+    assert!(store.remove("key1".to_owned()).is_err());
+    store.compact()?;
+    assert!(events.try_recv().is_err());
+
+    store.transaction(vec![
+        Op::Set { key: "key3".to_owned(), value: "value3".to_owned() },
+        Op::Remove { key: "key2".to_owned() },
+    ])?;
+    assert_eq!(
+        events.try_iter().collect::<Vec<_>>(),
+        [ChangeEvent::Set { key: "key3".to_owned() }, ChangeEvent::Remove { key: "key2".to_owned() }]
+    );
+
+    // A transaction sends one event per op, in the order of its ops.
+    let ops: Vec<_> = (0..20).rev().map(|i| Op::Set { key: format!("key{}", i), value: "v".to_owned() }).collect();
+    store.transaction(ops)?;
+    let keys: Vec<_> = (0..20).rev().map(|i| ChangeEvent::Set { key: format!("key{}", i) }).collect();
+    assert_eq!(events.try_iter().collect::<Vec<_>>(), keys);
+    Ok(())
+}
+
+// `for_each` and `for_each_unordered` should hand out exactly each value, even as values shrink.
+#[test]
+fn for_each() -> Result<()> {
+    let configs = layouts()
+        .into_iter()
+        .chain(vec![Config::default().with_index_kind(IndexKind::Hash), Config::default().with_dedup(true)]);
+    for config in configs {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        let mut model = BTreeMap::new();
+        // Later keys get shorter values, written in reverse key order.
+        for i in (0..10).rev() {
+            let value = char::from(b'a' + i as u8).to_string().repeat(100 - i * 10);
+            store.set(format!("key{}", i), value.clone())?;
+            model.insert(format!("key{}", i), value);
+        }
+        store.set("key3".to_owned(), "x".to_owned())?;
+        model.insert("key3".to_owned(), "x".to_owned());
+        store.remove("key5".to_owned())?;
+        model.remove("key5");
+        // A duplicate of a large value, stored as a reference with dedup.
+        store.set("key8".to_owned(), model["key0"].clone())?;
+        model.insert("key8".to_owned(), model["key0"].clone());
+
+        let mut seen = Vec::new();
+        store.for_each(|k, v| {
+            seen.push((k.to_owned(), String::from_utf8(v.to_vec()).unwrap()));
+            ControlFlow::Continue(())
+        })?;
+        assert_eq!(seen, model.clone().into_iter().collect::<Vec<_>>());
+
+        let mut seen = Vec::new();
+        store.for_each_unordered(|k, v| {
+            seen.push((k.to_owned(), String::from_utf8(v.to_vec()).unwrap()));
+            ControlFlow::Continue(())
+        })?;
+        seen.sort();
+        assert_eq!(seen, model.clone().into_iter().collect::<Vec<_>>());
+
+        // `Break` stops the visit.
+        let mut count = 0;
+        store.for_each(|_, _| {
+            count += 1;
+            if count == 3 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        })?;
+        assert_eq!(count, 3);
+        count = 0;
+        store.for_each_unordered(|_, _| {
+            count += 1;
+            ControlFlow::Break(())
+        })?;
+        assert_eq!(count, 1);
+    }
+    Ok(())
+}
+
+// `take` should return the removed value, and remove the key even when the value is unreadable.
+#[test]
+fn take() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.take("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.take("key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Corrupt the last byte of `value2`.
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = std::fs::read(&log)?;
+    let end = bytes.len() - (16 + 4);
+    bytes[end - 1] = 0xff;
+    std::fs::write(&log, bytes)?;
+    assert!(store.take("key2".to_owned()).is_err());
+    assert!(!store.contains_key("key2"));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert!(store.is_empty());
+
+    // Values in the value log are taken the same way, and stay gone after reopening.
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        store.set("job".to_owned(), "payload".repeat(100))?;
+        assert_eq!(store.take("job".to_owned())?, Some("payload".repeat(100)));
+        drop(store);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.take("job".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// Invalid UTF-8 in a log should be reported as `InvalidUtf8`, not panic.
+#[test]
+fn invalid_utf8() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let pos = store.index().get("key2").unwrap().pos();
+    drop(store);
+
+    // Break the last byte of `value2`.
+    let log = temp_dir.path().join("1.log");
+    let mut bytes = std::fs::read(&log)?;
+    let len = bytes.len();
+    bytes[len - 1] = 0xff;
+    std::fs::write(&log, &bytes)?;
+    let is_invalid = |e: &KvsError| matches!(e, KvsError::InvalidUtf8 { generation: 1, pos: p } if *p == pos);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(is_invalid(&store.get("key2".to_owned()).unwrap_err()));
+    assert!(store.iter().any(|item| item.is_err_and(|e| is_invalid(&e))));
+    assert!(store.scan_unordered().any(|item| item.is_err_and(|e| is_invalid(&e))));
+    drop(store);
+
+    // Break the key of the same record: `open` itself fails.
+    bytes[len - 1] = b'2';
+    bytes[pos as usize + 16] = 0xff;
+    std::fs::write(&log, &bytes)?;
+    let err = KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(is_invalid(&err), "{}", err);
+    Ok(())
+}
+
+// `set_get` should return the value it overwrites.
+#[test]
+fn set_get() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.set_get("key1".to_owned(), "value1".to_owned())?, None);
+        assert_eq!(store.set_get("key1".to_owned(), "value2".to_owned())?, Some("value1".to_owned()));
+        store.remove("key1".to_owned())?;
+        assert_eq!(store.set_get("key1".to_owned(), "value3".to_owned())?, None);
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.set_get("key1".to_owned(), "value4".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    }
+    Ok(())
+}
+
+// `estimate_index_memory` should predict the index size of a store before opening it.
+#[test]
+fn estimate_index_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(KvStore::estimate_index_memory(temp_dir.path(), IndexKind::Ordered)?, 0);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), "value".to_owned())?;
+    }
+    for i in 0..500 {
+        store.set(format!("key{:04}", i), "other".to_owned())?;
+    }
+    for i in 900..1000 {
+        store.remove(format!("key{:04}", i))?;
+    }
+    let loaded = store.estimated_index_memory();
+    drop(store);
+
+    let estimate = KvStore::estimate_index_memory(temp_dir.path(), IndexKind::Ordered)?;
+    assert_eq!(estimate, loaded);
+    // 900 keys of 7 bytes, each with an entry of some tens of bytes.
+    assert!(estimate > 900 * 40 && estimate < 900 * 400, "{}", estimate);
+
+    let hashed = KvStore::estimate_index_memory(temp_dir.path(), IndexKind::Hash)?;
+    let store = KvStore::open_with_config(temp_dir.path(), Config::default().with_index_kind(IndexKind::Hash))?;
+    let loaded = store.estimated_index_memory();
+    assert!(hashed / 2 < loaded && loaded < hashed * 2, "{} vs {}", hashed, loaded);
+    Ok(())
+}
+
+// Files whose names aren't valid UTF-8 should be ignored when looking for logs.
+#[cfg(unix)]
+#[test]
+fn non_utf8_file_names() -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let loaded = store.estimated_index_memory();
+    drop(store);
+    let name = std::ffi::OsStr::from_bytes(b"\xff.log");
+    if std::fs::write(temp_dir.path().join(name), b"").is_err() {
+        // The file system doesn't allow such names.
+        return Ok(());
+    }
+
+    assert_eq!(KvStore::estimate_index_memory(temp_dir.path(), IndexKind::Ordered)?, loaded);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Exactly one thread should win `set_if_absent` on a `SharedKvStore`.
+#[test]
+fn shared_set_if_absent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(KvStore::open(temp_dir.path())?);
+    let threads: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || store.set_if_absent("slot".to_owned(), t.to_string()))
+        })
+        .collect();
+    let mut winners = Vec::new();
+    for (t, thread) in threads.into_iter().enumerate() {
+        if thread.join().unwrap()? {
+            winners.push(t.to_string());
+        }
+    }
+    assert_eq!(winners.len(), 1);
+    assert_eq!(store.get("slot".to_owned())?, winners.pop());
+    Ok(())
+}
+
+// Compaction and `close` should leave a hint file that reopening loads
+// instead of replaying the log, as long as the log hasn't changed.
+#[test]
+fn hint_file() -> Result<()> {
+    fn snapshot(store: &KvStore) -> Vec<(String, DataIndex)> {
+        let mut entries: Vec<_> = store.index().iter().map(|(k, v)| (k.to_owned(), v.clone())).collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let hint = temp_dir.path().join("INDEX.hint");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+            store.set(format!("key{}", i), format!("other{}", i))?;
+        }
+        assert!(!hint.exists());
+        store.compact()?;
+        assert!(hint.exists());
+        store.remove("key0".to_owned())?;
+        let before = snapshot(&store);
+        let stats = store.stats();
+        store.close()?;
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(snapshot(&store), before);
+        assert_eq!(store.stats(), stats);
+        assert_eq!(store.get("key7".to_owned())?, Some("other7".to_owned()));
+
+        // Writes after the hint make it stale; the log is replayed again.
+        store.set("key100".to_owned(), "value100".to_owned())?;
+        let before = snapshot(&store);
+        drop(store);
+        let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(snapshot(&store), before);
+        store.close()?;
+
+        // With a current hint, the keys in the log aren't even read.
+        let logs: Vec<_> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|e| e.unwrap().into_path())
+            .filter(|p| p.extension() == Some("log".as_ref()))
+            .collect();
+        for log in &logs {
+            let bytes = std::fs::read(log)?.iter().map(|&b| if b == b'k' { b'K' } else { b }).collect::<Vec<_>>();
+            std::fs::write(log, bytes)?;
+        }
+        let store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert!(store.contains_key("key100"));
+        drop(store);
+        std::fs::remove_file(&hint)?;
+        let store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert!(!store.contains_key("key100"));
+        assert!(store.contains_key("Key100"));
+    }
+    Ok(())
+}
+
+// `compare_and_swap` should only write when the current value is the expected
+// one, and report the actual value otherwise.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let some = |s: &str| Some(s.to_owned());
+    let dir_size = |path: &std::path::Path| -> u64 {
+        WalkDir::new(path)
+            .into_iter()
+            .map(|res| res.and_then(|entry| entry.metadata()).unwrap().len())
+            .sum()
+    };
+
+    // create-if-absent
+    assert_eq!(store.compare_and_swap("key1".to_owned(), None, some("value1"))?, CasResult::Swapped);
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), None, some("value2"))?,
+        CasResult::Mismatch { current: some("value1") }
+    );
+    // update-if-equal
+    assert_eq!(store.compare_and_swap("key1".to_owned(), some("value1"), some("value2"))?, CasResult::Swapped);
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), some("value1"), some("value3"))?,
+        CasResult::Mismatch { current: some("value2") }
+    );
+    assert_eq!(store.get("key1".to_owned())?, some("value2"));
+    // delete-if-equal
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), some("value1"), None)?,
+        CasResult::Mismatch { current: some("value2") }
+    );
+    assert_eq!(store.compare_and_swap("key1".to_owned(), some("value2"), None)?, CasResult::Swapped);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.compare_and_swap("key1".to_owned(), some("value2"), some("value3"))?,
+        CasResult::Mismatch { current: None }
+    );
+
+    // Failed compares should not write anything.
+    let size = dir_size(temp_dir.path());
+    store.compare_and_swap("key1".to_owned(), some("value2"), None)?;
+    store.compare_and_swap("key1".to_owned(), None, None)?;
+    assert_eq!(dir_size(temp_dir.path()), size);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    // Concurrent increments should not lose updates.
+    let store = SharedKvStore::new(store);
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    let mut current = store.get("counter".to_owned())?;
+                    loop {
+                        let next = (current.as_deref().unwrap().parse::<u32>().unwrap() + 1).to_string();
+                        match store.compare_and_swap("counter".to_owned(), current, Some(next))? {
+                            CasResult::Swapped => break,
+                            CasResult::Mismatch { current: actual } => current = actual,
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, some("100"));
+    Ok(())
+}
+
+// `update` should call the closure once with the current value and write
+// its result, leaving the store unchanged if it panics.
+#[test]
+fn update() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut calls = 0;
+    let written = store.update("counter".to_owned(), |v| {
+        calls += 1;
+        assert_eq!(v, None);
+        Some("1".to_owned())
+    })?;
+    assert_eq!(calls, 1);
+    assert_eq!(written, Some("1".to_owned()));
+    let increment = |v: Option<&str>| Some((v.unwrap().parse::<u32>().unwrap() + 1).to_string());
+    assert_eq!(store.update("counter".to_owned(), increment)?, Some("2".to_owned()));
+    assert_eq!(store.get("counter".to_owned())?, Some("2".to_owned()));
+
+    // A panicking closure should not write anything.
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        store.update("counter".to_owned(), |_| panic!("no new value"))
+    }));
+    assert!(result.is_err());
+    assert_eq!(store.get("counter".to_owned())?, Some("2".to_owned()));
+
+    // `None` should remove the key, and do nothing for a missing one.
+    assert_eq!(store.update("counter".to_owned(), |_| None)?, None);
+    assert_eq!(store.get("counter".to_owned())?, None);
+    assert_eq!(store.update("missing".to_owned(), |_| None)?, None);
+    drop(store);
+
+    let store = SharedKvStore::new(KvStore::open(temp_dir.path())?);
+    assert_eq!(store.get("counter".to_owned())?, None);
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    store.update("counter".to_owned(), |v| {
+                        Some((v.map_or(0, |v| v.parse::<u32>().unwrap()) + 1).to_string())
+                    })?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("100".to_owned()));
+    Ok(())
+}
+
+// Values larger than `max_value_size` should be rejected before anything is
+// written to the log.
+#[test]
+fn max_value_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_max_value_size(8);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "12345678".to_owned())?;
+    let log_bytes = || -> Vec<Vec<u8>> {
+        let mut logs: Vec<_> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|e| e.unwrap().into_path())
+            .filter(|p| p.extension() == Some("log".as_ref()))
+            .collect();
+        logs.sort();
+        logs.iter().map(|p| std::fs::read(p).unwrap()).collect()
+    };
+    let before = log_bytes();
+
+    let too_large = "123456789".to_owned();
+    assert!(matches!(store.set("key1".to_owned(), too_large.clone()), Err(KvsError::ValueTooLarge(9))));
+    assert!(matches!(
+        store.set_from_reader("key2".to_owned(), 9, too_large.as_bytes()),
+        Err(KvsError::ValueTooLarge(9))
+    ));
+    let ops = vec![
+        Op::Set { key: "key3".to_owned(), value: "value3".to_owned() },
+        Op::Set { key: "key4".to_owned(), value: too_large.clone() },
+    ];
+    assert!(matches!(store.transaction(ops), Err(KvsError::ValueTooLarge(9))));
+    assert!(matches!(
+        store.update("key1".to_owned(), |v| Some(format!("{}9", v.unwrap()))),
+        Err(KvsError::ValueTooLarge(9))
+    ));
+    assert_eq!(log_bytes(), before);
+    assert_eq!(store.get("key1".to_owned())?, Some("12345678".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    drop(store);
+
+    // Existing larger values stay readable with a smaller limit.
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.with_max_value_size(4))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("12345678".to_owned()));
+    Ok(())
+}
+
+// Concurrent `incr_by` calls on a shared store should not lose updates.
+#[test]
+fn shared_incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::new(KvStore::open(temp_dir.path())?);
+    let threads: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    store.incr_by("counter".to_owned(), if t % 2 == 0 { 3 } else { -1 })?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+    assert_eq!(store.incr("counter".to_owned())?, 201);
+    Ok(())
+}
+
+// `append` should extend the value of a key and return its new length.
+#[test]
+fn append() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.append("events".to_owned(), "a;")?, 2);
+        assert_eq!(store.append("events".to_owned(), "b;")?, 4);
+        assert_eq!(store.append("events".to_owned(), "")?, 4);
+        assert_eq!(store.get("events".to_owned())?, Some("a;b;".to_owned()));
+
+        // Appending after a remove starts over.
+        store.remove("events".to_owned())?;
+        assert_eq!(store.append("events".to_owned(), "c;")?, 2);
+        assert!(store.stats().uncompacted_bytes > 0);
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.append("events".to_owned(), "d;")?, 4);
+        store.compact()?;
+        assert_eq!(store.get("events".to_owned())?, Some("c;d;".to_owned()));
+    }
+    Ok(())
+}
+
+// Only one writable store should have a directory open at a time;
+// `open_with_timeout` should wait for the lock to be released.
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(KvStore::open(temp_dir.path()), Err(KvsError::Locked)));
+    let start = Instant::now();
+    assert!(matches!(
+        KvStore::open_with_timeout(temp_dir.path(), Duration::from_millis(50)),
+        Err(KvsError::Locked)
+    ));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // Read-only stores don't take the lock.
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let holder = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        drop(store);
+    });
+    let mut store = KvStore::open_with_timeout(temp_dir.path(), Duration::from_secs(10))?;
+    holder.join().unwrap();
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// `modified_since` should return only the keys written after the given time,
+// also after compaction and reopening.
+#[test]
+fn modified_since() -> Result<()> {
+    let now = || SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &["a", "b", "c", "d"] {
+        store.set((*key).to_owned(), "old".to_owned())?;
+    }
+    let since = now();
+    while now() == since {
+        thread::sleep(Duration::from_millis(10));
+    }
+    store.set("c".to_owned(), "new".to_owned())?;
+    store.set("a".to_owned(), "new".to_owned())?;
+    store.set("e".to_owned(), "new".to_owned())?;
+    store.remove("d".to_owned())?;
+
+    assert_eq!(store.modified_since(since), ["a", "c", "e"]);
+    assert_eq!(store.modified_since(since - 1), ["a", "b", "c", "e"]);
+    assert!(store.modified_since(now()).is_empty());
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.modified_since(since), ["a", "c", "e"]);
+    drop(store);
+    let config = Config::default().with_index_kind(IndexKind::Hash);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.modified_since(since), ["a", "c", "e"]);
+    Ok(())
+}
+
+// `swap` should exchange two values in one batch, accounting for both
+// displaced records, and refuse missing keys.
+#[test]
+fn swap() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        store.set("blue".to_owned(), "v1".to_owned())?;
+        store.set("green".to_owned(), "v2".to_owned())?;
+        let before = store.stats().uncompacted_bytes;
+
+        store.swap("blue".to_owned(), "green".to_owned())?;
+        assert_eq!(store.get("blue".to_owned())?, Some("v2".to_owned()));
+        assert_eq!(store.get("green".to_owned())?, Some("v1".to_owned()));
+        assert_eq!(store.len(), 2);
+        let after = store.stats().uncompacted_bytes;
+        assert!(after > before);
+
+        // Swapping a key with itself changes nothing.
+        store.swap("blue".to_owned(), "blue".to_owned())?;
+        assert_eq!(store.get("blue".to_owned())?, Some("v2".to_owned()));
+        assert_eq!(store.stats().uncompacted_bytes, after);
+
+        // A missing key is an error and nothing is written.
+        assert!(matches!(store.swap("blue".to_owned(), "red".to_owned()), Err(KvsError::KeyNotFound)));
+        assert!(matches!(store.swap("red".to_owned(), "blue".to_owned()), Err(KvsError::KeyNotFound)));
+        assert!(matches!(store.swap("red".to_owned(), "red".to_owned()), Err(KvsError::KeyNotFound)));
+        assert_eq!(store.get("blue".to_owned())?, Some("v2".to_owned()));
+        assert!(!store.contains_key("red"));
+        let stats = store.stats();
+        assert_eq!(stats.uncompacted_bytes, after);
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.stats(), stats);
+        assert_eq!(store.get("blue".to_owned())?, Some("v2".to_owned()));
+        assert_eq!(store.get("green".to_owned())?, Some("v1".to_owned()));
+    }
+    Ok(())
+}
+
+// A crash in the middle of `swap` should never leave both keys holding the
+// same value.
+#[test]
+fn swap_torn_batch() -> Result<()> {
+    check_torn_batch(
+        |store| {
+            store.set("blue".to_owned(), "v1".to_owned())?;
+            store.set("green".to_owned(), "v2".to_owned())
+        },
+        |store| store.swap("blue".to_owned(), "green".to_owned()),
+        |store| {
+            assert_eq!(store.get("blue".to_owned())?, Some("v1".to_owned()));
+            assert_eq!(store.get("green".to_owned())?, Some("v2".to_owned()));
+            Ok(())
+        },
+    )
+}
+
+// `compact_tiered` should merge only the smallest sealed generations, keeping
+// removed keys removed and references into the merged ones valid.
+#[test]
+fn compact_tiered() -> Result<()> {
+    for config in [Config::default(), Config::default().with_dedup(true)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = config.with_max_log_size(1024).with_compaction_tiers(2).with_auto_compaction(false);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        let shared = "s".repeat(100);
+        // Writes small records until the active log is sealed.
+        let fill = |store: &mut KvStore, iter: usize| -> Result<()> {
+            let generations = store.stats().generations;
+            let mut key_id = 0;
+            while store.stats().generations == generations {
+                store.set(format!("small{}", key_id), format!("value{}-{}", key_id, iter))?;
+                key_id += 1;
+            }
+            Ok(())
+        };
+
+        store.set("big".to_owned(), "b".repeat(6000))?;
+        store.set("mid".to_owned(), "m".repeat(3000))?;
+        store.set("shared1".to_owned(), shared.clone())?;
+        store.remove("mid".to_owned())?;
+        fill(&mut store, 0)?;
+        fill(&mut store, 1)?;
+        store.set("shared2".to_owned(), shared.clone())?;
+        let before = store.generations()?;
+        let sizes: Vec<_> = before.iter().map(|g| (g.generation, g.file_size)).collect();
+        assert_eq!(sizes.len(), 5);
+        assert!(sizes[0].1 > sizes[1].1 && sizes[1].1 > sizes[2].1.max(sizes[3].1));
+        let mut expected = BTreeMap::new();
+        for key in store.keys().map(str::to_owned).collect::<Vec<_>>() {
+            let value = store.get(key.clone())?.unwrap();
+            expected.insert(key, value);
+        }
+
+        store.compact_tiered()?;
+        let after = store.generations()?;
+        let generations: Vec<_> = after.iter().map(|g| g.generation).collect();
+        assert_eq!(generations, [1, 2, 5, 6, 7]);
+        assert_eq!((after[0].file_size, after[1].file_size), (sizes[0].1, sizes[1].1));
+        let check = |store: &mut KvStore| -> Result<()> {
+            for (key, value) in &expected {
+                assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+            }
+            assert_eq!(store.len(), expected.len());
+            assert_eq!(store.get("mid".to_owned())?, None);
+            Ok(())
+        };
+        check(&mut store)?;
+        let stats = store.stats();
+        drop(store);
+
+        // The bytes saved by deduplication are only counted since opening.
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        check(&mut store)?;
+        assert_eq!(store.stats(), Stats { dedup_bytes_saved: 0, ..stats });
+        store.compact()?;
+        check(&mut store)?;
+    }
+    Ok(())
+}
+
+// `remove_all` should remove the existing keys in one batch and count the
+// missing ones instead of failing.
+#[test]
+fn remove_all() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        let uncompacted = store.stats().uncompacted_bytes;
+
+        let keys = (0..50).map(|key_id| format!("key{}", key_id * 3));
+        let report = store.remove_all(keys.chain(vec!["key0".to_owned()]))?;
+        assert_eq!(report, RemoveReport { removed: 34, missing: 17 });
+        assert_eq!(store.len(), 66);
+        assert!(store.stats().uncompacted_bytes > uncompacted);
+        assert_eq!(store.remove_all(vec!["key0".to_owned(), "key1".to_owned()])?, RemoveReport { removed: 1, missing: 1 });
+        assert_eq!(store.remove_all(Vec::new())?, RemoveReport::default());
+        let stats = store.stats();
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.stats(), stats);
+        for key_id in 0..100 {
+            let expected = if key_id % 3 == 0 || key_id == 1 { None } else { Some(format!("value{}", key_id)) };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+    }
+    Ok(())
+}
+
+// With gaps in the generation numbers, `open` should keep appending to the
+// highest generation.
+#[test]
+fn reopen_with_generation_gap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_max_log_size(1024);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let mut key_id = 0;
+    while store.stats().generations == 1 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        key_id += 1;
+    }
+    store.set("last".to_owned(), "value".to_owned())?;
+    drop(store);
+    std::fs::rename(temp_dir.path().join("2.log"), temp_dir.path().join("5.log"))?;
+
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.stats().generations, 2);
+    assert_eq!(store.index().get("last").unwrap().generation(), 5);
+    store.set("new".to_owned(), "value".to_owned())?;
+    assert_eq!(store.index().get("new").unwrap().generation(), 5);
+    assert!(!temp_dir.path().join("2.log").exists());
+    assert!(!temp_dir.path().join("6.log").exists());
+    for key_id in 0..key_id {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    Ok(())
+}
+
+// With only `2.log` and `7.log` present, reads, writes, `generations` and
+// `compact` should all work on the generations that exist.
+#[test]
+fn non_contiguous_generations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_max_log_size(1024).with_auto_compaction(false);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let mut key_id = 0;
+    while store.stats().generations == 1 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        key_id += 1;
+    }
+    drop(store);
+    std::fs::rename(temp_dir.path().join("2.log"), temp_dir.path().join("7.log"))?;
+    std::fs::rename(temp_dir.path().join("1.log"), temp_dir.path().join("2.log"))?;
+
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let generations: Vec<u64> = store.generations()?.iter().map(|g| g.generation).collect();
+    assert_eq!(generations, vec![2, 7]);
+    for key_id in 0..key_id {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.index().get("key0").unwrap().generation(), 7);
+
+    store.compact()?;
+    assert!(!temp_dir.path().join("2.log").exists());
+    assert!(!temp_dir.path().join("7.log").exists());
+    assert!(temp_dir.path().join("8.log").exists());
+    drop(store);
+
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    for key_id in 2..key_id {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    Ok(())
+}
+
+// `clear` should remove every key and every old file, leave the store
+// writable, and stay empty after reopening.
+#[test]
+fn clear() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = config.with_max_log_size(1024);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for i in 0..200 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0".to_owned())?;
+        let events = store.subscribe();
+        let before: Vec<_> = std::fs::read_dir(temp_dir.path())?.map(|e| e.unwrap().path()).collect();
+
+        store.clear()?;
+        assert_eq!(events.try_iter().count(), 199);
+        assert_eq!(store.get("key1".to_owned())?, None);
+        let stats = store.stats();
+        assert_eq!((stats.keys, stats.generations, stats.uncompacted_bytes), (0, 1, 0));
+        for path in before {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let data = name.ends_with(".log") || name.ends_with(".vlog");
+            assert!(!data || !path.exists(), "{} still exists", name);
+        }
+
+        store.set("key1".to_owned(), "new".to_owned())?;
+        store.clear()?;
+        store.set("key2".to_owned(), "new".to_owned())?;
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("new".to_owned()));
+        assert_eq!(store.stats().keys, 1);
+    }
+    Ok(())
+}
+
+// With `IoBackend::Mmap`, values appended to the active log after it was
+// mapped should be readable, also across generations and after compaction.
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_reads_follow_appends() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = config.with_io_backend(IoBackend::Mmap).with_max_log_size(4096);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+            assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        }
+        assert!(store.stats().generations > 1);
+        store.compact()?;
+        store.set("key0".to_owned(), "new".to_owned())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        for key_id in 1..200 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+        }
+    }
+    Ok(())
+}
+
+// Keys written with `set_with_ttl` should disappear once the clock passes
+// their expiry, also across compaction and reopening; plain `set` never
+// expires and clears an expiry.
+#[test]
+fn set_with_ttl() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+        let config = config.with_clock(clock.clone());
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        store.set_with_ttl("short".to_owned(), "1".to_owned(), Duration::from_secs(60))?;
+        store.set_with_ttl("long".to_owned(), "2".to_owned(), Duration::from_secs(600))?;
+        store.set_with_ttl("reset".to_owned(), "3".to_owned(), Duration::from_secs(1))?;
+        store.set("reset".to_owned(), "4".to_owned())?;
+        store.set("plain".to_owned(), "5".to_owned())?;
+        assert!(store.contains_key("short"));
+        assert_eq!(store.get("short".to_owned())?, Some("1".to_owned()));
+
+        clock.advance(Duration::from_secs(60));
+        assert!(!store.contains_key("short"));
+        assert_eq!(store.get("short".to_owned())?, None);
+        assert!(matches!(store.remove("short".to_owned()), Err(KvsError::KeyNotFound)));
+        assert_eq!(store.get("long".to_owned())?, Some("2".to_owned()));
+        assert_eq!(store.get("reset".to_owned())?, Some("4".to_owned()));
+
+        // compaction keeps the expiry of the keys it copies
+        store.compact()?;
+        store.set_with_ttl("closed".to_owned(), "6".to_owned(), Duration::from_secs(10))?;
+        drop(store);
+        clock.advance(Duration::from_secs(10));
+
+        // keys that expired while the store was closed aren't loaded
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.stats().keys, 3);
+        assert_eq!(store.get("closed".to_owned())?, None);
+        assert_eq!(store.get("long".to_owned())?, Some("2".to_owned()));
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(store.get("long".to_owned())?, None);
+        assert_eq!(store.get("plain".to_owned())?, Some("5".to_owned()));
+        let pairs: Vec<_> = store.iter().collect::<Result<_>>()?;
+        assert_eq!(pairs, vec![("plain".to_owned(), "5".to_owned()), ("reset".to_owned(), "4".to_owned())]);
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.stats().keys, 2);
+        assert_eq!(store.get("long".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// A tiny and a large write buffer should produce the same readable data as
+// an unbuffered writer, also for records larger than the buffer.
+#[test]
+fn write_buffer_size() -> Result<()> {
+    let mut sizes = Vec::new();
+    for buffer in [0, 7, 1 << 20] {
+        for config in layouts() {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let config = config.with_write_buffer_size(buffer).with_max_log_size(64 * 1024);
+            let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+            for key_id in 0..300 {
+                store.set(format!("key{}", key_id), format!("value{}", key_id).repeat(key_id % 40 + 1))?;
+            }
+            store.transaction((0..100).map(|key_id| Op::Remove { key: format!("key{}", key_id * 3) }).collect())?;
+            store.remove("key1".to_owned())?;
+            let pairs: Vec<_> = store.iter().collect::<Result<_>>()?;
+            drop(store);
+
+            let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+            assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, pairs);
+            assert_eq!(pairs.len(), 199);
+            let stats = store.stats();
+            sizes.push((pairs, stats.generations, stats.uncompacted_bytes));
+        }
+    }
+    assert!(sizes.chunks(2).all(|c| c == &sizes[..2]));
+    Ok(())
+}
+
+// `expire` and `persist` should change the expiry of existing keys without
+// rewriting them, survive compaction and reopening, and be undone by `set`.
+#[test]
+fn expire_and_persist() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+        let config = config.with_clock(clock.clone()).with_max_log_size(1024).with_auto_compaction(false);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for key_id in 0..40 {
+            store.set(format!("key{}", key_id), "x".repeat(100))?;
+        }
+        assert!(!store.expire("missing", Duration::from_secs(1))?);
+        assert!(!store.persist("missing")?);
+
+        // the expiry record doesn't copy the value
+        let before = store.stats().uncompacted_bytes;
+        assert!(store.expire("key0", Duration::from_secs(10))?);
+        assert!(store.stats().uncompacted_bytes - before < 100);
+        assert!(store.expire("key1", Duration::from_secs(10))?);
+        assert!(store.expire("key2", Duration::from_secs(10))?);
+        store.set_with_ttl("ttl".to_owned(), "v".to_owned(), Duration::from_secs(10))?;
+        assert!(store.persist("key1")?);
+        assert!(store.persist("ttl")?);
+        assert!(store.persist("key3")?);
+        // `set` drops the expiry given by `expire`
+        store.set("key2".to_owned(), "new".to_owned())?;
+        // and `expire` replaces the one given by `set_with_ttl`
+        store.set_with_ttl("later".to_owned(), "v".to_owned(), Duration::from_secs(10))?;
+        assert!(store.expire("later", Duration::from_secs(100))?);
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        store.compact_tiered()?;
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert!(!store.expire("key0", Duration::from_secs(10))?);
+        assert_eq!(store.get("key1".to_owned())?, Some("x".repeat(100)));
+        assert_eq!(store.get("key2".to_owned())?, Some("new".to_owned()));
+        assert_eq!(store.get("ttl".to_owned())?, Some("v".to_owned()));
+        assert_eq!(store.get("later".to_owned())?, Some("v".to_owned()));
+
+        store.compact()?;
+        drop(store);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("later".to_owned())?, Some("v".to_owned()));
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(store.get("later".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("x".repeat(100)));
+        assert_eq!(store.get("ttl".to_owned())?, Some("v".to_owned()));
+        assert_eq!(store.stats().keys, 40);
+    }
+    Ok(())
+}
+
+// `locate` should report where a key's record is, and the new generation
+// after compaction moved it.
+#[test]
+fn locate() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.locate("key1"), None);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key1".to_owned(), "new".to_owned())?;
+        let (generation, pos, len) = store.locate("key1").unwrap();
+        let (generation2, pos2, _) = store.locate("key2").unwrap();
+        assert_eq!(generation, generation2);
+        assert!(pos > pos2);
+        assert!(len as usize > "key1".len());
+
+        store.compact()?;
+        let (moved, _, moved_len) = store.locate("key1").unwrap();
+        assert!(moved > generation);
+        assert_eq!(moved_len, len);
+        assert_eq!(store.generations()?.iter().map(|g| g.generation).min(), Some(moved));
+        store.remove("key1".to_owned())?;
+        assert_eq!(store.locate("key1"), None);
+    }
+    Ok(())
+}
+
+// `ttl` should tell missing keys, keys without expiry and the time left
+// apart, and agree with `get` at the same instant.
+#[test]
+fn ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+    let config = Config::default().with_clock(clock.clone());
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("plain".to_owned(), "1".to_owned())?;
+    store.set_with_ttl("short".to_owned(), "2".to_owned(), Duration::from_millis(1500))?;
+    assert_eq!(store.ttl("missing")?, TtlState::Missing);
+    assert_eq!(store.ttl("plain")?, TtlState::NoExpiry);
+    assert_eq!(store.ttl("short")?, TtlState::ExpiresIn(Duration::from_millis(1500)));
+
+    clock.advance(Duration::from_millis(1499));
+    assert_eq!(store.ttl("short")?, TtlState::ExpiresIn(Duration::from_millis(1)));
+    assert_eq!(store.get("short".to_owned())?, Some("2".to_owned()));
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(store.ttl("short")?, TtlState::Missing);
+    assert_eq!(store.get("short".to_owned())?, None);
+
+    assert!(store.expire("plain", Duration::from_secs(60))?);
+    drop(store);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.ttl("plain")?, TtlState::ExpiresIn(Duration::from_secs(60)));
+    assert!(store.persist("plain")?);
+    assert_eq!(store.ttl("plain")?, TtlState::NoExpiry);
+    Ok(())
+}
+
+// `on_evict` callbacks should hear about keys found expired by reads and
+// compaction, and a stuck callback should lose events instead of stalling
+// the store.
+#[test]
+fn on_evict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+    let config = Config::default().with_clock(clock.clone());
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    store.on_evict(move |key, reason| tx.send((key.to_owned(), reason)).unwrap());
+    for key in ["a", "b", "c"] {
+        store.set_with_ttl(key.to_owned(), "v".to_owned(), Duration::from_secs(1))?;
+    }
+    store.set("plain".to_owned(), "v".to_owned())?;
+    store.remove("c".to_owned())?;
+    clock.advance(Duration::from_secs(1));
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(rx.recv_timeout(timeout).unwrap(), ("a".to_owned(), EvictReason::Expired));
+    store.compact()?;
+    assert_eq!(rx.recv_timeout(timeout).unwrap(), ("b".to_owned(), EvictReason::Expired));
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+    let shared = SharedKvStore::new(store);
+    let (tx, rx) = std::sync::mpsc::channel();
+    shared.on_evict(move |key, _| tx.send(key.to_owned()).unwrap());
+    shared.set_with_ttl("d".to_owned(), "v".to_owned(), Duration::from_secs(1))?;
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(shared.get("d".to_owned())?, None);
+    assert_eq!(rx.recv_timeout(timeout).unwrap(), "d");
+    drop(shared);
+
+    // a callback that doesn't return
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_config(temp_dir.path(), Config::default().with_clock(clock.clone()))?;
+    let (release, stuck) = std::sync::mpsc::channel::<()>();
+    let stuck = std::sync::Mutex::new(stuck);
+    store.on_evict(move |_, _| {
+        let _ = stuck.lock().unwrap().recv();
+    });
+    for key_id in 0..1100 {
+        store.set_with_ttl(format!("key{}", key_id), "v".to_owned(), Duration::from_secs(1))?;
+    }
+    clock.advance(Duration::from_secs(1));
+    store.compact()?;
+    assert!(store.is_empty());
+    // at most one event is being handled, and 1024 are queued
+    assert!(store.dropped_evictions() >= 1100 - 1025);
+    drop(release);
+    Ok(())
+}
+
+// Compacting in small `compact_budget` steps should keep the store readable
+// after every step and end up with the same data as a full `compact`.
+#[test]
+fn compact_budget() -> Result<()> {
+    let mut configs = layouts();
+    configs.push(Config::default().with_dedup(true));
+    for config in configs {
+        let config = config.with_max_log_size(4096).with_auto_compaction(false);
+        let full_dir = TempDir::new().expect("unable to create temporary working directory");
+        let budget_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut full = KvStore::open_with_config(full_dir.path(), config.clone())?;
+        let mut store = KvStore::open_with_config(budget_dir.path(), config.clone())?;
+        for store in [&mut full, &mut store] {
+            for round in 0..3 {
+                for key_id in 0..100 {
+                    store.set(format!("key{}", key_id), format!("{}", round).repeat(70 + key_id % 3))?;
+                }
+            }
+            for key_id in 0..30 {
+                store.remove(format!("key{}", key_id * 3))?;
+            }
+        }
+        full.compact()?;
+        let expected: Vec<_> = full.iter().collect::<Result<_>>()?;
+
+        let mut steps = 0;
+        while store.compact_budget(200)? == CompactStatus::MoreWork {
+            steps += 1;
+            assert_eq!(store.get("key1".to_owned())?, Some("2".repeat(71)));
+            assert_eq!(store.get("key3".to_owned())?, None);
+            if steps == 5 {
+                // writes in between land after the compacted data
+                store.set("key3".to_owned(), "new".to_owned())?;
+                store.remove("key3".to_owned())?;
+            }
+        }
+        assert!(steps > 1);
+        assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, expected);
+        drop(store);
+
+        let mut store = KvStore::open_with_config(budget_dir.path(), config)?;
+        assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, expected);
+        assert_eq!(store.stats().keys, full.stats().keys);
+    }
+    Ok(())
+}
+
+// Metadata should survive reopening, compaction and `compact_to`, stay apart
+// from the keys and be bounded in size.
+#[test]
+fn meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_meta("schema"), None);
+    store.set_meta("schema", "1")?;
+    store.set_meta("schema", "2")?;
+    store.set_meta("owner", "tests")?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get_meta("schema"), Some("2"));
+    assert_eq!(store.get("schema".to_owned())?, None);
+    assert_eq!(store.stats().meta.get("owner").map(String::as_str), Some("tests"));
+
+    let err = store.set_meta("big", &"x".repeat(5000)).unwrap_err();
+    assert!(matches!(err, KvsError::MetaTooLarge(_)));
+    assert_eq!(store.get_meta("big"), None);
+
+    store.compact()?;
+    store.clear()?;
+    assert_eq!(store.get_meta("schema"), Some("2"));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_meta("schema"), Some("2"));
+    assert_eq!(store.get_meta("owner"), Some("tests"));
+    let backup = TempDir::new().expect("unable to create temporary working directory");
+    store.compact_to(backup.path())?;
+    drop(store);
+
+    let store = KvStore::open_read_only(backup.path())?;
+    assert_eq!(store.get_meta("schema"), Some("2"));
+    Ok(())
+}
+
+// `open` should name the path when it is a file or can't be written.
+#[cfg(unix)]
+#[test]
+fn open_bad_directory() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file = temp_dir.path().join("file");
+    std::fs::write(&file, "not a store")?;
+    match KvStore::open(&file) {
+        Err(KvsError::NotADirectory(path)) => assert_eq!(path, file),
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+    match KvStore::open_read_only(&file) {
+        Err(KvsError::NotADirectory(path)) => assert_eq!(path, file),
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+
+    let locked = temp_dir.path().join("locked");
+    std::fs::create_dir(&locked)?;
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o555))?;
+    // root ignores the permissions, nothing to test then
+    if std::fs::write(locked.join("probe"), "").is_err() {
+        for dir in [locked.clone(), locked.join("store")] {
+            match KvStore::open(&dir) {
+                Err(KvsError::PermissionDenied(path)) => assert_eq!(path, dir),
+                other => panic!("unexpected result {:?}", other.map(|_| ())),
+            }
+        }
+    }
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+// `metadata` should report the on-disk timestamp, the value length and the
+// generation of a key, through reopening and overwrites.
+#[test]
+fn metadata() -> Result<()> {
+    let now = || SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.metadata("key"), None);
+        let before = now();
+        store.set("key".to_owned(), "value".to_owned())?;
+        let meta = store.metadata("key").unwrap();
+        assert!(meta.timestamp() >= before && meta.timestamp() <= now());
+        assert_eq!(meta.value_len(), 5);
+        drop(store);
+
+        // Timestamps have second precision, so let one pass before replaying.
+        thread::sleep(Duration::from_millis(1100));
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.metadata("key"), Some(meta));
+        store.set("key".to_owned(), "longer value".to_owned())?;
+        let meta = store.metadata("key").unwrap();
+        assert!(meta.timestamp() > before);
+        assert_eq!(meta.value_len(), 12);
+        store.remove("key".to_owned())?;
+        assert_eq!(store.metadata("key"), None);
+    }
+    Ok(())
+}
+
+// `value_len` should report the byte length of the stored value, also for
+// values written with an expiry, compressed or deduplicated values, and after
+// reopening with or without a hint file.
+#[test]
+fn value_len() -> Result<()> {
+    let mut configs = layouts();
+    configs.push(Config::default().with_dedup(true));
+    #[cfg(feature = "compression")]
+    for config in layouts() {
+        configs.push(config.with_compression(kvs::Codec::Lz4).with_compression_threshold(0));
+    }
+    for config in configs {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.value_len("key1"), None);
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "välue".to_owned())?;
+        store.set_with_ttl("key3".to_owned(), "value3".repeat(10), Duration::from_secs(3600))?;
+        store.set("key4".to_owned(), String::new())?;
+        store.expire("key1", Duration::from_secs(3600))?;
+        // Compressible, and a duplicate of each other.
+        store.set("key5".to_owned(), "abc".repeat(100))?;
+        store.set("key6".to_owned(), "abc".repeat(100))?;
+        for reopen in 0..3 {
+            assert_eq!(store.value_len("key1"), Some(6));
+            assert_eq!(store.value_len("key2"), Some("välue".len() as u32));
+            assert_eq!(store.value_len("key3"), Some(60));
+            assert_eq!(store.value_len("key4"), Some(0));
+            assert_eq!(store.value_len("key5"), Some(300));
+            assert_eq!(store.metadata("key6").map(|meta| meta.value_len()), Some(300));
+            // Dropping replays the log on the next open, closing writes a hint.
+            if reopen == 1 {
+                store.close()?;
+            } else {
+                drop(store);
+            }
+            store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        }
+        store.compact()?;
+        assert_eq!(store.value_len("key1"), Some(6));
+        assert_eq!(store.value_len("key3"), Some(60));
+        assert_eq!(store.value_len("key6"), Some(300));
+        store.remove("key1".to_owned())?;
+        assert_eq!(store.value_len("key1"), None);
+    }
+    Ok(())
+}
+
+// `touch` should refresh the timestamp of a key without changing its value,
+// through reopening and compaction.
+#[test]
+fn touch() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        let before = store.metadata("key1").unwrap().timestamp();
+        assert!(!store.touch("missing")?);
+
+        // Timestamps have second precision.
+        thread::sleep(Duration::from_millis(1100));
+        let uncompacted = store.stats().uncompacted_bytes;
+        assert!(store.touch("key1")?);
+        let touched = store.metadata("key1").unwrap().timestamp();
+        assert!(touched > before);
+        assert_eq!(store.metadata("key2").unwrap().timestamp(), before);
+        assert_eq!(store.stats().uncompacted_bytes, uncompacted + 16 + 4);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.metadata("key1").unwrap().timestamp(), touched);
+        assert_eq!(store.stats().uncompacted_bytes, uncompacted + 16 + 4);
+        store.compact()?;
+        assert_eq!(store.metadata("key1").unwrap().timestamp(), touched);
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.metadata("key1").unwrap().timestamp(), touched);
+        assert_eq!(store.stats().uncompacted_bytes, 0);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert!(store.touch("key2")?);
+        store.remove("key2".to_owned())?;
+        assert!(!store.touch("key2")?);
+    }
+    Ok(())
+}
+
+// `get_at` should return the value a key had at an earlier time, until
+// compaction discards the history.
+#[test]
+fn get_at() -> Result<()> {
+    let at = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert!(matches!(store.get_at("key", SystemTime::now()), Err(KvsError::UnsupportedOperation(_))));
+        assert_eq!(store.stats().history_horizon, None);
+        drop(store);
+
+        let config = config.with_keep_history(true);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        store.set("key".to_owned(), "value1".to_owned())?;
+        let t1 = store.metadata("key").unwrap().timestamp();
+        // Timestamps have second precision.
+        thread::sleep(Duration::from_millis(1100));
+        store.set("key".to_owned(), "value2".to_owned())?;
+        let t2 = store.metadata("key").unwrap().timestamp();
+        thread::sleep(Duration::from_millis(1100));
+        store.set("other".to_owned(), "value".to_owned())?;
+        let t3 = store.metadata("other").unwrap().timestamp();
+        store.remove("key".to_owned())?;
+
+        assert_eq!(store.get_at("key", at(t1))?, Some("value1".to_owned()));
+        assert_eq!(store.get_at("key", at(t2))?, Some("value2".to_owned()));
+        assert_eq!(store.get_at("key", at(t3))?, None);
+        assert_eq!(store.get_at("other", at(t2))?, None);
+        assert_eq!(store.get_at("other", SystemTime::now())?, Some("value".to_owned()));
+        drop(store);
+
+        // The history is rebuilt from the log, starting with its oldest record.
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        assert_eq!(store.stats().history_horizon, Some(at(t1)));
+        assert!(matches!(store.get_at("key", at(t1 - 1)), Err(KvsError::HistoryTruncated)));
+        assert_eq!(store.get_at("key", at(t1))?, Some("value1".to_owned()));
+        assert_eq!(store.get_at("key", at(t2))?, Some("value2".to_owned()));
+        assert_eq!(store.get_at("key", at(t3))?, None);
+
+        store.compact()?;
+        let horizon = store.stats().history_horizon.unwrap();
+        assert!(horizon >= at(t3));
+        assert!(matches!(store.get_at("key", at(t1)), Err(KvsError::HistoryTruncated)));
+        assert_eq!(store.get_at("other", horizon)?, Some("value".to_owned()));
+        drop(store);
+
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.stats().history_horizon, Some(horizon));
+        assert_eq!(store.get_at("key", horizon)?, None);
+    }
+    Ok(())
+}
+
+// `get_into` should write the exact bytes of a value into the sink.
+#[test]
+fn get_into() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        let value: String = (0..1024 * 1024).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        store.set("big".to_owned(), value.clone())?;
+
+        let mut out = Vec::new();
+        assert!(store.get_into("big", &mut out)?);
+        assert_eq!(out, value.as_bytes());
+
+        let mut out = Vec::new();
+        assert!(!store.get_into("missing", &mut out)?);
+        assert!(out.is_empty());
+    }
+    Ok(())
+}
+
+// Large values should be streamed in and out of the store, also across compaction.
+#[test]
+fn streaming_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let len = 256 * 1024 * 1024;
+    store.set_from_reader("big".to_owned(), len, Pattern { offset: 0, left: len })?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    check_pattern(store.get_reader("big")?.unwrap(), len);
+
+    store.compact()?;
+    check_pattern(store.get_reader("big")?.unwrap(), len);
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert!(store.get_reader("missing")?.is_none());
+
+    // A reader that ends early should write nothing.
+    let short = Pattern { offset: 0, left: 10 };
+    assert!(store.set_from_reader("short".to_owned(), 100, short).is_err());
+    assert_eq!(store.get("short".to_owned())?, None);
+    let text = "streamed".as_bytes();
+    store.set_from_reader("text".to_owned(), text.len() as u64, text)?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check_pattern(store.get_reader("big")?.unwrap(), len);
+    assert_eq!(store.get("text".to_owned())?, Some("streamed".to_owned()));
+    Ok(())
+}
+
+// Keys and values that don't fit in the record format should be rejected
+// without writing anything.
+#[test]
+fn too_large() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let key = "k".repeat(16 * 1024 * 1024);
+    match store.set(key.clone(), "value".to_owned()) {
+        Err(KvsError::KeyTooLarge(len)) => assert_eq!(len, key.len() as u64),
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    // Values over 4 GiB are split into chunks instead of being rejected; this
+    // reader ends right away, so the write fails without a huge allocation.
+    let len = 5 * 1024 * 1024 * 1024;
+    match store.set_from_reader("key".to_owned(), len, std::io::empty()) {
+        Err(KvsError::IoAt { source, .. }) => {
+            assert_eq!(source.kind(), std::io::ErrorKind::UnexpectedEof)
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
+
+    let op = Op::Set { key, value: "value".to_owned() };
+    assert!(matches!(store.transaction(vec![op]), Err(KvsError::KeyTooLarge(_))));
+    // Only the file header was written.
+    assert_eq!(std::fs::metadata(temp_dir.path().join("1.log"))?.len(), 6);
+    assert_eq!(store.stats().keys, 0);
+    Ok(())
+}
+
+// Removing a key should count the whole removed record plus the tombstone as
+// stale, and `open` should arrive at the same number.
+#[test]
+fn uncompacted_accounting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_auto_compaction(false);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+
+    store.remove("key1".to_owned())?;
+    // The record has a 16 byte header, the tombstone has no value.
+    let record = 16 + 4 + 6;
+    let tombstone = 16 + 4;
+    assert_eq!(store.stats().uncompacted_bytes, record + tombstone);
+    drop(store);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.stats().uncompacted_bytes, record + tombstone);
+
+    // Overwrites and transactions are counted the same at runtime and on open.
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.transaction(vec![
+        Op::Set { key: "key3".to_owned(), value: "value5".to_owned() },
+        Op::Remove { key: "key2".to_owned() },
+    ])?;
+    // The transaction is framed by two markers of just a header.
+    let uncompacted = store.stats().uncompacted_bytes;
+    assert_eq!(uncompacted, 2 * (record + tombstone) + 2 * record + 2 * 16);
+    drop(store);
+    let store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.stats().uncompacted_bytes, uncompacted);
+    Ok(())
+}
+
+// `retain` should remove the rejected entries as tombstones and count them.
+#[test]
+fn retain() -> Result<()> {
+    for config in layouts() {
+        let config = config.with_auto_compaction(false);
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for i in 0..20 {
+            store.set(format!("key{:02}", i), "v".repeat(i))?;
+        }
+
+        let mut lens = HashMap::new();
+        let stats = store.retain(|key, meta| {
+            assert!(meta.timestamp() > 0);
+            lens.insert(key.to_owned(), meta.value_len());
+            meta.value_len() < 10
+        })?;
+        assert_eq!(stats, RetainStats { kept: 10, removed: 10 });
+        assert_eq!(lens.len(), 20);
+        assert_eq!(lens["key07"], 7);
+        assert_eq!(store.len(), 10);
+        assert_eq!(store.get("key09".to_owned())?, Some("v".repeat(9)));
+        assert_eq!(store.get("key10".to_owned())?, None);
+
+        // Each removal counts its record and tombstone, the same as after reopening.
+        let uncompacted = store.stats().uncompacted_bytes;
+        assert!(uncompacted >= 10 * (16 + 5));
+        drop(store);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.stats().uncompacted_bytes, uncompacted);
+        assert_eq!(store.len(), 10);
+
+        assert_eq!(store.retain(|_, _| true)?, RetainStats { kept: 10, removed: 0 });
+        assert_eq!(store.retain(|_, _| false)?, RetainStats { kept: 0, removed: 10 });
+        assert!(store.is_empty());
+    }
+    Ok(())
+}
+
+// With auto-compaction disabled, stale data should pile up until
+// `compact_if_needed` is called.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_auto_compaction(false);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert!(!store.compact_if_needed()?);
+
+    let value = "v".repeat(1000);
+    let mut uncompacted = 0;
+    for iter in 0..3000 {
+        store.set(format!("key{}", iter % 10), value.clone())?;
+        let stats = store.stats();
+        assert!(stats.uncompacted_bytes >= uncompacted);
+        uncompacted = stats.uncompacted_bytes;
+    }
+    assert!(uncompacted > 2 * 1024 * 1024);
+
+    assert!(store.compact_if_needed()?);
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+    assert!(!store.compact_if_needed()?);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// A read-only store should open and serve reads from a directory it cannot
+// write to, and refuse all writes.
+#[cfg(unix)]
+#[test]
+fn read_only() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let set_mode = |mode| -> Result<()> {
+        for entry in std::fs::read_dir(temp_dir.path())? {
+            let path = entry?.path();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode & 0o666))?;
+        }
+        std::fs::set_permissions(temp_dir.path(), std::fs::Permissions::from_mode(mode))?;
+        Ok(())
+    };
+    set_mode(0o555)?;
+    let listing = || -> Vec<(std::path::PathBuf, u64)> {
+        let mut files: Vec<_> = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|e| e.unwrap())
+            .map(|e| (e.path().to_owned(), e.metadata().unwrap().len()))
+            .collect();
+        files.sort();
+        files
+    };
+    let before = listing();
+
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key42".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(store.scan_unordered().count(), 99);
+    assert!(matches!(store.set("key".to_owned(), "value".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(store.remove("key1".to_owned()), Err(KvsError::ReadOnly)));
+    assert!(matches!(store.compact_if_needed(), Err(KvsError::ReadOnly)));
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert_eq!(listing(), before);
+
+    set_mode(0o755)?;
+    Ok(())
+}
+
+// With a value log, large values live in `*.vlog` files and every read path
+// follows the pointer to them.
+#[test]
+fn value_log() -> Result<()> {
+    store_semantics(Config::default().with_value_log(true).with_value_log_threshold(0))?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_value_log(true).with_value_log_threshold(100);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    let large = "0123456789".repeat(100);
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), large.clone())?;
+    // Only the large value goes to the value log.
+    let vlog_size = std::fs::metadata(temp_dir.path().join("1.vlog"))?.len();
+    assert!(vlog_size > 1000 && vlog_size < 1100);
+    assert!(std::fs::metadata(temp_dir.path().join("1.log"))?.len() < 200);
+    assert_eq!(std::fs::read_to_string(temp_dir.path().join("VERSION"))?, "3\n");
+
+    let mut value = String::new();
+    std::io::Read::read_to_string(&mut store.get_reader("large")?.unwrap(), &mut value)?;
+    assert_eq!(value, large);
+    let mut pairs: Vec<_> = store.scan_unordered().collect::<Result<_>>()?;
+    pairs.sort();
+    assert_eq!(pairs, vec![("large".to_owned(), large.clone()), ("small".to_owned(), "value".to_owned())]);
+    let values: Vec<_> = store.log_cursor().map(|r| r.unwrap().value.unwrap()).collect();
+    assert_eq!(values, vec![b"value".to_vec(), large.clone().into_bytes()]);
+
+    // Compaction of the key log leaves the value log alone.
+    store.compact()?;
+    assert_eq!(std::fs::metadata(temp_dir.path().join("1.vlog"))?.len(), vlog_size);
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+
+    let dest = temp_dir.path().join("copy");
+    store.compact_to(&dest)?;
+    let mut copy = KvStore::open(&dest)?;
+    assert_eq!(copy.get("large".to_owned())?, Some(large.clone()));
+
+    // Value logs stay readable with the value log disabled.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// `gc_value_log` should reclaim the space of overwritten values and keep the
+// live ones readable.
+#[test]
+fn value_log_gc() -> Result<()> {
+    let vlog_size = |path: &std::path::Path| -> u64 {
+        std::fs::read_dir(path)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "vlog"))
+            .map(|p| std::fs::metadata(p).unwrap().len())
+            .sum()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default()
+        .with_value_log(true)
+        .with_auto_compaction(false)
+        .with_max_log_size(64 * 1024);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for iter in 0..10 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, "x".repeat(8 * 1024)))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let garbage = store.stats().value_log_garbage_bytes;
+    assert!(garbage > 9 * 19 * 8 * 1024);
+
+    // The garbage is counted again on reopen.
+    drop(store);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    assert_eq!(store.stats().value_log_garbage_bytes, garbage);
+
+    let before = vlog_size(temp_dir.path());
+    store.gc_value_log()?;
+    assert_eq!(store.stats().value_log_garbage_bytes, 0);
+    assert_eq!(vlog_size(temp_dir.path()), before - garbage);
+
+    drop(store);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.stats().value_log_garbage_bytes, 0);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("9{}", "x".repeat(8 * 1024))));
+    }
+    assert_eq!(store.log_cursor().count(), 19);
+    Ok(())
+}
+
+// `warm_up` should read every live record once, or only the requested keys,
+// and stay within its byte budget.
+#[test]
+fn warm_up() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        for iter in 0..2 {
+            for key_id in 0..100 {
+                store.set(format!("key{:03}", key_id), format!("value{}", iter))?;
+            }
+        }
+        drop(store);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+
+        let all = store.warm_up(None, u64::MAX)?;
+        assert_eq!(all.records, 100);
+        assert!(all.bytes_read > 0);
+
+        let keys = vec!["key001".to_owned(), "key002".to_owned(), "missing".to_owned()];
+        let some = store.warm_up(Some(&keys), u64::MAX)?;
+        assert_eq!(some.records, 2);
+        assert_eq!(some.bytes_read * 50, all.bytes_read);
+
+        // The budget stops reading before it is exceeded.
+        let bounded = store.warm_up(None, all.bytes_read / 2)?;
+        assert!(bounded.records < 100);
+        assert!(bounded.bytes_read <= all.bytes_read / 2);
+        assert_eq!(store.warm_up(None, 0)?.records, 0);
+    }
+    Ok(())
+}
+
+// `remove_range` should remove exactly the keys within the range, durably.
+#[test]
+fn remove_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("k{:02}", i), format!("value{}", i))?;
+    }
+
+    assert_eq!(store.remove_range("k10".to_owned().."k20".to_owned())?, 10);
+    assert_eq!(store.stats().keys, 90);
+    assert_eq!(store.remove_range("k10".to_owned().."k20".to_owned())?, 0);
+    assert_eq!(store.remove_range("k20".to_owned().."k10".to_owned())?, 0);
+    assert_eq!(store.remove_range("k20".to_owned().."k20".to_owned())?, 0);
+    assert_eq!(store.remove_range("k95".to_owned()..)?, 5);
+    assert_eq!(store.remove_range(..="k02".to_owned())?, 3);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        let removed = (10..20).contains(&i) || i >= 95 || i <= 2;
+        let expected = if removed { None } else { Some(format!("value{}", i)) };
+        assert_eq!(store.get(format!("k{:02}", i))?, expected);
+    }
+    drop(store);
+
+    let config = Config::default().with_index_kind(IndexKind::Hash);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert!(matches!(
+        store.remove_range("k00".to_owned().."k10".to_owned()),
+        Err(KvsError::UnsupportedOperation(_))
+    ));
+    Ok(())
+}
+
+// `remove_prefix` should remove every key with the prefix, in several
+// batches for large ranges, and leave the uncompacted bytes as a reopen sees them.
+#[test]
+fn remove_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = || Config::default().with_auto_compaction(false);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config())?;
+    for i in 0..3000 {
+        store.set(format!("tenant1/{:04}", i), "value".to_owned())?;
+    }
+    store.set("tenant10".to_owned(), "value".to_owned())?;
+    store.set("tenant2/0000".to_owned(), "value".to_owned())?;
+
+    assert_eq!(store.remove_prefix("tenant1/")?, 3000);
+    assert_eq!(store.remove_prefix("tenant1/")?, 0);
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["tenant10", "tenant2/0000"]);
+    let stats = store.stats();
+
+    drop(store);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config())?;
+    assert_eq!(store.stats(), stats);
+    assert_eq!(store.get("tenant1/0042".to_owned())?, None);
+    assert_eq!(store.remove_prefix("")?, 2);
+    assert_eq!(store.stats().keys, 0);
+    Ok(())
+}
+
+// Every `flush_async` handle should complete, in order, and cover records of
+// sealed log files as well.
+#[test]
+fn flush_async() -> Result<()> {
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = config.with_max_log_size(1024);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        let mut handles = Vec::new();
+        for iter in 0..10 {
+            for key_id in 0..50 {
+                store.set(format!("key{}", key_id), format!("value{}-{}", key_id, iter))?;
+            }
+            handles.push(store.flush_async());
+        }
+        assert!(store.stats().generations > 10);
+        for handle in handles {
+            handle.wait()?;
+        }
+
+        drop(store);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        for key_id in 0..50 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}-9", key_id)));
+        }
+    }
+
+    // A read-only store has nothing to flush.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key".to_owned(), "value".to_owned())?;
+    KvStore::open_read_only(temp_dir.path())?.flush_async().wait()?;
+    Ok(())
+}
+
+// Log files without a file header (version 0) and with a version 1 header
+// should both open, and unknown file format versions should be rejected.
+#[test]
+fn file_header() -> Result<()> {
+    fn record(timestamp: u64, key: &str, value: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&timestamp.to_le_bytes());
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key.as_bytes());
+        buf.extend_from_slice(value.as_bytes());
+        buf
+    }
+
+    // Version 0: records start right at the beginning of the file.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(temp_dir.path().join("1.log"), record(1, "key1", "value1"))?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    // Compaction migrates the data to files with a header.
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.scan_unordered().count(), 2);
+
+    // Version 1: new generations start with the header.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log = std::fs::read(temp_dir.path().join("1.log"))?;
+    assert_eq!(&log[..6], b"KVSL\x01\x00");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.log_cursor().count(), 1);
+
+    // An unknown version is rejected instead of being read as records.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut log = b"KVSL\x07\x00".to_vec();
+    log.extend_from_slice(&record(1, "key1", "value1"));
+    std::fs::write(temp_dir.path().join("1.log"), log)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat(7)) => {}
+        other => panic!("expected UnsupportedFormat, got {:?}", other.map(|_| ())),
+    }
+    Ok(())
+}
+
+// `len` should match a `HashMap` fed the same random operations, across
+// overwrites, removes of missing keys, compaction and reopen.
+#[test]
+fn len_matches_model() -> Result<()> {
+    use rand::prelude::*;
+
+    for config in layouts() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = config.with_max_log_size(4096).with_auto_compaction(false);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+        let mut model = HashMap::new();
+        assert!(store.is_empty());
+
+        let mut rng = SmallRng::from_seed([0; 16]);
+        for i in 0..2000 {
+            let key = format!("key{}", rng.gen_range(0, 100));
+            match rng.gen_range(0, 10) {
+                0..=5 => {
+                    store.set(key.clone(), format!("value{}", i))?;
+                    model.insert(key, i);
+                }
+                6..=8 => {
+                    let removed = store.remove(key.clone());
+                    assert_eq!(removed.is_ok(), model.remove(&key).is_some());
+                }
+                _ => store.compact()?,
+            }
+            assert_eq!(store.len(), model.len());
+            assert_eq!(store.is_empty(), model.is_empty());
+        }
+
+        drop(store);
+        let store = KvStore::open_with_config(temp_dir.path(), config)?;
+        assert_eq!(store.len(), model.len());
+        assert_eq!(store.len(), store.scan_unordered().count());
+    }
+    Ok(())
+}
+
+// Repeated values should be stored once and stay readable after the key that
+// first wrote them is gone, across compaction and reopen.
+#[test]
+fn dedup() -> Result<()> {
+    let dir_size = |path: &std::path::Path| -> u64 {
+        WalkDir::new(path)
+            .into_iter()
+            .map(|res| res.and_then(|entry| entry.metadata()).unwrap().len())
+            .sum()
+    };
+    let value = "0123456789".repeat(100);
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(plain_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    assert_eq!(store.stats().dedup_bytes_saved, 0);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_dedup(true).with_max_log_size(10 * 1024);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config.clone())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    store.set("other".to_owned(), "x".repeat(100))?;
+    assert!(dir_size(temp_dir.path()) < dir_size(plain_dir.path()) / 10);
+    assert!(store.stats().dedup_bytes_saved > 0);
+    assert_eq!(store.stats().keys, 101);
+
+    // The key holding the original value is overwritten, then removed.
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.remove("key0".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some(value.clone()));
+    // Values written after compaction are still deduplicated.
+    let saved = store.stats().dedup_bytes_saved;
+    store.set("key100".to_owned(), value.clone())?;
+    assert!(store.stats().dedup_bytes_saved > saved);
+
+    drop(store);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..101 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+    let mut pairs = store.scan_unordered().collect::<Result<Vec<_>>>()?;
+    pairs.sort();
+    assert_eq!(pairs.len(), 101);
+    assert!(pairs.iter().all(|(k, v)| *v == value || k == "other"));
+    assert!(store.log_cursor().all(|r| r.unwrap().key != b""));
+    let mut read = String::new();
+    std::io::Read::read_to_string(&mut store.get_reader("key2")?.unwrap(), &mut read)?;
+    assert_eq!(read, value);
+
+    // `compact_to` copies the referenced data as well.
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    store.compact_to(dest.path())?;
+    let mut copy = KvStore::open(dest.path())?;
+    assert_eq!(copy.get("key50".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Large compressible values take less space on disk than their raw size.
+#[cfg(feature = "compression")]
+#[test]
+fn compress_large_values() -> Result<()> {
+    use kvs::Codec;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = Config::default().with_compression(Codec::Lz4);
+    let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+
+    let value = "abcdefgh".repeat(64 * 1024 / 8);
+    store.set("big".to_owned(), value.clone())?;
+    store.set("small".to_owned(), "tiny".to_owned())?;
+    let on_disk = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+    assert!(on_disk < value.len() as u64 / 4, "log is {} bytes", on_disk);
+    assert_eq!(store.get("big".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("tiny".to_owned()));
+    let mut read = String::new();
+    std::io::Read::read_to_string(&mut store.get_reader("big")?.unwrap(), &mut read)?;
+    assert_eq!(read, value);
+
+    // Compressed records are readable without the codec configured.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some(value));
+    assert_eq!(store.get("small".to_owned())?, Some("tiny".to_owned()));
+    Ok(())
 }